@@ -0,0 +1,595 @@
+//! Certificates issued by an ACME CA (RFC 8555), such as Let's Encrypt.
+//!
+//! Domain control is proven with the `tls-alpn-01` challenge (RFC 8737),
+//! answered by the public listener itself through [`CertStore`], so no extra
+//! port is needed. The CA connects to port 443 of each domain, which must
+//! reach `VRAM_SUPPLY_PORT`. The account key, certificate and its key are
+//! kept in `~/.vram-supply/acme/`.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use reqwest::header::{HeaderMap, CONTENT_TYPE, LOCATION};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::pki_types::PrivateKeyDer;
+use rustls::sign::CertifiedKey;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::Config;
+use crate::tls::{self, CertStore};
+
+/// Let's Encrypt's production directory, used unless another is configured.
+pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+/// Renew once the certificate expires within this long.
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 3600);
+const RENEW_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+const RENEW_RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: u32 = 60;
+
+const ACCOUNT_KEY_FILE: &str = "account.key";
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+
+/// Where the account key and issued certificate are kept.
+pub fn dir() -> Result<PathBuf> {
+    Ok(crate::config::state_dir()?.join("acme"))
+}
+
+/// The issued certificate chain and its key in `dir`.
+pub fn stored_files(dir: &Path) -> (PathBuf, PathBuf) {
+    (dir.join(CERT_FILE), dir.join(KEY_FILE))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    #[serde(default)]
+    certificate: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authorization {
+    identifier: Identifier,
+    status: String,
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+    #[serde(default)]
+    error: Option<Value>,
+}
+
+/// The ACME account's P-256 key, which signs every request (JWS ES256).
+struct AccountKey {
+    pair: EcdsaKeyPair,
+    rng: SystemRandom,
+}
+
+impl AccountKey {
+    fn from_pkcs8(pkcs8: &[u8]) -> Result<Self> {
+        let rng = SystemRandom::new();
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8, &rng)
+            .map_err(|e| anyhow::anyhow!("Invalid ACME account key: {}", e))?;
+        Ok(AccountKey { pair, rng })
+    }
+
+    /// Public key coordinates, base64url.
+    fn coordinates(&self) -> (String, String) {
+        // Uncompressed point: 0x04 || x || y.
+        let point = self.pair.public_key().as_ref();
+        (
+            URL_SAFE_NO_PAD.encode(&point[1..33]),
+            URL_SAFE_NO_PAD.encode(&point[33..65]),
+        )
+    }
+
+    fn jwk(&self) -> Value {
+        let (x, y) = self.coordinates();
+        json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y })
+    }
+
+    /// RFC 7638 thumbprint: members in lexicographic order, no whitespace.
+    fn thumbprint(&self) -> String {
+        let (x, y) = self.coordinates();
+        let jwk = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+        URL_SAFE_NO_PAD.encode(Sha256::digest(jwk.as_bytes()))
+    }
+
+    /// A flattened JWS over `payload`, which is empty for POST-as-GET.
+    fn sign(&self, protected: &Value, payload: Option<&Value>) -> Result<Value> {
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        let payload = payload
+            .map(|p| URL_SAFE_NO_PAD.encode(p.to_string()))
+            .unwrap_or_default();
+        let signature = self
+            .pair
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| anyhow::anyhow!("Failed to sign ACME request"))?;
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        }))
+    }
+}
+
+/// One conversation with the CA: directory, replay nonce and account URL.
+struct AcmeSession<'a> {
+    http: &'a reqwest::Client,
+    directory: Directory,
+    key: &'a AccountKey,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl<'a> AcmeSession<'a> {
+    async fn open(http: &'a reqwest::Client, url: &str, key: &'a AccountKey) -> Result<Self> {
+        let res = http
+            .get(url)
+            .send()
+            .await
+            .with_context(|| format!("Failed to fetch ACME directory {}", url))?;
+        if !res.status().is_success() {
+            bail!("ACME directory {} returned HTTP {}", url, res.status());
+        }
+        let directory = res.json().await.context("Invalid ACME directory")?;
+        Ok(AcmeSession {
+            http,
+            directory,
+            key,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    fn keep_nonce(&mut self, headers: &HeaderMap) {
+        if let Some(nonce) = headers.get("replay-nonce").and_then(|v| v.to_str().ok()) {
+            self.nonce = Some(nonce.to_string());
+        }
+    }
+
+    async fn nonce(&mut self) -> Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let res = self
+            .http
+            .head(&self.directory.new_nonce)
+            .send()
+            .await
+            .context("Failed to get an ACME nonce")?;
+        self.keep_nonce(res.headers());
+        self.nonce.take().context("ACME server sent no nonce")
+    }
+
+    /// POST a signed request, retrying once on a stale nonce.
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<reqwest::Response> {
+        let mut retried = false;
+        loop {
+            let mut protected = json!({
+                "alg": "ES256",
+                "nonce": self.nonce().await?,
+                "url": url,
+            });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.key.jwk(),
+            }
+            let body = self.key.sign(&protected, payload)?;
+            let res = self
+                .http
+                .post(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(body.to_string())
+                .send()
+                .await
+                .with_context(|| format!("ACME request to {} failed", url))?;
+            self.keep_nonce(res.headers());
+            if res.status().is_success() {
+                return Ok(res);
+            }
+            let status = res.status();
+            let problem: Problem = res.json().await.unwrap_or(Problem {
+                kind: String::new(),
+                detail: String::new(),
+            });
+            if problem.kind.ends_with(":badNonce") && !retried {
+                retried = true;
+                continue;
+            }
+            bail!(
+                "ACME request to {} failed ({}): {} {}",
+                url,
+                status,
+                problem.kind,
+                problem.detail
+            );
+        }
+    }
+
+    async fn post_json<T: DeserializeOwned>(
+        &mut self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<(T, Option<String>)> {
+        let res = self.post(url, payload).await?;
+        let location = res
+            .headers()
+            .get(LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let body = res
+            .json()
+            .await
+            .with_context(|| format!("Invalid ACME response from {}", url))?;
+        Ok((body, location))
+    }
+
+    /// Create the account, or look up the one this key already has.
+    async fn account(&mut self, email: Option<&str>) -> Result<()> {
+        let mut payload = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = email {
+            payload["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let url = self.directory.new_account.clone();
+        let (_, location): (Value, _) = self.post_json(&url, Some(&payload)).await?;
+        self.kid = Some(location.context("ACME account response has no Location")?);
+        Ok(())
+    }
+
+    /// Poll a resource until its status leaves `pending`/`processing`.
+    async fn poll<T: DeserializeOwned>(&mut self, url: &str, status: fn(&T) -> &str) -> Result<T> {
+        for _ in 0..POLL_ATTEMPTS {
+            let (resource, _): (T, _) = self.post_json(url, None).await?;
+            if !matches!(status(&resource), "pending" | "processing") {
+                return Ok(resource);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        bail!("Timed out waiting for ACME resource {}", url)
+    }
+}
+
+/// Self-signed certificate proving control of `domain` for `tls-alpn-01`.
+/// Built from its own key, so it skips [`tls::certified_key`]'s key check,
+/// which would reject the critical `acmeIdentifier` extension.
+fn challenge_cert(domain: &str, key_authorization: &str) -> Result<CertifiedKey> {
+    let key = rcgen::KeyPair::generate()?;
+    let mut params = rcgen::CertificateParams::new(vec![domain.to_string()])?;
+    params
+        .custom_extensions
+        .push(rcgen::CustomExtension::new_acme_identifier(
+            &Sha256::digest(key_authorization.as_bytes()),
+        ));
+    let cert = params.self_signed(&key)?;
+    let key = PrivateKeyDer::Pkcs8(key.serialize_der().into());
+    let signing_key = rustls::crypto::ring::sign::any_supported_type(&key)
+        .context("Unsupported challenge key type")?;
+    Ok(CertifiedKey::new(vec![cert.der().clone()], signing_key))
+}
+
+/// Keeps an ACME-issued certificate for the configured domains in a
+/// [`CertStore`], renewing it before it expires.
+pub struct AcmeManager {
+    http: reqwest::Client,
+    directory: String,
+    domains: Vec<String>,
+    email: Option<String>,
+    store: Arc<CertStore>,
+    dir: PathBuf,
+}
+
+impl AcmeManager {
+    pub fn new(config: &Config, store: Arc<CertStore>, dir: PathBuf) -> Result<Self> {
+        let mut http = reqwest::Client::builder();
+        if let Some(ca) = &config.acme_ca_cert {
+            let pem = std::fs::read(ca)
+                .with_context(|| format!("Failed to read ACME CA certificate {}", ca.display()))?;
+            http = http.add_root_certificate(
+                reqwest::Certificate::from_pem(&pem).context("Invalid ACME CA certificate")?,
+            );
+        }
+        Ok(AcmeManager {
+            http: http.build().context("Failed to build ACME HTTP client")?,
+            directory: config.acme_directory.clone(),
+            domains: config.acme_domains.clone(),
+            email: config.acme_email.clone(),
+            store,
+            dir,
+        })
+    }
+
+    /// Serve the stored certificate if it covers our domains, returning its
+    /// expiry.
+    fn load_stored(&self) -> Option<u64> {
+        let (cert, key) = stored_files(&self.dir);
+        let cert_pem = std::fs::read(cert).ok()?;
+        let key_pem = std::fs::read(key).ok()?;
+        let info = tls::inspect(&cert_pem).ok()?;
+        let covered = self
+            .domains
+            .iter()
+            .all(|d| info.dns_names.iter().any(|n| n.eq_ignore_ascii_case(d)));
+        if !covered {
+            return None;
+        }
+        match tls::certified_key(&cert_pem, &key_pem) {
+            Ok(certified) => {
+                self.store.set(certified);
+                Some(info.not_after)
+            }
+            Err(e) => {
+                tracing::warn!("Ignoring stored ACME certificate: {:#}", e);
+                None
+            }
+        }
+    }
+
+    fn due(not_after: u64) -> bool {
+        not_after.saturating_sub(crate::outbox::unix_now()) < RENEW_BEFORE.as_secs()
+    }
+
+    /// Serve a valid certificate, issuing one if none is stored or the stored
+    /// one is due for renewal. The public listener must already be serving
+    /// the store, so the CA can validate through it.
+    pub async fn ensure(&self) -> Result<()> {
+        match self.load_stored() {
+            Some(not_after) if !Self::due(not_after) => {
+                tracing::info!(
+                    "Using stored ACME certificate for {}, valid until {}",
+                    self.domains.join(", "),
+                    crate::transitions::format_timestamp(not_after)
+                );
+                Ok(())
+            }
+            _ => self.issue().await,
+        }
+    }
+
+    fn account_key(&self) -> Result<AccountKey> {
+        let path = self.dir.join(ACCOUNT_KEY_FILE);
+        let pem = match std::fs::read_to_string(&path) {
+            Ok(pem) => pem,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let pem = rcgen::KeyPair::generate()?.serialize_pem();
+                crate::service::write_private(&path, &pem)?;
+                pem
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let key = rcgen::KeyPair::from_pem(&pem)
+            .with_context(|| format!("Invalid ACME account key {}", path.display()))?;
+        AccountKey::from_pkcs8(&key.serialize_der())
+    }
+
+    /// Order a certificate for our domains and start serving it.
+    #[tracing::instrument(name = "acme_issue", skip_all, fields(domains = %self.domains.join(",")))]
+    pub async fn issue(&self) -> Result<()> {
+        tracing::info!("Requesting a certificate from {}", self.directory);
+        let account_key = self.account_key()?;
+        let mut session = AcmeSession::open(&self.http, &self.directory, &account_key).await?;
+        session.account(self.email.as_deref()).await?;
+
+        let identifiers: Vec<Value> = self
+            .domains
+            .iter()
+            .map(|d| json!({ "type": "dns", "value": d }))
+            .collect();
+        let new_order = session.directory.new_order.clone();
+        let (order, location): (Order, _) = session
+            .post_json(&new_order, Some(&json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = location.context("ACME order response has no Location")?;
+
+        for authz_url in &order.authorizations {
+            let (authz, _): (Authorization, _) = session.post_json(authz_url, None).await?;
+            if authz.status == "valid" {
+                continue;
+            }
+            let domain = authz.identifier.value;
+            let challenge = authz
+                .challenges
+                .iter()
+                .find(|c| c.kind == "tls-alpn-01")
+                .with_context(|| format!("CA offers no tls-alpn-01 challenge for {}", domain))?;
+            let key_authorization = format!("{}.{}", challenge.token, account_key.thumbprint());
+            self.store
+                .set_challenge(&domain, challenge_cert(&domain, &key_authorization)?);
+            let result = async {
+                session
+                    .post_json::<Value>(&challenge.url, Some(&json!({})))
+                    .await?;
+                session
+                    .poll::<Authorization>(authz_url, |a| a.status.as_str())
+                    .await
+            }
+            .await;
+            self.store.remove_challenge(&domain);
+            let authz = result?;
+            if authz.status != "valid" {
+                let error = authz
+                    .challenges
+                    .iter()
+                    .find_map(|c| c.error.as_ref())
+                    .map(|e| e.to_string())
+                    .unwrap_or_default();
+                bail!(
+                    "Validation of {} failed: {} {}",
+                    domain,
+                    authz.status,
+                    error
+                );
+            }
+        }
+
+        let key = rcgen::KeyPair::generate()?;
+        let csr = rcgen::CertificateParams::new(self.domains.clone())?.serialize_request(&key)?;
+        session
+            .post_json::<Value>(
+                &order.finalize,
+                Some(&json!({ "csr": URL_SAFE_NO_PAD.encode(csr.der()) })),
+            )
+            .await?;
+        let order = session
+            .poll::<Order>(&order_url, |o| o.status.as_str())
+            .await?;
+        let cert_url = match (order.status.as_str(), order.certificate) {
+            ("valid", Some(url)) => url,
+            (status, _) => bail!("ACME order ended {}", status),
+        };
+        let chain = session.post(&cert_url, None).await?.text().await?;
+
+        let key_pem = key.serialize_pem();
+        let certified = tls::certified_key(chain.as_bytes(), key_pem.as_bytes())?;
+        let (cert, key) = stored_files(&self.dir);
+        crate::service::write_private(&key, &key_pem)?;
+        crate::service::write_private(&cert, &chain)?;
+        self.store.set(certified);
+        tracing::info!("Issued certificate for {}", self.domains.join(", "));
+        Ok(())
+    }
+
+    /// Spawn the renewal loop. Checks twice a day and retries hourly after a
+    /// failure; the current certificate keeps being served meanwhile.
+    pub fn spawn_renewal(self, shutdown: CancellationToken) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut wait = RENEW_CHECK_INTERVAL;
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = tokio::time::sleep(wait) => {}
+                }
+                let due = self.load_stored().is_none_or(Self::due);
+                wait = match due {
+                    false => RENEW_CHECK_INTERVAL,
+                    true => match self.issue().await {
+                        Ok(()) => RENEW_CHECK_INTERVAL,
+                        Err(e) => {
+                            tracing::warn!("Certificate renewal failed: {:#}", e);
+                            RENEW_RETRY_INTERVAL
+                        }
+                    },
+                };
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+
+    fn account_key() -> AccountKey {
+        AccountKey::from_pkcs8(&rcgen::KeyPair::generate().unwrap().serialize_der()).unwrap()
+    }
+
+    #[test]
+    fn test_jws_verifies_with_jwk() {
+        let key = account_key();
+        let protected = json!({ "alg": "ES256", "nonce": "n", "url": "https://ca/x" });
+        let jws = key.sign(&protected, Some(&json!({ "a": 1 }))).unwrap();
+
+        let jwk = key.jwk();
+        let mut point = vec![4u8];
+        for coordinate in ["x", "y"] {
+            point.extend(
+                URL_SAFE_NO_PAD
+                    .decode(jwk[coordinate].as_str().unwrap())
+                    .unwrap(),
+            );
+        }
+        let signed = format!(
+            "{}.{}",
+            jws["protected"].as_str().unwrap(),
+            jws["payload"].as_str().unwrap()
+        );
+        let signature = URL_SAFE_NO_PAD
+            .decode(jws["signature"].as_str().unwrap())
+            .unwrap();
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, &point)
+            .verify(signed.as_bytes(), &signature)
+            .unwrap();
+
+        // POST-as-GET carries an empty payload.
+        assert_eq!(key.sign(&protected, None).unwrap()["payload"], "");
+        assert_eq!(key.thumbprint().len(), 43);
+    }
+
+    #[test]
+    fn test_challenge_cert_carries_key_authorization_digest() {
+        let certified = challenge_cert("node.example.com", "token.thumb").unwrap();
+        let (_, cert) = x509_parser::parse_x509_certificate(&certified.cert[0]).unwrap();
+        let ext = cert
+            .extensions()
+            .iter()
+            .find(|e| e.oid.to_id_string() == "1.3.6.1.5.5.7.1.31")
+            .unwrap();
+        assert!(ext.critical);
+        // DER OCTET STRING of the SHA-256 digest.
+        assert_eq!(&ext.value[2..], Sha256::digest(b"token.thumb").as_slice());
+    }
+
+    /// Issues a certificate from a local Pebble (https://github.com/letsencrypt/pebble)
+    /// started with `PEBBLE_VA_ALWAYS_VALID=1`, e.g.
+    /// `PEBBLE_DIRECTORY=https://localhost:14000/dir PEBBLE_CA=pebble.minica.pem`.
+    #[tokio::test]
+    #[ignore]
+    async fn test_issue_from_pebble() {
+        let mut config = crate::test_support::config("http://127.0.0.1:1");
+        config.acme_directory = std::env::var("PEBBLE_DIRECTORY")
+            .unwrap_or_else(|_| "https://localhost:14000/dir".to_string());
+        config.acme_ca_cert = std::env::var("PEBBLE_CA").ok().map(PathBuf::from);
+        config.acme_domains = vec!["node.example.com".to_string()];
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(CertStore::default());
+        let manager =
+            AcmeManager::new(&config, Arc::clone(&store), dir.path().to_path_buf()).unwrap();
+
+        manager.ensure().await.unwrap();
+        let not_after = manager.load_stored().unwrap();
+        assert!(!AcmeManager::due(not_after));
+    }
+}
@@ -1,10 +1,15 @@
+use std::collections::VecDeque;
 use std::process::Stdio;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use anyhow::{Context, Result};
 use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
 use tokio::process::{Child, Command};
 
+use super::metrics::{slot_is_processing, MetricsSample, SlotUsage};
+
 const HEALTH_STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
 const GRACEFUL_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 const HEALTH_POLL_INTERVAL: Duration = Duration::from_millis(500);
@@ -12,6 +17,61 @@ const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
 const INITIAL_RESTART_BACKOFF: Duration = Duration::from_secs(1);
 const SLOTS_REQUEST_TIMEOUT: Duration = Duration::from_secs(3);
 const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Lines of llama-server output kept for `vramsply top`.
+const LOG_TAIL_LINES: usize = 200;
+
+/// The most recent lines llama-server wrote to stdout and stderr. Shared, so
+/// readers never wait on the `LlamaServer` lock.
+#[derive(Debug, Clone, Default)]
+pub struct LogTail(Arc<Mutex<VecDeque<String>>>);
+
+impl LogTail {
+    fn push(&self, line: String) {
+        let mut lines = self.0.lock().expect("log tail lock poisoned");
+        if lines.len() == LOG_TAIL_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// Up to `n` most recent lines, oldest first.
+    pub fn lines(&self, n: usize) -> Vec<String> {
+        let lines = self.0.lock().expect("log tail lock poisoned");
+        lines
+            .iter()
+            .skip(lines.len().saturating_sub(n))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Log llama-server output under the `llama_server` target, keeping a tail
+/// of it. Lines that are not valid UTF-8 are logged lossily rather than
+/// ending the copy.
+fn forward_output<R>(output: R, tail: LogTail, stream: &'static str)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut reader = BufReader::new(output);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf).await {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::warn!("Failed reading llama-server {}: {}", stream, e);
+                    break;
+                }
+            }
+            let line = String::from_utf8_lossy(&buf);
+            let line = line.trim_end_matches(['\r', '\n']);
+            tracing::info!(target: "llama_server", stream, "{}", line);
+            tail.push(line.to_string());
+        }
+    });
+}
 
 pub struct LlamaServer {
     child: Option<Child>,
@@ -21,6 +81,7 @@ pub struct LlamaServer {
     gpu_layers: u32,
     context_length: u32,
     restart_backoff: Duration,
+    log: LogTail,
 }
 
 impl LlamaServer {
@@ -39,11 +100,27 @@ impl LlamaServer {
             gpu_layers,
             context_length,
             restart_backoff: INITIAL_RESTART_BACKOFF,
+            log: LogTail::default(),
         }
     }
 
-    /// Best-effort estimate of currently active requests from /slots.
-    pub async fn active_requests(&self) -> Result<u32> {
+    /// Recent llama-server output, across restarts.
+    pub fn log_tail(&self) -> LogTail {
+        self.log.clone()
+    }
+
+    /// Process id of the running llama-server, if started.
+    pub fn pid(&self) -> Option<u32> {
+        self.child.as_ref().and_then(|c| c.id())
+    }
+
+    /// Point the server at a different model file. Takes effect on the next `start()`.
+    pub fn set_model_path(&mut self, model_path: String) {
+        self.model_path = model_path;
+    }
+
+    /// Fetch the raw `/slots` array.
+    async fn fetch_slots(&self) -> Result<Vec<Value>> {
         let url = format!("http://127.0.0.1:{}/slots", self.port);
         let client = reqwest::Client::builder()
             .timeout(SLOTS_REQUEST_TIMEOUT)
@@ -61,39 +138,39 @@ impl LlamaServer {
             body.get("slots").and_then(|v| v.as_array())
         };
 
-        let Some(slots) = slots else {
-            return Ok(0);
-        };
+        Ok(slots.cloned().unwrap_or_default())
+    }
 
-        let mut active = 0u32;
-        for slot in slots {
-            let is_processing = slot
-                .get("is_processing")
-                .and_then(|v| v.as_bool())
-                .unwrap_or(false);
-            let processing_i64 = slot
-                .get("is_processing")
-                .and_then(|v| v.as_i64())
-                .map(|v| v > 0)
-                .unwrap_or(false);
-            let state = slot
-                .get("state")
-                .and_then(|v| v.as_str())
-                .map(|v| matches!(v, "processing" | "running" | "active"))
-                .unwrap_or(false);
-
-            if is_processing || processing_i64 || state {
-                active += 1;
-            }
-        }
+    /// Best-effort estimate of currently active requests from /slots.
+    pub async fn active_requests(&self) -> Result<u32> {
+        let slots = self.fetch_slots().await?;
+        Ok(slots.iter().filter(|s| slot_is_processing(s)).count() as u32)
+    }
 
-        Ok(active)
+    /// Context usage and busy state of each slot, from `/slots`.
+    pub async fn slot_usage(&self) -> Result<Vec<SlotUsage>> {
+        Ok(SlotUsage::from_slots(&self.fetch_slots().await?))
+    }
+
+    /// Scrape `/metrics` for throughput and queue depth.
+    pub async fn metrics_sample(&self) -> Result<MetricsSample> {
+        let url = format!("http://127.0.0.1:{}/metrics", self.port);
+        let client = reqwest::Client::builder()
+            .timeout(SLOTS_REQUEST_TIMEOUT)
+            .build()?;
+
+        let response = client.get(&url).send().await?;
+        if !response.status().is_success() {
+            anyhow::bail!("/metrics returned HTTP {}", response.status());
+        }
+        Ok(MetricsSample::parse(&response.text().await?))
     }
 
     /// Start the llama-server subprocess.
+    #[tracing::instrument(name = "backend_start", skip_all, fields(model = %self.model_path, port = self.port))]
     pub async fn start(&mut self) -> Result<()> {
         tracing::info!(
-            "Starting llama-server: {} -m {} --host 127.0.0.1 --port {} -ngl {} --ctx-size {}",
+            "Starting llama-server: {} -m {} --host 127.0.0.1 --port {} -ngl {} --ctx-size {} --metrics",
             self.llama_server_path,
             self.model_path,
             self.port,
@@ -101,7 +178,7 @@ impl LlamaServer {
             self.context_length,
         );
 
-        let child = Command::new(&self.llama_server_path)
+        let mut child = Command::new(&self.llama_server_path)
             .arg("-m")
             .arg(&self.model_path)
             .arg("--host")
@@ -112,8 +189,9 @@ impl LlamaServer {
             .arg(self.gpu_layers.to_string())
             .arg("--ctx-size")
             .arg(self.context_length.to_string())
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
+            .arg("--metrics")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
             .spawn()
             .with_context(|| {
                 format!(
@@ -122,6 +200,13 @@ impl LlamaServer {
                 )
             })?;
 
+        if let Some(stdout) = child.stdout.take() {
+            forward_output(stdout, self.log.clone(), "stdout");
+        }
+        if let Some(stderr) = child.stderr.take() {
+            forward_output(stderr, self.log.clone(), "stderr");
+        }
+
         let pid = child.id().unwrap_or(0);
         tracing::info!("llama-server started with PID {}", pid);
         self.child = Some(child);
@@ -134,6 +219,7 @@ impl LlamaServer {
     }
 
     /// Stop the llama-server subprocess. Sends SIGTERM, then SIGKILL after 5 seconds.
+    #[tracing::instrument(name = "backend_stop", skip_all, fields(model = %self.model_path, port = self.port))]
     pub async fn stop(&mut self) -> Result<()> {
         if let Some(ref mut child) = self.child {
             let pid = child.id();
@@ -233,3 +319,22 @@ impl Drop for LlamaServer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_output_is_forwarded_past_invalid_utf8() {
+        let tail = LogTail::default();
+        forward_output(&b"first\n\xff\xfe bad\r\nlast"[..], tail.clone(), "stderr");
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while tail.lines(10).len() < 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(tail.lines(10), ["first", "\u{fffd}\u{fffd} bad", "last"]);
+    }
+}
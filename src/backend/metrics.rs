@@ -0,0 +1,332 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Window over which throughput is averaged.
+const UTILIZATION_WINDOW: Duration = Duration::from_secs(60);
+
+/// Counters and gauges scraped from llama-server's Prometheus `/metrics`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetricsSample {
+    pub prompt_tokens_total: f64,
+    pub predicted_tokens_total: f64,
+    pub requests_processing: f64,
+    pub requests_deferred: f64,
+    pub kv_cache_usage_ratio: Option<f64>,
+}
+
+impl MetricsSample {
+    /// Build a sample from Prometheus text exposition format.
+    pub fn parse(text: &str) -> Self {
+        let values = parse_prometheus(text);
+        let get = |name: &str| values.get(name).copied();
+        MetricsSample {
+            prompt_tokens_total: get("llamacpp:prompt_tokens_total").unwrap_or(0.0),
+            predicted_tokens_total: get("llamacpp:tokens_predicted_total").unwrap_or(0.0),
+            requests_processing: get("llamacpp:requests_processing").unwrap_or(0.0),
+            requests_deferred: get("llamacpp:requests_deferred").unwrap_or(0.0),
+            kv_cache_usage_ratio: get("llamacpp:kv_cache_usage_ratio"),
+        }
+    }
+}
+
+/// Context usage of a single llama-server slot.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SlotUsage {
+    pub id: u32,
+    pub n_ctx: u32,
+    /// Tokens currently held in the slot's context, if llama-server reports it.
+    pub n_ctx_used: Option<u32>,
+    pub is_processing: bool,
+}
+
+impl SlotUsage {
+    /// Parse slot entries from a `/slots` response.
+    pub fn from_slots(slots: &[Value]) -> Vec<Self> {
+        slots
+            .iter()
+            .enumerate()
+            .map(|(i, slot)| {
+                let uint = |key: &str| slot.get(key).and_then(|v| v.as_u64()).map(|v| v as u32);
+                SlotUsage {
+                    id: uint("id").unwrap_or(i as u32),
+                    n_ctx: uint("n_ctx").unwrap_or(0),
+                    n_ctx_used: uint("n_past"),
+                    is_processing: slot_is_processing(slot),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Whether a `/slots` entry is busy. Handles the boolean, integer and string
+/// `state` forms used by different llama-server versions.
+pub fn slot_is_processing(slot: &Value) -> bool {
+    let is_processing = slot
+        .get("is_processing")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    let processing_i64 = slot
+        .get("is_processing")
+        .and_then(|v| v.as_i64())
+        .map(|v| v > 0)
+        .unwrap_or(false);
+    let state = slot
+        .get("state")
+        .and_then(|v| v.as_str())
+        .map(|v| matches!(v, "processing" | "running" | "active"))
+        .unwrap_or(false);
+
+    is_processing || processing_i64 || state
+}
+
+/// Compact load summary published in presence so routing can prefer nodes
+/// with headroom.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct UtilizationSummary {
+    pub window_secs: u64,
+    pub prompt_tokens_per_sec: f64,
+    pub generation_tokens_per_sec: f64,
+    pub requests_processing: u32,
+    pub queue_depth: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kv_cache_usage_pct: Option<f64>,
+    pub slots: Vec<SlotUsage>,
+}
+
+impl UtilizationSummary {
+    /// Load across several llama-servers: throughput and queues add up, the
+    /// KV cache reports the fullest one, and slots are listed together.
+    pub fn combine<'a>(
+        summaries: impl IntoIterator<Item = &'a UtilizationSummary>,
+    ) -> Option<UtilizationSummary> {
+        let mut combined: Option<UtilizationSummary> = None;
+        for summary in summaries {
+            let Some(total) = combined.as_mut() else {
+                combined = Some(summary.clone());
+                continue;
+            };
+            total.window_secs = total.window_secs.max(summary.window_secs);
+            total.prompt_tokens_per_sec =
+                round2(total.prompt_tokens_per_sec + summary.prompt_tokens_per_sec);
+            total.generation_tokens_per_sec =
+                round2(total.generation_tokens_per_sec + summary.generation_tokens_per_sec);
+            total.requests_processing += summary.requests_processing;
+            total.queue_depth += summary.queue_depth;
+            total.kv_cache_usage_pct = match (total.kv_cache_usage_pct, summary.kv_cache_usage_pct)
+            {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                (a, b) => a.or(b),
+            };
+            total.slots.extend(summary.slots.iter().cloned());
+        }
+        combined
+    }
+}
+
+/// Rolling window of metrics samples used to derive throughput.
+#[derive(Debug, Default)]
+pub struct UtilizationTracker {
+    samples: VecDeque<(Instant, MetricsSample)>,
+}
+
+impl UtilizationTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a sample taken at `at` and return the summary over the window.
+    pub fn record(
+        &mut self,
+        at: Instant,
+        sample: MetricsSample,
+        slots: Vec<SlotUsage>,
+    ) -> UtilizationSummary {
+        // A counter going backwards means llama-server restarted; start over.
+        if let Some((_, last)) = self.samples.back() {
+            if sample.prompt_tokens_total < last.prompt_tokens_total
+                || sample.predicted_tokens_total < last.predicted_tokens_total
+            {
+                self.samples.clear();
+            }
+        }
+        self.samples.push_back((at, sample));
+        while self.samples.len() > 2 && at.duration_since(self.samples[0].0) > UTILIZATION_WINDOW {
+            self.samples.pop_front();
+        }
+
+        let (first_at, first) = &self.samples[0];
+        let (_, latest) = self.samples.back().expect("just pushed");
+        let elapsed = at.duration_since(*first_at).as_secs_f64();
+        let rate = |delta: f64| {
+            if elapsed > 0.0 {
+                round2(delta / elapsed)
+            } else {
+                0.0
+            }
+        };
+
+        UtilizationSummary {
+            window_secs: elapsed.round() as u64,
+            prompt_tokens_per_sec: rate(latest.prompt_tokens_total - first.prompt_tokens_total),
+            generation_tokens_per_sec: rate(
+                latest.predicted_tokens_total - first.predicted_tokens_total,
+            ),
+            requests_processing: latest.requests_processing as u32,
+            queue_depth: latest.requests_deferred as u32,
+            kv_cache_usage_pct: latest.kv_cache_usage_ratio.map(|r| round2(r * 100.0)),
+            slots,
+        }
+    }
+}
+
+fn round2(v: f64) -> f64 {
+    (v * 100.0).round() / 100.0
+}
+
+/// Parse unlabelled samples from Prometheus text format into name → value.
+/// Labelled series are keyed by their bare metric name (last one wins).
+fn parse_prometheus(text: &str) -> HashMap<String, f64> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let series = parts.next()?;
+            let value = parts.next()?.parse().ok()?;
+            let name = series.split('{').next().unwrap_or(series);
+            Some((name.to_string(), value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METRICS: &str = "\
+# HELP llamacpp:prompt_tokens_total Number of prompt tokens processed.
+# TYPE llamacpp:prompt_tokens_total counter
+llamacpp:prompt_tokens_total 1200
+llamacpp:tokens_predicted_total 300
+llamacpp:kv_cache_usage_ratio 0.425
+llamacpp:requests_processing 2
+llamacpp:requests_deferred 3
+";
+
+    fn sample(prompt: f64, predicted: f64) -> MetricsSample {
+        MetricsSample {
+            prompt_tokens_total: prompt,
+            predicted_tokens_total: predicted,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_metrics() {
+        let s = MetricsSample::parse(METRICS);
+        assert_eq!(s.prompt_tokens_total, 1200.0);
+        assert_eq!(s.predicted_tokens_total, 300.0);
+        assert_eq!(s.requests_processing, 2.0);
+        assert_eq!(s.requests_deferred, 3.0);
+        assert_eq!(s.kv_cache_usage_ratio, Some(0.425));
+
+        assert_eq!(MetricsSample::parse(""), MetricsSample::default());
+    }
+
+    #[test]
+    fn test_slot_usage() {
+        let slots: Vec<Value> = serde_json::from_str(
+            r#"[{"id":0,"n_ctx":4096,"n_past":1500,"is_processing":true},
+                {"id":1,"n_ctx":4096,"state":"idle"}]"#,
+        )
+        .unwrap();
+        let usage = SlotUsage::from_slots(&slots);
+        assert_eq!(usage[0].n_ctx_used, Some(1500));
+        assert!(usage[0].is_processing);
+        assert_eq!(usage[1].n_ctx_used, None);
+        assert!(!usage[1].is_processing);
+    }
+
+    #[test]
+    fn test_tracker_rates_over_window() {
+        let mut tracker = UtilizationTracker::new();
+        let t0 = Instant::now();
+
+        let first = tracker.record(t0, sample(0.0, 0.0), Vec::new());
+        assert_eq!(first.generation_tokens_per_sec, 0.0);
+
+        let summary = tracker.record(
+            t0 + Duration::from_secs(10),
+            sample(500.0, 200.0),
+            Vec::new(),
+        );
+        assert_eq!(summary.window_secs, 10);
+        assert_eq!(summary.prompt_tokens_per_sec, 50.0);
+        assert_eq!(summary.generation_tokens_per_sec, 20.0);
+
+        // Old samples fall out of the 60s window.
+        tracker.record(
+            t0 + Duration::from_secs(70),
+            sample(1500.0, 800.0),
+            Vec::new(),
+        );
+        let summary = tracker.record(
+            t0 + Duration::from_secs(80),
+            sample(1600.0, 900.0),
+            Vec::new(),
+        );
+        assert_eq!(summary.window_secs, 10);
+        assert_eq!(summary.generation_tokens_per_sec, 10.0);
+    }
+
+    #[test]
+    fn test_tracker_resets_on_counter_reset() {
+        let mut tracker = UtilizationTracker::new();
+        let t0 = Instant::now();
+        tracker.record(t0, sample(1000.0, 1000.0), Vec::new());
+        let summary = tracker.record(t0 + Duration::from_secs(5), sample(10.0, 5.0), Vec::new());
+        assert_eq!(summary.window_secs, 0);
+        assert_eq!(summary.generation_tokens_per_sec, 0.0);
+    }
+
+    #[test]
+    fn test_combine_summaries() {
+        let slot = |id| SlotUsage {
+            id,
+            n_ctx: 4096,
+            n_ctx_used: None,
+            is_processing: true,
+        };
+        let a = UtilizationSummary {
+            window_secs: 30,
+            prompt_tokens_per_sec: 10.5,
+            generation_tokens_per_sec: 2.25,
+            requests_processing: 1,
+            queue_depth: 0,
+            kv_cache_usage_pct: Some(40.0),
+            slots: vec![slot(0)],
+        };
+        let b = UtilizationSummary {
+            window_secs: 45,
+            prompt_tokens_per_sec: 1.0,
+            generation_tokens_per_sec: 1.0,
+            requests_processing: 2,
+            queue_depth: 3,
+            kv_cache_usage_pct: None,
+            slots: vec![slot(0), slot(1)],
+        };
+
+        assert_eq!(UtilizationSummary::combine([]), None);
+        assert_eq!(UtilizationSummary::combine([&a]), Some(a.clone()));
+        let total = UtilizationSummary::combine([&a, &b]).unwrap();
+        assert_eq!(total.window_secs, 45);
+        assert_eq!(total.prompt_tokens_per_sec, 11.5);
+        assert_eq!(total.generation_tokens_per_sec, 3.25);
+        assert_eq!((total.requests_processing, total.queue_depth), (3, 3));
+        assert_eq!(total.kv_cache_usage_pct, Some(40.0));
+        assert_eq!(total.slots.len(), 3);
+    }
+}
@@ -1,3 +1,4 @@
 pub mod llama_server;
+pub mod metrics;
 
-pub use llama_server::LlamaServer;
+pub use llama_server::{LlamaServer, LogTail};
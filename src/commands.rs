@@ -0,0 +1,290 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::connection::PlatformCommand;
+use crate::presence::AgentPresenceStatus;
+
+/// A validated instruction from the platform or the owner's dashboard.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AgentCommand {
+    /// Stop taking new work and shut down once in-flight requests finish.
+    Drain,
+    /// Swap a served model for another local model. `target` names the
+    /// served model to replace, and may be left out when only one is served.
+    ReloadModel {
+        model: String,
+        hf_repo: Option<String>,
+        target: Option<String>,
+    },
+    /// Change the advertised per-million-token prices of the served model
+    /// `target`; required when several models are served.
+    SetPrice {
+        input_price_per_million: Option<u32>,
+        output_price_per_million: Option<u32>,
+        target: Option<String>,
+    },
+    /// Shut down immediately.
+    Shutdown,
+}
+
+#[derive(Deserialize)]
+struct ReloadModelParams {
+    model: String,
+    hf_repo: Option<String>,
+    target: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SetPriceParams {
+    input_price_per_million: Option<u32>,
+    output_price_per_million: Option<u32>,
+    target: Option<String>,
+}
+
+impl AgentCommand {
+    /// Parse a raw platform command into a typed command.
+    pub fn parse(raw: &PlatformCommand) -> Result<Self> {
+        let params = || {
+            if raw.params.is_null() {
+                serde_json::Value::Object(Default::default())
+            } else {
+                raw.params.clone()
+            }
+        };
+        match raw.command.as_str() {
+            "drain" => Ok(AgentCommand::Drain),
+            "shutdown" => Ok(AgentCommand::Shutdown),
+            "reload_model" => {
+                let p: ReloadModelParams = serde_json::from_value(params())
+                    .context("reload_model requires a 'model' parameter")?;
+                if p.model.trim().is_empty() {
+                    bail!("reload_model 'model' must not be empty");
+                }
+                Ok(AgentCommand::ReloadModel {
+                    model: p.model,
+                    hf_repo: p.hf_repo,
+                    target: p.target,
+                })
+            }
+            "set_price" => {
+                let p: SetPriceParams =
+                    serde_json::from_value(params()).context("Invalid set_price parameters")?;
+                if p.input_price_per_million.is_none() && p.output_price_per_million.is_none() {
+                    bail!(
+                        "set_price requires input_price_per_million and/or output_price_per_million"
+                    );
+                }
+                Ok(AgentCommand::SetPrice {
+                    input_price_per_million: p.input_price_per_million,
+                    output_price_per_million: p.output_price_per_million,
+                    target: p.target,
+                })
+            }
+            other => bail!("Unknown command '{}'", other),
+        }
+    }
+
+    /// Check that the command makes sense from the agent's current status,
+    /// or that of the served model it targets.
+    ///
+    /// ```text
+    /// drain, shutdown → any status that can move to Unavailable
+    /// reload_model    → any status that can move to LoadingModel (not mid-request)
+    /// set_price       → Ready, Serving, Degraded (i.e. registered)
+    /// ```
+    pub fn validate(&self, status: &AgentPresenceStatus) -> Result<()> {
+        use AgentPresenceStatus::*;
+        let allowed = match self {
+            AgentCommand::Drain | AgentCommand::Shutdown => status.can_transition_to(&Unavailable),
+            AgentCommand::ReloadModel { .. } => status.can_transition_to(&LoadingModel),
+            AgentCommand::SetPrice { .. } => matches!(status, Ready | Serving | Degraded),
+        };
+        if !allowed {
+            bail!("{} is not allowed while {:?}", self.name(), status);
+        }
+        Ok(())
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AgentCommand::Drain => "drain",
+            AgentCommand::ReloadModel { .. } => "reload_model",
+            AgentCommand::SetPrice { .. } => "set_price",
+            AgentCommand::Shutdown => "shutdown",
+        }
+    }
+}
+
+/// Progress of a command, reported back to the platform and to the audit log.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandOutcome {
+    Accepted,
+    Rejected,
+    Completed,
+    Failed,
+}
+
+/// Acknowledgement of a command, sent to the platform.
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandAck {
+    pub id: String,
+    pub outcome: CommandOutcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+    timestamp: u64,
+    id: &'a str,
+    command: &'a str,
+    params: &'a serde_json::Value,
+    outcome: CommandOutcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<&'a str>,
+}
+
+/// Append one line to the JSONL command audit log at `path`.
+pub fn append_audit_log(
+    path: &Path,
+    raw: &PlatformCommand,
+    outcome: CommandOutcome,
+    message: Option<&str>,
+) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed creating directory {}", parent.display()))?;
+    }
+    let entry = AuditEntry {
+        timestamp: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        id: &raw.id,
+        command: &raw.command,
+        params: &raw.params,
+        outcome,
+        message,
+    };
+    let mut line = serde_json::to_string(&entry)?;
+    line.push('\n');
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed opening audit log {}", path.display()))?;
+    file.write_all(line.as_bytes())
+        .with_context(|| format!("Failed writing audit log {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(command: &str, params: serde_json::Value) -> PlatformCommand {
+        PlatformCommand {
+            id: "cmd-1".to_string(),
+            command: command.to_string(),
+            params,
+        }
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(
+            AgentCommand::parse(&raw("drain", serde_json::Value::Null)).unwrap(),
+            AgentCommand::Drain
+        );
+        assert_eq!(
+            AgentCommand::parse(&raw(
+                "reload_model",
+                serde_json::json!({"model": "llama-3.1-8b"})
+            ))
+            .unwrap(),
+            AgentCommand::ReloadModel {
+                model: "llama-3.1-8b".to_string(),
+                hf_repo: None,
+                target: None
+            }
+        );
+        assert_eq!(
+            AgentCommand::parse(&raw(
+                "set_price",
+                serde_json::json!({"output_price_per_million": 150, "target": "qwen"})
+            ))
+            .unwrap(),
+            AgentCommand::SetPrice {
+                input_price_per_million: None,
+                output_price_per_million: Some(150),
+                target: Some("qwen".to_string())
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_commands() {
+        assert!(AgentCommand::parse(&raw("format_disk", serde_json::Value::Null)).is_err());
+        assert!(AgentCommand::parse(&raw("reload_model", serde_json::Value::Null)).is_err());
+        assert!(AgentCommand::parse(&raw("set_price", serde_json::json!({}))).is_err());
+        assert!(AgentCommand::parse(&raw(
+            "set_price",
+            serde_json::json!({"input_price_per_million": -1})
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_validate_against_presence_status() {
+        use AgentPresenceStatus::*;
+        let reload = AgentCommand::ReloadModel {
+            model: "m".to_string(),
+            hf_repo: None,
+            target: None,
+        };
+        assert!(reload.validate(&Ready).is_ok());
+        assert!(reload.validate(&Error).is_ok());
+        assert!(reload.validate(&Serving).is_err());
+        assert!(reload.validate(&LoadingModel).is_err());
+
+        let price = AgentCommand::SetPrice {
+            input_price_per_million: Some(1),
+            output_price_per_million: None,
+            target: None,
+        };
+        assert!(price.validate(&Serving).is_ok());
+        assert!(price.validate(&Idle).is_err());
+
+        assert!(AgentCommand::Drain.validate(&Serving).is_ok());
+        assert!(AgentCommand::Shutdown.validate(&Unavailable).is_err());
+    }
+
+    #[test]
+    fn test_audit_log_appends_jsonl() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit").join("commands.jsonl");
+        let cmd = raw("drain", serde_json::Value::Null);
+
+        append_audit_log(&path, &cmd, CommandOutcome::Accepted, None).unwrap();
+        append_audit_log(&path, &cmd, CommandOutcome::Completed, Some("done")).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["outcome"], "accepted");
+        assert_eq!(lines[0]["command"], "drain");
+        assert!(lines[0].get("message").is_none());
+        assert_eq!(lines[1]["outcome"], "completed");
+        assert_eq!(lines[1]["message"], "done");
+    }
+}
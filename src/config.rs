@@ -1,3 +1,4 @@
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::str::FromStr;
 
@@ -11,16 +12,82 @@ pub struct Config {
     pub llama_server_path: String,
     pub gpu_layers: u32,
     pub port: u16,
+    pub bind_address: IpAddr,
+    pub backend_port: u16,
     pub max_concurrent: u32,
     pub context_length_offered: u32,
     pub input_price_per_million: u32,
     pub output_price_per_million: u32,
     pub api_key: String,
+    pub platform_channel: PlatformChannel,
+    pub drain_timeout_secs: u64,
+    pub shutdown_timeout_secs: u64,
+    /// Base64 Ed25519 keys that sign request tokens. Empty means fetch them
+    /// from the platform.
+    pub platform_signing_keys: Vec<String>,
+    /// Where to serve Prometheus `/metrics`. `None` disables the endpoint.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Register a `public_url` that resolves to a loopback or private address.
+    pub allow_private_url: bool,
+    /// Accept requests through a platform relay instead of on `public_url`.
+    pub tunnel: bool,
+    /// PEM certificate chain and key for TLS on the public listener. Reloaded
+    /// when either file changes.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Domains to get a certificate for from `acme_directory`. Non-empty
+    /// enables TLS with ACME-issued certificates.
+    pub acme_domains: Vec<String>,
+    pub acme_directory: String,
+    pub acme_email: Option<String>,
+    /// Extra root certificate trusted for `acme_directory`, for a test CA.
+    pub acme_ca_cert: Option<PathBuf>,
+}
+
+/// How the agent talks to the platform for presence, heartbeats and commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlatformChannel {
+    /// Periodic HTTP POSTs only.
+    Http,
+    /// A persistent WebSocket, falling back to HTTP while it is unavailable.
+    WebSocket,
+}
+
+impl FromStr for PlatformChannel {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "http" => Ok(PlatformChannel::Http),
+            "websocket" | "ws" => Ok(PlatformChannel::WebSocket),
+            other => Err(format!("expected 'http' or 'websocket', got '{}'", other)),
+        }
+    }
+}
+
+/// Read a comma-separated environment variable, empty when unset.
+fn env_list(var: &str) -> Vec<String> {
+    std::env::var(var)
+        .map(|list| {
+            list.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Read an environment variable as a path, `None` when unset or empty.
+fn env_path(var: &str) -> Option<PathBuf> {
+    std::env::var_os(var)
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
 }
 
 /// Read an environment variable, returning `default` when the var is unset.
 /// Fails with a clear message if the var is set but cannot be parsed.
-fn env_or<T: FromStr>(var: &str, default: T) -> Result<T>
+pub(crate) fn env_or<T: FromStr>(var: &str, default: T) -> Result<T>
 where
     T::Err: std::fmt::Display,
 {
@@ -45,8 +112,28 @@ pub fn model_dir() -> Result<PathBuf> {
     }
 }
 
+/// Resolve the agent state directory (`~/.vram-supply`), which holds the
+/// identity file, caches and local logs.
+pub fn state_dir() -> Result<PathBuf> {
+    let home =
+        dirs::home_dir().ok_or_else(|| anyhow::anyhow!("Could not determine home directory"))?;
+    Ok(home.join(".vram-supply"))
+}
+
 impl Config {
     pub fn load() -> Result<Self> {
+        let config = Self::from_env()?;
+        if config.api_key.is_empty() {
+            bail!("VRAM_SUPPLY_API_KEY is required. Create an API key at https://vram.supply/keys and set it in your environment.");
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Read the configuration without requiring an API key or validating it,
+    /// so `doctor` can report each problem separately. Fails only on values
+    /// that do not parse.
+    pub fn from_env() -> Result<Self> {
         let model_dir = model_dir()?;
 
         let platform_url = env_or(
@@ -54,6 +141,8 @@ impl Config {
             "https://api.vram.supply".to_string(),
         )?;
         let port: u16 = env_or("VRAM_SUPPLY_PORT", 8080)?;
+        let bind_address: IpAddr = env_or("VRAM_SUPPLY_BIND_ADDRESS", IpAddr::from([0, 0, 0, 0]))?;
+        let backend_port: u16 = env_or("VRAM_SUPPLY_BACKEND_PORT", port.saturating_add(1))?;
         let public_url = env_or(
             "VRAM_SUPPLY_PUBLIC_URL",
             format!("http://localhost:{}", port),
@@ -65,8 +154,34 @@ impl Config {
         let context_length_offered: u32 = env_or("VRAM_SUPPLY_CONTEXT_LENGTH", 8192)?;
         let input_price_per_million: u32 = env_or("VRAM_SUPPLY_INPUT_PRICE", 100)?;
         let output_price_per_million: u32 = env_or("VRAM_SUPPLY_OUTPUT_PRICE", 200)?;
-        let api_key = std::env::var("VRAM_SUPPLY_API_KEY")
-            .map_err(|_| anyhow::anyhow!("VRAM_SUPPLY_API_KEY is required. Create an API key at https://vram.supply/keys and set it in your environment."))?;
+        let platform_channel = env_or("VRAM_SUPPLY_PLATFORM_CHANNEL", PlatformChannel::Http)?;
+        let drain_timeout_secs: u64 = env_or("VRAM_SUPPLY_DRAIN_TIMEOUT", 30)?;
+        let shutdown_timeout_secs: u64 = env_or("VRAM_SUPPLY_SHUTDOWN_TIMEOUT", 60)?;
+        let platform_signing_keys = env_list("VRAM_SUPPLY_PLATFORM_SIGNING_KEYS");
+        let metrics_addr = match std::env::var("VRAM_SUPPLY_METRICS_ADDR") {
+            Ok(addr) if !addr.trim().is_empty() => Some(addr.trim().parse().map_err(|e| {
+                anyhow::anyhow!(
+                    "invalid value for VRAM_SUPPLY_METRICS_ADDR: '{}' ({})",
+                    addr,
+                    e
+                )
+            })?),
+            _ => None,
+        };
+        let allow_private_url: bool = env_or("VRAM_SUPPLY_ALLOW_PRIVATE_URL", false)?;
+        let tunnel: bool = env_or("VRAM_SUPPLY_TUNNEL", false)?;
+        let tls_cert = env_path("VRAM_SUPPLY_TLS_CERT");
+        let tls_key = env_path("VRAM_SUPPLY_TLS_KEY");
+        let acme_domains = env_list("VRAM_SUPPLY_ACME_DOMAINS");
+        let acme_directory = env_or(
+            "VRAM_SUPPLY_ACME_DIRECTORY",
+            crate::acme::LETS_ENCRYPT_DIRECTORY.to_string(),
+        )?;
+        let acme_email = std::env::var("VRAM_SUPPLY_ACME_EMAIL")
+            .ok()
+            .filter(|e| !e.is_empty());
+        let acme_ca_cert = env_path("VRAM_SUPPLY_ACME_CA_CERT");
+        let api_key = std::env::var("VRAM_SUPPLY_API_KEY").unwrap_or_default();
 
         let config = Config {
             platform_url,
@@ -75,35 +190,150 @@ impl Config {
             llama_server_path,
             gpu_layers,
             port,
+            bind_address,
+            backend_port,
             max_concurrent,
             context_length_offered,
             input_price_per_million,
             output_price_per_million,
             api_key,
+            platform_channel,
+            drain_timeout_secs,
+            shutdown_timeout_secs,
+            platform_signing_keys,
+            metrics_addr,
+            allow_private_url,
+            tunnel,
+            tls_cert,
+            tls_key,
+            acme_domains,
+            acme_directory,
+            acme_email,
+            acme_ca_cert,
         };
-        config.validate()?;
         Ok(config)
     }
 
-    fn validate(&self) -> Result<()> {
+    /// Check that values are consistent with each other.
+    pub fn validate(&self) -> Result<()> {
         if self.port == 0 {
             bail!("VRAM_SUPPLY_PORT must be > 0");
         }
+        if self.backend_port == 0 || self.backend_port == self.port {
+            bail!("VRAM_SUPPLY_BACKEND_PORT must be > 0 and differ from VRAM_SUPPLY_PORT");
+        }
+        for key in &self.platform_signing_keys {
+            if let Err(e) = crate::proxy::parse_signing_key(key) {
+                bail!("VRAM_SUPPLY_PLATFORM_SIGNING_KEYS: {:#}", e);
+            }
+        }
         if self.max_concurrent == 0 {
             bail!("VRAM_SUPPLY_MAX_CONCURRENT must be > 0");
         }
         if self.context_length_offered == 0 {
             bail!("VRAM_SUPPLY_CONTEXT_LENGTH must be > 0");
         }
+        if self.drain_timeout_secs >= self.shutdown_timeout_secs {
+            bail!("VRAM_SUPPLY_DRAIN_TIMEOUT must be less than VRAM_SUPPLY_SHUTDOWN_TIMEOUT");
+        }
         if self.platform_url.is_empty() {
             bail!("VRAM_SUPPLY_PLATFORM_URL must not be empty");
         }
         if self.public_url.is_empty() {
             bail!("VRAM_SUPPLY_PUBLIC_URL must not be empty");
         }
-        if self.api_key.is_empty() {
-            bail!("VRAM_SUPPLY_API_KEY must not be empty");
+        self.validate_tls()
+    }
+
+    /// Check the listener's TLS settings against each other and against the
+    /// scheme of `public_url`.
+    fn validate_tls(&self) -> Result<()> {
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            bail!("VRAM_SUPPLY_TLS_CERT and VRAM_SUPPLY_TLS_KEY must be set together");
+        }
+        if self.tls_cert.is_some() && !self.acme_domains.is_empty() {
+            bail!("Set either VRAM_SUPPLY_TLS_CERT or VRAM_SUPPLY_ACME_DOMAINS, not both");
+        }
+        let tls = crate::tls::enabled(self);
+        if tls && self.tunnel {
+            bail!("TLS on the public listener cannot be combined with VRAM_SUPPLY_TUNNEL; the relay terminates TLS");
+        }
+        if self.tunnel {
+            return Ok(());
+        }
+        let Ok(url) = reqwest::Url::parse(&self.public_url) else {
+            // Reported with a remediation hint by the startup address check.
+            return Ok(());
+        };
+        match (url.scheme(), tls) {
+            ("http", true) => {
+                bail!("VRAM_SUPPLY_PUBLIC_URL must use https:// when the listener serves TLS")
+            }
+            ("https", false) if url.port() == Some(self.port) => bail!(
+                "VRAM_SUPPLY_PUBLIC_URL is https:// on VRAM_SUPPLY_PORT, but the listener \
+                 serves plain HTTP; set VRAM_SUPPLY_TLS_CERT or VRAM_SUPPLY_ACME_DOMAINS, \
+                 or put a TLS proxy in front"
+            ),
+            _ => {}
+        }
+        if !self.acme_domains.is_empty() {
+            let host = url.host_str().unwrap_or_default();
+            if !self
+                .acme_domains
+                .iter()
+                .any(|d| d.eq_ignore_ascii_case(host))
+            {
+                bail!(
+                    "VRAM_SUPPLY_PUBLIC_URL host '{}' is not in VRAM_SUPPLY_ACME_DOMAINS",
+                    host
+                );
+            }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support;
+
+    #[test]
+    fn test_public_url_scheme_matches_listener() {
+        let mut config = test_support::config("http://127.0.0.1:1");
+        assert!(config.validate().is_ok());
+
+        config.public_url = "https://node.example.com:8080".to_string();
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("plain HTTP"), "{}", err);
+        // A TLS proxy in front on its own port is fine.
+        config.public_url = "https://node.example.com".to_string();
+        assert!(config.validate().is_ok());
+
+        config.acme_domains = vec!["node.example.com".to_string()];
+        assert!(config.validate().is_ok());
+        config.public_url = "http://node.example.com:8080".to_string();
+        assert!(config
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("https://"));
+        config.public_url = "https://other.example.com".to_string();
+        assert!(config.validate().is_err());
+
+        config.public_url = "https://node.example.com".to_string();
+        config.tunnel = true;
+        assert!(config.validate().is_err());
+        config.tunnel = false;
+
+        config.tls_cert = Some("cert.pem".into());
+        assert!(config.validate().is_err());
+        config.acme_domains.clear();
+        assert!(config
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("together"));
+        config.tls_key = Some("key.pem".into());
+        assert!(config.validate().is_ok());
+    }
+}
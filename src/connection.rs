@@ -0,0 +1,1152 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
+
+use crate::commands::CommandAck;
+use crate::config::Config;
+use crate::outbox::{self, LinkStatus, Outbox, OutboxEvent, OutboxEventKind};
+use crate::usage::UsageRecord;
+
+const SOCKET_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const INITIAL_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_secs(15);
+const OUTBOX_FLUSH_INTERVAL: Duration = Duration::from_secs(15);
+const OUTBOX_BATCH_SIZE: usize = 100;
+
+type SocketStream =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// A command pushed by the platform to this agent.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct PlatformCommand {
+    pub id: String,
+    pub command: String,
+    #[serde(default)]
+    pub params: serde_json::Value,
+}
+
+/// Frames sent from the agent over the platform socket.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OutboundFrame {
+    Presence { payload: serde_json::Value },
+    Heartbeat { provider_id: String },
+    Ack(CommandAck),
+}
+
+#[derive(Serialize)]
+struct EventsRequest<'a> {
+    agent_uid: &'a str,
+    events: &'a [OutboxEvent],
+}
+
+/// Frames received from the platform over the socket. Unknown frame types are
+/// ignored so the platform can add new ones without breaking older agents.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum InboundFrame {
+    Command(PlatformCommand),
+    /// The platform no longer knows this provider id (e.g. it expired).
+    ProviderUnknown {
+        provider_id: String,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+/// The agent's link to the platform.
+///
+/// Presence updates and provider heartbeats go over a persistent WebSocket when
+/// one is configured and connected, and fall back to plain HTTP POSTs otherwise.
+/// Commands pushed by the platform are forwarded to the receiver returned by
+/// `new()`.
+///
+/// Every call also tells us whether the platform is reachable. While it is
+/// not, events that must not be lost are kept in a bounded on-disk outbox and
+/// replayed in order once it is back. All fields are Arc-wrapped so this is
+/// cheap to Clone.
+#[derive(Clone)]
+pub struct PlatformConnection {
+    client: reqwest::Client,
+    config: Config,
+    token: Arc<Mutex<String>>,
+    agent_uid: String,
+    /// Provider ids by the index of the served model they were registered for.
+    provider_ids: Arc<Mutex<BTreeMap<usize, String>>>,
+    socket: Arc<Mutex<Option<mpsc::UnboundedSender<Message>>>>,
+    socket_connected: Arc<Notify>,
+    commands: mpsc::UnboundedSender<PlatformCommand>,
+    outbox: Arc<std::sync::Mutex<Outbox>>,
+    link: Arc<std::sync::Mutex<LinkStatus>>,
+    link_path: Option<PathBuf>,
+    reconnected: Arc<Notify>,
+    unknown_providers: Arc<std::sync::Mutex<VecDeque<String>>>,
+    unknown_provider_notify: Arc<Notify>,
+    /// Usage of served requests, waiting for the usage reporter.
+    usage: mpsc::UnboundedSender<OutboxEvent>,
+    usage_rx: Arc<std::sync::Mutex<Option<mpsc::UnboundedReceiver<OutboxEvent>>>>,
+}
+
+impl PlatformConnection {
+    /// Create the connection. With `state_dir` set the outbox and link status
+    /// are persisted there; otherwise they only live in memory.
+    pub fn new(
+        client: reqwest::Client,
+        config: Config,
+        token: Arc<Mutex<String>>,
+        agent_uid: String,
+        state_dir: Option<PathBuf>,
+    ) -> (Self, mpsc::UnboundedReceiver<PlatformCommand>) {
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        let (usage, usage_rx) = mpsc::unbounded_channel();
+        let outbox = Outbox::open(state_dir.as_ref().map(|d| d.join(outbox::OUTBOX_FILE)));
+        let link = LinkStatus {
+            queued_events: outbox.len(),
+            updated_at: outbox::unix_now(),
+            ..Default::default()
+        };
+        let connection = PlatformConnection {
+            client,
+            config,
+            token,
+            agent_uid,
+            provider_ids: Arc::new(Mutex::new(BTreeMap::new())),
+            socket: Arc::new(Mutex::new(None)),
+            socket_connected: Arc::new(Notify::new()),
+            commands,
+            outbox: Arc::new(std::sync::Mutex::new(outbox)),
+            link: Arc::new(std::sync::Mutex::new(link)),
+            link_path: state_dir.map(|d| d.join(outbox::LINK_STATUS_FILE)),
+            reconnected: Arc::new(Notify::new()),
+            unknown_providers: Arc::new(std::sync::Mutex::new(VecDeque::new())),
+            unknown_provider_notify: Arc::new(Notify::new()),
+            usage,
+            usage_rx: Arc::new(std::sync::Mutex::new(Some(usage_rx))),
+        };
+        (connection, commands_rx)
+    }
+
+    /// Set the provider id registered for served model `model` (its index).
+    /// Heartbeats are sent for every id that is set.
+    pub async fn set_provider_id(&self, model: usize, id: Option<String>) {
+        let mut ids = self.provider_ids.lock().await;
+        match id {
+            Some(id) => ids.insert(model, id),
+            None => ids.remove(&model),
+        };
+        crate::telemetry::metrics()
+            .registered
+            .set(!ids.is_empty() as i64);
+    }
+
+    /// The provider id registered for served model `model`, if any.
+    pub async fn provider_id(&self, model: usize) -> Option<String> {
+        self.provider_ids.lock().await.get(&model).cloned()
+    }
+
+    /// Every registered provider id, by served model index.
+    pub async fn provider_ids(&self) -> BTreeMap<usize, String> {
+        self.provider_ids.lock().await.clone()
+    }
+
+    /// The index of the served model registered as `provider_id`.
+    pub async fn served_model(&self, provider_id: &str) -> Option<usize> {
+        self.provider_ids
+            .lock()
+            .await
+            .iter()
+            .find(|(_, id)| id.as_str() == provider_id)
+            .map(|(model, _)| *model)
+    }
+
+    /// Resolves with a provider id the platform has reported it does not
+    /// know, so the caller can register again.
+    pub async fn provider_unknown(&self) -> String {
+        loop {
+            let id = self
+                .unknown_providers
+                .lock()
+                .expect("unknown provider lock poisoned")
+                .pop_front();
+            if let Some(id) = id {
+                return id;
+            }
+            self.unknown_provider_notify.notified().await;
+        }
+    }
+
+    fn report_unknown_provider(&self, provider_id: String) {
+        tracing::warn!("Platform does not recognise provider {}", provider_id);
+        let mut unknown = self
+            .unknown_providers
+            .lock()
+            .expect("unknown provider lock poisoned");
+        if !unknown.contains(&provider_id) {
+            unknown.push_back(provider_id);
+        }
+        self.unknown_provider_notify.notify_one();
+    }
+
+    /// Whether outbound frames currently go over the socket rather than HTTP.
+    pub async fn is_socket_connected(&self) -> bool {
+        self.socket.lock().await.is_some()
+    }
+
+    /// Resolves each time the socket (re)connects, so callers can resend state.
+    pub async fn socket_connected(&self) {
+        self.socket_connected.notified().await;
+    }
+
+    /// Whether the last call to the platform got through.
+    pub fn is_connected(&self) -> bool {
+        self.link
+            .lock()
+            .expect("link status lock poisoned")
+            .connected
+    }
+
+    /// Record whether the platform answered, logging and persisting changes.
+    fn observe(&self, reachable: bool) {
+        let now = outbox::unix_now();
+        let mut link = self.link.lock().expect("link status lock poisoned");
+        if reachable {
+            if !link.connected {
+                if link.disconnected_since.is_some() {
+                    tracing::info!("Platform reachable again");
+                }
+                link.disconnected_since = None;
+                self.reconnected.notify_one();
+            }
+            link.last_contact_at = Some(now);
+        } else if link.connected || link.disconnected_since.is_none() {
+            tracing::warn!("Platform unreachable, buffering events until it is back");
+            link.disconnected_since = Some(now);
+        }
+        link.connected = reachable;
+        self.save_link(&mut link);
+    }
+
+    /// Classify an HTTP call: transport failures and 5xx mean unreachable.
+    fn observe_response(&self, res: &reqwest::Result<reqwest::Response>) {
+        let reachable = matches!(res, Ok(r) if !r.status().is_server_error());
+        self.observe(reachable);
+    }
+
+    fn save_link(&self, link: &mut LinkStatus) {
+        link.queued_events = self.outbox.lock().expect("outbox lock poisoned").len();
+        link.updated_at = outbox::unix_now();
+        if let Some(path) = &self.link_path {
+            if let Err(e) = link.save(path) {
+                tracing::debug!("Failed to save link status: {:#}", e);
+            }
+        }
+    }
+
+    /// Keep events for delivery once the platform is reachable again.
+    pub fn buffer_events(&self, events: Vec<OutboxEvent>) {
+        if events.is_empty() {
+            return;
+        }
+        self.outbox
+            .lock()
+            .expect("outbox lock poisoned")
+            .push_all(events);
+        let mut link = self.link.lock().expect("link status lock poisoned");
+        self.save_link(&mut link);
+    }
+
+    /// Send a presence payload over the socket, or POST it to `/v1/agents/presence`.
+    pub async fn send_presence<T: Serialize>(&self, payload: &T) -> Result<()> {
+        let frame = OutboundFrame::Presence {
+            payload: serde_json::to_value(payload)?,
+        };
+        if self.send_frame(&frame).await {
+            return Ok(());
+        }
+
+        let url = format!("{}/v1/agents/presence", self.config.platform_url);
+        let current_token = self.token.lock().await.clone();
+        let res = self
+            .client
+            .post(url)
+            .header("Authorization", format!("Bearer {}", current_token))
+            .json(payload)
+            .send()
+            .await;
+        self.observe_response(&res);
+        let res = res?;
+
+        if !res.status().is_success() {
+            let status = res.status();
+            let body = res.text().await.unwrap_or_default();
+            // The platform names the provider it no longer knows, if any.
+            if status == reqwest::StatusCode::NOT_FOUND {
+                if let Some(id) = serde_json::from_str::<serde_json::Value>(&body)
+                    .ok()
+                    .and_then(|v| v["provider_id"].as_str().map(str::to_string))
+                {
+                    self.report_unknown_provider(id);
+                }
+            }
+            bail!("Presence update failed ({}): {}", status, body);
+        }
+        Ok(())
+    }
+
+    /// Send a liveness heartbeat for every registered provider over the
+    /// socket, or POST each to `/v1/providers/heartbeat`. Does nothing until
+    /// a provider id is set.
+    pub async fn send_heartbeat(&self) -> Result<()> {
+        let mut failed = None;
+        for provider_id in self.provider_ids().await.into_values() {
+            if let Err(e) = self.send_provider_heartbeat(provider_id).await {
+                failed = Some(e);
+            }
+        }
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    async fn send_provider_heartbeat(&self, provider_id: String) -> Result<()> {
+        if self
+            .send_frame(&OutboundFrame::Heartbeat {
+                provider_id: provider_id.clone(),
+            })
+            .await
+        {
+            return Ok(());
+        }
+
+        let url = format!("{}/v1/providers/heartbeat", self.config.platform_url);
+        let current_token = self.token.lock().await.clone();
+        let res = self
+            .client
+            .post(url)
+            .header("Authorization", format!("Bearer {}", current_token))
+            .json(&serde_json::json!({ "provider_id": provider_id }))
+            .send()
+            .await;
+        self.observe_response(&res);
+        let res = res?;
+
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            self.report_unknown_provider(provider_id);
+        }
+        if !res.status().is_success() {
+            bail!("Heartbeat failed: {}", res.status());
+        }
+        Ok(())
+    }
+
+    /// Acknowledge a command over the socket, or POST the ack to
+    /// `/v1/agents/commands/{id}/ack`.
+    pub async fn send_ack(&self, ack: &CommandAck) -> Result<()> {
+        if self.send_frame(&OutboundFrame::Ack(ack.clone())).await {
+            return Ok(());
+        }
+
+        let url = format!(
+            "{}/v1/agents/commands/{}/ack",
+            self.config.platform_url, ack.id
+        );
+        let current_token = self.token.lock().await.clone();
+        let res = self
+            .client
+            .post(url)
+            .header("Authorization", format!("Bearer {}", current_token))
+            .json(ack)
+            .send()
+            .await;
+        self.observe_response(&res);
+        let res = res?;
+
+        if !res.status().is_success() {
+            bail!("Command ack failed: {}", res.status());
+        }
+        Ok(())
+    }
+
+    /// Fetch pending commands from `/v1/agents/commands` and forward them to
+    /// the command receiver.
+    async fn poll_commands(&self) -> Result<()> {
+        let url = format!("{}/v1/agents/commands", self.config.platform_url);
+        let current_token = self.token.lock().await.clone();
+        let res = self
+            .client
+            .get(url)
+            .query(&[("agent_uid", &self.agent_uid)])
+            .header("Authorization", format!("Bearer {}", current_token))
+            .send()
+            .await;
+        self.observe_response(&res);
+        let res = res?;
+
+        if !res.status().is_success() {
+            bail!("Command poll failed: {}", res.status());
+        }
+        let commands: Vec<PlatformCommand> =
+            res.json().await.context("Invalid command poll response")?;
+        for command in commands {
+            let _ = self.commands.send(command);
+        }
+        Ok(())
+    }
+
+    /// POST events to `/v1/agents/events`. Always over HTTP, even with the
+    /// socket up: the socket has no delivery ack, and only a 2xx confirms the
+    /// platform has the events so they can leave the outbox.
+    async fn send_events(&self, events: &[OutboxEvent]) -> Result<()> {
+        let url = format!("{}/v1/agents/events", self.config.platform_url);
+        let current_token = self.token.lock().await.clone();
+        let res = self
+            .client
+            .post(url)
+            .header("Authorization", format!("Bearer {}", current_token))
+            .json(&EventsRequest {
+                agent_uid: &self.agent_uid,
+                events,
+            })
+            .send()
+            .await;
+        self.observe_response(&res);
+        let res = res?;
+
+        if !res.status().is_success() {
+            bail!("Event replay failed: {}", res.status());
+        }
+        Ok(())
+    }
+
+    /// Replay buffered events oldest first, in batches, until the outbox is
+    /// empty or a batch fails. Returns how many were delivered.
+    async fn flush_outbox(&self) -> Result<usize> {
+        let mut delivered = 0;
+        loop {
+            let batch = self
+                .outbox
+                .lock()
+                .expect("outbox lock poisoned")
+                .peek(OUTBOX_BATCH_SIZE);
+            if batch.is_empty() {
+                break;
+            }
+            self.send_events(&batch).await?;
+            self.outbox
+                .lock()
+                .expect("outbox lock poisoned")
+                .remove(batch.len());
+            delivered += batch.len();
+        }
+        let mut link = self.link.lock().expect("link status lock poisoned");
+        self.save_link(&mut link);
+        Ok(delivered)
+    }
+
+    /// Spawn the outbox replayer, which flushes buffered events as soon as the
+    /// platform is reachable again and retries every 15s while any remain.
+    pub fn spawn_outbox_flush(&self, shutdown: CancellationToken) -> tokio::task::JoinHandle<()> {
+        let connection = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(OUTBOX_FLUSH_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = interval.tick() => {}
+                    _ = connection.reconnected.notified() => {}
+                }
+                let pending = !connection
+                    .outbox
+                    .lock()
+                    .expect("outbox lock poisoned")
+                    .is_empty();
+                if !pending || !connection.is_connected() {
+                    continue;
+                }
+                match connection.flush_outbox().await {
+                    Ok(n) => tracing::info!("Replayed {} buffered platform events", n),
+                    Err(e) => tracing::debug!("Outbox replay incomplete: {:#}", e),
+                }
+            }
+        })
+    }
+
+    /// Report the usage of a served request. It is sent by the usage reporter
+    /// in the background, and kept in the outbox like any other event while
+    /// the platform is unreachable.
+    pub fn report_usage(&self, record: &UsageRecord) {
+        match serde_json::to_value(record) {
+            Ok(payload) => {
+                let _ = self.usage.send(OutboxEvent::new(
+                    OutboxEventKind::Usage,
+                    record.started_at,
+                    payload,
+                ));
+            }
+            Err(e) => tracing::warn!("Failed to encode usage event: {}", e),
+        }
+    }
+
+    /// Spawn the usage reporter, which sends reported usage in batches as it
+    /// arrives. Whatever is still queued at shutdown goes to the outbox.
+    pub fn spawn_usage_reports(&self, shutdown: CancellationToken) -> tokio::task::JoinHandle<()> {
+        let connection = self.clone();
+        let mut usage = self
+            .usage_rx
+            .lock()
+            .expect("usage lock poisoned")
+            .take()
+            .expect("usage reporter spawned twice");
+        tokio::spawn(async move {
+            loop {
+                let first = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    event = usage.recv() => match event {
+                        Some(event) => event,
+                        None => break,
+                    },
+                };
+                let mut batch = vec![first];
+                while batch.len() < OUTBOX_BATCH_SIZE {
+                    match usage.try_recv() {
+                        Ok(event) => batch.push(event),
+                        Err(_) => break,
+                    }
+                }
+                connection.deliver(batch).await;
+            }
+            let mut rest = Vec::new();
+            while let Ok(event) = usage.try_recv() {
+                rest.push(event);
+            }
+            connection.buffer_events(rest);
+        })
+    }
+
+    /// Send events now, or buffer them while the platform is unreachable or
+    /// earlier events are still waiting to be replayed ahead of them.
+    async fn deliver(&self, events: Vec<OutboxEvent>) {
+        let queued = !self.outbox.lock().expect("outbox lock poisoned").is_empty();
+        if !queued && self.is_connected() {
+            match self.send_events(&events).await {
+                Ok(()) => return,
+                Err(e) => tracing::debug!("Failed to send events: {:#}", e),
+            }
+        }
+        self.buffer_events(events);
+    }
+
+    /// Spawn the command poller, which checks for pending commands over HTTP
+    /// every 15s while the socket is not connected.
+    pub fn spawn_command_poll(&self, shutdown: CancellationToken) -> tokio::task::JoinHandle<()> {
+        let connection = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(COMMAND_POLL_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = interval.tick() => {}
+                }
+                if connection.is_socket_connected().await {
+                    continue;
+                }
+                if let Err(e) = connection.poll_commands().await {
+                    tracing::debug!("{}", e);
+                }
+            }
+        })
+    }
+
+    /// Queue a frame on the socket. Returns false if no socket is connected,
+    /// in which case the caller should fall back to HTTP.
+    async fn send_frame(&self, frame: &OutboundFrame) -> bool {
+        let mut socket = self.socket.lock().await;
+        let Some(tx) = socket.as_ref() else {
+            return false;
+        };
+        let json = match serde_json::to_string(frame) {
+            Ok(json) => json,
+            Err(e) => {
+                tracing::warn!("Failed to serialize socket frame: {}", e);
+                return false;
+            }
+        };
+        if tx.send(Message::text(json)).is_err() {
+            // Writer task has gone away; stop routing frames to it.
+            *socket = None;
+            return false;
+        }
+        true
+    }
+
+    /// Spawn the socket supervisor, which keeps a WebSocket open to
+    /// `/v1/agents/connect` and reconnects with exponential backoff.
+    ///
+    /// While the socket is down, presence and heartbeats use HTTP.
+    pub fn spawn_socket(&self, shutdown: CancellationToken) -> tokio::task::JoinHandle<()> {
+        let connection = self.clone();
+        tokio::spawn(async move {
+            let mut backoff = INITIAL_RECONNECT_BACKOFF;
+            loop {
+                match connection.connect_socket().await {
+                    Ok(stream) => {
+                        backoff = INITIAL_RECONNECT_BACKOFF;
+                        tracing::info!("Connected to platform socket");
+                        connection.run_socket(stream, &shutdown).await;
+                        if shutdown.is_cancelled() {
+                            break;
+                        }
+                        tracing::warn!("Platform socket disconnected, falling back to HTTP");
+                    }
+                    Err(e) => {
+                        tracing::warn!("Platform socket unavailable, using HTTP: {:#}", e);
+                    }
+                }
+
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = tokio::time::sleep(backoff) => {}
+                }
+                backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+            }
+        })
+    }
+
+    async fn connect_socket(&self) -> Result<SocketStream> {
+        let url = websocket_url(&self.config.platform_url, "/v1/agents/connect")?;
+        let mut request = url
+            .as_str()
+            .into_client_request()
+            .with_context(|| format!("Invalid platform socket URL {}", url))?;
+        let current_token = self.token.lock().await.clone();
+        request.headers_mut().insert(
+            "Authorization",
+            format!("Bearer {}", current_token)
+                .parse()
+                .context("API key is not a valid header value")?,
+        );
+
+        let (stream, _) = tokio::time::timeout(
+            SOCKET_CONNECT_TIMEOUT,
+            tokio_tungstenite::connect_async(request),
+        )
+        .await
+        .with_context(|| format!("Timed out connecting to {}", url))?
+        .with_context(|| format!("Failed to connect to {}", url))?;
+        Ok(stream)
+    }
+
+    /// Pump frames between the socket and the rest of the agent until the
+    /// socket closes or shutdown is requested.
+    async fn run_socket(&self, stream: SocketStream, shutdown: &CancellationToken) {
+        let (mut sink, mut source) = stream.split();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        *self.socket.lock().await = Some(tx);
+        // The handshake and every frame received are replies from the
+        // platform; a frame merely queued for sending is not.
+        self.observe(true);
+        self.socket_connected.notify_one();
+
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => {
+                    let _ = sink.send(Message::Close(None)).await;
+                    break;
+                }
+                Some(msg) = rx.recv() => {
+                    if let Err(e) = sink.send(msg).await {
+                        tracing::debug!("Platform socket write failed: {}", e);
+                        break;
+                    }
+                }
+                incoming = source.next() => match incoming {
+                    Some(Ok(Message::Text(text))) => {
+                        self.observe(true);
+                        self.handle_frame(&text);
+                    }
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        tracing::debug!("Platform socket read failed: {}", e);
+                        break;
+                    }
+                },
+            }
+        }
+
+        *self.socket.lock().await = None;
+    }
+
+    fn handle_frame(&self, text: &str) {
+        match serde_json::from_str::<InboundFrame>(text) {
+            Ok(InboundFrame::Command(command)) => {
+                tracing::debug!("Platform command received: {:?}", command);
+                let _ = self.commands.send(command);
+            }
+            Ok(InboundFrame::ProviderUnknown { provider_id }) => {
+                self.report_unknown_provider(provider_id);
+            }
+            Ok(InboundFrame::Unknown) => {
+                tracing::trace!("Ignoring unknown platform socket frame");
+            }
+            Err(e) => {
+                tracing::warn!("Malformed platform socket frame: {}", e);
+            }
+        }
+    }
+}
+
+/// Derive the WebSocket URL of `path` from the platform's HTTP base URL.
+pub(crate) fn websocket_url(platform_url: &str, path: &str) -> Result<String> {
+    let base = platform_url.trim_end_matches('/');
+    let ws_base = if let Some(rest) = base.strip_prefix("https://") {
+        format!("wss://{}", rest)
+    } else if let Some(rest) = base.strip_prefix("http://") {
+        format!("ws://{}", rest)
+    } else {
+        bail!(
+            "Platform URL must start with http:// or https://: {}",
+            platform_url
+        );
+    };
+    Ok(format!("{}{}", ws_base, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::extract::ws::{Message as WsMessage, WebSocketUpgrade};
+    use axum::extract::{Query, State};
+    use axum::routing::{any, get, post};
+    use axum::{Json, Router};
+
+    use crate::test_support;
+
+    type Recorded = Arc<std::sync::Mutex<Vec<serde_json::Value>>>;
+
+    fn connection(
+        platform_url: &str,
+    ) -> (PlatformConnection, mpsc::UnboundedReceiver<PlatformCommand>) {
+        PlatformConnection::new(
+            reqwest::Client::new(),
+            test_support::config(platform_url),
+            Arc::new(Mutex::new("test-key".to_string())),
+            "test-agent".to_string(),
+            None,
+        )
+    }
+
+    async fn record(State(seen): State<Recorded>, Json(body): Json<serde_json::Value>) {
+        seen.lock().unwrap().push(body);
+    }
+
+    #[test]
+    fn test_socket_url() {
+        assert_eq!(
+            websocket_url("https://api.vram.supply", "/v1/agents/connect").unwrap(),
+            "wss://api.vram.supply/v1/agents/connect"
+        );
+        assert_eq!(
+            websocket_url("http://127.0.0.1:9000/", "/v1/agents/connect").unwrap(),
+            "ws://127.0.0.1:9000/v1/agents/connect"
+        );
+        assert!(websocket_url("ftp://example.com", "/v1/agents/connect").is_err());
+    }
+
+    #[test]
+    fn test_inbound_frame_parsing() {
+        let frame: InboundFrame = serde_json::from_str(
+            r#"{"type":"command","id":"c1","command":"drain","params":{"deadline_secs":30}}"#,
+        )
+        .unwrap();
+        match frame {
+            InboundFrame::Command(cmd) => {
+                assert_eq!(cmd.id, "c1");
+                assert_eq!(cmd.command, "drain");
+                assert_eq!(cmd.params["deadline_secs"], 30);
+            }
+            other => panic!("unexpected frame: {:?}", other),
+        }
+
+        let frame: InboundFrame = serde_json::from_str(r#"{"type":"welcome"}"#).unwrap();
+        assert!(matches!(frame, InboundFrame::Unknown));
+    }
+
+    #[tokio::test]
+    async fn test_http_fallback_without_socket() {
+        let seen: Recorded = Default::default();
+        let router = Router::new()
+            .route("/v1/agents/presence", post(record))
+            .with_state(Arc::clone(&seen));
+        let url = test_support::serve(router).await;
+        let (conn, _rx) = connection(&url);
+
+        conn.send_presence(&serde_json::json!({"status": "idle"}))
+            .await
+            .unwrap();
+
+        // No provider id yet, so the heartbeat is a no-op rather than a 404.
+        conn.send_heartbeat().await.unwrap();
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0]["status"], "idle");
+    }
+
+    #[tokio::test]
+    async fn test_buffered_events_replay_after_outage() {
+        use axum::http::StatusCode;
+        use std::sync::atomic::AtomicBool;
+
+        use crate::outbox::OutboxEventKind;
+
+        #[derive(Clone, Default)]
+        struct Platform {
+            up: Arc<AtomicBool>,
+            events: Recorded,
+        }
+        let platform = Platform::default();
+        let router = Router::new()
+            .route(
+                "/v1/agents/presence",
+                post(|State(p): State<Platform>| async move {
+                    if p.up.load(Ordering::SeqCst) {
+                        StatusCode::OK
+                    } else {
+                        StatusCode::SERVICE_UNAVAILABLE
+                    }
+                }),
+            )
+            .route(
+                "/v1/agents/events",
+                post(
+                    |State(p): State<Platform>, Json(body): Json<serde_json::Value>| async move {
+                        p.events.lock().unwrap().push(body);
+                    },
+                ),
+            )
+            .with_state(platform.clone());
+        let url = test_support::serve(router).await;
+        let (conn, _rx) = connection(&url);
+        let shutdown = CancellationToken::new();
+        let flusher = conn.spawn_outbox_flush(shutdown.clone());
+
+        assert!(conn
+            .send_presence(&serde_json::json!({"status": "ready"}))
+            .await
+            .is_err());
+        assert!(!conn.is_connected());
+        conn.buffer_events(
+            (1..=3)
+                .map(|n| {
+                    OutboxEvent::new(
+                        OutboxEventKind::PresenceTransition,
+                        n,
+                        serde_json::json!({ "n": n }),
+                    )
+                })
+                .collect(),
+        );
+
+        platform.up.store(true, Ordering::SeqCst);
+        conn.send_presence(&serde_json::json!({"status": "ready"}))
+            .await
+            .unwrap();
+        assert!(conn.is_connected());
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while platform.events.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let events = platform.events.lock().unwrap().clone();
+        assert_eq!(events[0]["agent_uid"], "test-agent");
+        let times: Vec<_> = events[0]["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["recorded_at"].as_u64().unwrap())
+            .collect();
+        assert_eq!(times, vec![1, 2, 3]);
+        assert!(conn.outbox.lock().unwrap().is_empty());
+        shutdown.cancel();
+        flusher.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_usage_is_buffered_until_platform_is_back() {
+        let recorded = Recorded::default();
+        let router = Router::new()
+            .route("/v1/agents/presence", post(|| async {}))
+            .route(
+                "/v1/agents/events",
+                post(
+                    |State(r): State<Recorded>, Json(body): Json<serde_json::Value>| async move {
+                        r.lock().unwrap().push(body);
+                    },
+                ),
+            )
+            .with_state(recorded.clone());
+        let url = test_support::serve(router).await;
+        let (conn, _rx) = connection(&url);
+        let shutdown = CancellationToken::new();
+        let reporter = conn.spawn_usage_reports(shutdown.clone());
+        let flusher = conn.spawn_outbox_flush(shutdown.clone());
+
+        // Nothing has reached the platform yet, so usage waits in the outbox.
+        let record = UsageRecord {
+            request_id: "req-1".to_string(),
+            client_request_id: None,
+            model: "m".to_string(),
+            started_at: 42,
+            latency_ms: 10,
+            status: 200,
+            prompt_tokens: 5,
+            completion_tokens: 7,
+            input_price_per_million: 100,
+            output_price_per_million: 200,
+        };
+        conn.report_usage(&record);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while conn.outbox.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(recorded.lock().unwrap().is_empty());
+
+        conn.send_presence(&serde_json::json!({"status": "ready"}))
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while recorded.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let event = recorded.lock().unwrap()[0]["events"][0].clone();
+        assert_eq!(event["kind"], "usage");
+        assert_eq!(event["recorded_at"], 42);
+        assert_eq!(event["payload"]["request_id"], "req-1");
+
+        shutdown.cancel();
+        reporter.await.unwrap();
+        flusher.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_404_reports_unknown_provider() {
+        use axum::http::StatusCode;
+
+        let router = Router::new().route(
+            "/v1/providers/heartbeat",
+            post(|Json(body): Json<serde_json::Value>| async move {
+                if body["provider_id"] == "prov-2" {
+                    StatusCode::NOT_FOUND
+                } else {
+                    StatusCode::OK
+                }
+            }),
+        );
+        let url = test_support::serve(router).await;
+        let (conn, _rx) = connection(&url);
+        conn.set_provider_id(0, Some("prov-1".to_string())).await;
+        conn.set_provider_id(1, Some("prov-2".to_string())).await;
+        assert_eq!(conn.served_model("prov-2").await, Some(1));
+        assert_eq!(conn.served_model("prov-3").await, None);
+
+        let waiter = tokio::spawn({
+            let conn = conn.clone();
+            async move { conn.provider_unknown().await }
+        });
+        assert!(conn.send_heartbeat().await.is_err());
+
+        let id = tokio::time::timeout(Duration::from_secs(5), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(id, "prov-2");
+    }
+
+    #[tokio::test]
+    async fn test_presence_404_reports_only_the_named_provider() {
+        use axum::http::StatusCode;
+
+        let router = Router::new().route(
+            "/v1/agents/presence",
+            post(|| async {
+                (
+                    StatusCode::NOT_FOUND,
+                    Json(serde_json::json!({ "provider_id": "prov-2" })),
+                )
+            }),
+        );
+        let url = test_support::serve(router).await;
+        let (conn, _rx) = connection(&url);
+        conn.set_provider_id(0, Some("prov-1".to_string())).await;
+        conn.set_provider_id(1, Some("prov-2".to_string())).await;
+
+        let waiter = tokio::spawn({
+            let conn = conn.clone();
+            async move { conn.provider_unknown().await }
+        });
+        assert!(conn.send_presence(&serde_json::json!({})).await.is_err());
+
+        let id = tokio::time::timeout(Duration::from_secs(5), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(id, "prov-2");
+    }
+
+    #[tokio::test]
+    async fn test_http_command_poll_and_ack() {
+        let acks: Recorded = Default::default();
+        let router = Router::new()
+            .route(
+                "/v1/agents/commands",
+                get(|Query(q): Query<HashMap<String, String>>| async move {
+                    assert_eq!(q["agent_uid"], "test-agent");
+                    Json(serde_json::json!([
+                        {"id": "c1", "command": "set_price", "params": {"input_price_per_million": 90}}
+                    ]))
+                }),
+            )
+            .route("/v1/agents/commands/{id}/ack", post(record))
+            .with_state(Arc::clone(&acks));
+        let url = test_support::serve(router).await;
+        let (conn, mut rx) = connection(&url);
+
+        conn.poll_commands().await.unwrap();
+        let command = rx.try_recv().unwrap();
+        assert_eq!(command.id, "c1");
+        assert_eq!(command.params["input_price_per_million"], 90);
+
+        conn.send_ack(&CommandAck {
+            id: "c1".to_string(),
+            outcome: crate::commands::CommandOutcome::Completed,
+            message: None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(acks.lock().unwrap()[0]["outcome"], "completed");
+    }
+
+    #[tokio::test]
+    async fn test_socket_carries_presence_and_commands() {
+        let seen: Recorded = Default::default();
+        let router = Router::new()
+            .route(
+                "/v1/agents/connect",
+                any(
+                    |ws: WebSocketUpgrade, State(seen): State<Recorded>| async move {
+                        ws.on_upgrade(move |mut socket| async move {
+                            let command = r#"{"type":"command","id":"c1","command":"drain"}"#;
+                            socket.send(WsMessage::text(command)).await.unwrap();
+                            while let Some(Ok(WsMessage::Text(text))) = socket.recv().await {
+                                let frame = serde_json::from_str(&text).unwrap();
+                                seen.lock().unwrap().push(frame);
+                            }
+                        })
+                    },
+                ),
+            )
+            .with_state(Arc::clone(&seen));
+        let url = test_support::serve(router).await;
+        let (conn, mut rx) = connection(&url);
+        let shutdown = CancellationToken::new();
+        let task = conn.spawn_socket(shutdown.clone());
+
+        tokio::time::timeout(Duration::from_secs(5), conn.socket_connected())
+            .await
+            .unwrap();
+        let command = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(command.command, "drain");
+        assert_eq!(command.params, serde_json::Value::Null);
+
+        conn.set_provider_id(0, Some("prov-1".to_string())).await;
+        conn.send_presence(&serde_json::json!({"status": "ready"}))
+            .await
+            .unwrap();
+        conn.send_heartbeat().await.unwrap();
+
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        while seen.lock().unwrap().len() < 2 {
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "frames not received"
+            );
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        {
+            let seen = seen.lock().unwrap();
+            assert_eq!(seen[0]["type"], "presence");
+            assert_eq!(seen[0]["payload"]["status"], "ready");
+            assert_eq!(seen[1]["type"], "heartbeat");
+            assert_eq!(seen[1]["provider_id"], "prov-1");
+        }
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!conn.is_socket_connected().await);
+    }
+
+    #[tokio::test]
+    async fn test_socket_reconnects_after_drop() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let router = Router::new()
+            .route(
+                "/v1/agents/connect",
+                any(
+                    |ws: WebSocketUpgrade, State(connects): State<Arc<AtomicUsize>>| async move {
+                        connects.fetch_add(1, Ordering::SeqCst);
+                        // Drop the socket immediately to force a reconnect.
+                        ws.on_upgrade(|socket| async move { drop(socket) })
+                    },
+                ),
+            )
+            .with_state(Arc::clone(&connects));
+        let url = test_support::serve(router).await;
+        let (conn, _rx) = connection(&url);
+        let shutdown = CancellationToken::new();
+        let task = conn.spawn_socket(shutdown.clone());
+
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        while connects.load(Ordering::SeqCst) < 2 {
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "socket did not reconnect"
+            );
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        shutdown.cancel();
+        let _ = tokio::time::timeout(Duration::from_secs(5), task).await;
+    }
+}
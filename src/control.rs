@@ -0,0 +1,601 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+#[cfg(unix)]
+use std::time::Duration;
+
+#[cfg(unix)]
+use anyhow::Context;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+#[cfg(unix)]
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+
+use crate::backend::metrics::UtilizationSummary;
+use crate::commands::CommandOutcome;
+use crate::connection::{PlatformCommand, PlatformConnection};
+use crate::presence::{AgentPresenceStatus, ModelPresence, PresenceHandle};
+use crate::served::ServedModel;
+use crate::transitions::TransitionEvent;
+use crate::usage::{UsageLedger, UsageRecord};
+
+/// File name of the control socket under the state directory.
+pub const CONTROL_SOCKET_FILE: &str = "control.sock";
+
+/// How many error transitions `status` returns.
+const RECENT_ERRORS: usize = 10;
+/// How many served requests and backend log lines `dashboard` returns.
+const DASHBOARD_REQUESTS: usize = 20;
+const DASHBOARD_LOG_LINES: usize = 50;
+/// Clients give up on a reply after this long; a model reload can be slow.
+#[cfg(unix)]
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(600);
+/// Queries are answered at once by a serve that is up. One still verifying or
+/// loading its models does not answer yet, and is not worth waiting for.
+#[cfg(unix)]
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One request line on the control socket.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ControlRequest {
+    pub method: String,
+    #[serde(default)]
+    pub params: serde_json::Value,
+}
+
+/// One reply line on the control socket.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ControlResponse {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ControlResponse {
+    fn ok(result: serde_json::Value) -> Self {
+        ControlResponse {
+            ok: true,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    fn error(msg: impl Into<String>) -> Self {
+        ControlResponse {
+            ok: false,
+            result: None,
+            error: Some(msg.into()),
+        }
+    }
+}
+
+/// Live state of a running `serve`, as returned by the `status` method.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ServeStatus {
+    pub pid: u32,
+    pub started_at: u64,
+    pub status: AgentPresenceStatus,
+    pub model: Option<String>,
+    pub provider_id: Option<String>,
+    /// `None` while llama-server is stopped or being restarted.
+    pub backend_pid: Option<u32>,
+    pub active_requests: u32,
+    pub error_code: Option<String>,
+    pub error_message: Option<String>,
+    pub platform_connected: bool,
+    /// Throughput and slot usage from llama-server's metrics, if scraped.
+    #[serde(default)]
+    pub utilization: Option<UtilizationSummary>,
+    /// Most recent transitions that carried an error code, oldest first.
+    pub recent_errors: Vec<TransitionEvent>,
+    /// Each served model; the fields above aggregate them.
+    #[serde(default)]
+    pub models: Vec<ServedModelStatus>,
+}
+
+impl ServeStatus {
+    /// The served model names, comma separated.
+    pub fn model_label(&self) -> String {
+        self.join_models(self.model.clone().unwrap_or_else(|| "-".to_string()), |m| {
+            m.presence.model.clone()
+        })
+    }
+
+    /// The registered provider ids, comma separated.
+    pub fn provider_label(&self) -> String {
+        self.join_models(
+            self.provider_id
+                .clone()
+                .unwrap_or_else(|| "not registered".to_string()),
+            |m| {
+                m.presence
+                    .provider_id
+                    .clone()
+                    .unwrap_or_else(|| "-".to_string())
+            },
+        )
+    }
+
+    /// The llama-server pids, comma separated.
+    pub fn backend_label(&self) -> String {
+        let pid = |pid: Option<u32>| match pid {
+            Some(pid) => format!("pid {}", pid),
+            None => "not running".to_string(),
+        };
+        self.join_models(pid(self.backend_pid), |m| pid(m.backend_pid))
+    }
+
+    /// `single` for one model, otherwise `each` of the models joined.
+    fn join_models(&self, single: String, each: impl Fn(&ServedModelStatus) -> String) -> String {
+        if self.models.len() <= 1 {
+            return single;
+        }
+        self.models.iter().map(each).collect::<Vec<_>>().join(", ")
+    }
+}
+
+/// One served model in [`ServeStatus`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ServedModelStatus {
+    #[serde(flatten)]
+    pub presence: ModelPresence,
+    pub backend_port: u16,
+    pub backend_pid: Option<u32>,
+}
+
+/// Requests served through the proxy over the last hour.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct HourSummary {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub earned_cents: f64,
+}
+
+/// Everything `vramsply top` shows, as returned by the `dashboard` method.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Dashboard {
+    pub status: ServeStatus,
+    pub last_hour: HourSummary,
+    /// Most recently served requests, oldest first.
+    pub recent_requests: Vec<UsageRecord>,
+    /// Tail of llama-server's output, oldest first. With several models,
+    /// each one's tail in turn, its lines prefixed with the model name.
+    pub backend_log: Vec<String>,
+}
+
+/// A command issued over the control socket, executed by the serve loop's
+/// command handler like a platform command.
+pub struct LocalCommand {
+    pub raw: PlatformCommand,
+    pub reply: oneshot::Sender<std::result::Result<CommandOutcome, String>>,
+}
+
+/// Serves the control socket of a running `serve`.
+#[derive(Clone)]
+pub struct ControlServer {
+    pub presence: PresenceHandle,
+    pub connection: PlatformConnection,
+    pub models: Vec<ServedModel>,
+    pub commands: mpsc::UnboundedSender<LocalCommand>,
+    pub ledger: Arc<UsageLedger>,
+    pub started_at: u64,
+}
+
+/// Default location of the control socket.
+pub fn socket_path() -> Result<PathBuf> {
+    Ok(crate::config::state_dir()?.join(CONTROL_SOCKET_FILE))
+}
+
+/// The bound control socket, owned by this process until dropped.
+#[cfg(unix)]
+pub struct ControlListener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+/// Without Unix sockets there is no control socket.
+#[cfg(not(unix))]
+pub struct ControlListener {}
+
+/// Bind the control socket at `path`, readable only by the owner. Fails if
+/// another `serve` already owns it; a stale socket from a crash is replaced.
+#[cfg(unix)]
+pub async fn bind(path: PathBuf) -> Result<ControlListener> {
+    if path.exists() {
+        if UnixStream::connect(&path).await.is_ok() {
+            bail!(
+                "Another vramsply serve is already running (control socket {})",
+                path.display()
+            );
+        }
+        std::fs::remove_file(&path)
+            .with_context(|| format!("Failed removing stale socket {}", path.display()))?;
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed creating directory {}", parent.display()))?;
+    }
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Failed binding control socket {}", path.display()))?;
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("Failed securing {}", path.display()))?;
+    }
+    Ok(ControlListener { listener, path })
+}
+
+#[cfg(not(unix))]
+pub async fn bind(_path: PathBuf) -> Result<ControlListener> {
+    Ok(ControlListener {})
+}
+
+#[cfg(unix)]
+impl ControlServer {
+    /// Serve `listener` until `shutdown`, then remove the socket file.
+    pub fn spawn(
+        self,
+        listener: ControlListener,
+        shutdown: CancellationToken,
+    ) -> tokio::task::JoinHandle<()> {
+        let ControlListener { listener, path } = listener;
+        tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            tracing::warn!("Control socket accept failed: {}", e);
+                            continue;
+                        }
+                    },
+                };
+                let server = self.clone();
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    tokio::select! {
+                        _ = shutdown.cancelled() => {}
+                        result = server.serve_connection(stream) => {
+                            if let Err(e) = result {
+                                tracing::debug!("Control connection ended: {}", e);
+                            }
+                        }
+                    }
+                });
+            }
+            let _ = std::fs::remove_file(&path);
+        })
+    }
+
+    async fn serve_connection(&self, stream: UnixStream) -> Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        while let Some(line) = lines.next_line().await? {
+            let response = match serde_json::from_str::<ControlRequest>(&line) {
+                Ok(request) => self.dispatch(request).await,
+                Err(e) => ControlResponse::error(format!("Invalid request: {}", e)),
+            };
+            let mut out = serde_json::to_string(&response)?;
+            out.push('\n');
+            write.write_all(out.as_bytes()).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+impl ControlServer {
+    pub fn spawn(
+        self,
+        _listener: ControlListener,
+        shutdown: CancellationToken,
+    ) -> tokio::task::JoinHandle<()> {
+        tracing::debug!("No control socket on this platform");
+        tokio::spawn(async move { shutdown.cancelled().await })
+    }
+}
+
+impl ControlServer {
+    async fn dispatch(&self, request: ControlRequest) -> ControlResponse {
+        let command = match request.method.as_str() {
+            "status" => {
+                return match serde_json::to_value(self.status().await) {
+                    Ok(status) => ControlResponse::ok(status),
+                    Err(e) => ControlResponse::error(e.to_string()),
+                };
+            }
+            "dashboard" => {
+                return match serde_json::to_value(self.dashboard().await) {
+                    Ok(dashboard) => ControlResponse::ok(dashboard),
+                    Err(e) => ControlResponse::error(e.to_string()),
+                };
+            }
+            "drain" => "drain",
+            "stop" => "shutdown",
+            "reload" => "reload_model",
+            other => return ControlResponse::error(format!("Unknown method '{}'", other)),
+        };
+
+        let (reply, outcome) = oneshot::channel();
+        let local = LocalCommand {
+            raw: PlatformCommand {
+                id: format!("local-{}", uuid::Uuid::new_v4()),
+                command: command.to_string(),
+                params: request.params,
+            },
+            reply,
+        };
+        if self.commands.send(local).is_err() {
+            return ControlResponse::error("Agent is shutting down");
+        }
+        match outcome.await {
+            Ok(Ok(outcome)) => ControlResponse::ok(serde_json::json!({ "outcome": outcome })),
+            Ok(Err(e)) => ControlResponse::error(e),
+            Err(_) => ControlResponse::error("Agent is shutting down"),
+        }
+    }
+
+    async fn status(&self) -> ServeStatus {
+        let state = self.presence.snapshot().await;
+        // llama-server is locked for the whole of a restart; don't wait on it.
+        let models: Vec<ServedModelStatus> = self
+            .presence
+            .models()
+            .await
+            .into_iter()
+            .zip(&self.models)
+            .map(|(presence, served)| ServedModelStatus {
+                presence,
+                backend_port: served.backend_port,
+                backend_pid: served.llama.try_lock().ok().and_then(|l| l.pid()),
+            })
+            .collect();
+        let mut recent_errors: Vec<TransitionEvent> = self
+            .presence
+            .history(usize::MAX)
+            .into_iter()
+            .filter(|e| e.error_code.is_some())
+            .collect();
+        let skip = recent_errors.len().saturating_sub(RECENT_ERRORS);
+        recent_errors.drain(..skip);
+
+        ServeStatus {
+            pid: std::process::id(),
+            started_at: self.started_at,
+            status: state.status,
+            model: state.current_model,
+            provider_id: self.connection.provider_id(0).await,
+            backend_pid: models.first().and_then(|m| m.backend_pid),
+            active_requests: state.active_requests,
+            error_code: state.error_code.map(|c| c.to_string()),
+            error_message: state.error_message,
+            platform_connected: self.connection.is_connected(),
+            utilization: state.utilization,
+            recent_errors,
+            models,
+        }
+    }
+
+    async fn dashboard(&self) -> Dashboard {
+        let served = self.ledger.recent();
+        let mut last_hour = HourSummary::default();
+        for record in served.iter().filter(|r| (200..300).contains(&r.status)) {
+            last_hour.requests += 1;
+            last_hour.prompt_tokens += record.prompt_tokens;
+            last_hour.completion_tokens += record.completion_tokens;
+            last_hour.earned_cents += record.earnings_cents();
+        }
+        let skip = served.len().saturating_sub(DASHBOARD_REQUESTS);
+        let status = self.status().await;
+        let backend_log = match self.models.as_slice() {
+            [model] => model.backend_log.lines(DASHBOARD_LOG_LINES),
+            models => {
+                let per_model = (DASHBOARD_LOG_LINES / models.len().max(1)).max(1);
+                models
+                    .iter()
+                    .zip(&status.models)
+                    .flat_map(|(served, model)| {
+                        let name = &model.presence.model;
+                        served
+                            .backend_log
+                            .lines(per_model)
+                            .into_iter()
+                            .map(move |line| format!("[{}] {}", name, line))
+                    })
+                    .collect()
+            }
+        };
+        Dashboard {
+            status,
+            last_hour,
+            recent_requests: served.into_iter().skip(skip).collect(),
+            backend_log,
+        }
+    }
+}
+
+/// Send one request to a running `serve` and return its result.
+#[cfg(unix)]
+pub async fn request(
+    path: &Path,
+    method: &str,
+    params: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let stream = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("No running vramsply serve (socket {})", path.display()))?;
+    let (read, mut write) = stream.into_split();
+    let mut line = serde_json::to_string(&ControlRequest {
+        method: method.to_string(),
+        params,
+    })?;
+    line.push('\n');
+    write.write_all(line.as_bytes()).await?;
+
+    let timeout = match method {
+        "status" | "dashboard" => QUERY_TIMEOUT,
+        _ => CLIENT_TIMEOUT,
+    };
+    let reply = tokio::time::timeout(timeout, BufReader::new(read).lines().next_line())
+        .await
+        .context("Timed out waiting for the agent")??
+        .context("Agent closed the control connection")?;
+    let response: ControlResponse =
+        serde_json::from_str(&reply).context("Invalid control response")?;
+    match (response.ok, response.result) {
+        (true, Some(result)) => Ok(result),
+        (true, None) => Ok(serde_json::Value::Null),
+        (false, _) => bail!(
+            "{}",
+            response
+                .error
+                .unwrap_or_else(|| "Request failed".to_string())
+        ),
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn request(
+    _path: &Path,
+    _method: &str,
+    _params: serde_json::Value,
+) -> Result<serde_json::Value> {
+    bail!("The control socket is only available on Unix")
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use tokio::sync::Mutex;
+
+    use crate::backend::LlamaServer;
+    use crate::hardware::HardwareInventory;
+    use crate::test_support;
+
+    #[tokio::test]
+    async fn test_status_and_commands_over_socket() {
+        let (connection, _rx) = PlatformConnection::new(
+            reqwest::Client::new(),
+            test_support::config("http://127.0.0.1:1"),
+            Arc::new(Mutex::new("test-key".to_string())),
+            "test-agent".to_string(),
+            None,
+        );
+        connection
+            .set_provider_id(0, Some("prov-1".to_string()))
+            .await;
+        let presence = PresenceHandle::new(
+            vec!["m".to_string()],
+            connection.clone(),
+            test_support::identity(),
+            HardwareInventory::default(),
+            None,
+        );
+        let llama = LlamaServer::new(
+            "/nonexistent.gguf".to_string(),
+            8081,
+            "llama-server".to_string(),
+            99,
+            8192,
+        );
+        let model = ServedModel {
+            backend_log: llama.log_tail(),
+            llama: Arc::new(Mutex::new(llama)),
+            registration: Arc::new(Mutex::new(test_support::offer("m"))),
+            backend_port: 8081,
+        };
+        let (commands_tx, mut commands_rx) = mpsc::unbounded_channel::<LocalCommand>();
+        let ledger = Arc::new(UsageLedger::new(None));
+        ledger.record(&UsageRecord {
+            request_id: "req-1".to_string(),
+            client_request_id: None,
+            model: "m".to_string(),
+            started_at: crate::outbox::unix_now(),
+            latency_ms: 250,
+            status: 200,
+            prompt_tokens: 1_000_000,
+            completion_tokens: 500_000,
+            input_price_per_million: 100,
+            output_price_per_million: 200,
+        });
+        let server = ControlServer {
+            presence,
+            connection,
+            models: vec![model],
+            commands: commands_tx,
+            ledger,
+            started_at: 42,
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONTROL_SOCKET_FILE);
+        let shutdown = CancellationToken::new();
+        let listener = bind(path.clone()).await.unwrap();
+        // A second agent must not steal the socket.
+        assert!(bind(path.clone()).await.is_err());
+        let handle = server.spawn(listener, shutdown.clone());
+
+        let status: ServeStatus = serde_json::from_value(
+            request(&path, "status", serde_json::Value::Null)
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(status.status, AgentPresenceStatus::Idle);
+        assert_eq!(status.provider_id.as_deref(), Some("prov-1"));
+        assert_eq!(status.started_at, 42);
+        assert_eq!(status.backend_pid, None);
+        assert_eq!(status.models.len(), 1);
+        assert_eq!(status.models[0].presence.model, "m");
+        assert_eq!(
+            status.models[0].presence.provider_id.as_deref(),
+            Some("prov-1")
+        );
+        assert_eq!(status.models[0].backend_port, 8081);
+
+        let dashboard: Dashboard = serde_json::from_value(
+            request(&path, "dashboard", serde_json::Value::Null)
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(dashboard.status.started_at, 42);
+        assert_eq!(dashboard.last_hour.requests, 1);
+        assert_eq!(dashboard.last_hour.earned_cents, 200.0);
+        assert_eq!(dashboard.recent_requests[0].request_id, "req-1");
+        assert!(dashboard.backend_log.is_empty());
+
+        tokio::spawn(async move {
+            while let Some(local) = commands_rx.recv().await {
+                let reply = match local.raw.command.as_str() {
+                    "shutdown" => Ok(CommandOutcome::Accepted),
+                    other => Err(format!("{} rejected", other)),
+                };
+                let _ = local.reply.send(reply);
+            }
+        });
+        let stopped = request(&path, "stop", serde_json::Value::Null)
+            .await
+            .unwrap();
+        assert_eq!(stopped["outcome"], "accepted");
+        let err = request(&path, "reload", serde_json::json!({ "model": "x" }))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "reload_model rejected");
+        assert!(request(&path, "bogus", serde_json::Value::Null)
+            .await
+            .is_err());
+
+        shutdown.cancel();
+        handle.await.unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,807 @@
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::hardware::{self, HardwareInventory};
+use crate::models::format_size;
+
+/// Platform and public URL requests give up after this long.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Request tokens tolerate this much clock difference (see `proxy`).
+const MAX_CLOCK_SKEW_SECS: u64 = 30;
+const WARN_CLOCK_SKEW_SECS: u64 = 5;
+/// Less free space than this in the model directory is worth a warning.
+const MIN_FREE_DISK_BYTES: u64 = 20 * 1024 * 1024 * 1024;
+/// A certificate from files expiring sooner than this is worth a warning.
+/// ACME certificates renew themselves 30 days out.
+const WARN_CERT_EXPIRY_SECS: u64 = 14 * 24 * 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// The outcome of one diagnostic.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Check {
+    pub name: String,
+    pub status: CheckStatus,
+    pub message: String,
+    /// What to do about a warning or failure.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fix: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &str, message: impl Into<String>) -> Self {
+        Check {
+            name: name.to_string(),
+            status: CheckStatus::Pass,
+            message: message.into(),
+            fix: None,
+        }
+    }
+
+    fn warn(name: &str, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Check {
+            name: name.to_string(),
+            status: CheckStatus::Warn,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn fail(name: &str, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Check {
+            name: name.to_string(),
+            status: CheckStatus::Fail,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+/// Everything `vramsply doctor` found, as printed with `--json`.
+#[derive(Debug, Clone, Serialize)]
+pub struct DoctorReport {
+    pub agent_version: String,
+    pub os: String,
+    pub arch: String,
+    pub generated_at: u64,
+    pub checks: Vec<Check>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hardware: Option<HardwareInventory>,
+}
+
+impl DoctorReport {
+    pub fn failures(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|c| c.status == CheckStatus::Fail)
+            .count()
+    }
+}
+
+/// Run every check. `model` is the `--model` argument `serve` would get.
+pub async fn run(model: Option<&str>) -> DoctorReport {
+    let mut report = DoctorReport {
+        agent_version: env!("CARGO_PKG_VERSION").to_string(),
+        os: std::env::consts::OS.to_string(),
+        arch: std::env::consts::ARCH.to_string(),
+        generated_at: crate::outbox::unix_now(),
+        checks: Vec::new(),
+        hardware: None,
+    };
+
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            report.checks.push(Check::fail(
+                "config",
+                format!("{:#}", e),
+                "Fix the variable named above; see `Configuration` in the README",
+            ));
+            return report;
+        }
+    };
+    report.checks.push(match config.validate() {
+        Ok(()) => Check::pass("config", "Configuration is valid"),
+        Err(e) => Check::fail(
+            "config",
+            format!("{:#}", e),
+            "Fix the variable named above; see `Configuration` in the README",
+        ),
+    });
+
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .unwrap_or_default();
+    report
+        .checks
+        .extend(check_platform(&client, &config, SystemTime::now()).await);
+
+    let inventory = hardware::detect(&config.model_dir, &config.llama_server_path).await;
+    report
+        .checks
+        .extend(check_llama_server(&config, &inventory));
+    report.checks.extend(check_network(&client, &config).await);
+    report
+        .checks
+        .push(check_tls(&config, crate::outbox::unix_now()));
+    report
+        .checks
+        .extend(check_storage(&config, &inventory, model));
+    report.hardware = Some(inventory);
+    report
+}
+
+/// API key and clock skew, both from one authenticated platform request.
+async fn check_platform(
+    client: &reqwest::Client,
+    config: &Config,
+    sent_at: SystemTime,
+) -> Vec<Check> {
+    let missing_key = Check::fail(
+        "api_key",
+        "VRAM_SUPPLY_API_KEY is not set",
+        "Create an API key at https://vram.supply/keys and set VRAM_SUPPLY_API_KEY",
+    );
+    // Earnings are private to the account, so only a valid key gets a 2xx.
+    let url = format!("{}/v1/agents/earnings", config.platform_url);
+    let res = match client
+        .get(&url)
+        .query(&[("period", "daily"), ("days", "1")])
+        .header("Authorization", format!("Bearer {}", config.api_key))
+        .send()
+        .await
+    {
+        Ok(res) => res,
+        Err(e) => {
+            let mut checks = vec![Check::fail(
+                "platform",
+                format!("Cannot reach {}: {}", config.platform_url, e),
+                "Check your network, proxy and firewall, and VRAM_SUPPLY_PLATFORM_URL",
+            )];
+            if config.api_key.is_empty() {
+                checks.push(missing_key);
+            }
+            return checks;
+        }
+    };
+
+    let mut checks = vec![Check::pass(
+        "platform",
+        format!("Reached {}", config.platform_url),
+    )];
+    let status = res.status();
+    checks.push(if config.api_key.is_empty() {
+        missing_key
+    } else if status == reqwest::StatusCode::UNAUTHORIZED
+        || status == reqwest::StatusCode::FORBIDDEN
+    {
+        Check::fail(
+            "api_key",
+            format!("The platform rejected the API key ({})", status),
+            "Create a new key at https://vram.supply/keys; check for stray quotes or whitespace",
+        )
+    } else if status.is_success() {
+        Check::pass("api_key", "API key accepted")
+    } else {
+        Check::warn(
+            "api_key",
+            format!("Could not verify the API key: platform returned {}", status),
+            "Retry later; if it persists, include this report in a support ticket",
+        )
+    });
+
+    let server_time = res
+        .headers()
+        .get(reqwest::header::DATE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok());
+    checks.push(match server_time {
+        Some(server) => check_clock(sent_at, server),
+        None => Check::warn(
+            "clock",
+            "The platform response had no Date header to compare against",
+            "Make sure NTP is enabled (e.g. `timedatectl set-ntp true`)",
+        ),
+    });
+    checks
+}
+
+fn check_clock(local: SystemTime, server: SystemTime) -> Check {
+    let (skew, direction) = match local.duration_since(server) {
+        Ok(ahead) => (ahead.as_secs(), "ahead of"),
+        Err(e) => (e.duration().as_secs(), "behind"),
+    };
+    let message = format!("Local clock is {}s {} the platform", skew, direction);
+    let fix = "Enable time sync (e.g. `timedatectl set-ntp true`)";
+    if skew > MAX_CLOCK_SKEW_SECS {
+        Check::fail(
+            "clock",
+            format!("{}; request tokens will be rejected", message),
+            fix,
+        )
+    } else if skew > WARN_CLOCK_SKEW_SECS {
+        Check::warn("clock", message, fix)
+    } else {
+        Check::pass("clock", message)
+    }
+}
+
+fn check_llama_server(config: &Config, inventory: &HardwareInventory) -> Vec<Check> {
+    let Some(build) = &inventory.llama_build else {
+        return vec![Check::fail(
+            "llama_server",
+            format!(
+                "`{} --version` failed; llama-server is not installed or not in PATH",
+                config.llama_server_path
+            ),
+            "Install llama.cpp (https://github.com/ggerganov/llama.cpp) or set VRAM_SUPPLY_LLAMA_SERVER_PATH",
+        )];
+    };
+    let version = build.version.as_deref().unwrap_or("unknown version");
+    let mut checks = vec![Check::pass(
+        "llama_server",
+        format!(
+            "{} ({}, {} backend)",
+            config.llama_server_path, version, build.backend
+        ),
+    )];
+
+    let gpus: Vec<String> = inventory
+        .gpus
+        .iter()
+        .map(|g| {
+            let name = g.model.as_deref().unwrap_or(&g.vendor);
+            match g.vram_total_bytes {
+                Some(vram) => format!("{} ({})", name, format_size(vram)),
+                None => name.to_string(),
+            }
+        })
+        .collect();
+    checks.push(if gpus.is_empty() {
+        Check::warn(
+            "gpu",
+            "No GPU detected; models will run on the CPU",
+            "Install your GPU driver (nvidia-smi or rocm-smi should work), or serve a small model",
+        )
+    } else if build.backend == "cpu" {
+        Check::fail(
+            "gpu",
+            format!(
+                "llama-server was built without GPU support, but found {}",
+                gpus.join(", ")
+            ),
+            "Install a llama.cpp build for your GPU (CUDA, ROCm, Metal or Vulkan)",
+        )
+    } else {
+        Check::pass("gpu", gpus.join(", "))
+    });
+    checks
+}
+
+/// Ports and the public URL. While a serve is running its own ports are
+/// expected to be taken, so only its public URL is probed.
+async fn check_network(client: &reqwest::Client, config: &Config) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let running = match crate::control::socket_path() {
+        Ok(path) => crate::control::request(&path, "status", serde_json::Value::Null)
+            .await
+            .ok()
+            .and_then(|v| v["pid"].as_u64()),
+        Err(_) => None,
+    };
+
+    let mut proxy_listener = None;
+    if let Some(pid) = running {
+        checks.push(Check::pass(
+            "ports",
+            format!("In use by the running serve (pid {})", pid),
+        ));
+    } else {
+        let mut ports = vec![
+            (
+                "VRAM_SUPPLY_PORT",
+                SocketAddr::new(config.bind_address, config.port),
+            ),
+            (
+                "VRAM_SUPPLY_BACKEND_PORT",
+                SocketAddr::new(IpAddr::from([127, 0, 0, 1]), config.backend_port),
+            ),
+        ];
+        if let Some(addr) = config.metrics_addr {
+            ports.push(("VRAM_SUPPLY_METRICS_ADDR", addr));
+        }
+        let mut busy = Vec::new();
+        for (var, addr) in ports {
+            match tokio::net::TcpListener::bind(addr).await {
+                Ok(listener) if var == "VRAM_SUPPLY_PORT" => proxy_listener = Some(listener),
+                Ok(_) => {}
+                Err(e) => busy.push(format!("{} ({}): {}", addr, var, e)),
+            }
+        }
+        checks.push(if busy.is_empty() {
+            Check::pass("ports", "Proxy, backend and metrics ports are free")
+        } else {
+            Check::fail(
+                "ports",
+                format!("Cannot listen on {}", busy.join("; ")),
+                "Stop the process using the port (`ss -ltnp`) or choose another with the variable named",
+            )
+        });
+    }
+
+    checks.push(check_public_url(client, config, running.is_some(), proxy_listener).await);
+    checks
+}
+
+async fn check_public_url(
+    client: &reqwest::Client,
+    config: &Config,
+    serving: bool,
+    listener: Option<tokio::net::TcpListener>,
+) -> Check {
+    if config.tunnel {
+        return Check::pass(
+            "public_url",
+            "Not used: requests arrive through the platform relay (VRAM_SUPPLY_TUNNEL)",
+        );
+    }
+    let url = match reqwest::Url::parse(&config.public_url) {
+        Ok(url) => url,
+        Err(e) => {
+            return Check::fail(
+                "public_url",
+                format!("'{}' is not a valid URL: {}", config.public_url, e),
+                "Set VRAM_SUPPLY_PUBLIC_URL to e.g. https://node.example.com",
+            )
+        }
+    };
+    let host = url.host_str().unwrap_or("").to_string();
+    let private = match crate::reachability::check_address(config).await {
+        Ok(private) => private,
+        Err(e) => {
+            return Check::fail(
+                "public_url",
+                format!("{:#}", e),
+                "Set VRAM_SUPPLY_PUBLIC_URL to an address reachable from the internet",
+            )
+        }
+    };
+
+    // With no serve running, answer /health ourselves for the duration of the
+    // probe, so a reply proves the request reached this machine. Over TLS,
+    // with the certificate serve would use.
+    let nonce = uuid::Uuid::new_v4().to_string();
+    let shutdown = tokio_util::sync::CancellationToken::new();
+    if !serving {
+        let Some(listener) = listener else {
+            return Check::warn(
+                "public_url",
+                format!("Not probed: port {} is not free to answer on", config.port),
+                "Free the proxy port and run doctor again",
+            );
+        };
+        let body = nonce.clone();
+        let router =
+            axum::Router::new().route("/health", axum::routing::get(move || async move { body }));
+        match tls_files(config) {
+            Some((cert, key)) => {
+                let listener = crate::tls::load_files(&cert, &key).and_then(|certified| {
+                    let store = Arc::new(crate::tls::CertStore::default());
+                    store.set(certified);
+                    crate::tls::TlsListener::new(listener, crate::tls::server_config(store)?)
+                });
+                match listener {
+                    Ok(listener) => spawn_probe_server(listener, router, shutdown.clone()),
+                    Err(e) => {
+                        return Check::warn(
+                            "public_url",
+                            format!("Not probed: no certificate to serve ({:#})", e),
+                            "See the tls check",
+                        )
+                    }
+                }
+            }
+            None => spawn_probe_server(listener, router, shutdown.clone()),
+        }
+    }
+
+    let health = format!("{}/health", config.public_url.trim_end_matches('/'));
+    let reached = match client.get(&health).send().await {
+        Err(e) => Err(e.to_string()),
+        Ok(res) if !res.status().is_success() => Err(format!("returned {}", res.status())),
+        Ok(_) if serving => Ok(()),
+        Ok(res) => match res.text().await {
+            Ok(body) if body == nonce => Ok(()),
+            _ => Err("answered, but not from this agent's proxy port".to_string()),
+        },
+    };
+    shutdown.cancel();
+
+    match reached {
+        Ok(()) if private => Check::warn(
+            "public_url",
+            format!("{} is a private address, allowed by VRAM_SUPPLY_ALLOW_PRIVATE_URL", url),
+            "Only a platform on the same network can reach it; use a public address to serve the marketplace",
+        ),
+        Ok(()) => Check::pass("public_url", format!("{} reached this agent", health)),
+        // Checked from this machine, so a router without hairpin NAT can fail
+        // here even when the platform gets through.
+        Err(e) => Check::warn(
+            "public_url",
+            format!("GET {} failed: {}", health, e),
+            format!(
+                "Make sure {} resolves to this machine and port {} is open in your firewall and forwarded by your router",
+                host, config.port
+            ),
+        ),
+    }
+}
+
+/// Serve `router` on `listener` in the background until `shutdown`.
+fn spawn_probe_server<L>(
+    listener: L,
+    router: axum::Router,
+    shutdown: tokio_util::sync::CancellationToken,
+) where
+    L: axum::serve::Listener,
+    L::Addr: std::fmt::Debug,
+{
+    tokio::spawn(async move {
+        let _ = axum::serve(listener, router)
+            .with_graceful_shutdown(shutdown.cancelled_owned())
+            .await;
+    });
+}
+
+/// The certificate and key files the listener serves, if it serves TLS.
+fn tls_files(config: &Config) -> Option<(PathBuf, PathBuf)> {
+    match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some((cert.clone(), key.clone())),
+        _ if !config.acme_domains.is_empty() => crate::acme::dir()
+            .ok()
+            .map(|dir| crate::acme::stored_files(&dir)),
+        _ => None,
+    }
+}
+
+/// The listener's certificate: loadable, unexpired and naming `public_url`.
+fn check_tls(config: &Config, now: u64) -> Check {
+    let acme = !config.acme_domains.is_empty();
+    let Some((cert, key)) = tls_files(config) else {
+        return Check::pass("tls", "Not enabled: the listener serves plain HTTP");
+    };
+    if acme && !cert.exists() {
+        return Check::pass(
+            "tls",
+            format!(
+                "No certificate issued yet; serve requests one for {} from {}",
+                config.acme_domains.join(", "),
+                config.acme_directory
+            ),
+        );
+    }
+    let info = crate::tls::load_files(&cert, &key).and_then(|_| {
+        let pem = std::fs::read(&cert)?;
+        crate::tls::inspect(&pem)
+    });
+    let info = match info {
+        Ok(info) => info,
+        Err(e) => return Check::fail(
+            "tls",
+            format!("{:#}", e),
+            "Point VRAM_SUPPLY_TLS_CERT and VRAM_SUPPLY_TLS_KEY at a matching PEM chain and key",
+        ),
+    };
+    let expires = crate::transitions::format_timestamp(info.not_after);
+    let host = reqwest::Url::parse(&config.public_url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    let covers_host = info.dns_names.iter().any(|name| {
+        name.eq_ignore_ascii_case(&host)
+            || name.strip_prefix("*.").is_some_and(|suffix| {
+                host.split_once('.')
+                    .is_some_and(|(_, rest)| rest.eq_ignore_ascii_case(suffix))
+            })
+    });
+    if info.not_after <= now {
+        Check::fail(
+            "tls",
+            format!("{} expired on {}", cert.display(), expires),
+            if acme {
+                "Start serve to renew it"
+            } else {
+                "Install a renewed certificate; serve reloads it without a restart"
+            },
+        )
+    } else if !config.tunnel && !covers_host {
+        Check::warn(
+            "tls",
+            format!(
+                "Certificate is for {}, not {}",
+                info.dns_names.join(", "),
+                host
+            ),
+            "Clients will reject it; use a certificate that names the VRAM_SUPPLY_PUBLIC_URL host",
+        )
+    } else if !acme && info.not_after - now < WARN_CERT_EXPIRY_SECS {
+        Check::warn(
+            "tls",
+            format!("Certificate expires on {}", expires),
+            "Install a renewed certificate; serve reloads it without a restart",
+        )
+    } else {
+        Check::pass(
+            "tls",
+            format!(
+                "Certificate for {} valid until {}",
+                info.dns_names.join(", "),
+                expires
+            ),
+        )
+    }
+}
+
+/// Create and remove a file in `dir`.
+fn probe_writable(dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let probe = dir.join(format!(".vramsply-doctor-{}", std::process::id()));
+    std::fs::write(&probe, b"ok")?;
+    std::fs::remove_file(&probe)
+}
+
+fn check_storage(
+    config: &Config,
+    inventory: &HardwareInventory,
+    model: Option<&str>,
+) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    checks.push(match crate::config::state_dir() {
+        Ok(dir) => match probe_writable(&dir) {
+            Ok(()) => Check::pass("state_dir", format!("{} is writable", dir.display())),
+            Err(e) => Check::fail(
+                "state_dir",
+                format!("Cannot write to {}: {}", dir.display(), e),
+                format!("Fix ownership: `sudo chown -R $USER {}`", dir.display()),
+            ),
+        },
+        Err(e) => Check::fail("state_dir", format!("{:#}", e), "Set HOME"),
+    });
+
+    let dir = &config.model_dir;
+    checks.push(if !dir.exists() {
+        Check::warn(
+            "model_dir",
+            format!("{} does not exist", dir.display()),
+            "`vramsply models pull <hf_repo_id>` creates it, or set VRAM_SUPPLY_MODEL_DIR",
+        )
+    } else {
+        match probe_writable(dir) {
+            Ok(()) => Check::pass("model_dir", format!("{} is writable", dir.display())),
+            Err(e) => Check::warn(
+                "model_dir",
+                format!(
+                    "Cannot write to {}: {}; `models pull` will fail",
+                    dir.display(),
+                    e
+                ),
+                format!("Fix ownership: `sudo chown -R $USER {}`", dir.display()),
+            ),
+        }
+    });
+
+    checks.push(match model {
+        Some(name) => match crate::models::find_model(config, name) {
+            Ok(path) => Check::pass("model", format!("Will serve {}", path)),
+            Err(e) => Check::fail(
+                "model",
+                format!("{:#}", e),
+                "List available models with `vramsply models list`",
+            ),
+        },
+        None => match crate::models::list_local_models(config) {
+            Ok(models) if models.is_empty() => Check::fail(
+                "model",
+                format!("No .gguf models in {}", dir.display()),
+                "Download one with `vramsply models pull <hf_repo_id>`, or pass --model <path>",
+            ),
+            Ok(models) => Check::pass(
+                "model",
+                format!("{} model(s) found, e.g. {}", models.len(), models[0].name),
+            ),
+            Err(e) => Check::fail(
+                "model",
+                format!("{:#}", e),
+                format!("Make {} readable by this user", dir.display()),
+            ),
+        },
+    });
+
+    if let Some(free) = inventory.disk_free_bytes {
+        checks.push(if free < MIN_FREE_DISK_BYTES {
+            Check::warn(
+                "disk",
+                format!("Only {} free for models", format_size(free)),
+                "Free up space or point VRAM_SUPPLY_MODEL_DIR at a larger disk",
+            )
+        } else {
+            Check::pass("disk", format!("{} free for models", format_size(free)))
+        });
+    }
+    checks
+}
+
+/// Render a report as the text shown by `vramsply doctor`.
+pub fn render(report: &DoctorReport) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "vramsply doctor ({}, {} {})\n",
+        report.agent_version, report.os, report.arch
+    );
+    for check in &report.checks {
+        let label = match check.status {
+            CheckStatus::Pass => "PASS",
+            CheckStatus::Warn => "WARN",
+            CheckStatus::Fail => "FAIL",
+        };
+        let _ = writeln!(out, "{}  {:<13} {}", label, check.name, check.message);
+        if let Some(fix) = &check.fix {
+            let _ = writeln!(out, "      {:<13} fix: {}", "", fix);
+        }
+    }
+    let warnings = report
+        .checks
+        .iter()
+        .filter(|c| c.status == CheckStatus::Warn)
+        .count();
+    let _ = writeln!(
+        out,
+        "\n{} failed, {} warning(s). Attach `vramsply doctor --json` to support tickets.",
+        report.failures(),
+        warnings
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::http::{header, StatusCode};
+    use axum::routing::get;
+    use axum::Router;
+
+    use crate::hardware::{GpuInfo, LlamaBuild};
+    use crate::test_support;
+
+    #[tokio::test]
+    async fn test_rejected_key_and_clock_skew() {
+        let app = Router::new().route(
+            "/v1/agents/earnings",
+            get(|| async {
+                let date = httpdate::fmt_http_date(SystemTime::now() - Duration::from_secs(120));
+                (StatusCode::UNAUTHORIZED, [(header::DATE, date)])
+            }),
+        );
+        let url = test_support::serve(app).await;
+        let checks = check_platform(
+            &reqwest::Client::new(),
+            &test_support::config(&url),
+            SystemTime::now(),
+        )
+        .await;
+
+        let by_name = |name: &str| checks.iter().find(|c| c.name == name).unwrap().clone();
+        assert_eq!(by_name("platform").status, CheckStatus::Pass);
+        assert_eq!(by_name("api_key").status, CheckStatus::Fail);
+        let clock = by_name("clock");
+        assert_eq!(clock.status, CheckStatus::Fail);
+        assert!(clock.message.contains("ahead of"));
+
+        let unreachable = check_platform(
+            &reqwest::Client::new(),
+            &test_support::config("http://127.0.0.1:1"),
+            SystemTime::now(),
+        )
+        .await;
+        assert_eq!(unreachable.len(), 1);
+        assert_eq!(unreachable[0].status, CheckStatus::Fail);
+    }
+
+    #[test]
+    fn test_tls_certificate_expiry_and_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_support::config("http://127.0.0.1:1");
+        assert_eq!(check_tls(&config, 0).status, CheckStatus::Pass);
+
+        let key = rcgen::KeyPair::generate().unwrap();
+        let mut params =
+            rcgen::CertificateParams::new(vec!["node.example.com".to_string()]).unwrap();
+        params.not_after = rcgen::date_time_ymd(2030, 1, 2);
+        let cert = params.self_signed(&key).unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, key.serialize_pem()).unwrap();
+        config.tls_cert = Some(cert_path);
+        config.tls_key = Some(key_path.clone());
+        config.public_url = "https://node.example.com".to_string();
+
+        let not_after = 1_893_542_400;
+        let check = check_tls(&config, not_after - 365 * 24 * 3600);
+        assert_eq!(check.status, CheckStatus::Pass, "{:?}", check);
+        assert_eq!(
+            check_tls(&config, not_after - 24 * 3600).status,
+            CheckStatus::Warn
+        );
+        let expired = check_tls(&config, not_after + 1);
+        assert_eq!(expired.status, CheckStatus::Fail);
+        assert!(expired.message.contains("expired"));
+
+        config.public_url = "https://other.example.com".to_string();
+        let check = check_tls(&config, 0);
+        assert_eq!(check.status, CheckStatus::Warn);
+        assert!(check.message.contains("not other.example.com"));
+
+        std::fs::write(
+            &key_path,
+            rcgen::KeyPair::generate().unwrap().serialize_pem(),
+        )
+        .unwrap();
+        assert_eq!(check_tls(&config, 0).status, CheckStatus::Fail);
+    }
+
+    #[test]
+    fn test_cpu_build_with_gpu_fails() {
+        let config = test_support::config("http://127.0.0.1:1");
+        let mut inventory = HardwareInventory {
+            gpus: vec![GpuInfo {
+                vendor: "nvidia".to_string(),
+                model: Some("RTX 4090".to_string()),
+                vram_total_bytes: Some(24 * 1024 * 1024 * 1024),
+            }],
+            llama_build: Some(LlamaBuild {
+                version: Some("b4000".to_string()),
+                backend: "cpu".to_string(),
+            }),
+            ..Default::default()
+        };
+        let checks = check_llama_server(&config, &inventory);
+        assert_eq!(checks[0].status, CheckStatus::Pass);
+        assert_eq!(checks[1].status, CheckStatus::Fail);
+        assert!(checks[1].message.contains("RTX 4090 (24.0 GB)"));
+
+        inventory.llama_build = None;
+        let checks = check_llama_server(&config, &inventory);
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].status, CheckStatus::Fail);
+
+        let report = DoctorReport {
+            agent_version: "0.1.0".to_string(),
+            os: "linux".to_string(),
+            arch: "x86_64".to_string(),
+            generated_at: 0,
+            checks,
+            hardware: None,
+        };
+        let text = render(&report);
+        assert!(text.contains("FAIL  llama_server"));
+        assert!(text.contains("fix: Install llama.cpp"));
+        assert!(text.contains("1 failed, 0 warning(s)"));
+    }
+}
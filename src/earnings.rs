@@ -0,0 +1,267 @@
+use std::fmt::Write;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::config::Config;
+use crate::transitions::format_timestamp;
+
+/// Granularity of the earnings breakdown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Period {
+    Daily,
+    Weekly,
+}
+
+impl Period {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Period::Daily => "daily",
+            Period::Weekly => "weekly",
+        }
+    }
+}
+
+/// Earnings for one model over one day or week.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EarningsBucket {
+    /// Start of the period, as unix seconds (UTC midnight).
+    pub period_start: u64,
+    pub model: String,
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub earned_cents: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Payout {
+    pub id: String,
+    pub amount_cents: u64,
+    /// e.g. `pending`, `paid` or `failed`.
+    pub status: String,
+    pub created_at: u64,
+    #[serde(default)]
+    pub paid_at: Option<u64>,
+}
+
+/// Response of `/v1/agents/earnings`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EarningsReport {
+    pub total_earned_cents: u64,
+    /// Earned but not yet paid out.
+    pub pending_payout_cents: u64,
+    #[serde(default)]
+    pub breakdown: Vec<EarningsBucket>,
+    #[serde(default)]
+    pub payouts: Vec<Payout>,
+}
+
+/// Which earnings to fetch.
+#[derive(Debug, Clone)]
+pub struct EarningsQuery {
+    /// `None` covers every agent on the account.
+    pub agent_uid: Option<String>,
+    pub period: Period,
+    pub days: u32,
+}
+
+/// Fetch earnings, served tokens and payout history from the platform.
+pub async fn fetch_earnings(
+    client: &reqwest::Client,
+    config: &Config,
+    token: &Arc<Mutex<String>>,
+    query: &EarningsQuery,
+) -> Result<EarningsReport> {
+    let url = format!("{}/v1/agents/earnings", config.platform_url);
+    let mut params = vec![
+        ("period", query.period.as_str().to_string()),
+        ("days", query.days.to_string()),
+    ];
+    if let Some(agent_uid) = &query.agent_uid {
+        params.push(("agent_uid", agent_uid.clone()));
+    }
+    let current_token = token.lock().await.clone();
+    let res = client
+        .get(url)
+        .query(&params)
+        .header("Authorization", format!("Bearer {}", current_token))
+        .send()
+        .await
+        .context("Earnings request failed")?;
+
+    let status = res.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+        bail!(
+            "Earnings request rejected ({}); check VRAM_SUPPLY_API_KEY with `vramsply auth`",
+            status
+        );
+    }
+    if !status.is_success() {
+        bail!("Earnings request failed: {}", status);
+    }
+    res.json().await.context("Invalid earnings response")
+}
+
+fn dollars(cents: u64) -> String {
+    format!("${}.{:02}", cents / 100, cents % 100)
+}
+
+/// Render a report as the text shown by `vramsply earnings`.
+pub fn render(report: &EarningsReport, period: Period) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "Total earned:   {}",
+        dollars(report.total_earned_cents)
+    );
+    let _ = writeln!(
+        out,
+        "Pending payout: {}",
+        dollars(report.pending_payout_cents)
+    );
+
+    let label = match period {
+        Period::Daily => "DAY",
+        Period::Weekly => "WEEK OF",
+    };
+    let _ = writeln!(out);
+    if report.breakdown.is_empty() {
+        let _ = writeln!(out, "No earnings in this period.");
+    } else {
+        let _ = writeln!(
+            out,
+            "{:<10}  {:<40} {:>9} {:>14} {:>14} {:>10}",
+            label, "MODEL", "REQUESTS", "PROMPT TOK", "COMPL TOK", "EARNED"
+        );
+        let mut buckets = report.breakdown.clone();
+        buckets.sort_by(|a, b| {
+            b.period_start
+                .cmp(&a.period_start)
+                .then_with(|| a.model.cmp(&b.model))
+        });
+        for b in &buckets {
+            let _ = writeln!(
+                out,
+                "{:<10}  {:<40} {:>9} {:>14} {:>14} {:>10}",
+                &format_timestamp(b.period_start)[..10],
+                b.model,
+                b.requests,
+                b.prompt_tokens,
+                b.completion_tokens,
+                dollars(b.earned_cents)
+            );
+        }
+    }
+
+    let _ = writeln!(out);
+    if report.payouts.is_empty() {
+        let _ = writeln!(out, "No payouts yet.");
+    } else {
+        let _ = writeln!(
+            out,
+            "{:<24} {:<10} {:>10}  {:<10}",
+            "PAYOUT", "STATUS", "AMOUNT", "DATE"
+        );
+        for p in &report.payouts {
+            let date = p.paid_at.unwrap_or(p.created_at);
+            let _ = writeln!(
+                out,
+                "{:<24} {:<10} {:>10}  {:<10}",
+                p.id,
+                p.status,
+                dollars(p.amount_cents),
+                &format_timestamp(date)[..10]
+            );
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::extract::Query;
+    use axum::routing::get;
+    use axum::Router;
+    use std::collections::HashMap;
+
+    use crate::test_support;
+
+    #[tokio::test]
+    async fn test_fetch_and_render_earnings() {
+        let app = Router::new().route(
+            "/v1/agents/earnings",
+            get(|Query(q): Query<HashMap<String, String>>| async move {
+                assert_eq!(q["period"], "weekly");
+                assert_eq!(q["days"], "28");
+                assert_eq!(q["agent_uid"], "test-agent");
+                axum::Json(serde_json::json!({
+                    "total_earned_cents": 12345,
+                    "pending_payout_cents": 205,
+                    "breakdown": [
+                        { "period_start": 1_760_313_600u64, "model": "llama", "requests": 3,
+                          "prompt_tokens": 300, "completion_tokens": 900, "earned_cents": 105 },
+                        { "period_start": 1_760_918_400u64, "model": "llama", "requests": 1,
+                          "prompt_tokens": 10, "completion_tokens": 20, "earned_cents": 100 }
+                    ],
+                    "payouts": [
+                        { "id": "po_1", "amount_cents": 12140, "status": "paid",
+                          "created_at": 1_760_000_000u64, "paid_at": 1_760_313_600u64 }
+                    ]
+                }))
+            }),
+        );
+        let url = test_support::serve(app).await;
+
+        let report = fetch_earnings(
+            &reqwest::Client::new(),
+            &test_support::config(&url),
+            &Arc::new(Mutex::new("test-key".to_string())),
+            &EarningsQuery {
+                agent_uid: Some("test-agent".to_string()),
+                period: Period::Weekly,
+                days: 28,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.breakdown.len(), 2);
+        assert_eq!(report.payouts[0].paid_at, Some(1_760_313_600));
+
+        let text = render(&report, Period::Weekly);
+        assert!(text.contains("Total earned:   $123.45"));
+        assert!(text.contains("Pending payout: $2.05"));
+        // Most recent period first.
+        let newer = text.find("2025-10-20").unwrap();
+        let older = text.find("2025-10-13").unwrap();
+        assert!(newer < older);
+        assert!(text.contains("po_1"));
+        assert!(text.contains("$121.40"));
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized_points_at_api_key() {
+        let app = Router::new().route(
+            "/v1/agents/earnings",
+            get(|| async { axum::http::StatusCode::UNAUTHORIZED }),
+        );
+        let url = test_support::serve(app).await;
+        let err = fetch_earnings(
+            &reqwest::Client::new(),
+            &test_support::config(&url),
+            &Arc::new(Mutex::new("bad".to_string())),
+            &EarningsQuery {
+                agent_uid: None,
+                period: Period::Daily,
+                days: 7,
+            },
+        )
+        .await
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("VRAM_SUPPLY_API_KEY"));
+    }
+}
@@ -0,0 +1,184 @@
+use serde::{Deserialize, Serialize};
+
+/// Errors the agent reports in presence, so the platform and alerting can
+/// decide whether to wait, page someone, or stop routing for good.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentErrorCode {
+    /// llama-server failed to start or become healthy.
+    LlamaStartFailed,
+    /// llama-server exited while serving; the agent will restart it.
+    LlamaStopped,
+    /// Restarting llama-server after it stopped failed.
+    LlamaRestartFailed,
+    /// The platform rejected or could not be reached for registration.
+    ProviderRegisterFailed,
+    /// The model file did not match the published checksum, or could not be
+    /// checked.
+    ModelVerificationFailed,
+    /// The authenticating proxy could not bind the public port.
+    ProxyStartFailed,
+    /// `public_url` does not resolve, or resolves to a loopback or private
+    /// address without `VRAM_SUPPLY_ALLOW_PRIVATE_URL`.
+    PublicUrlInvalid,
+    /// The relay did not issue a tunnel URL in time.
+    TunnelUnavailable,
+    /// No certificate could be loaded or issued for the TLS listener.
+    TlsCertificateFailed,
+}
+
+/// How urgently an error needs attention.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// Impaired but recovering on its own.
+    Warning,
+    /// Not serving; may recover on retry.
+    Error,
+    /// Not serving and will not recover without operator action.
+    Critical,
+}
+
+/// Severity, retryability and remediation for an error code, as published in
+/// presence alongside the code itself.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ErrorInfo {
+    pub severity: Severity,
+    pub retryable: bool,
+    pub remediation: &'static str,
+}
+
+impl AgentErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AgentErrorCode::LlamaStartFailed => "llama_start_failed",
+            AgentErrorCode::LlamaStopped => "llama_stopped",
+            AgentErrorCode::LlamaRestartFailed => "llama_restart_failed",
+            AgentErrorCode::ProviderRegisterFailed => "provider_register_failed",
+            AgentErrorCode::ModelVerificationFailed => "model_verification_failed",
+            AgentErrorCode::ProxyStartFailed => "proxy_start_failed",
+            AgentErrorCode::PublicUrlInvalid => "public_url_invalid",
+            AgentErrorCode::TunnelUnavailable => "tunnel_unavailable",
+            AgentErrorCode::TlsCertificateFailed => "tls_certificate_failed",
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        match self {
+            AgentErrorCode::LlamaStopped => Severity::Warning,
+            AgentErrorCode::ProviderRegisterFailed | AgentErrorCode::TunnelUnavailable => {
+                Severity::Error
+            }
+            AgentErrorCode::LlamaStartFailed
+            | AgentErrorCode::LlamaRestartFailed
+            | AgentErrorCode::ModelVerificationFailed
+            | AgentErrorCode::ProxyStartFailed
+            | AgentErrorCode::PublicUrlInvalid
+            | AgentErrorCode::TlsCertificateFailed => Severity::Critical,
+        }
+    }
+
+    /// Whether the same operation may succeed if simply tried again.
+    pub fn retryable(&self) -> bool {
+        match self {
+            AgentErrorCode::LlamaStopped
+            | AgentErrorCode::ProviderRegisterFailed
+            | AgentErrorCode::TunnelUnavailable => true,
+            AgentErrorCode::LlamaStartFailed
+            | AgentErrorCode::LlamaRestartFailed
+            | AgentErrorCode::ModelVerificationFailed
+            | AgentErrorCode::ProxyStartFailed
+            | AgentErrorCode::PublicUrlInvalid
+            | AgentErrorCode::TlsCertificateFailed => false,
+        }
+    }
+
+    /// Short operator-facing hint for fixing the error.
+    pub fn remediation(&self) -> &'static str {
+        match self {
+            AgentErrorCode::LlamaStartFailed => {
+                "Check llama-server output, the model path and available VRAM; \
+                 try fewer GPU layers or a smaller context length"
+            }
+            AgentErrorCode::LlamaStopped => {
+                "llama-server exited unexpectedly and is being restarted; \
+                 check for out-of-memory kills if this repeats"
+            }
+            AgentErrorCode::LlamaRestartFailed => {
+                "Restart the agent; if it fails again check llama-server output and VRAM usage"
+            }
+            AgentErrorCode::ProviderRegisterFailed => {
+                "Check network access to the platform and that the API key is valid"
+            }
+            AgentErrorCode::ModelVerificationFailed => {
+                "Re-download the model with `vramsply models pull` and check --hf-repo"
+            }
+            AgentErrorCode::ProxyStartFailed => {
+                "Check that VRAM_SUPPLY_PORT is free and VRAM_SUPPLY_BIND_ADDRESS is an address of this machine"
+            }
+            AgentErrorCode::PublicUrlInvalid => {
+                "Set VRAM_SUPPLY_PUBLIC_URL to an address reachable from the internet, \
+                 or VRAM_SUPPLY_ALLOW_PRIVATE_URL=true if the platform shares this network"
+            }
+            AgentErrorCode::TunnelUnavailable => {
+                "Check outbound access to the platform and that the API key is valid; \
+                 unset VRAM_SUPPLY_TUNNEL to serve on VRAM_SUPPLY_PUBLIC_URL instead"
+            }
+            AgentErrorCode::TlsCertificateFailed => {
+                "Check VRAM_SUPPLY_TLS_CERT and VRAM_SUPPLY_TLS_KEY are a matching PEM pair; \
+                 for ACME, check each domain resolves here and port 443 reaches VRAM_SUPPLY_PORT"
+            }
+        }
+    }
+
+    pub fn info(&self) -> ErrorInfo {
+        ErrorInfo {
+            severity: self.severity(),
+            retryable: self.retryable(),
+            remediation: self.remediation(),
+        }
+    }
+}
+
+impl std::fmt::Display for AgentErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes_serialize_as_their_string() {
+        for code in [
+            AgentErrorCode::LlamaStartFailed,
+            AgentErrorCode::LlamaStopped,
+            AgentErrorCode::LlamaRestartFailed,
+            AgentErrorCode::ProviderRegisterFailed,
+            AgentErrorCode::ModelVerificationFailed,
+            AgentErrorCode::ProxyStartFailed,
+            AgentErrorCode::PublicUrlInvalid,
+            AgentErrorCode::TunnelUnavailable,
+            AgentErrorCode::TlsCertificateFailed,
+        ] {
+            assert_eq!(
+                serde_json::to_value(code).unwrap(),
+                serde_json::Value::String(code.to_string())
+            );
+            assert!(!code.remediation().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_error_info() {
+        let info = serde_json::to_value(AgentErrorCode::LlamaStopped.info()).unwrap();
+        assert_eq!(info["severity"], "warning");
+        assert_eq!(info["retryable"], true);
+        assert_eq!(
+            AgentErrorCode::ModelVerificationFailed.severity(),
+            Severity::Critical
+        );
+    }
+}
@@ -0,0 +1,412 @@
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::process::Command;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What this node is: GPUs, CPU, memory, disk and llama.cpp build.
+///
+/// Every field is best-effort; anything that cannot be detected is left empty
+/// rather than failing startup.
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct HardwareInventory {
+    pub gpus: Vec<GpuInfo>,
+    pub cpu_model: Option<String>,
+    pub cpu_cores: Option<u32>,
+    pub memory_total_bytes: Option<u64>,
+    pub disk_free_bytes: Option<u64>,
+    pub llama_build: Option<LlamaBuild>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct GpuInfo {
+    pub vendor: String,
+    pub model: Option<String>,
+    pub vram_total_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct LlamaBuild {
+    pub version: Option<String>,
+    /// Compute backend llama.cpp was built for: cuda, rocm, metal, vulkan, sycl or cpu.
+    pub backend: String,
+}
+
+/// Detect the inventory of this machine, running the GPU, CPU and llama.cpp
+/// probes concurrently.
+pub async fn detect(model_dir: &Path, llama_server_path: &str) -> HardwareInventory {
+    let root = Path::new("/");
+    let ((cpu_model, memory_total_bytes), gpus, llama_build) =
+        tokio::join!(detect_cpu_and_memory(root), detect_gpus(root), async {
+            run_probe(llama_server_path, &["--version"])
+                .await
+                .map(|out| parse_llama_version(&out))
+        },);
+
+    HardwareInventory {
+        gpus,
+        cpu_model,
+        cpu_cores: std::thread::available_parallelism()
+            .ok()
+            .map(|n| n.get() as u32),
+        memory_total_bytes,
+        disk_free_bytes: disk_free_bytes(model_dir),
+        llama_build,
+    }
+}
+
+/// GPUs from nvidia-smi, else rocm-smi, else sysfs.
+async fn detect_gpus(root: &Path) -> Vec<GpuInfo> {
+    let mut gpus = match run_probe(
+        "nvidia-smi",
+        &[
+            "--query-gpu=name,memory.total",
+            "--format=csv,noheader,nounits",
+        ],
+    )
+    .await
+    {
+        Some(out) => parse_nvidia_smi(&out),
+        None => Vec::new(),
+    };
+    if gpus.is_empty() {
+        if let Some(out) = run_probe(
+            "rocm-smi",
+            &["--showproductname", "--showmeminfo", "vram", "--json"],
+        )
+        .await
+        {
+            gpus = parse_rocm_smi(&out);
+        }
+    }
+    if gpus.is_empty() {
+        gpus = detect_sysfs_gpus(root);
+    }
+    gpus
+}
+
+/// CPU model and total memory from procfs, or sysctl on macOS.
+async fn detect_cpu_and_memory(root: &Path) -> (Option<String>, Option<u64>) {
+    let (mut cpu_model, mut memory_total_bytes) = (detect_cpu_model(root), detect_memory(root));
+    if cfg!(target_os = "macos") {
+        if cpu_model.is_none() {
+            cpu_model = run_probe("sysctl", &["-n", "machdep.cpu.brand_string"])
+                .await
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty());
+        }
+        if memory_total_bytes.is_none() {
+            memory_total_bytes = run_probe("sysctl", &["-n", "hw.memsize"])
+                .await
+                .and_then(|s| s.trim().parse().ok());
+        }
+    }
+    (cpu_model, memory_total_bytes)
+}
+
+/// Run a probe command, returning its combined stdout and stderr on success.
+/// Missing binaries, failures and timeouts all yield `None`.
+async fn run_probe(program: &str, args: &[&str]) -> Option<String> {
+    let output = tokio::time::timeout(
+        PROBE_TIMEOUT,
+        Command::new(program).args(args).kill_on_drop(true).output(),
+    )
+    .await
+    .ok()?
+    .ok()?;
+    if !output.status.success() {
+        tracing::debug!("{} exited with {}", program, output.status);
+        return None;
+    }
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Some(text)
+}
+
+/// Parse `nvidia-smi --query-gpu=name,memory.total --format=csv,noheader,nounits`.
+fn parse_nvidia_smi(output: &str) -> Vec<GpuInfo> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (name, mem_mib) = line.rsplit_once(',')?;
+            let name = name.trim();
+            if name.is_empty() {
+                return None;
+            }
+            Some(GpuInfo {
+                vendor: "nvidia".to_string(),
+                model: Some(name.to_string()),
+                vram_total_bytes: mem_mib.trim().parse::<u64>().ok().map(|m| m * 1024 * 1024),
+            })
+        })
+        .collect()
+}
+
+/// Parse `rocm-smi --showproductname --showmeminfo vram --json`.
+fn parse_rocm_smi(output: &str) -> Vec<GpuInfo> {
+    // rocm-smi may print warnings before the JSON object.
+    let Some(start) = output.find('{') else {
+        return Vec::new();
+    };
+    let Ok(serde_json::Value::Object(cards)) =
+        serde_json::from_str::<serde_json::Value>(&output[start..])
+    else {
+        return Vec::new();
+    };
+
+    let mut names: Vec<_> = cards.keys().filter(|k| k.starts_with("card")).collect();
+    names.sort();
+    names
+        .into_iter()
+        .map(|name| {
+            let card = &cards[name];
+            let field = |key: &str| {
+                card.get(key)
+                    .and_then(|v| v.as_str())
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+            };
+            GpuInfo {
+                vendor: "amd".to_string(),
+                model: field("Card Series")
+                    .or_else(|| field("Card series"))
+                    .or_else(|| field("Card model")),
+                vram_total_bytes: field("VRAM Total Memory (B)").and_then(|s| s.parse().ok()),
+            }
+        })
+        .collect()
+}
+
+/// Enumerate GPUs from `/sys/class/drm/cardN/device` under `root`.
+fn detect_sysfs_gpus(root: &Path) -> Vec<GpuInfo> {
+    let drm = root.join("sys/class/drm");
+    let Ok(entries) = fs::read_dir(&drm) else {
+        return Vec::new();
+    };
+
+    let mut cards: Vec<_> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        // Skip connector entries like card0-DP-1.
+        .filter(|name| {
+            name.strip_prefix("card")
+                .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+        })
+        .collect();
+    cards.sort();
+
+    cards
+        .into_iter()
+        .filter_map(|card| {
+            let device = drm.join(&card).join("device");
+            let read = |file: &str| {
+                fs::read_to_string(device.join(file))
+                    .ok()
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+            };
+            let vendor = match read("vendor")?.as_str() {
+                "0x10de" => "nvidia",
+                "0x1002" => "amd",
+                "0x8086" => "intel",
+                other => other,
+            }
+            .to_string();
+            Some(GpuInfo {
+                vendor,
+                model: read("product_name").or_else(|| read("device")),
+                vram_total_bytes: read("mem_info_vram_total").and_then(|s| s.parse().ok()),
+            })
+        })
+        .collect()
+}
+
+fn detect_cpu_model(root: &Path) -> Option<String> {
+    let cpuinfo = fs::read_to_string(root.join("proc/cpuinfo")).ok()?;
+    cpuinfo.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        // "model name" on x86, "Model" on some ARM boards.
+        matches!(key.trim(), "model name" | "Model" | "cpu model")
+            .then(|| value.trim().to_string())
+            .filter(|v| !v.is_empty())
+    })
+}
+
+fn detect_memory(root: &Path) -> Option<u64> {
+    let meminfo = fs::read_to_string(root.join("proc/meminfo")).ok()?;
+    meminfo.lines().find_map(|line| {
+        let rest = line.strip_prefix("MemTotal:")?;
+        let kb: u64 = rest.trim().trim_end_matches("kB").trim().parse().ok()?;
+        Some(kb * 1024)
+    })
+}
+
+#[cfg(unix)]
+fn disk_free_bytes(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    // The model directory may not exist yet; measure the nearest existing ancestor.
+    let existing = path.ancestors().find(|p| p.exists())?;
+    let c_path = std::ffi::CString::new(existing.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn disk_free_bytes(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Parse `llama-server --version` output into a version and compute backend.
+fn parse_llama_version(output: &str) -> LlamaBuild {
+    let version = output.lines().find_map(|line| {
+        line.trim()
+            .strip_prefix("version:")
+            .map(|v| v.trim().to_string())
+    });
+
+    // Match whole words so e.g. "chip" is not mistaken for HIP.
+    let lower = output.to_lowercase();
+    let words: Vec<&str> = lower
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect();
+    let backend = [
+        ("cuda", "cuda"),
+        ("rocm", "rocm"),
+        ("hip", "rocm"),
+        ("metal", "metal"),
+        ("vulkan", "vulkan"),
+        ("sycl", "sycl"),
+    ]
+    .iter()
+    .find(|(needle, _)| words.contains(needle))
+    .map(|(_, backend)| backend.to_string())
+    .unwrap_or_else(|| "cpu".to_string());
+
+    LlamaBuild { version, backend }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(root: &Path, rel: &str, contents: &str) {
+        let path = root.join(rel);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn test_proc_fixture() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            dir.path(),
+            "proc/cpuinfo",
+            "processor\t: 0\nvendor_id\t: AuthenticAMD\nmodel name\t: AMD Ryzen 9 7950X 16-Core Processor\n",
+        );
+        write(
+            dir.path(),
+            "proc/meminfo",
+            "MemTotal:       65536000 kB\nMemFree:        1000 kB\n",
+        );
+
+        assert_eq!(
+            detect_cpu_model(dir.path()).as_deref(),
+            Some("AMD Ryzen 9 7950X 16-Core Processor")
+        );
+        assert_eq!(detect_memory(dir.path()), Some(65536000 * 1024));
+    }
+
+    #[test]
+    fn test_missing_fixture_is_absent() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(detect_cpu_model(dir.path()), None);
+        assert_eq!(detect_memory(dir.path()), None);
+        assert!(detect_sysfs_gpus(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn test_sysfs_gpu_fixture() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "sys/class/drm/card0/device/vendor", "0x1002\n");
+        write(dir.path(), "sys/class/drm/card0/device/device", "0x744c\n");
+        write(
+            dir.path(),
+            "sys/class/drm/card0/device/mem_info_vram_total",
+            "25753026560\n",
+        );
+        write(dir.path(), "sys/class/drm/card0-DP-1/status", "connected\n");
+        write(dir.path(), "sys/class/drm/card1/device/vendor", "0x8086\n");
+
+        let gpus = detect_sysfs_gpus(dir.path());
+        assert_eq!(
+            gpus,
+            vec![
+                GpuInfo {
+                    vendor: "amd".to_string(),
+                    model: Some("0x744c".to_string()),
+                    vram_total_bytes: Some(25753026560),
+                },
+                GpuInfo {
+                    vendor: "intel".to_string(),
+                    model: None,
+                    vram_total_bytes: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_nvidia_smi() {
+        let gpus =
+            parse_nvidia_smi("NVIDIA GeForce RTX 4090, 24564\nNVIDIA A100-SXM4-80GB, 81920\n");
+        assert_eq!(gpus.len(), 2);
+        assert_eq!(gpus[0].model.as_deref(), Some("NVIDIA GeForce RTX 4090"));
+        assert_eq!(gpus[0].vram_total_bytes, Some(24564 * 1024 * 1024));
+        assert_eq!(gpus[1].vendor, "nvidia");
+    }
+
+    #[test]
+    fn test_parse_rocm_smi() {
+        let out = r#"WARNING: something noisy
+{"card0": {"Card Series": "Radeon RX 7900 XTX", "VRAM Total Memory (B)": "25753026560"},
+ "system": {"Driver version": "6.8"}}"#;
+        let gpus = parse_rocm_smi(out);
+        assert_eq!(gpus.len(), 1);
+        assert_eq!(gpus[0].vendor, "amd");
+        assert_eq!(gpus[0].model.as_deref(), Some("Radeon RX 7900 XTX"));
+        assert_eq!(gpus[0].vram_total_bytes, Some(25753026560));
+    }
+
+    #[test]
+    fn test_parse_llama_version() {
+        let cuda = "ggml_cuda_init: found 1 CUDA devices:\n  Device 0: NVIDIA GeForce RTX 4090\nversion: 4589 (1a2b3c4)\nbuilt with cc (GCC) 13.2.0 for x86_64-linux-gnu\n";
+        assert_eq!(
+            parse_llama_version(cuda),
+            LlamaBuild {
+                version: Some("4589 (1a2b3c4)".to_string()),
+                backend: "cuda".to_string(),
+            }
+        );
+
+        let cpu = "version: 4589 (1a2b3c4)\nbuilt with cc for x86_64-linux-gnu\n";
+        assert_eq!(parse_llama_version(cpu).backend, "cpu");
+
+        let metal = "ggml_metal_init: found device: Apple M2 Max chip\nversion: 1\n";
+        assert_eq!(parse_llama_version(metal).backend, "metal");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_disk_free_for_missing_dir_uses_ancestor() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(disk_free_bytes(&dir.path().join("not/yet/created")).is_some());
+    }
+}
@@ -2,6 +2,9 @@ use std::fs;
 use std::path::PathBuf;
 
 use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ed25519_dalek::SigningKey;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -17,6 +20,8 @@ pub struct AgentIdentity {
     pub platform: String,
     pub arch: String,
     pub agent_version: String,
+    /// Base64 Ed25519 public key the agent signs usage receipts with.
+    pub public_key: String,
 }
 
 fn identity_path() -> Result<PathBuf> {
@@ -25,6 +30,10 @@ fn identity_path() -> Result<PathBuf> {
     Ok(home.join(".vram-supply").join("vramsply.json"))
 }
 
+fn signing_key_path() -> Result<PathBuf> {
+    Ok(crate::config::state_dir()?.join("identity.key"))
+}
+
 fn detect_hostname() -> Option<String> {
     for key in ["HOSTNAME", "COMPUTERNAME"] {
         if let Ok(value) = std::env::var(key) {
@@ -65,16 +74,60 @@ fn write_agent_uid(path: &PathBuf, agent_uid: &str) -> Result<()> {
     Ok(())
 }
 
-pub fn load_or_create_identity() -> Result<AgentIdentity> {
+/// Load the agent's Ed25519 signing key, generating and saving one (readable
+/// only by the owner) on first use.
+pub fn load_or_create_signing_key() -> Result<SigningKey> {
+    let path = signing_key_path()?;
+    if path.exists() {
+        let raw = fs::read_to_string(&path)
+            .with_context(|| format!("Failed reading signing key {}", path.display()))?;
+        let bytes: [u8; 32] = STANDARD
+            .decode(raw.trim())
+            .ok()
+            .and_then(|b| b.try_into().ok())
+            .ok_or_else(|| anyhow::anyhow!("Invalid signing key in {}", path.display()))?;
+        return Ok(SigningKey::from_bytes(&bytes));
+    }
+
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| anyhow::anyhow!("Failed generating signing key: {}", e))?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed creating directory {}", parent.display()))?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(&path)
+        .with_context(|| format!("Failed creating signing key {}", path.display()))?;
+    std::io::Write::write_all(&mut file, STANDARD.encode(bytes).as_bytes())
+        .with_context(|| format!("Failed writing signing key {}", path.display()))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+/// Load the agent's uid, generating and saving one on first use.
+pub fn load_or_create_agent_uid() -> Result<String> {
     let path = identity_path()?;
-    let agent_uid = match read_agent_uid(&path)? {
-        Some(uid) => uid,
+    match read_agent_uid(&path)? {
+        Some(uid) => Ok(uid),
         None => {
             let uid = Uuid::new_v4().to_string();
             write_agent_uid(&path, &uid)?;
-            uid
+            Ok(uid)
         }
-    };
+    }
+}
+
+/// The identity `serve` announces, publishing the public half of `key`.
+pub fn load_or_create_identity(key: &SigningKey) -> Result<AgentIdentity> {
+    let agent_uid = load_or_create_agent_uid()?;
+    let public_key = STANDARD.encode(key.verifying_key().to_bytes());
 
     let hostname = detect_hostname().unwrap_or_else(|| "unknown-host".to_string());
     let platform = std::env::consts::OS.to_string();
@@ -86,5 +139,6 @@ pub fn load_or_create_identity() -> Result<AgentIdentity> {
         platform,
         arch,
         agent_version: env!("CARGO_PKG_VERSION").to_string(),
+        public_key,
     })
 }
@@ -0,0 +1,262 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+use crate::config::env_or;
+
+/// Rotated log files kept by default.
+const DEFAULT_MAX_LOG_FILES: usize = 7;
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    Text,
+    /// One JSON object per line, including the fields of enclosing spans.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("expected 'text' or 'json', got '{}'", other)),
+        }
+    }
+}
+
+/// How often the log file rolls over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+impl FromStr for LogRotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "hourly" => Ok(LogRotation::Hourly),
+            "daily" => Ok(LogRotation::Daily),
+            "never" => Ok(LogRotation::Never),
+            other => Err(format!(
+                "expected 'hourly', 'daily' or 'never', got '{}'",
+                other
+            )),
+        }
+    }
+}
+
+impl From<LogRotation> for Rotation {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        }
+    }
+}
+
+/// Logging settings, read from the environment before `Config` (which
+/// requires an API key) so every command logs the same way.
+#[derive(Debug, Clone)]
+pub struct LogSettings {
+    pub format: LogFormat,
+    /// Also write logs here, rotated by `rotation`.
+    pub file: Option<PathBuf>,
+    pub rotation: LogRotation,
+    pub max_files: usize,
+    /// Export spans over OTLP/HTTP. Set when an OTLP endpoint is configured.
+    pub otlp: bool,
+}
+
+impl LogSettings {
+    pub fn from_env() -> Result<Self> {
+        let file = std::env::var("VRAM_SUPPLY_LOG_FILE")
+            .ok()
+            .filter(|f| !f.is_empty())
+            .map(PathBuf::from);
+        let otlp = [
+            "OTEL_EXPORTER_OTLP_ENDPOINT",
+            "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+        ]
+        .iter()
+        .any(|var| std::env::var(var).is_ok_and(|v| !v.is_empty()));
+        Ok(LogSettings {
+            format: env_or("VRAM_SUPPLY_LOG_FORMAT", LogFormat::Text)?,
+            file,
+            rotation: env_or("VRAM_SUPPLY_LOG_ROTATION", LogRotation::Daily)?,
+            max_files: env_or("VRAM_SUPPLY_LOG_MAX_FILES", DEFAULT_MAX_LOG_FILES)?,
+            otlp,
+        })
+    }
+}
+
+/// Keeps log output alive. Dropping it flushes the log file and any spans
+/// not yet exported.
+pub struct LoggingGuard {
+    _file: Option<WorkerGuard>,
+    tracer_provider: Option<SdkTracerProvider>,
+}
+
+impl Drop for LoggingGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.tracer_provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to flush OTLP spans: {}", e);
+            }
+        }
+    }
+}
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+fn fmt_layer<W>(format: LogFormat, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().with_current_span(true).boxed(),
+    }
+}
+
+/// Install the global subscriber: stdout, plus the log file and OTLP export
+/// when configured. `RUST_LOG` filters all outputs (default `info`).
+pub fn init(settings: &LogSettings) -> Result<LoggingGuard> {
+    let mut layers: Vec<BoxedLayer> = vec![fmt_layer(settings.format, std::io::stdout, true)];
+
+    let mut file_guard = None;
+    if let Some(path) = &settings.file {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let prefix = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .context("VRAM_SUPPLY_LOG_FILE must name a file")?;
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed creating log directory {}", dir.display()))?;
+        let appender = RollingFileAppender::builder()
+            .rotation(settings.rotation.into())
+            .filename_prefix(prefix)
+            .max_log_files(settings.max_files.max(1))
+            .build(&dir)
+            .with_context(|| format!("Failed to open log file in {}", dir.display()))?;
+        let (writer, guard) = tracing_appender::non_blocking(appender);
+        layers.push(fmt_layer(settings.format, writer, false));
+        file_guard = Some(guard);
+    }
+
+    let mut tracer_provider = None;
+    if settings.otlp {
+        // Endpoint, headers and timeout come from the standard OTEL_* variables.
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .build()
+            .context("Failed to create OTLP span exporter")?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                opentelemetry_sdk::Resource::builder()
+                    .with_service_name("vramsply")
+                    .build(),
+            )
+            .build();
+        layers.push(
+            tracing_opentelemetry::layer()
+                .with_tracer(provider.tracer("vramsply"))
+                .boxed(),
+        );
+        tracer_provider = Some(provider);
+    }
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .try_init()
+        .context("Failed to install log subscriber")?;
+
+    Ok(LoggingGuard {
+        _file: file_guard,
+        tracer_provider,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_lines_carry_span_fields() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::registry().with(fmt_layer(
+            LogFormat::Json,
+            move || writer.clone(),
+            false,
+        ));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!(
+                "serve",
+                agent_uid = "agent-1",
+                provider_id = tracing::field::Empty
+            );
+            let _entered = span.enter();
+            span.record("provider_id", "prov-1");
+            tracing::info!("Registered");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line: serde_json::Value = serde_json::from_str(output.trim()).unwrap();
+        assert_eq!(line["fields"]["message"], "Registered");
+        assert_eq!(line["span"]["name"], "serve");
+        assert_eq!(line["span"]["agent_uid"], "agent-1");
+        assert_eq!(line["span"]["provider_id"], "prov-1");
+    }
+
+    #[test]
+    fn test_parse_settings() {
+        assert_eq!("JSON".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert!("xml".parse::<LogFormat>().is_err());
+        assert_eq!(
+            "hourly".parse::<LogRotation>().unwrap(),
+            LogRotation::Hourly
+        );
+        assert!("weekly".parse::<LogRotation>().is_err());
+    }
+}
@@ -1,19 +1,54 @@
+mod acme;
 mod auth;
 mod backend;
+mod commands;
 mod config;
+mod connection;
+mod control;
+mod doctor;
+mod earnings;
+mod errors;
+mod hardware;
 mod identity;
+mod logging;
 mod models;
+mod outbox;
 mod presence;
+mod proxy;
+mod reachability;
+mod receipts;
+mod registration;
+mod served;
+mod service;
+mod signals;
+mod telemetry;
+#[cfg(test)]
+mod test_support;
+mod tls;
+mod top;
+mod transitions;
+mod tunnel;
+mod usage;
 mod verification;
 
 use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::{Context, Result};
+use anyhow::Result;
 use clap::{Parser, Subcommand};
+use commands::{AgentCommand, CommandAck, CommandOutcome};
+use connection::{PlatformCommand, PlatformConnection};
+use errors::AgentErrorCode;
 use presence::{AgentPresenceStatus, PresenceHandle};
+use registration::{RegisterRequest, Registrar};
 use tokio_util::sync::CancellationToken;
 
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How long the relay has to issue a tunnel URL at startup.
+const TUNNEL_URL_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait before registering a new tunnel URL again after a failure.
+const TUNNEL_URL_RETRY: Duration = Duration::from_secs(30);
+
 #[derive(Parser)]
 #[command(
     name = "vramsply",
@@ -31,29 +66,146 @@ enum Commands {
     Auth,
     /// Start providing model inference
     Serve {
-        /// Path to the model file to serve
+        /// Model file to serve, optionally with per-model options
+        /// (e.g., "qwen.gguf,name=qwen/qwen2.5-7b,hf_repo=Qwen/Qwen2.5-7B-GGUF,input_price=50").
+        /// Repeat to serve several models, each registered separately
         #[arg(long)]
-        model: Option<String>,
+        model: Vec<served::ModelSpec>,
 
-        /// Override the model name sent to the platform (e.g., "meta-llama/llama-3.1-8b-instruct")
+        /// Override the model name sent to the platform (e.g., "meta-llama/llama-3.1-8b-instruct").
+        /// Only with a single --model
         #[arg(long)]
         model_name: Option<String>,
 
-        /// HuggingFace repository ID for model verification (e.g., TheBloke/Llama-2-7B-GGUF)
+        /// HuggingFace repository ID for model verification (e.g., TheBloke/Llama-2-7B-GGUF).
+        /// Only with a single --model
         #[arg(long)]
         hf_repo: Option<String>,
 
         /// Skip model integrity verification
         #[arg(long)]
         skip_verify: bool,
+
+        /// Run in the background, detached from the terminal
+        #[arg(long)]
+        daemon: bool,
+
+        /// Write the serve process id here (default with --daemon: ~/.vram-supply/vramsply.pid)
+        #[arg(long)]
+        pid_file: Option<std::path::PathBuf>,
+
+        /// Where --daemon sends output (default: ~/.vram-supply/serve.log)
+        #[arg(long)]
+        log_file: Option<std::path::PathBuf>,
+    },
+    /// Manage a systemd unit that runs `vramsply serve`
+    Service {
+        #[command(subcommand)]
+        command: ServiceCommands,
     },
     /// Model management commands
     Models {
         #[command(subcommand)]
         command: ModelCommands,
     },
+    /// Remove registrations left behind by earlier runs (e.g. after a crash)
+    Deregister {
+        /// Provider id to remove (defaults to every registration stored for this agent)
+        #[arg(long)]
+        id: Option<String>,
+    },
+    /// Ask a running serve to stop taking work and shut down once in-flight requests finish
+    Drain,
+    /// Ask a running serve to shut down without waiting for in-flight requests
+    Stop,
+    /// Ask a running serve to switch to another local model
+    Reload {
+        /// Model to load (path or name in the model directory)
+        #[arg(long)]
+        model: String,
+
+        /// HuggingFace repository ID to verify the new model against
+        #[arg(long)]
+        hf_repo: Option<String>,
+
+        /// Served model to replace, by its registered name (required when several are served)
+        #[arg(long)]
+        target: Option<String>,
+    },
     /// Show current agent status
-    Status,
+    Status {
+        /// Show recent presence transitions from the local event log
+        #[arg(long)]
+        history: bool,
+
+        /// Number of transitions to show with --history
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Check this machine's setup and print fixes for anything wrong
+    Doctor {
+        /// The model `serve --model` would be given
+        #[arg(long)]
+        model: Option<String>,
+
+        /// Print the report as JSON (attach it to support tickets)
+        #[arg(long)]
+        json: bool,
+    },
+    /// Live dashboard of the running serve
+    Top {
+        /// Seconds between refreshes
+        #[arg(long, default_value_t = 1)]
+        interval: u64,
+    },
+    /// Show earnings, served tokens and payout history from the platform
+    Earnings {
+        /// Breakdown granularity
+        #[arg(long, value_enum, default_value_t = earnings::Period::Daily)]
+        period: earnings::Period,
+
+        /// How many days back to cover
+        #[arg(long, default_value_t = 30)]
+        days: u32,
+
+        /// Cover every agent on the account instead of just this one
+        #[arg(long)]
+        account: bool,
+
+        /// Print the platform's report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Summarize tokens served and expected earnings from the local usage ledger
+    Usage {
+        /// Only count requests from the last N days
+        #[arg(long)]
+        days: Option<u64>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ServiceCommands {
+    /// Generate, enable and start the unit (pass serve options after `--`)
+    Install {
+        /// Install a system unit instead of a user unit
+        #[arg(long)]
+        system: bool,
+
+        /// Options for `vramsply serve`, e.g. `-- --model ./my-model.gguf --skip-verify`
+        #[arg(last = true)]
+        serve_args: Vec<String>,
+    },
+    /// Stop, disable and remove the unit
+    Uninstall {
+        #[arg(long)]
+        system: bool,
+    },
+    /// Show whether the unit is installed and running
+    Status {
+        #[arg(long)]
+        system: bool,
+    },
 }
 
 #[derive(Subcommand)]
@@ -73,13 +225,8 @@ enum ModelCommands {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
-        )
-        .init();
+    // Initialize tracing; the guard flushes the log file and spans on exit.
+    let _logging = logging::init(&logging::LogSettings::from_env()?)?;
 
     let cli = Cli::parse();
 
@@ -93,11 +240,28 @@ async fn main() -> Result<()> {
             model_name,
             hf_repo,
             skip_verify,
+            daemon,
+            pid_file,
+            log_file,
         } => {
             let config = config::Config::load()?;
-            run_serve(&config, model, model_name, hf_repo, skip_verify).await?;
+            if daemon {
+                let pid_file = pid_file.map_or_else(service::default_pid_file, Ok)?;
+                let log_file = log_file.map_or_else(service::default_log_file, Ok)?;
+                return service::daemonize(&pid_file, &log_file).await;
+            }
+            run_serve(&config, model, model_name, hf_repo, skip_verify, pid_file).await?;
         }
 
+        Commands::Service { command } => match command {
+            ServiceCommands::Install { system, serve_args } => {
+                let config = config::Config::load()?;
+                service::install(&config, system, serve_args)?;
+            }
+            ServiceCommands::Uninstall { system } => service::uninstall(system)?,
+            ServiceCommands::Status { system } => service::status(system)?,
+        },
+
         Commands::Models { command } => match command {
             ModelCommands::List => {
                 let config = config::Config::load()?;
@@ -122,317 +286,1559 @@ async fn main() -> Result<()> {
             }
         },
 
-        Commands::Status => {
+        Commands::Deregister { id } => {
+            let config = config::Config::load()?;
+            run_deregister(&config, id).await?;
+        }
+
+        Commands::Earnings {
+            period,
+            days,
+            account,
+            json,
+        } => {
+            let config = config::Config::load()?;
+            let agent_uid = if account {
+                None
+            } else {
+                Some(identity::load_or_create_agent_uid()?)
+            };
+            let report = earnings::fetch_earnings(
+                &reqwest::Client::new(),
+                &config,
+                &Arc::new(tokio::sync::Mutex::new(config.api_key.clone())),
+                &earnings::EarningsQuery {
+                    agent_uid,
+                    period,
+                    days,
+                },
+            )
+            .await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print!("{}", earnings::render(&report, period));
+            }
+        }
+
+        Commands::Usage { days } => {
+            show_usage(days)?;
+        }
+
+        Commands::Doctor { model, json } => {
+            let report = doctor::run(model.as_deref()).await;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print!("{}", doctor::render(&report));
+            }
+            if report.failures() > 0 {
+                anyhow::bail!("{} check(s) failed", report.failures());
+            }
+        }
+
+        Commands::Top { interval } => {
+            top::run(
+                control::socket_path()?,
+                Duration::from_secs(interval.max(1)),
+            )
+            .await?;
+        }
+
+        Commands::Drain => {
+            control::request(&control::socket_path()?, "drain", serde_json::Value::Null).await?;
+            println!("Drain accepted; the agent will shut down once in-flight requests finish.");
+        }
+
+        Commands::Stop => {
+            control::request(&control::socket_path()?, "stop", serde_json::Value::Null).await?;
+            println!("Shutdown accepted.");
+        }
+
+        Commands::Reload {
+            model,
+            hf_repo,
+            target,
+        } => {
+            println!("Reloading {}...", model);
+            control::request(
+                &control::socket_path()?,
+                "reload",
+                serde_json::json!({ "model": model, "hf_repo": hf_repo, "target": target }),
+            )
+            .await?;
+            println!("Model reloaded.");
+        }
+
+        Commands::Status { history, limit } => {
+            if history {
+                show_transition_history(limit)?;
+                return Ok(());
+            }
+
             let config = config::Config::load()?;
             println!("Agent status:");
             auth::show_auth_status();
 
             let local_models = models::list_local_models(&config)?;
             println!("Local models: {}", local_models.len());
+            show_serve_status().await?;
+            show_link_status()?;
         }
     }
 
     Ok(())
 }
 
-#[derive(serde::Serialize)]
-struct RegisterRequest {
-    endpoint_url: String,
-    model: String,
-    max_concurrent: u32,
-    context_length_offered: u32,
-    input_price_per_million: u32,
-    output_price_per_million: u32,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    model_sha256: Option<String>,
+async fn run_deregister(config: &config::Config, id: Option<String>) -> Result<()> {
+    let agent_uid = identity::load_or_create_agent_uid()?;
+    let registrar = Registrar::new(
+        reqwest::Client::new(),
+        config.clone(),
+        Arc::new(tokio::sync::Mutex::new(config.api_key.clone())),
+        registration::RegistrationStore::open_default()?,
+        agent_uid,
+    );
+
+    let targets: Vec<(String, Option<String>)> = match id {
+        Some(id) => vec![(id, None)],
+        None => registrar
+            .stored()?
+            .into_iter()
+            .map(|e| (e.provider_id, Some(e.model)))
+            .collect(),
+    };
+    if targets.is_empty() {
+        println!(
+            "No stored registrations in {}",
+            registrar.store_path().display()
+        );
+        return Ok(());
+    }
+
+    let mut failed = 0;
+    for (provider_id, model) in &targets {
+        let label = match model {
+            Some(model) => format!("{} ({})", provider_id, model),
+            None => provider_id.clone(),
+        };
+        match registrar.deregister(provider_id).await {
+            Ok(()) => println!("Deregistered {}", label),
+            Err(e) => {
+                println!("Failed to deregister {}: {:#}", label, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!(
+            "{} of {} registrations could not be removed",
+            failed,
+            targets.len()
+        );
+    }
+    Ok(())
 }
 
-#[derive(serde::Deserialize)]
-struct RegisterResponse {
-    id: String,
-    status: String,
+/// Print the live state of a running serve, read over its control socket.
+async fn show_serve_status() -> Result<()> {
+    let path = control::socket_path()?;
+    let status = match control::request(&path, "status", serde_json::Value::Null).await {
+        Ok(value) => serde_json::from_value::<control::ServeStatus>(value)?,
+        Err(e) if e.downcast_ref::<tokio::time::error::Elapsed>().is_some() => {
+            println!("Serve: starting (not answering on its control socket yet)");
+            return Ok(());
+        }
+        Err(e) => {
+            tracing::debug!("No running serve: {:#}", e);
+            println!("Serve: not running");
+            return Ok(());
+        }
+    };
+
+    println!(
+        "Serve: running (pid {}, since {})",
+        status.pid,
+        transitions::format_timestamp(status.started_at)
+    );
+    println!("  Status: {:?}", status.status);
+    if status.models.len() > 1 {
+        println!("  Models:");
+        for m in &status.models {
+            println!(
+                "    {} ({:?}): {}, llama-server {} on port {}, {} active",
+                m.presence.model,
+                m.presence.status,
+                m.presence
+                    .provider_id
+                    .as_deref()
+                    .unwrap_or("not registered"),
+                m.backend_pid
+                    .map_or_else(|| "not running".to_string(), |pid| format!("pid {}", pid)),
+                m.backend_port,
+                m.presence.active_requests
+            );
+        }
+    } else {
+        if let Some(model) = &status.model {
+            println!("  Model: {}", model);
+        }
+        println!(
+            "  Registration: {}",
+            status.provider_id.as_deref().unwrap_or("not registered")
+        );
+        match status.backend_pid {
+            Some(pid) => println!("  llama-server pid: {}", pid),
+            None => println!("  llama-server: not running"),
+        }
+    }
+    println!("  Active requests: {}", status.active_requests);
+    if let Some(code) = &status.error_code {
+        println!(
+            "  Error: [{}] {}",
+            code,
+            status.error_message.as_deref().unwrap_or("")
+        );
+    }
+    if !status.recent_errors.is_empty() {
+        println!("  Recent errors:");
+        for e in &status.recent_errors {
+            println!(
+                "    {}  [{}] {}",
+                transitions::format_timestamp(e.timestamp),
+                e.error_code.as_deref().unwrap_or(""),
+                e.error_message.as_deref().unwrap_or("")
+            );
+        }
+    }
+    Ok(())
+}
+
+fn show_link_status() -> Result<()> {
+    let path = config::state_dir()?.join(outbox::LINK_STATUS_FILE);
+    let Some(link) = outbox::LinkStatus::load(&path)? else {
+        println!("Platform: not contacted yet");
+        return Ok(());
+    };
+
+    let as_of = transitions::format_timestamp(link.updated_at);
+    if link.connected {
+        println!("Platform: connected (as of {})", as_of);
+    } else {
+        match link.disconnected_since {
+            Some(since) => println!(
+                "Platform: DISCONNECTED since {} (as of {})",
+                transitions::format_timestamp(since),
+                as_of
+            ),
+            None => println!("Platform: not contacted yet (as of {})", as_of),
+        }
+        if let Some(last) = link.last_contact_at {
+            println!("  Last contact: {}", transitions::format_timestamp(last));
+        }
+    }
+    if link.queued_events > 0 {
+        println!(
+            "  Undelivered events: {} (replayed once the platform is reachable)",
+            link.queued_events
+        );
+    }
+    Ok(())
+}
+
+fn show_usage(days: Option<u64>) -> Result<()> {
+    let path = usage::ledger_path()?;
+    let records = usage::read_ledger(&path)?;
+    let since = days.map_or(0, |d| outbox::unix_now().saturating_sub(d * 86_400));
+    let totals = usage::summarize(&records, since);
+    if totals.is_empty() {
+        println!("No usage recorded in {}", path.display());
+        return Ok(());
+    }
+
+    match days {
+        Some(d) => println!("Usage over the last {} days ({}):", d, path.display()),
+        None => println!("Usage ({}):", path.display()),
+    }
+    println!(
+        "  {:<40} {:>9} {:>14} {:>14} {:>12}",
+        "MODEL", "REQUESTS", "PROMPT TOK", "COMPL TOK", "EARNINGS"
+    );
+    let mut all = usage::UsageTotals::default();
+    for (model, t) in &totals {
+        println!(
+            "  {:<40} {:>9} {:>14} {:>14} {:>12}",
+            model,
+            t.requests,
+            t.prompt_tokens,
+            t.completion_tokens,
+            format!("${:.4}", t.earnings_cents / 100.0)
+        );
+        all.requests += t.requests;
+        all.prompt_tokens += t.prompt_tokens;
+        all.completion_tokens += t.completion_tokens;
+        all.earnings_cents += t.earnings_cents;
+    }
+    if totals.len() > 1 {
+        println!(
+            "  {:<40} {:>9} {:>14} {:>14} {:>12}",
+            "TOTAL",
+            all.requests,
+            all.prompt_tokens,
+            all.completion_tokens,
+            format!("${:.4}", all.earnings_cents / 100.0)
+        );
+    }
+    println!("Expected earnings use the prices in effect when each request was served.");
+
+    let cursor_path = config::state_dir()?.join(receipts::RECEIPT_CURSOR_FILE);
+    let cursor = receipts::ReceiptCursor::load(&cursor_path)?;
+    let unsigned = usage::read_ledger_from(&path, cursor.offset, usize::MAX)?
+        .0
+        .len();
+    if unsigned > 0 {
+        println!(
+            "{} requests not yet covered by a signed receipt accepted by the platform",
+            unsigned
+        );
+    }
+    Ok(())
 }
 
+fn show_transition_history(limit: usize) -> Result<()> {
+    let path = transitions::log_path()?;
+    let events = transitions::read_log(&path, limit)?;
+    if events.is_empty() {
+        println!("No presence transitions recorded in {}", path.display());
+        return Ok(());
+    }
+
+    println!("Presence transitions ({}):", path.display());
+    for e in &events {
+        let mut line = format!(
+            "  {}  {:?} → {:?}  ({})",
+            transitions::format_timestamp(e.timestamp),
+            e.from,
+            e.to,
+            e.cause
+        );
+        if let Some(model) = &e.model {
+            line.push_str(&format!("  model {}", model));
+        }
+        if let Some(code) = &e.error_code {
+            line.push_str(&format!("  [{}]", code));
+        }
+        if let Some(message) = &e.error_message {
+            line.push_str(&format!(" {}", message));
+        }
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "serve",
+    skip_all,
+    fields(agent_uid = tracing::field::Empty, provider_id = tracing::field::Empty)
+)]
 async fn run_serve(
     config: &config::Config,
-    model_arg: Option<String>,
+    mut specs: Vec<served::ModelSpec>,
     model_name_override: Option<String>,
     hf_repo: Option<String>,
     skip_verify: bool,
+    pid_file: Option<std::path::PathBuf>,
 ) -> Result<()> {
     let shutdown = CancellationToken::new();
 
     let token = Arc::new(tokio::sync::Mutex::new(config.api_key.clone()));
-    let identity = identity::load_or_create_identity()?;
+    let signing_key = identity::load_or_create_signing_key()?;
+    let identity = identity::load_or_create_identity(&signing_key)?;
+    tracing::Span::current().record("agent_uid", identity.agent_uid.as_str());
     let client = reqwest::Client::new();
 
-    // Determine which model to serve
-    let model_path = match model_arg {
-        Some(m) => models::find_model(config, &m)?,
-        None => {
-            let local = models::list_local_models(config)?;
-            if local.is_empty() {
+    // Determine which models to serve
+    if specs.len() > 1 && (model_name_override.is_some() || hf_repo.is_some()) {
+        anyhow::bail!(
+            "--model-name and --hf-repo only apply to a single --model; \
+             give each model its own name= and hf_repo= options instead"
+        );
+    }
+    let mut model_paths = Vec::with_capacity(specs.len().max(1));
+    if specs.is_empty() {
+        let local = models::list_local_models(config)?;
+        if local.is_empty() {
+            anyhow::bail!(
+                "No models found. Specify --model or download one with: vramsply models pull <hf_repo_id>"
+            );
+        }
+        if local.len() > 1 {
+            println!("Multiple models found, using first one: {}", local[0].name);
+            println!("Use --model to specify a different one.");
+        }
+        specs.push(served::ModelSpec {
+            model: local[0].name.clone(),
+            ..Default::default()
+        });
+        model_paths.push(local[0].path.clone());
+    } else {
+        for spec in &specs {
+            model_paths.push(models::find_model(config, &spec.model)?);
+        }
+    }
+    if let [spec] = specs.as_mut_slice() {
+        spec.name = spec.name.take().or(model_name_override);
+        spec.hf_repo = spec.hf_repo.take().or(hf_repo);
+    }
+
+    let mut model_names: Vec<String> = Vec::with_capacity(specs.len());
+    for (spec, model_path) in specs.iter().zip(&model_paths) {
+        tracing::info!("Serving model: {}", model_path);
+        if !skip_verify && spec.hf_repo.is_none() {
+            if specs.len() > 1 {
                 anyhow::bail!(
-                    "No models found. Specify --model or download one with: vramsply models pull <hf_repo_id>"
+                    "Model verification requires hf_repo=<repo_id> for {} \
+                     (e.g., --model {},hf_repo=TheBloke/Llama-2-7B-GGUF).\n\
+                     Use --skip-verify to bypass verification.",
+                    spec.model,
+                    spec.model
                 );
             }
-            if local.len() > 1 {
-                println!("Multiple models found, using first one: {}", local[0].name);
-                println!("Use --model to specify a different one.");
-            }
-            local[0].path.clone()
-        }
-    };
-    tracing::info!("Serving model: {}", model_path);
-
-    // Verify model integrity
-    let model_sha256 = if skip_verify {
-        verification::verify_model(&model_path, "", true).await?
-    } else {
-        let hf_repo_id = hf_repo.as_ref().ok_or_else(|| {
-            anyhow::anyhow!(
+            anyhow::bail!(
                 "Model verification requires --hf-repo <repo_id> \
                  (e.g., --hf-repo TheBloke/Llama-2-7B-GGUF).\n\
                  Use --skip-verify to bypass verification."
-            )
-        })?;
-        let sha = verification::verify_model(&model_path, hf_repo_id, false).await?;
-        println!("Model verified: {} (SHA-256: {})", hf_repo_id, sha);
-        sha
-    };
+            );
+        }
+        let name = match &spec.name {
+            Some(name) => name.clone(),
+            None => models::normalize_model_name(model_path),
+        };
+        // Registrations are stored per model name, so names must not repeat.
+        if model_names.contains(&name) {
+            anyhow::bail!(
+                "Model '{}' is given more than once; serve each model once, or set distinct name= options",
+                name
+            );
+        }
+        model_names.push(name);
+    }
+    let backend_ports = served::backend_ports(config, specs.len())?;
+
+    // Handle shutdown signals from here on, so one during startup still
+    // stops the backends and registrations started so far.
+    let mut signals = signals::ShutdownSignals::new()?;
 
-    let model_name = match model_name_override {
-        Some(name) => name,
-        None => models::normalize_model_name(&model_path),
+    // Claim the control socket first, so a second serve exits before it
+    // touches the platform or the GPU.
+    let control_listener = control::bind(control::socket_path()?).await?;
+    let _pid_file = pid_file.map(service::PidFile::create).transpose()?;
+    let watchdog_handle = service::spawn_watchdog(shutdown.clone());
+    let metrics_handle = match config.metrics_addr {
+        Some(addr) => {
+            let handle = telemetry::spawn_server(addr, shutdown.clone()).await?;
+            tracing::info!("Serving metrics on http://{}/metrics", addr);
+            Some(handle)
+        }
+        None => None,
     };
 
-    // Create presence handle and start heartbeat loop
-    let presence = PresenceHandle::new(
-        Some(model_name.clone()),
+    // Connect to the platform and start the presence/heartbeat loop
+    let (connection, commands_rx) = PlatformConnection::new(
         client.clone(),
         config.clone(),
         Arc::clone(&token),
+        identity.agent_uid.clone(),
+        Some(config::state_dir()?),
+    );
+    let socket_handle = match config.platform_channel {
+        config::PlatformChannel::WebSocket => Some(connection.spawn_socket(shutdown.clone())),
+        config::PlatformChannel::Http => None,
+    };
+    let hardware = hardware::detect(&config.model_dir, &config.llama_server_path).await;
+    tracing::debug!("Hardware inventory: {:?}", hardware);
+    let presence = PresenceHandle::new(
+        model_names.clone(),
+        connection.clone(),
         identity.clone(),
+        hardware,
+        Some(transitions::log_path()?),
     );
     presence.publish().await;
     let presence_handle = presence.spawn_loop(shutdown.clone());
+    let poll_handle = connection.spawn_command_poll(shutdown.clone());
+    let outbox_handle = connection.spawn_outbox_flush(shutdown.clone());
+    let usage_handle = connection.spawn_usage_reports(shutdown.clone());
+    let receipts_handle = receipts::ReceiptSubmitter::new(
+        client.clone(),
+        config.clone(),
+        Arc::clone(&token),
+        identity.agent_uid.clone(),
+        signing_key,
+        usage::ledger_path()?,
+        &config::state_dir()?,
+    )
+    .spawn(shutdown.clone());
+
+    // Refuse a public_url the platform could never reach before loading
+    // anything. A tunnel registers the relay's URL instead.
+    let checked = if config.tunnel {
+        Ok(false)
+    } else {
+        reachability::check_address(config).await
+    };
+    let private_url = match checked {
+        Ok(private) => private,
+        Err(e) => {
+            return abort_startup(
+                &presence,
+                presence_handle,
+                &shutdown,
+                AgentErrorCode::PublicUrlInvalid,
+                e,
+            )
+            .await;
+        }
+    };
 
-    // Start llama-server
+    // Verify model integrity
+    let mut model_sha256s = Vec::with_capacity(specs.len());
+    for (spec, model_path) in specs.iter().zip(&model_paths) {
+        let verified = match spec.hf_repo.as_deref().filter(|_| !skip_verify) {
+            Some(hf_repo_id) => signals
+                .interruptible(verification::verify_model(model_path, hf_repo_id, false))
+                .await
+                .inspect(|sha| println!("Model verified: {} (SHA-256: {})", hf_repo_id, sha)),
+            None => verification::verify_model(model_path, "", true).await,
+        };
+        match verified {
+            Ok(sha) => model_sha256s.push(sha),
+            Err(e) => {
+                return abort_startup(
+                    &presence,
+                    presence_handle,
+                    &shutdown,
+                    AgentErrorCode::ModelVerificationFailed,
+                    e,
+                )
+                .await;
+            }
+        }
+    }
+
+    // Start one llama-server per model
     presence
-        .transition(AgentPresenceStatus::LoadingModel)
+        .transition(AgentPresenceStatus::LoadingModel, "startup")
         .await
         .expect("Idle → LoadingModel transition must be valid");
-    let llama = Arc::new(tokio::sync::Mutex::new(backend::LlamaServer::new(
-        model_path.clone(),
-        config.port,
-        config.llama_server_path.clone(),
-        config.gpu_layers,
-        config.context_length_offered,
-    )));
-    if let Err(e) = llama.lock().await.start().await {
-        presence
-            .report_error("llama_start_failed", &e.to_string())
+    let mut served_models = Vec::with_capacity(specs.len());
+    for (i, spec) in specs.iter().enumerate() {
+        let context_length = spec.context_length.unwrap_or(config.context_length_offered);
+        let llama = backend::LlamaServer::new(
+            model_paths[i].clone(),
+            backend_ports[i],
+            config.llama_server_path.clone(),
+            config.gpu_layers,
+            context_length,
+        );
+        // What we offer the platform; also stamped onto usage records.
+        let model_sha256 = Some(model_sha256s[i].clone()).filter(|sha| sha != "unverified");
+        let registration = RegisterRequest {
+            endpoint_url: config.public_url.clone(),
+            model: model_names[i].clone(),
+            max_concurrent: spec.max_concurrent.unwrap_or(config.max_concurrent),
+            context_length_offered: context_length,
+            input_price_per_million: spec
+                .input_price_per_million
+                .unwrap_or(config.input_price_per_million),
+            output_price_per_million: spec
+                .output_price_per_million
+                .unwrap_or(config.output_price_per_million),
+            model_sha256,
+        };
+        served_models.push(served::ServedModel {
+            backend_log: llama.log_tail(),
+            llama: Arc::new(tokio::sync::Mutex::new(llama)),
+            registration: Arc::new(tokio::sync::Mutex::new(registration)),
+            backend_port: backend_ports[i],
+        });
+    }
+    let ledger = Arc::new(usage::UsageLedger::new(Some(usage::ledger_path()?)));
+    let (local_tx, local_rx) = tokio::sync::mpsc::unbounded_channel();
+    let control_handle = control::ControlServer {
+        presence: presence.clone(),
+        connection: connection.clone(),
+        models: served_models.clone(),
+        commands: local_tx,
+        ledger: Arc::clone(&ledger),
+        started_at: outbox::unix_now(),
+    }
+    .spawn(control_listener, shutdown.clone());
+    for model in &served_models {
+        let started = signals
+            .interruptible(async { model.llama.lock().await.start().await })
             .await;
-        shutdown.cancel();
-        let _ = tokio::time::timeout(Duration::from_secs(2), presence_handle).await;
-        return Err(e);
+        if let Err(e) = started {
+            return abort_startup(
+                &presence,
+                presence_handle,
+                &shutdown,
+                AgentErrorCode::LlamaStartFailed,
+                e,
+            )
+            .await;
+        }
+        tracing::info!("llama-server is healthy on port {}", model.backend_port);
     }
-    tracing::info!("llama-server is healthy on port {}", config.port);
 
-    // Register with platform
-    let model_sha256_field = if model_sha256 == "unverified" {
-        None
+    // Serve the public port through the authenticating proxy. Requests are
+    // refused until registration sets the provider ids their tokens must
+    // name, and go to the backend of the model registered as that provider.
+    let verifier = Arc::new(proxy::TokenVerifier::new(Vec::new()));
+    let keys_handle = proxy::spawn_key_refresh(
+        Arc::clone(&verifier),
+        client.clone(),
+        config.clone(),
+        Arc::clone(&token),
+        shutdown.clone(),
+    )?;
+    // Tunneled requests arrive from the relay client in this process, so in
+    // tunnel mode nothing else needs to reach the proxy.
+    let proxy_host = if config.tunnel {
+        std::net::IpAddr::from([127, 0, 0, 1])
     } else {
-        Some(model_sha256)
+        config.bind_address
     };
-    let reg = register_with_platform(
+    let proxy_addr = std::net::SocketAddr::new(proxy_host, config.port);
+    let echo = reachability::EchoNonce::default();
+    // With TLS, the listener serves whatever certificate is in the store:
+    // the configured files now, or an ACME certificate once it is issued.
+    let cert_store = Arc::new(tls::CertStore::default());
+    let tls_config = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => match tls::load_files(cert, key) {
+            Ok(certified) => {
+                cert_store.set(certified);
+                Some(tls::server_config(Arc::clone(&cert_store))?)
+            }
+            Err(e) => {
+                return abort_startup(
+                    &presence,
+                    presence_handle,
+                    &shutdown,
+                    AgentErrorCode::TlsCertificateFailed,
+                    e,
+                )
+                .await;
+            }
+        },
+        _ if tls::enabled(config) => Some(tls::server_config(Arc::clone(&cert_store))?),
+        _ => None,
+    };
+    let routes = served_models
+        .iter()
+        .map(|model| proxy::Route {
+            backend_port: model.backend_port,
+            offer: Arc::clone(&model.registration),
+        })
+        .collect();
+    let proxy_handle = match proxy::spawn_proxy(
+        proxy_addr,
+        routes,
+        verifier,
+        connection.clone(),
+        ledger,
+        echo.clone(),
+        tls_config,
+        shutdown.clone(),
+    )
+    .await
+    {
+        Ok(handle) => handle,
+        Err(e) => {
+            return abort_startup(
+                &presence,
+                presence_handle,
+                &shutdown,
+                AgentErrorCode::ProxyStartFailed,
+                e,
+            )
+            .await;
+        }
+    };
+    tracing::info!("Proxy listening on {}", proxy_addr);
+
+    // Keep the certificate current: reload changed files, or get one from
+    // the ACME CA, which validates through the listener we just started.
+    let tls_handle = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::spawn_reload(
+            Arc::clone(&cert_store),
+            cert.clone(),
+            key.clone(),
+            shutdown.clone(),
+        )),
+        _ if tls::enabled(config) => {
+            let acme = acme::AcmeManager::new(config, Arc::clone(&cert_store), acme::dir()?)?;
+            if let Err(e) = signals.interruptible(acme.ensure()).await {
+                return abort_startup(
+                    &presence,
+                    presence_handle,
+                    &shutdown,
+                    AgentErrorCode::TlsCertificateFailed,
+                    e,
+                )
+                .await;
+            }
+            Some(acme.spawn_renewal(shutdown.clone()))
+        }
+        _ => None,
+    };
+
+    // In tunnel mode, requests arrive over an outbound connection to the
+    // platform relay, and the URL it issues is the one registered.
+    let mut tunnel_handle = None;
+    let mut tunnel_url = None;
+    if config.tunnel {
+        // The relay carries requests for every model over one tunnel.
+        let mut tunnel_config = config.clone();
+        tunnel_config.max_concurrent = 0;
+        for model in &served_models {
+            tunnel_config.max_concurrent += model.registration.lock().await.max_concurrent;
+        }
+        let (tunnel, mut url_rx) = tunnel::TunnelClient::new(
+            tunnel_config,
+            Arc::clone(&token),
+            identity.agent_uid.clone(),
+            proxy_addr,
+        )?;
+        tunnel_handle = Some(tunnel.spawn(shutdown.clone()));
+        let issued = signals
+            .interruptible(async {
+                let issued =
+                    tokio::time::timeout(TUNNEL_URL_TIMEOUT, url_rx.wait_for(Option::is_some))
+                        .await;
+                match issued {
+                    Ok(Ok(url)) => Ok(url.clone().unwrap_or_default()),
+                    _ => Err(anyhow::anyhow!(
+                        "The platform relay did not issue a tunnel URL within {}s",
+                        TUNNEL_URL_TIMEOUT.as_secs()
+                    )),
+                }
+            })
+            .await;
+        match issued {
+            Ok(url) => {
+                for model in &served_models {
+                    model.registration.lock().await.endpoint_url = url.clone();
+                }
+            }
+            Err(e) => {
+                return abort_startup(
+                    &presence,
+                    presence_handle,
+                    &shutdown,
+                    AgentErrorCode::TunnelUnavailable,
+                    e,
+                )
+                .await;
+            }
+        }
+        tunnel_url = Some(url_rx);
+    }
+    let endpoint_url = served_models[0]
+        .registration
+        .lock()
+        .await
+        .endpoint_url
+        .clone();
+
+    // Have the platform fetch a nonce through the endpoint. A failure is
+    // published rather than fatal, since the platform decides on routing.
+    let reachability = reachability::verify(
         &client,
         config,
         &token,
-        &model_name,
-        model_sha256_field,
-        &presence,
+        &identity.agent_uid,
+        &endpoint_url,
+        &echo,
+        private_url,
     )
-    .await?;
-    let deregister_url = format!("{}/v1/providers/{}", config.platform_url, reg.id);
+    .await;
+    match (&reachability.status, &reachability.error) {
+        (reachability::ReachabilityStatus::Reachable, _) => {
+            tracing::info!("Platform reached this agent at {}", endpoint_url)
+        }
+        (status, error) => tracing::warn!(
+            "Reachability of {} is {:?}: {}",
+            endpoint_url,
+            status,
+            error.as_deref().unwrap_or("unknown")
+        ),
+    }
+    presence.set_reachability(reachability).await;
+
+    // Register with platform
+    let registrar = Registrar::new(
+        client.clone(),
+        config.clone(),
+        Arc::clone(&token),
+        registration::RegistrationStore::open_default()?,
+        identity.agent_uid.clone(),
+    );
+    let mut provider_ids = Vec::with_capacity(served_models.len());
+    for (i, model) in served_models.iter().enumerate() {
+        let register_body = model.registration.lock().await.clone();
+        let registered = signals
+            .interruptible(registrar.register(&register_body))
+            .await;
+        let provider_id = match registered {
+            Ok(id) => id,
+            Err(e) => {
+                tracing::error!("Registration of {} failed: {:#}", register_body.model, e);
+                deregister_all(&registrar, &connection).await;
+                return abort_startup(
+                    &presence,
+                    presence_handle,
+                    &shutdown,
+                    AgentErrorCode::ProviderRegisterFailed,
+                    e,
+                )
+                .await;
+            }
+        };
+        connection
+            .set_provider_id(i, Some(provider_id.clone()))
+            .await;
+        provider_ids.push(provider_id);
+    }
+    tracing::Span::current().record("provider_id", provider_ids.join(",").as_str());
 
     presence
-        .transition(AgentPresenceStatus::Ready)
+        .transition(AgentPresenceStatus::Ready, "model_loaded")
         .await
         .expect("LoadingModel → Ready transition must be valid");
+    service::notify(&format!(
+        "READY=1\nSTATUS=Serving {}",
+        model_names.join(", ")
+    ));
     println!("vram.supply provider runtime is running. Press Ctrl+C to stop.");
-    println!("  Model: {}", model_name);
-    println!("  Endpoint: {}", config.public_url);
-    println!("  Instance ID: {}", reg.id);
+    if let ([model_name], [provider_id]) = (model_names.as_slice(), provider_ids.as_slice()) {
+        println!("  Model: {}", model_name);
+        println!("  Endpoint: {}", endpoint_url);
+        println!("  Instance ID: {}", provider_id);
+    } else {
+        println!("  Endpoint: {}", endpoint_url);
+        for (model_name, provider_id) in model_names.iter().zip(&provider_ids) {
+            println!("  Model: {} (Instance ID: {})", model_name, provider_id);
+        }
+    }
+    if socket_handle.is_some() {
+        let state = if connection.is_socket_connected().await {
+            "connected"
+        } else {
+            "connecting, using HTTP meanwhile"
+        };
+        println!("  Platform socket: {}", state);
+    }
 
-    // Spawn background tasks
-    let heartbeat_handle = spawn_heartbeat_loop(
-        client.clone(),
-        config.clone(),
-        Arc::clone(&token),
-        shutdown.clone(),
+    // Spawn background tasks. The health monitor stops as soon as draining
+    // begins so it cannot restart the backend or flip the status back to Ready.
+    let monitor_shutdown = shutdown.child_token();
+    let monitor_handles: Vec<_> = served_models
+        .iter()
+        .enumerate()
+        .map(|(i, model)| {
+            spawn_health_monitor(
+                i,
+                Arc::clone(&model.llama),
+                presence.clone(),
+                monitor_shutdown.clone(),
+            )
+        })
+        .collect();
+    let registrations: Vec<_> = served_models
+        .iter()
+        .map(|model| Arc::clone(&model.registration))
+        .collect();
+    let reregister_handle = spawn_reregistration(
+        registrar.clone(),
+        connection.clone(),
+        presence.clone(),
+        registrations.clone(),
+        monitor_shutdown.clone(),
     );
-    let monitor_handle =
-        spawn_health_monitor(Arc::clone(&llama), presence.clone(), shutdown.clone());
+    let tunnel_url_handle = tunnel_url.map(|url_rx| {
+        spawn_tunnel_url_updates(
+            registrar.clone(),
+            connection.clone(),
+            registrations,
+            url_rx,
+            monitor_shutdown.clone(),
+        )
+    });
+    let (stop_tx, mut stop_rx) = tokio::sync::mpsc::unbounded_channel();
+    let handler = CommandHandler {
+        config: config.clone(),
+        registrar: registrar.clone(),
+        connection: connection.clone(),
+        presence: presence.clone(),
+        models: served_models.clone(),
+        skip_verify,
+        stop: stop_tx,
+        audit_path: config::state_dir()?.join("commands.jsonl"),
+    };
+    let commands_handle = handler
+        .clone()
+        .spawn(commands_rx, local_rx, shutdown.clone());
 
-    // Wait for shutdown signal
-    tokio::signal::ctrl_c()
-        .await
-        .context("Failed to listen for Ctrl+C")?;
+    // Wait for a shutdown signal or a drain/shutdown command from the platform
+    let mut stop_requests = Vec::new();
+    let (stop_command, stop_cause) = tokio::select! {
+        name = signals.recv() => {
+            tracing::info!("Received {}", name);
+            (None, name)
+        }
+        request = stop_rx.recv() => {
+            let command = request.as_ref().map(|r| r.command.clone());
+            let cause = command.as_ref().map_or("stop", AgentCommand::name);
+            stop_requests.extend(request);
+            (command, cause)
+        }
+    };
 
     tracing::info!("Shutting down...");
-    println!("\nShutting down...");
+    service::notify("STOPPING=1");
+    match &stop_command {
+        Some(command) => println!(
+            "\nShutting down ({} requested by platform)...",
+            command.name()
+        ),
+        None => println!("\nShutting down..."),
+    }
 
-    // Signal all tasks to stop
-    shutdown.cancel();
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout_secs);
+    let graceful = async {
+        // Stop taking new work: go Draining and deregister so the platform stops
+        // routing here, then let in-flight requests finish. A shutdown command
+        // skips the wait, as does anything that cannot drain (e.g. Error).
+        monitor_shutdown.cancel();
+        let draining = !matches!(stop_command, Some(AgentCommand::Shutdown))
+            && presence
+                .transition(AgentPresenceStatus::Draining, stop_cause)
+                .await
+                .is_ok();
 
-    // Explicitly stop llama-server before waiting on tasks
-    if let Err(e) = llama.lock().await.stop().await {
-        tracing::warn!("Error stopping llama-server: {}", e);
-    }
+        deregister_all(&registrar, &connection).await;
 
-    presence
-        .transition(AgentPresenceStatus::Unavailable)
-        .await
-        .expect("Any → Unavailable transition must be valid");
-
-    // Deregister (best-effort on shutdown path — log but don't propagate)
-    let current_token = token.lock().await.clone();
-    match client
-        .delete(&deregister_url)
-        .header("Authorization", format!("Bearer {}", current_token))
-        .send()
-        .await
-    {
-        Ok(resp) if resp.status().is_success() => {
-            tracing::info!("Deregistered from platform");
+        if draining {
+            wait_for_drain(
+                &served_models,
+                &presence,
+                Duration::from_secs(config.drain_timeout_secs),
+                &mut stop_rx,
+                &mut stop_requests,
+                &mut signals,
+            )
+            .await;
         }
-        Ok(resp) => {
-            tracing::warn!("Deregister returned HTTP {}", resp.status());
+
+        // Acknowledge the drain/shutdown commands while the link is still up.
+        for request in stop_requests.drain(..) {
+            handler.complete(request).await;
         }
-        Err(e) => {
-            tracing::warn!("Failed to deregister from platform: {}", e);
+
+        // Signal all tasks to stop
+        shutdown.cancel();
+
+        // Explicitly stop every llama-server before waiting on tasks
+        for model in &served_models {
+            if let Err(e) = model.llama.lock().await.stop().await {
+                tracing::warn!("Error stopping llama-server: {}", e);
+            }
         }
-    }
 
-    // Wait for tasks to finish (with timeout)
-    let _ = tokio::time::timeout(Duration::from_secs(5), async {
-        let (r1, r2, r3) = tokio::join!(heartbeat_handle, monitor_handle, presence_handle);
-        let _ = (r1, r2, r3);
-    })
-    .await;
+        presence
+            .transition(AgentPresenceStatus::Unavailable, stop_cause)
+            .await
+            .expect("Any → Unavailable transition must be valid");
+
+        // Wait for tasks to finish (with timeout)
+        let _ = tokio::time::timeout(Duration::from_secs(5), async {
+            let (r1, r2, r3, r4, r5, r6) = tokio::join!(
+                futures_util::future::join_all(monitor_handles),
+                reregister_handle,
+                presence_handle,
+                commands_handle,
+                poll_handle,
+                outbox_handle
+            );
+            let _ = (r1, r2, r3, r4, r5, r6);
+            let _ = tokio::join!(proxy_handle, receipts_handle, control_handle, usage_handle);
+            for handle in [
+                socket_handle,
+                keys_handle,
+                metrics_handle,
+                tunnel_handle,
+                tunnel_url_handle,
+                tls_handle,
+                watchdog_handle,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                let _ = handle.await;
+            }
+        })
+        .await;
+    };
+    if tokio::time::timeout(shutdown_timeout, graceful)
+        .await
+        .is_err()
+    {
+        // Each llama-server is killed when its LlamaServer is dropped on exit.
+        tracing::warn!(
+            "Shutdown did not complete within {}s, forcing exit",
+            shutdown_timeout.as_secs()
+        );
+        shutdown.cancel();
+    }
 
     Ok(())
 }
 
-/// Register this provider instance with the platform, returning the response.
-async fn register_with_platform(
-    client: &reqwest::Client,
-    config: &config::Config,
-    token: &Arc<tokio::sync::Mutex<String>>,
-    model_name: &str,
-    model_sha256: Option<String>,
+/// Give up on starting: publish why, or go Unavailable when a shutdown signal
+/// interrupted startup, and stop the tasks started so far. Each llama-server
+/// is killed when its `LlamaServer` is dropped on exit.
+async fn abort_startup(
     presence: &PresenceHandle,
-) -> Result<RegisterResponse> {
-    let register_url = format!("{}/v1/providers/register", config.platform_url);
-    let register_body = RegisterRequest {
-        endpoint_url: config.public_url.clone(),
-        model: model_name.to_string(),
-        max_concurrent: config.max_concurrent,
-        context_length_offered: config.context_length_offered,
-        input_price_per_million: config.input_price_per_million,
-        output_price_per_million: config.output_price_per_million,
-        model_sha256,
-    };
-
-    let reg_token = token.lock().await.clone();
-    let res = client
-        .post(&register_url)
-        .header("Authorization", format!("Bearer {}", reg_token))
-        .json(&register_body)
-        .send()
-        .await
-        .map_err(|e| {
-            // Fire-and-forget: presence will be updated after this returns Err
-            tracing::error!("Registration request failed: {}", e);
-            e
-        })?;
-
-    if !res.status().is_success() {
-        let status = res.status();
-        let body = res.text().await.unwrap_or_default();
-        presence
-            .report_error(
-                "provider_register_failed",
-                &format!("status {}: {}", status, body),
-            )
-            .await;
-        anyhow::bail!("Registration failed ({}): {}", status, body);
+    presence_handle: tokio::task::JoinHandle<()>,
+    shutdown: &CancellationToken,
+    code: AgentErrorCode,
+    e: anyhow::Error,
+) -> Result<()> {
+    let interrupted = e.downcast_ref::<signals::Interrupted>().map(|i| i.0);
+    match interrupted {
+        Some(name) => {
+            tracing::info!("{}, shutting down", e);
+            service::notify("STOPPING=1");
+            println!("\nShutting down...");
+            presence
+                .transition(AgentPresenceStatus::Unavailable, name)
+                .await
+                .expect("Any → Unavailable transition must be valid");
+        }
+        None => presence.report_error(code, &format!("{:#}", e)).await,
     }
+    shutdown.cancel();
+    let _ = tokio::time::timeout(Duration::from_secs(2), presence_handle).await;
+    match interrupted {
+        Some(_) => Ok(()),
+        None => Err(e),
+    }
+}
 
-    let reg: RegisterResponse = res.json().await.map_err(|e| {
-        tracing::error!("Invalid registration response: {}", e);
-        e
-    })?;
+/// Deregister every served model (best-effort on shutdown paths — log but
+/// don't propagate).
+async fn deregister_all(registrar: &Registrar, connection: &PlatformConnection) {
+    for (model, provider_id) in connection.provider_ids().await {
+        connection.set_provider_id(model, None).await;
+        if let Err(e) = registrar.deregister(&provider_id).await {
+            tracing::warn!(
+                "Failed to deregister {} from platform: {:#}",
+                provider_id,
+                e
+            );
+        }
+    }
+}
 
-    tracing::info!(
-        "Registered with platform: id={}, status={}",
-        reg.id,
-        reg.status
+/// Wait for in-flight requests to finish, polling each llama-server's `/slots`.
+///
+/// Gives up after `timeout`, on a second shutdown signal, or on a shutdown
+/// command. Stop commands received meanwhile are added to `requests`.
+async fn wait_for_drain(
+    models: &[served::ServedModel],
+    presence: &PresenceHandle,
+    timeout: Duration,
+    stop: &mut tokio::sync::mpsc::UnboundedReceiver<StopRequest>,
+    requests: &mut Vec<StopRequest>,
+    signals: &mut signals::ShutdownSignals,
+) {
+    println!(
+        "Draining: waiting up to {}s for in-flight requests (Ctrl+C again to force)",
+        timeout.as_secs()
     );
-    Ok(reg)
+    let deadline = tokio::time::sleep(timeout);
+    tokio::pin!(deadline);
+    let mut interval = tokio::time::interval(DRAIN_POLL_INTERVAL);
+    let mut active = 0;
+    loop {
+        tokio::select! {
+            _ = &mut deadline => {
+                tracing::warn!("Drain timed out with {} request(s) still in flight", active);
+                return;
+            }
+            name = signals.recv() => {
+                println!("Received {} again, forcing shutdown", name);
+                return;
+            }
+            Some(request) = stop.recv() => {
+                let shutdown = request.command == AgentCommand::Shutdown;
+                requests.push(request);
+                if shutdown {
+                    tracing::info!("Shutdown command received while draining, forcing shutdown");
+                    return;
+                }
+            }
+            _ = interval.tick() => {}
+        }
+
+        // An unreachable backend has nothing left to drain.
+        active = 0;
+        for (i, model) in models.iter().enumerate() {
+            let n = model
+                .llama
+                .lock()
+                .await
+                .active_requests()
+                .await
+                .unwrap_or(0);
+            presence.update_model_active_requests(i, n).await;
+            active += n;
+        }
+        if active == 0 {
+            tracing::info!("All in-flight requests finished");
+            return;
+        }
+    }
 }
 
-/// Spawn the provider heartbeat loop (every 30s).
+/// Where the outcome of a command from the control socket is sent.
+type LocalReply = tokio::sync::oneshot::Sender<std::result::Result<CommandOutcome, String>>;
+
+/// Executes commands from the platform against the running agent.
 ///
-/// This sends an empty-body liveness ping to `/v1/providers/heartbeat` at the
-/// provider/instance level. It is distinct from the presence loop in
-/// `PresenceHandle::spawn_loop`, which sends the full agent state (status,
-/// model, active requests, errors) to `/v1/agents/presence` every 15s.
-fn spawn_heartbeat_loop(
-    client: reqwest::Client,
+/// Every command is validated against the current presence status, acknowledged
+/// to the platform (accepted/rejected, then completed/failed) and appended to
+/// the local audit log. Drain and shutdown are forwarded to `run_serve` via
+/// `stop`, which owns the shutdown sequence and completes them.
+#[derive(Clone)]
+struct CommandHandler {
     config: config::Config,
-    token: Arc<tokio::sync::Mutex<String>>,
+    registrar: Registrar,
+    connection: PlatformConnection,
+    presence: PresenceHandle,
+    models: Vec<served::ServedModel>,
+    /// Whether `serve` runs with `--skip-verify`, which reloads follow too.
+    skip_verify: bool,
+    stop: tokio::sync::mpsc::UnboundedSender<StopRequest>,
+    audit_path: std::path::PathBuf,
+}
+
+/// An accepted drain or shutdown command, acknowledged as completed once
+/// `run_serve` has carried it out.
+struct StopRequest {
+    command: AgentCommand,
+    raw: PlatformCommand,
+    local: bool,
+}
+
+impl CommandHandler {
+    /// Execute platform commands and, from the control socket, local ones.
+    fn spawn(
+        self,
+        mut commands: tokio::sync::mpsc::UnboundedReceiver<PlatformCommand>,
+        mut local: tokio::sync::mpsc::UnboundedReceiver<control::LocalCommand>,
+        shutdown: CancellationToken,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            // Polled commands are redelivered until acked; skip ones already seen.
+            let mut seen = std::collections::VecDeque::with_capacity(64);
+            loop {
+                let raw = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    raw = commands.recv() => match raw {
+                        Some(raw) => raw,
+                        None => break,
+                    },
+                    Some(command) = local.recv() => {
+                        self.handle(command.raw, Some(command.reply)).await;
+                        continue;
+                    }
+                };
+                if seen.contains(&raw.id) {
+                    continue;
+                }
+                if seen.len() == 64 {
+                    seen.pop_front();
+                }
+                seen.push_back(raw.id.clone());
+                self.handle(raw, None).await;
+            }
+        })
+    }
+
+    /// Run one command. Local commands (with a `reply`) get their outcome
+    /// sent back instead of acknowledged to the platform; both are audited.
+    async fn handle(&self, raw: PlatformCommand, reply: Option<LocalReply>) {
+        let local = reply.is_some();
+        let validated = match AgentCommand::parse(&raw) {
+            Ok(command) => {
+                let status = self.status_for(&command).await;
+                command.validate(&status).map(|()| command)
+            }
+            Err(e) => Err(e),
+        };
+        let command = match validated {
+            Ok(command) => command,
+            Err(e) => {
+                self.acknowledge(&raw, local, CommandOutcome::Rejected, Some(e.to_string()))
+                    .await;
+                if let Some(reply) = reply {
+                    let _ = reply.send(Err(e.to_string()));
+                }
+                return;
+            }
+        };
+        self.acknowledge(&raw, local, CommandOutcome::Accepted, None)
+            .await;
+
+        let result = match &command {
+            AgentCommand::Drain | AgentCommand::Shutdown => {
+                let _ = self.stop.send(StopRequest {
+                    command,
+                    raw,
+                    local,
+                });
+                if let Some(reply) = reply {
+                    let _ = reply.send(Ok(CommandOutcome::Accepted));
+                }
+                return;
+            }
+            AgentCommand::ReloadModel {
+                model,
+                hf_repo,
+                target,
+            } => {
+                self.reload_model(model, hf_repo.as_deref(), target.as_deref())
+                    .await
+            }
+            AgentCommand::SetPrice {
+                input_price_per_million,
+                output_price_per_million,
+                target,
+            } => {
+                self.set_price(
+                    *input_price_per_million,
+                    *output_price_per_million,
+                    target.as_deref(),
+                )
+                .await
+            }
+        };
+        let (outcome, message) = match result {
+            Ok(()) => (CommandOutcome::Completed, None),
+            Err(e) => (CommandOutcome::Failed, Some(format!("{:#}", e))),
+        };
+        self.acknowledge(&raw, local, outcome, message.clone())
+            .await;
+        if let Some(reply) = reply {
+            let _ = reply.send(match message {
+                None => Ok(outcome),
+                Some(message) => Err(message),
+            });
+        }
+    }
+
+    /// Acknowledge a drain or shutdown that has been carried out.
+    async fn complete(&self, request: StopRequest) {
+        self.acknowledge(&request.raw, request.local, CommandOutcome::Completed, None)
+            .await;
+    }
+
+    async fn acknowledge(
+        &self,
+        raw: &PlatformCommand,
+        local: bool,
+        outcome: CommandOutcome,
+        message: Option<String>,
+    ) {
+        match &message {
+            Some(msg) => tracing::info!(
+                "Command {} ({}): {:?}: {}",
+                raw.command,
+                raw.id,
+                outcome,
+                msg
+            ),
+            None => tracing::info!("Command {} ({}): {:?}", raw.command, raw.id, outcome),
+        }
+        if let Err(e) =
+            commands::append_audit_log(&self.audit_path, raw, outcome, message.as_deref())
+        {
+            tracing::warn!("Failed to write command audit log: {}", e);
+        }
+        if local {
+            return;
+        }
+        let ack = CommandAck {
+            id: raw.id.clone(),
+            outcome,
+            message,
+        };
+        if let Err(e) = self.connection.send_ack(&ack).await {
+            tracing::warn!("Failed to acknowledge command {}: {}", raw.id, e);
+        }
+    }
+
+    /// The status a command is validated against: that of the served model
+    /// a reload replaces, so others keep serving meanwhile, or the agent's.
+    async fn status_for(&self, command: &AgentCommand) -> AgentPresenceStatus {
+        if let AgentCommand::ReloadModel { target, .. } = command {
+            if let Ok(model) = served::find(&self.models, target.as_deref()).await {
+                if let Some(m) = self.presence.models().await.get(model) {
+                    return m.status.clone();
+                }
+            }
+        }
+        self.presence.status().await
+    }
+
+    async fn reload_model(
+        &self,
+        model: &str,
+        hf_repo: Option<&str>,
+        target: Option<&str>,
+    ) -> Result<()> {
+        let index = served::find(&self.models, target).await?;
+        let served = &self.models[index];
+        let model_path = models::find_model(&self.config, model)?;
+        // Held to the same rule as `serve`: verified unless --skip-verify.
+        let model_sha256 = match (hf_repo, self.skip_verify) {
+            (Some(repo), false) => {
+                Some(verification::verify_model(&model_path, repo, false).await?)
+            }
+            (None, false) => anyhow::bail!(
+                "hf_repo is required to verify the model (serve runs without --skip-verify)"
+            ),
+            (_, true) => None,
+        };
+        let model_name = models::normalize_model_name(&model_path);
+        for (i, other) in self.models.iter().enumerate() {
+            if i != index && other.registration.lock().await.model == model_name {
+                anyhow::bail!("{} is already being served", model_name);
+            }
+        }
+
+        self.presence.set_model_loading(index, "reload_model").await;
+        {
+            let mut llama = served.llama.lock().await;
+            if let Err(e) = llama.stop().await {
+                tracing::warn!("Error stopping llama-server before reload: {}", e);
+            }
+            llama.set_model_path(model_path.clone());
+            if let Err(e) = llama.start().await {
+                self.presence
+                    .report_model_error(index, AgentErrorCode::LlamaStartFailed, &e.to_string())
+                    .await;
+                return Err(e);
+            }
+        }
+        tracing::info!("Reloaded llama-server with model: {}", model_path);
+        self.presence
+            .set_model_name(index, model_name.clone())
+            .await;
+
+        let body = {
+            let mut reg = served.registration.lock().await;
+            reg.model = model_name;
+            reg.model_sha256 = model_sha256;
+            reg.clone()
+        };
+        if let Err(e) = self.push_registration(index, &body).await {
+            self.presence
+                .report_model_error(
+                    index,
+                    AgentErrorCode::ProviderRegisterFailed,
+                    &e.to_string(),
+                )
+                .await;
+            return Err(e);
+        }
+
+        self.presence.model_recovered(index, "model_reloaded").await;
+        Ok(())
+    }
+
+    async fn set_price(
+        &self,
+        input: Option<u32>,
+        output: Option<u32>,
+        target: Option<&str>,
+    ) -> Result<()> {
+        let index = served::find(&self.models, target).await?;
+        // Only take the new prices once the platform has accepted them, so
+        // usage is never metered at a price it does not know.
+        let mut body = self.models[index].registration.lock().await.clone();
+        if let Some(price) = input {
+            body.input_price_per_million = price;
+        }
+        if let Some(price) = output {
+            body.output_price_per_million = price;
+        }
+        self.push_registration(index, &body).await?;
+        {
+            let mut reg = self.models[index].registration.lock().await;
+            reg.input_price_per_million = body.input_price_per_million;
+            reg.output_price_per_million = body.output_price_per_million;
+        }
+        tracing::info!(
+            "Prices of {} updated: input={} output={} per million tokens",
+            body.model,
+            body.input_price_per_million,
+            body.output_price_per_million
+        );
+        Ok(())
+    }
+
+    async fn push_registration(&self, model: usize, body: &RegisterRequest) -> Result<()> {
+        let provider_id = self
+            .connection
+            .provider_id(model)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Not registered with the platform"))?;
+        self.registrar.update(&provider_id, body).await
+    }
+}
+
+/// Spawn a task that points every registration at the relay's new URL
+/// whenever it issues a different one after reconnecting. Stops when
+/// draining begins.
+fn spawn_tunnel_url_updates(
+    registrar: Registrar,
+    connection: PlatformConnection,
+    registrations: Vec<Arc<tokio::sync::Mutex<RegisterRequest>>>,
+    mut url_rx: tokio::sync::watch::Receiver<Option<String>>,
     shutdown: CancellationToken,
 ) -> tokio::task::JoinHandle<()> {
-    let heartbeat_url = format!("{}/v1/providers/heartbeat", config.platform_url);
     tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(30));
+        let mut retry = false;
         loop {
             tokio::select! {
                 _ = shutdown.cancelled() => break,
-                _ = interval.tick() => {}
+                changed = url_rx.changed() => if changed.is_err() { break },
+                _ = tokio::time::sleep(TUNNEL_URL_RETRY), if retry => {}
             }
-
-            let current_token = token.lock().await.clone();
-            let res = client
-                .post(&heartbeat_url)
-                .header("Authorization", format!("Bearer {}", current_token))
-                .send()
-                .await;
-            match res {
-                Ok(r) if r.status().is_success() => {
-                    tracing::trace!("Heartbeat sent");
+            retry = false;
+            let Some(url) = url_rx.borrow_and_update().clone() else {
+                continue;
+            };
+            for (model, registration) in registrations.iter().enumerate() {
+                let Some(provider_id) = connection.provider_id(model).await else {
+                    // Not registered yet: it will register with the new URL.
+                    registration.lock().await.endpoint_url = url.clone();
+                    continue;
+                };
+                let body = {
+                    let registration = registration.lock().await;
+                    if registration.endpoint_url == url {
+                        continue;
+                    }
+                    RegisterRequest {
+                        endpoint_url: url.clone(),
+                        ..registration.clone()
+                    }
+                };
+                // Keep the old URL until the platform has the new one.
+                match registrar.update(&provider_id, &body).await {
+                    Ok(()) => {
+                        registration.lock().await.endpoint_url = url.clone();
+                        tracing::info!("Registered new tunnel URL {}", url);
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            "Failed to register new tunnel URL {}, retrying in {}s: {:#}",
+                            url,
+                            TUNNEL_URL_RETRY.as_secs(),
+                            e
+                        );
+                        retry = true;
+                    }
                 }
-                Ok(r) => {
-                    tracing::warn!("Heartbeat failed: {}", r.status());
+            }
+        }
+    })
+}
+
+/// Spawn a task that registers a model again whenever the platform reports
+/// that it no longer knows the model's provider id. Stops when draining begins.
+fn spawn_reregistration(
+    registrar: Registrar,
+    connection: PlatformConnection,
+    presence: PresenceHandle,
+    registrations: Vec<Arc<tokio::sync::Mutex<RegisterRequest>>>,
+    shutdown: CancellationToken,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let unknown_id = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                id = connection.provider_unknown() => id,
+            };
+            // A stale report about an id we have already replaced.
+            let Some(model) = connection.served_model(&unknown_id).await else {
+                continue;
+            };
+
+            tracing::warn!(
+                "Registering again after platform forgot provider {}",
+                unknown_id
+            );
+            registrar.forget(&unknown_id);
+            let body = registrations[model].lock().await.clone();
+            let result = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                result = registrar.register(&body) => result,
+            };
+            match result {
+                Ok(provider_id) => {
+                    connection.set_provider_id(model, Some(provider_id)).await;
+                    let degraded = presence
+                        .models()
+                        .await
+                        .get(model)
+                        .is_some_and(|m| m.status == AgentPresenceStatus::Degraded);
+                    if degraded {
+                        presence.model_recovered(model, "reregistered").await;
+                    } else {
+                        presence.publish().await;
+                    }
                 }
                 Err(e) => {
-                    tracing::warn!("Heartbeat error: {}", e);
+                    tracing::error!("Re-registration failed: {:#}", e);
+                    presence
+                        .report_model_degraded(
+                            model,
+                            AgentErrorCode::ProviderRegisterFailed,
+                            &format!("{:#}", e),
+                        )
+                        .await;
                 }
             }
         }
     })
 }
 
-/// Spawn a health monitor that checks llama-server status and restarts it if needed.
+/// Spawn a health monitor that checks the llama-server of served model `model`
+/// and restarts it if needed.
 fn spawn_health_monitor(
+    model: usize,
     llama: Arc<tokio::sync::Mutex<backend::LlamaServer>>,
     presence: PresenceHandle,
     shutdown: CancellationToken,
 ) -> tokio::task::JoinHandle<()> {
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(Duration::from_secs(15));
+        let mut utilization = backend::metrics::UtilizationTracker::new();
         loop {
             tokio::select! {
                 _ = shutdown.cancelled() => break,
@@ -442,9 +1848,16 @@ fn spawn_health_monitor(
             if !guard.is_running() {
                 let backoff = guard.next_backoff();
                 drop(guard);
+                let metrics = telemetry::metrics();
+                metrics.backend_restarts.inc();
+                metrics.backend_restart_backoff.set(backoff.as_secs_f64());
 
                 presence
-                    .report_degraded("llama_stopped", "llama-server process stopped unexpectedly")
+                    .report_model_degraded(
+                        model,
+                        AgentErrorCode::LlamaStopped,
+                        "llama-server process stopped unexpectedly",
+                    )
                     .await;
                 tracing::warn!("Restarting llama-server after backoff of {:?}", backoff);
 
@@ -464,24 +1877,38 @@ fn spawn_health_monitor(
                     tracing::warn!("Error stopping llama-server before restart: {}", e);
                 }
                 match guard.start().await {
-                    Ok(()) => presence
-                        .transition(AgentPresenceStatus::Ready)
-                        .await
-                        .expect("Degraded → Ready transition must be valid"),
+                    Ok(()) => presence.model_recovered(model, "llama_restarted").await,
                     Err(e) => {
                         tracing::error!("Failed to restart llama-server: {}", e);
                         presence
-                            .report_error("llama_restart_failed", &e.to_string())
+                            .report_model_error(
+                                model,
+                                AgentErrorCode::LlamaRestartFailed,
+                                &e.to_string(),
+                            )
                             .await;
                     }
                 }
             } else {
-                match guard.active_requests().await {
-                    Ok(active) => {
-                        presence.update_active_requests(active).await;
+                // One /slots fetch gives both the active count and context usage.
+                let slots = match guard.slot_usage().await {
+                    Ok(slots) => slots,
+                    Err(e) => {
+                        tracing::debug!("Failed to inspect llama-server slots: {}", e);
+                        presence.set_model_utilization(model, None).await;
+                        continue;
+                    }
+                };
+                let active = slots.iter().filter(|s| s.is_processing).count() as u32;
+                presence.update_model_active_requests(model, active).await;
+                match guard.metrics_sample().await {
+                    Ok(sample) => {
+                        let summary = utilization.record(std::time::Instant::now(), sample, slots);
+                        presence.set_model_utilization(model, Some(summary)).await;
                     }
                     Err(e) => {
-                        tracing::debug!("Failed to inspect active request count: {}", e);
+                        tracing::debug!("Failed to scrape llama-server metrics: {}", e);
+                        presence.set_model_utilization(model, None).await;
                     }
                 }
             }
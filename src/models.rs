@@ -58,6 +58,7 @@ pub fn list_local_models(config: &Config) -> Result<Vec<LocalModel>> {
 }
 
 /// Download a GGUF model file from a HuggingFace repository.
+#[tracing::instrument(name = "pull_model", skip(hf_repo_id), fields(hf_repo = hf_repo_id))]
 pub async fn pull_model(hf_repo_id: &str, file: Option<&str>) -> Result<()> {
     let model_dir = crate::config::model_dir()?;
     fs::create_dir_all(&model_dir)
@@ -148,6 +149,9 @@ pub async fn pull_model(hf_repo_id: &str, file: Option<&str>) -> Result<()> {
             file.write_all(&chunk)
                 .with_context(|| format!("Failed to write to {}", partial.display()))?;
             downloaded += chunk.len() as u64;
+            crate::telemetry::metrics()
+                .download_bytes
+                .inc_by(chunk.len() as u64);
             eprint!(
                 "\r  {}/{} ({:.0}%)",
                 format_size(downloaded),
@@ -0,0 +1,321 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// File names under the state directory.
+pub const OUTBOX_FILE: &str = "outbox.jsonl";
+pub const LINK_STATUS_FILE: &str = "link.json";
+
+/// Maximum number of events kept while the platform is unreachable. When
+/// full, the oldest usage event is dropped, or the oldest event if there are
+/// only presence transitions left.
+const OUTBOX_CAPACITY: usize = 1000;
+
+/// Kinds of event that must reach the platform even if it is briefly down.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutboxEventKind {
+    PresenceTransition,
+    /// A served request's [`UsageRecord`](crate::usage::UsageRecord).
+    Usage,
+}
+
+/// An event waiting to be delivered, stamped with the time it happened.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct OutboxEvent {
+    pub kind: OutboxEventKind,
+    pub recorded_at: u64,
+    pub payload: serde_json::Value,
+}
+
+impl OutboxEvent {
+    pub fn new(kind: OutboxEventKind, recorded_at: u64, payload: serde_json::Value) -> Self {
+        OutboxEvent {
+            kind,
+            recorded_at,
+            payload,
+        }
+    }
+}
+
+/// Bounded FIFO of undelivered events, persisted as JSONL so a restart does
+/// not lose them.
+///
+/// The file is rewritten on a thread of its own, so queueing events from the
+/// runtime never waits on the disk.
+#[derive(Debug)]
+pub struct Outbox {
+    events: VecDeque<OutboxEvent>,
+    writes: Option<mpsc::Sender<Vec<OutboxEvent>>>,
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+impl Outbox {
+    /// Open the outbox at `path`, loading any events left from a previous run.
+    /// Without a path the outbox only lives in memory.
+    pub fn open(path: Option<PathBuf>) -> Self {
+        let mut events = VecDeque::new();
+        if let Some(path) = &path {
+            match fs::read_to_string(path) {
+                Ok(contents) => {
+                    events.extend(
+                        contents
+                            .lines()
+                            .filter_map(|line| serde_json::from_str::<OutboxEvent>(line).ok()),
+                    );
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => tracing::warn!("Failed to read outbox {}: {}", path.display(), e),
+            }
+        }
+        while events.len() > OUTBOX_CAPACITY {
+            evict_one(&mut events);
+        }
+        if !events.is_empty() {
+            tracing::info!(
+                "{} undelivered platform events from a previous run",
+                events.len()
+            );
+        }
+        let (writes, writer) = match path.map(spawn_writer) {
+            Some(Ok((writes, writer))) => (Some(writes), Some(writer)),
+            Some(Err(e)) => {
+                tracing::warn!("Failed to start outbox writer: {}", e);
+                (None, None)
+            }
+            None => (None, None),
+        };
+        Outbox {
+            events,
+            writes,
+            writer,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Queue events, making room by dropping usage before presence
+    /// transitions if the outbox is full. Persisted once for the whole batch.
+    pub fn push_all(&mut self, events: impl IntoIterator<Item = OutboxEvent>) {
+        let mut dropped = 0;
+        for event in events {
+            if self.events.len() == OUTBOX_CAPACITY {
+                evict_one(&mut self.events);
+                dropped += 1;
+            }
+            self.events.push_back(event);
+        }
+        if dropped > 0 {
+            tracing::warn!(
+                "Outbox full ({} events), dropped the {} oldest",
+                OUTBOX_CAPACITY,
+                dropped
+            );
+        }
+        self.persist();
+    }
+
+    /// The oldest `n` events, in order.
+    pub fn peek(&self, n: usize) -> Vec<OutboxEvent> {
+        self.events.iter().take(n).cloned().collect()
+    }
+
+    /// Remove the oldest `n` events once they have been delivered.
+    pub fn remove(&mut self, n: usize) {
+        let n = n.min(self.events.len());
+        self.events.drain(..n);
+        self.persist();
+    }
+
+    fn persist(&self) {
+        if let Some(writes) = &self.writes {
+            let _ = writes.send(self.events.iter().cloned().collect());
+        }
+    }
+}
+
+impl Drop for Outbox {
+    /// Finish writing the latest snapshot before the outbox goes away.
+    fn drop(&mut self) {
+        self.writes.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Drop the oldest usage event, or the oldest event if there is none, so
+/// presence transitions outlive usage when the outbox overflows.
+fn evict_one(events: &mut VecDeque<OutboxEvent>) {
+    match events.iter().position(|e| e.kind == OutboxEventKind::Usage) {
+        Some(i) => {
+            events.remove(i);
+        }
+        None => {
+            events.pop_front();
+        }
+    }
+}
+
+fn spawn_writer(
+    path: PathBuf,
+) -> std::io::Result<(mpsc::Sender<Vec<OutboxEvent>>, thread::JoinHandle<()>)> {
+    let (tx, rx) = mpsc::channel::<Vec<OutboxEvent>>();
+    let writer = thread::Builder::new()
+        .name("outbox".to_string())
+        .spawn(move || {
+            while let Ok(mut events) = rx.recv() {
+                // Only the newest snapshot matters; skip any it supersedes.
+                while let Ok(newer) = rx.try_recv() {
+                    events = newer;
+                }
+                if let Err(e) = write_events(&path, &events) {
+                    tracing::warn!("Failed to write outbox {}: {:#}", path.display(), e);
+                }
+            }
+        })?;
+    Ok((tx, writer))
+}
+
+fn write_events(path: &Path, events: &[OutboxEvent]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed creating directory {}", parent.display()))?;
+    }
+    let tmp = path.with_extension("jsonl.tmp");
+    let mut file =
+        fs::File::create(&tmp).with_context(|| format!("Failed creating {}", tmp.display()))?;
+    for event in events {
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())
+            .with_context(|| format!("Failed writing {}", tmp.display()))?;
+    }
+    fs::rename(&tmp, path)
+        .with_context(|| format!("Failed renaming {} → {}", tmp.display(), path.display()))?;
+    Ok(())
+}
+
+/// Whether the platform is reachable, as last seen by the running agent.
+/// Written to disk so `vramsply status` can show it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct LinkStatus {
+    pub connected: bool,
+    pub last_contact_at: Option<u64>,
+    pub disconnected_since: Option<u64>,
+    pub queued_events: usize,
+    pub updated_at: u64,
+}
+
+impl LinkStatus {
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed creating directory {}", parent.display()))?;
+        }
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(path, json).with_context(|| format!("Failed writing {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed reading {}", path.display()))?;
+        let status = serde_json::from_str(&contents)
+            .with_context(|| format!("Failed parsing {}", path.display()))?;
+        Ok(Some(status))
+    }
+}
+
+/// Current unix time in seconds.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(n: u64) -> OutboxEvent {
+        OutboxEvent::new(
+            OutboxEventKind::PresenceTransition,
+            n,
+            serde_json::json!({ "n": n }),
+        )
+    }
+
+    #[test]
+    fn test_outbox_survives_reopen_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbox.jsonl");
+
+        let mut outbox = Outbox::open(Some(path.clone()));
+        outbox.push_all((0..5).map(event));
+        outbox.remove(2);
+        drop(outbox);
+
+        let reopened = Outbox::open(Some(path));
+        assert_eq!(reopened.len(), 3);
+        let times: Vec<u64> = reopened.peek(10).iter().map(|e| e.recorded_at).collect();
+        assert_eq!(times, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn test_outbox_drops_oldest_when_full() {
+        let mut outbox = Outbox::open(None);
+        outbox.push_all((0..(OUTBOX_CAPACITY as u64 + 3)).map(event));
+        assert_eq!(outbox.len(), OUTBOX_CAPACITY);
+        assert_eq!(outbox.peek(1)[0].recorded_at, 3);
+    }
+
+    #[test]
+    fn test_outbox_drops_usage_before_transitions() {
+        let mut outbox = Outbox::open(None);
+        outbox.push_all([event(0), event(1)]);
+        outbox.push_all(
+            (2..(OUTBOX_CAPACITY as u64 + 2)).map(|n| {
+                OutboxEvent::new(OutboxEventKind::Usage, n, serde_json::json!({ "n": n }))
+            }),
+        );
+        assert_eq!(outbox.len(), OUTBOX_CAPACITY);
+        let oldest = outbox.peek(3);
+        assert_eq!(oldest[0].recorded_at, 0);
+        assert_eq!(oldest[1].recorded_at, 1);
+        assert_eq!(oldest[2].recorded_at, 4);
+    }
+
+    #[test]
+    fn test_link_status_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("link.json");
+        assert_eq!(LinkStatus::load(&path).unwrap(), None);
+
+        let status = LinkStatus {
+            connected: false,
+            last_contact_at: Some(10),
+            disconnected_since: Some(20),
+            queued_events: 4,
+            updated_at: 30,
+        };
+        status.save(&path).unwrap();
+        assert_eq!(LinkStatus::load(&path).unwrap(), Some(status));
+    }
+}
@@ -1,14 +1,24 @@
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{bail, Result};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tokio_util::sync::CancellationToken;
 
-use crate::config::Config;
+use crate::backend::metrics::UtilizationSummary;
+use crate::connection::PlatformConnection;
+use crate::errors::{AgentErrorCode, ErrorInfo};
+use crate::hardware::HardwareInventory;
 use crate::identity::AgentIdentity;
+use crate::outbox::{OutboxEvent, OutboxEventKind};
+use crate::reachability::ReachabilityReport;
+use crate::transitions::{TransitionEvent, TransitionHistory};
 
-#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(15);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum AgentPresenceStatus {
     Unavailable,
@@ -16,22 +26,49 @@ pub enum AgentPresenceStatus {
     LoadingModel,
     Ready,
     Serving,
+    Draining,
     Degraded,
     Error,
 }
 
 impl AgentPresenceStatus {
+    pub const ALL: [AgentPresenceStatus; 8] = [
+        AgentPresenceStatus::Unavailable,
+        AgentPresenceStatus::Idle,
+        AgentPresenceStatus::LoadingModel,
+        AgentPresenceStatus::Ready,
+        AgentPresenceStatus::Serving,
+        AgentPresenceStatus::Draining,
+        AgentPresenceStatus::Degraded,
+        AgentPresenceStatus::Error,
+    ];
+
+    /// The wire (snake_case) name of the status.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AgentPresenceStatus::Unavailable => "unavailable",
+            AgentPresenceStatus::Idle => "idle",
+            AgentPresenceStatus::LoadingModel => "loading_model",
+            AgentPresenceStatus::Ready => "ready",
+            AgentPresenceStatus::Serving => "serving",
+            AgentPresenceStatus::Draining => "draining",
+            AgentPresenceStatus::Degraded => "degraded",
+            AgentPresenceStatus::Error => "error",
+        }
+    }
+
     /// Returns whether transitioning from `self` to `target` is valid.
     ///
     /// ```text
     /// Idle         → LoadingModel, Unavailable, Error
     /// LoadingModel → Ready, Error, Unavailable
-    /// Ready        → Serving, LoadingModel, Degraded, Error, Unavailable
-    /// Serving      → Ready, Degraded, Error, Unavailable
-    /// Degraded     → Ready, LoadingModel, Error, Unavailable
+    /// Ready        → Serving, LoadingModel, Draining, Degraded, Error, Unavailable
+    /// Serving      → Ready, Draining, Degraded, Error, Unavailable
+    /// Draining     → Error, Unavailable
+    /// Degraded     → Ready, LoadingModel, Draining, Error, Unavailable
     /// Error        → LoadingModel, Unavailable
     /// ```
-    fn can_transition_to(&self, target: &AgentPresenceStatus) -> bool {
+    pub fn can_transition_to(&self, target: &AgentPresenceStatus) -> bool {
         use AgentPresenceStatus::*;
         matches!(
             (self, target),
@@ -39,13 +76,62 @@ impl AgentPresenceStatus {
                 | (LoadingModel, Ready | Error | Unavailable)
                 | (
                     Ready,
-                    Serving | LoadingModel | Degraded | Error | Unavailable
+                    Serving | LoadingModel | Draining | Degraded | Error | Unavailable
+                )
+                | (Serving, Ready | Draining | Degraded | Error | Unavailable)
+                | (Draining, Error | Unavailable)
+                | (
+                    Degraded,
+                    Ready | LoadingModel | Draining | Error | Unavailable
                 )
-                | (Serving, Ready | Degraded | Error | Unavailable)
-                | (Degraded, Ready | LoadingModel | Error | Unavailable)
                 | (Error, LoadingModel | Unavailable)
         )
     }
+
+    /// How well a model in this status serves, for picking the agent-level
+    /// status of several models: the best any of them manages.
+    fn rank(&self) -> u8 {
+        match self {
+            AgentPresenceStatus::Serving => 5,
+            AgentPresenceStatus::Ready => 4,
+            AgentPresenceStatus::LoadingModel => 3,
+            AgentPresenceStatus::Degraded => 2,
+            AgentPresenceStatus::Error => 1,
+            _ => 0,
+        }
+    }
+}
+
+/// Presence of one served model. An agent serving several models reports
+/// one of these per model next to its aggregated status.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ModelPresence {
+    pub model: String,
+    pub status: AgentPresenceStatus,
+    /// Filled in from the connection when published.
+    #[serde(default)]
+    pub provider_id: Option<String>,
+    pub active_requests: u32,
+    #[serde(default)]
+    pub error_code: Option<AgentErrorCode>,
+    #[serde(default)]
+    pub error_message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub utilization: Option<UtilizationSummary>,
+}
+
+impl ModelPresence {
+    fn new(model: String, status: AgentPresenceStatus) -> Self {
+        ModelPresence {
+            model,
+            status,
+            provider_id: None,
+            active_requests: 0,
+            error_code: None,
+            error_message: None,
+            utilization: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -54,21 +140,88 @@ pub struct AgentPresenceState {
     pub current_model: Option<String>,
     pub loading_progress_pct: Option<u8>,
     pub active_requests: u32,
-    pub error_code: Option<String>,
+    pub error_code: Option<AgentErrorCode>,
     pub error_message: Option<String>,
+    pub utilization: Option<UtilizationSummary>,
+    /// Whether the platform could reach `public_url` at startup.
+    pub reachability: Option<ReachabilityReport>,
+    /// One entry per served model, in serve order. The fields above are
+    /// their aggregate.
+    pub models: Vec<ModelPresence>,
 }
 
 impl AgentPresenceState {
-    pub fn new(status: AgentPresenceStatus, current_model: Option<String>) -> Self {
+    /// A state serving `models`; the first is advertised as `current_model`.
+    pub fn new(status: AgentPresenceStatus, models: Vec<String>) -> Self {
         AgentPresenceState {
+            current_model: models.first().cloned(),
+            models: models
+                .into_iter()
+                .map(|m| ModelPresence::new(m, status.clone()))
+                .collect(),
             status,
-            current_model,
             loading_progress_pct: None,
             active_requests: 0,
             error_code: None,
             error_message: None,
+            utilization: None,
+            reachability: None,
         }
     }
+
+    /// Recompute the agent-level status from the models after one of them
+    /// changed: the best status any model has, with that model's error
+    /// details. Returns the previous status if it changed. Idle, Draining
+    /// and Unavailable concern the whole agent and are left alone.
+    fn aggregate(&mut self) -> Option<AgentPresenceStatus> {
+        self.active_requests = self.models.iter().map(|m| m.active_requests).sum();
+        self.utilization =
+            UtilizationSummary::combine(self.models.iter().filter_map(|m| m.utilization.as_ref()));
+        if matches!(
+            self.status,
+            AgentPresenceStatus::Idle
+                | AgentPresenceStatus::Draining
+                | AgentPresenceStatus::Unavailable
+        ) {
+            return None;
+        }
+        let best = self.models.iter().rev().max_by_key(|m| m.status.rank())?;
+        let status = best.status.clone();
+        if matches!(
+            status,
+            AgentPresenceStatus::Degraded | AgentPresenceStatus::Error
+        ) {
+            let m = best.clone();
+            self.error_code = m.error_code;
+            self.error_message = m.error_message;
+        } else {
+            self.error_code = None;
+            self.error_message = None;
+        }
+        if status == self.status {
+            return None;
+        }
+        self.loading_progress_pct = None;
+        Some(std::mem::replace(&mut self.status, status))
+    }
+}
+
+/// The Ready/Serving toggle driven by an active request count; Draining
+/// only tracks the count.
+fn status_for_active_requests(status: &AgentPresenceStatus, n: u32) -> AgentPresenceStatus {
+    if n > 0 && *status != AgentPresenceStatus::Draining {
+        AgentPresenceStatus::Serving
+    } else if matches!(
+        status,
+        AgentPresenceStatus::Ready
+            | AgentPresenceStatus::Serving
+            | AgentPresenceStatus::Idle
+            | AgentPresenceStatus::LoadingModel
+    ) {
+        AgentPresenceStatus::Ready
+    } else {
+        status.clone()
+    }
 }
 
 /// Wrapper around presence state with methods to transition status and publish.
@@ -76,43 +229,90 @@ impl AgentPresenceState {
 #[derive(Clone)]
 pub struct PresenceHandle {
     state: Arc<tokio::sync::Mutex<AgentPresenceState>>,
-    client: reqwest::Client,
-    config: Config,
-    token: Arc<tokio::sync::Mutex<String>>,
+    connection: PlatformConnection,
     identity: AgentIdentity,
+    hardware: Arc<HardwareInventory>,
+    history: Arc<std::sync::Mutex<TransitionHistory>>,
+    /// Transitions not yet carried by a successful publish.
+    undelivered: Arc<std::sync::Mutex<Vec<TransitionEvent>>>,
 }
 
 impl PresenceHandle {
+    /// Create a handle in `Idle` serving `models`. Transitions are appended
+    /// to `event_log` when given.
     pub fn new(
-        model_name: Option<String>,
-        client: reqwest::Client,
-        config: Config,
-        token: Arc<tokio::sync::Mutex<String>>,
+        models: Vec<String>,
+        connection: PlatformConnection,
         identity: AgentIdentity,
+        hardware: HardwareInventory,
+        event_log: Option<PathBuf>,
     ) -> Self {
+        crate::telemetry::metrics().set_status(&AgentPresenceStatus::Idle);
         let state = Arc::new(tokio::sync::Mutex::new(AgentPresenceState::new(
             AgentPresenceStatus::Idle,
-            model_name,
+            models,
         )));
         PresenceHandle {
             state,
-            client,
-            config,
-            token,
+            connection,
             identity,
+            hardware: Arc::new(hardware),
+            history: Arc::new(std::sync::Mutex::new(TransitionHistory::new(event_log))),
+            undelivered: Arc::new(std::sync::Mutex::new(Vec::new())),
         }
     }
 
-    /// Transition to a new status, clearing error fields and publishing.
+    fn record(&self, event: TransitionEvent) {
+        // The metrics track the agent; per-model changes are only logged.
+        if event.model.is_none() {
+            let metrics = crate::telemetry::metrics();
+            metrics
+                .transitions
+                .with_label_values(&[event.to.as_str(), event.cause.as_str()])
+                .inc();
+            metrics.set_status(&event.to);
+        }
+        self.undelivered
+            .lock()
+            .expect("undelivered transitions lock poisoned")
+            .push(event.clone());
+        self.history
+            .lock()
+            .expect("transition history lock poisoned")
+            .record(event);
+    }
+
+    /// The most recent `limit` transitions, oldest first.
+    pub fn history(&self, limit: usize) -> Vec<TransitionEvent> {
+        self.history
+            .lock()
+            .expect("transition history lock poisoned")
+            .recent(limit)
+    }
+
+    /// Transition the agent and every model to a new status, clearing error
+    /// fields and publishing. `cause` is recorded in the transition history.
     ///
     /// Returns an error if the transition is not allowed from the current state.
     /// Invalid transitions indicate a programming bug in the caller.
-    pub async fn transition(&self, status: AgentPresenceStatus) -> Result<()> {
+    pub async fn transition(&self, status: AgentPresenceStatus, cause: &str) -> Result<()> {
         {
             let mut s = self.state.lock().await;
             if !s.status.can_transition_to(&status) {
                 bail!("Invalid presence transition: {:?} → {:?}", s.status, status);
             }
+            self.record(TransitionEvent::new(
+                s.status.clone(),
+                status.clone(),
+                cause,
+                None,
+                None,
+            ));
+            for m in &mut s.models {
+                m.status = status.clone();
+                m.error_code = None;
+                m.error_message = None;
+            }
             s.status = status;
             s.loading_progress_pct = None;
             s.error_code = None;
@@ -122,90 +322,341 @@ impl PresenceHandle {
         Ok(())
     }
 
+    /// Current presence status.
+    pub async fn status(&self) -> AgentPresenceStatus {
+        self.state.lock().await.status.clone()
+    }
+
+    /// A copy of the full presence state.
+    pub async fn snapshot(&self) -> AgentPresenceState {
+        self.state.lock().await.clone()
+    }
+
+    /// Per-model presence with provider ids filled in. While the agent is
+    /// Idle, Draining or Unavailable, so is every model.
+    pub async fn models(&self) -> Vec<ModelPresence> {
+        let state = self.snapshot().await;
+        self.effective_models(&state).await
+    }
+
+    async fn effective_models(&self, state: &AgentPresenceState) -> Vec<ModelPresence> {
+        let provider_ids = self.connection.provider_ids().await;
+        state
+            .models
+            .iter()
+            .enumerate()
+            .map(|(i, m)| {
+                let mut m = m.clone();
+                m.provider_id = provider_ids.get(&i).cloned();
+                if matches!(
+                    state.status,
+                    AgentPresenceStatus::Idle
+                        | AgentPresenceStatus::Draining
+                        | AgentPresenceStatus::Unavailable
+                ) {
+                    m.status = state.status.clone();
+                }
+                m
+            })
+            .collect()
+    }
+
+    /// Change the name model `model` is advertised under (e.g. after a
+    /// reload), then publish. The first model is also `current_model`.
+    pub async fn set_model_name(&self, model: usize, name: String) {
+        {
+            let mut s = self.state.lock().await;
+            if model == 0 {
+                s.current_model = Some(name.clone());
+            }
+            if let Some(m) = s.models.get_mut(model) {
+                m.model = name;
+            }
+        }
+        self.publish().await;
+    }
+
+    /// Apply `update` to model `model`, then recompute the agent status from
+    /// all models and publish. The change is recorded under `cause`.
+    async fn update_model(
+        &self,
+        model: usize,
+        cause: &str,
+        update: impl FnOnce(&mut ModelPresence),
+    ) {
+        {
+            let mut s = self.state.lock().await;
+            let Some(m) = s.models.get_mut(model) else {
+                return;
+            };
+            update(m);
+            if let Some(previous) = s.aggregate() {
+                let code = s.error_code.map(|c| c.as_str());
+                self.record(TransitionEvent::new(
+                    previous,
+                    s.status.clone(),
+                    cause,
+                    code,
+                    s.error_message.as_deref(),
+                ));
+            }
+        }
+        self.publish().await;
+    }
+
+    /// Mark model `model` as loading (e.g. for a reload). The agent only
+    /// goes LoadingModel if no other model is serving.
+    pub async fn set_model_loading(&self, model: usize, cause: &str) {
+        self.update_model(model, cause, |m| {
+            m.status = AgentPresenceStatus::LoadingModel;
+            m.active_requests = 0;
+            m.utilization = None;
+            m.error_code = None;
+            m.error_message = None;
+        })
+        .await;
+    }
+
+    /// Mark model `model` as serving again after a restart, reload or
+    /// re-registration. A Degraded or Error agent returns to Ready.
+    pub async fn model_recovered(&self, model: usize, cause: &str) {
+        self.update_model(model, cause, |m| {
+            m.status = status_for_active_requests(&AgentPresenceStatus::Ready, m.active_requests);
+            m.error_code = None;
+            m.error_message = None;
+        })
+        .await;
+    }
+
+    /// Report model `model` degraded, then publish.
+    ///
+    /// Like `report_error()`, this bypasses state validation. Zeros the model's
+    /// `active_requests` because degraded means "I'm impaired, stop routing to
+    /// me" — any in-flight work is assumed lost. The agent is only Degraded
+    /// once no model is left serving.
+    pub async fn report_model_degraded(&self, model: usize, code: AgentErrorCode, msg: &str) {
+        self.update_model(model, "report_degraded", |m| {
+            m.status = AgentPresenceStatus::Degraded;
+            m.active_requests = 0;
+            m.utilization = None;
+            m.error_code = Some(code);
+            m.error_message = Some(msg.to_string());
+        })
+        .await;
+    }
+
+    /// Report model `model` failed. The agent is only in Error once every
+    /// model is.
+    pub async fn report_model_error(&self, model: usize, code: AgentErrorCode, msg: &str) {
+        self.update_model(model, "report_error", |m| {
+            m.status = AgentPresenceStatus::Error;
+            m.error_code = Some(code);
+            m.error_message = Some(msg.to_string());
+        })
+        .await;
+    }
+
     /// Report an error status with code and message, then publish.
     ///
     /// Unlike `transition()`, this bypasses state validation — errors can occur
     /// from any state. Preserves `active_requests` because the error may have
     /// occurred mid-request; dropping the count would lose track of in-flight work.
-    pub async fn report_error(&self, code: &str, msg: &str) {
+    pub async fn report_error(&self, code: AgentErrorCode, msg: &str) {
         {
             let mut s = self.state.lock().await;
+            self.record(TransitionEvent::new(
+                s.status.clone(),
+                AgentPresenceStatus::Error,
+                "report_error",
+                Some(code.as_str()),
+                Some(msg),
+            ));
+            for m in &mut s.models {
+                m.status = AgentPresenceStatus::Error;
+                m.error_code = Some(code);
+                m.error_message = Some(msg.to_string());
+            }
             s.status = AgentPresenceStatus::Error;
-            s.error_code = Some(code.to_string());
+            s.error_code = Some(code);
             s.error_message = Some(msg.to_string());
         }
         self.publish().await;
     }
 
-    /// Report a degraded status with code and message, then publish.
-    ///
-    /// Unlike `transition()`, this bypasses state validation — degraded can be
-    /// entered from any state. Zeros `active_requests` because degraded means
-    /// "I'm impaired, stop routing to me" — any in-flight work is assumed lost.
-    pub async fn report_degraded(&self, code: &str, msg: &str) {
-        {
-            let mut s = self.state.lock().await;
-            s.status = AgentPresenceStatus::Degraded;
-            s.active_requests = 0;
-            s.error_code = Some(code.to_string());
-            s.error_message = Some(msg.to_string());
+    /// Store the latest utilization summary of model `model`; the agent
+    /// reports the combination of all models with the next publish.
+    pub async fn set_model_utilization(
+        &self,
+        model: usize,
+        utilization: Option<UtilizationSummary>,
+    ) {
+        let mut s = self.state.lock().await;
+        if let Some(m) = s.models.get_mut(model) {
+            m.utilization = utilization;
         }
+        s.utilization =
+            UtilizationSummary::combine(s.models.iter().filter_map(|m| m.utilization.as_ref()));
+    }
+
+    /// Record the result of the reachability check, then publish.
+    pub async fn set_reachability(&self, report: ReachabilityReport) {
+        self.state.lock().await.reachability = Some(report);
         self.publish().await;
     }
 
-    /// Update the active request count and toggle Ready/Serving, then publish.
-    pub async fn update_active_requests(&self, n: u32) {
+    /// Update the active request count of model `model` and toggle it and the
+    /// agent, whose count is the sum over all models, between Ready and
+    /// Serving; then publish.
+    ///
+    /// While Draining only the count changes, so routing stays disabled until
+    /// the agent goes Unavailable. A model that is not Ready or Serving (e.g.
+    /// Degraded, or still loading) keeps its status.
+    pub async fn update_model_active_requests(&self, model: usize, n: u32) {
         let mut s = self.state.lock().await;
-        s.active_requests = n;
-        if n > 0 {
-            s.status = AgentPresenceStatus::Serving;
-        } else if matches!(
-            s.status,
-            AgentPresenceStatus::Ready
-                | AgentPresenceStatus::Serving
-                | AgentPresenceStatus::Idle
-                | AgentPresenceStatus::LoadingModel
-        ) {
-            s.status = AgentPresenceStatus::Ready;
+        if let Some(m) = s.models.get_mut(model) {
+            m.active_requests = n;
+            let previous = m.status.clone();
+            if matches!(
+                previous,
+                AgentPresenceStatus::Ready | AgentPresenceStatus::Serving
+            ) {
+                m.status = if n > 0 {
+                    AgentPresenceStatus::Serving
+                } else {
+                    AgentPresenceStatus::Ready
+                };
+            }
+            if m.status != previous {
+                self.record(
+                    TransitionEvent::new(previous, m.status.clone(), "active_requests", None, None)
+                        .for_model(&m.model),
+                );
+            }
         }
+        let total = s.models.iter().map(|m| m.active_requests).sum();
+        self.set_active_requests(&mut s, total);
         // Drop lock before publish — publish will re-lock to snapshot.
         drop(s);
         self.publish().await;
     }
 
+    fn set_active_requests(&self, s: &mut AgentPresenceState, n: u32) {
+        s.active_requests = n;
+        crate::telemetry::metrics().active_requests.set(n as i64);
+        let previous = s.status.clone();
+        s.status = status_for_active_requests(&s.status, n);
+        if s.status != previous {
+            self.record(TransitionEvent::new(
+                previous,
+                s.status.clone(),
+                "active_requests",
+                None,
+                None,
+            ));
+        }
+    }
+
     /// Publish the current state snapshot to the platform.
-    pub async fn publish(&self) {
-        let current_token = self.token.lock().await.clone();
-        let snapshot = self.state.lock().await.clone();
-        if let Err(e) = send_presence_once(
-            &self.client,
-            &self.config,
-            &current_token,
-            &self.identity,
-            &snapshot,
+    ///
+    /// If the platform cannot be reached, transitions since the last
+    /// successful publish go to the connection's outbox for later replay.
+    #[tracing::instrument(
+        name = "publish_presence",
+        skip_all,
+        fields(
+            agent_uid = %self.identity.agent_uid,
+            provider_id = tracing::field::Empty,
+            status = tracing::field::Empty
         )
-        .await
-        {
+    )]
+    pub async fn publish(&self) {
+        let (snapshot, pending) = {
+            let state = self.state.lock().await;
+            let pending = std::mem::take(
+                &mut *self
+                    .undelivered
+                    .lock()
+                    .expect("undelivered transitions lock poisoned"),
+            );
+            (state.clone(), pending)
+        };
+        let span = tracing::Span::current();
+        span.record("status", snapshot.status.as_str());
+        if let Some(provider_id) = self.connection.provider_id(0).await {
+            span.record("provider_id", provider_id.as_str());
+        }
+        let models = self.effective_models(&snapshot).await;
+        let payload = make_payload(&self.identity, &self.hardware, &snapshot, models);
+        let started = std::time::Instant::now();
+        let result = self.connection.send_presence(&payload).await;
+        crate::telemetry::metrics().observe_platform_request(
+            "presence",
+            result.is_ok(),
+            started.elapsed(),
+        );
+        if let Err(e) = result {
             tracing::warn!("Presence update failed: {}", e);
+            if self.connection.is_connected() {
+                // Not an outage: keep them for the next publish, ahead of
+                // anything recorded since.
+                let mut undelivered = self
+                    .undelivered
+                    .lock()
+                    .expect("undelivered transitions lock poisoned");
+                let newer = std::mem::replace(&mut *undelivered, pending);
+                undelivered.extend(newer);
+            } else {
+                self.connection.buffer_events(
+                    pending
+                        .into_iter()
+                        .filter_map(|t| {
+                            let at = t.timestamp;
+                            serde_json::to_value(t).ok().map(|v| {
+                                OutboxEvent::new(OutboxEventKind::PresenceTransition, at, v)
+                            })
+                        })
+                        .collect(),
+                );
+            }
+        }
+    }
+
+    /// Send a provider liveness heartbeat (no-op until registered).
+    async fn heartbeat(&self) {
+        if self.connection.provider_ids().await.is_empty() {
+            return;
+        }
+        let started = std::time::Instant::now();
+        let result = self.connection.send_heartbeat().await;
+        crate::telemetry::metrics().observe_platform_request(
+            "heartbeat",
+            result.is_ok(),
+            started.elapsed(),
+        );
+        match result {
+            Ok(()) => tracing::trace!("Heartbeat sent"),
+            Err(e) => tracing::warn!("Heartbeat error: {}", e),
         }
     }
 
-    /// Spawn the periodic presence heartbeat loop (every 15s).
+    /// Spawn the single loop that keeps the platform informed.
     ///
-    /// This sends the full agent state (status, model, active requests, errors)
-    /// to `/v1/agents/presence`. It is distinct from the provider heartbeat in
-    /// `spawn_heartbeat_loop` (main.rs), which is an empty-body liveness ping
-    /// to `/v1/providers/heartbeat` every 30s at the provider/instance level.
+    /// Publishes the full agent state (status, model, active requests, errors)
+    /// every 15s and a provider heartbeat every 30s, both through the
+    /// `PlatformConnection` so they share the socket when one is open. State is
+    /// also republished immediately whenever the socket (re)connects.
     pub fn spawn_loop(&self, shutdown: CancellationToken) -> tokio::task::JoinHandle<()> {
         let handle = self.clone();
         tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Duration::from_secs(15));
+            let mut presence_interval = tokio::time::interval(PRESENCE_INTERVAL);
+            let mut heartbeat_interval = tokio::time::interval(HEARTBEAT_INTERVAL);
             loop {
                 tokio::select! {
                     _ = shutdown.cancelled() => break,
-                    _ = interval.tick() => {}
+                    _ = presence_interval.tick() => handle.publish().await,
+                    _ = heartbeat_interval.tick() => handle.heartbeat().await,
+                    _ = handle.connection.socket_connected() => handle.publish().await,
                 }
-                handle.publish().await;
             }
         })
     }
@@ -218,52 +669,253 @@ struct PresencePayload {
     platform: String,
     arch: String,
     agent_version: String,
+    agent_public_key: String,
     status: AgentPresenceStatus,
     current_model: Option<String>,
     loading_progress_pct: Option<u8>,
     active_requests: u32,
-    error_code: Option<String>,
+    error_code: Option<AgentErrorCode>,
     error_message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_info: Option<ErrorInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    utilization: Option<UtilizationSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reachability: Option<ReachabilityReport>,
+    hardware: HardwareInventory,
+    models: Vec<ModelPresence>,
 }
 
-fn make_payload(agent: &AgentIdentity, state: &AgentPresenceState) -> PresencePayload {
+fn make_payload(
+    agent: &AgentIdentity,
+    hardware: &HardwareInventory,
+    state: &AgentPresenceState,
+    models: Vec<ModelPresence>,
+) -> PresencePayload {
     PresencePayload {
         agent_uid: agent.agent_uid.clone(),
         device_name: agent.device_name.clone(),
         platform: agent.platform.clone(),
         arch: agent.arch.clone(),
         agent_version: agent.agent_version.clone(),
+        agent_public_key: agent.public_key.clone(),
         status: state.status.clone(),
         current_model: state.current_model.clone(),
         loading_progress_pct: state.loading_progress_pct,
         active_requests: state.active_requests,
-        error_code: state.error_code.clone(),
+        error_code: state.error_code,
         error_message: state.error_message.clone(),
+        error_info: state.error_code.map(|c| c.info()),
+        utilization: state.utilization.clone(),
+        reachability: state.reachability.clone(),
+        hardware: hardware.clone(),
+        models,
     }
 }
 
-async fn send_presence_once(
-    client: &reqwest::Client,
-    config: &Config,
-    access_token: &str,
-    agent: &AgentIdentity,
-    state: &AgentPresenceState,
-) -> Result<()> {
-    let url = format!("{}/v1/agents/presence", config.platform_url);
-    let payload = make_payload(agent, state);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support;
+
+    fn handle() -> PresenceHandle {
+        handle_serving(&["m"])
+    }
+
+    fn handle_serving(models: &[&str]) -> PresenceHandle {
+        // Nothing listens on port 1, so publishes fail fast and are only logged.
+        let (connection, _rx) = PlatformConnection::new(
+            reqwest::Client::new(),
+            test_support::config("http://127.0.0.1:1"),
+            Arc::new(tokio::sync::Mutex::new("test-key".to_string())),
+            "test-agent".to_string(),
+            None,
+        );
+        PresenceHandle::new(
+            models.iter().map(|m| m.to_string()).collect(),
+            connection,
+            test_support::identity(),
+            HardwareInventory::default(),
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_transitions_are_kept_when_publish_is_rejected() {
+        use axum::http::StatusCode;
+        use axum::routing::post;
+        use AgentPresenceStatus::*;
+
+        // The platform answers, so the link stays up, but refuses the update.
+        let app = axum::Router::new().route(
+            "/v1/agents/presence",
+            post(|| async { StatusCode::BAD_REQUEST }),
+        );
+        let url = test_support::serve(app).await;
+        let (connection, _rx) = PlatformConnection::new(
+            reqwest::Client::new(),
+            test_support::config(&url),
+            Arc::new(tokio::sync::Mutex::new("test-key".to_string())),
+            "test-agent".to_string(),
+            None,
+        );
+        let presence = PresenceHandle::new(
+            vec!["m".to_string()],
+            connection.clone(),
+            test_support::identity(),
+            HardwareInventory::default(),
+            None,
+        );
+
+        presence.transition(LoadingModel, "startup").await.unwrap();
+        presence.transition(Ready, "model_loaded").await.unwrap();
+        assert!(connection.is_connected());
+        let kept: Vec<_> = presence
+            .undelivered
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| e.to.clone())
+            .collect();
+        assert_eq!(kept, vec![LoadingModel, Ready]);
+    }
 
-    let res = client
-        .post(url)
-        .header("Authorization", format!("Bearer {}", access_token))
-        .json(&payload)
-        .send()
-        .await?;
+    #[test]
+    fn test_draining_transitions() {
+        use AgentPresenceStatus::*;
+        assert!(Ready.can_transition_to(&Draining));
+        assert!(Serving.can_transition_to(&Draining));
+        assert!(Degraded.can_transition_to(&Draining));
+        assert!(Draining.can_transition_to(&Unavailable));
+        assert!(!Draining.can_transition_to(&Ready));
+        assert!(!Draining.can_transition_to(&Serving));
+        assert!(!Idle.can_transition_to(&Draining));
+    }
+
+    #[tokio::test]
+    async fn test_active_requests_do_not_leave_draining() {
+        let presence = handle();
+        presence
+            .transition(AgentPresenceStatus::LoadingModel, "startup")
+            .await
+            .unwrap();
+        presence.update_model_active_requests(0, 2).await;
+        assert_eq!(presence.status().await, AgentPresenceStatus::Serving);
+
+        presence
+            .transition(AgentPresenceStatus::Draining, "drain")
+            .await
+            .unwrap();
+        presence.update_model_active_requests(0, 1).await;
+        assert_eq!(presence.status().await, AgentPresenceStatus::Draining);
+        presence.update_model_active_requests(0, 0).await;
+        assert_eq!(presence.status().await, AgentPresenceStatus::Draining);
+        assert_eq!(presence.state.lock().await.active_requests, 0);
+    }
 
-    if !res.status().is_success() {
-        let status = res.status();
-        let body = res.text().await.unwrap_or_default();
-        bail!("Presence update failed ({}): {}", status, body);
+    #[tokio::test]
+    async fn test_transitions_are_recorded() {
+        use AgentPresenceStatus::*;
+
+        let presence = handle();
+        presence.transition(LoadingModel, "startup").await.unwrap();
+        presence.transition(Ready, "model_loaded").await.unwrap();
+        presence.update_model_active_requests(0, 0).await;
+        presence.update_model_active_requests(0, 1).await;
+        presence
+            .report_model_degraded(
+                0,
+                AgentErrorCode::LlamaStopped,
+                "llama-server process stopped unexpectedly",
+            )
+            .await;
+        // A request finishing on a degraded model does not make it Ready.
+        presence.update_model_active_requests(0, 0).await;
+        assert_eq!(presence.models().await[0].status, Degraded);
+        assert!(presence.transition(Serving, "bogus").await.is_err());
+
+        let history = presence.history(usize::MAX);
+        let steps: Vec<_> = history
+            .iter()
+            .map(|e| {
+                (
+                    e.from.clone(),
+                    e.to.clone(),
+                    e.cause.as_str(),
+                    e.model.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                (Idle, LoadingModel, "startup", None),
+                (LoadingModel, Ready, "model_loaded", None),
+                (Ready, Serving, "active_requests", Some("m")),
+                (Ready, Serving, "active_requests", None),
+                (Serving, Degraded, "report_degraded", None),
+            ]
+        );
+        assert_eq!(history[4].error_code.as_deref(), Some("llama_stopped"));
+        assert_eq!(presence.history(1), history[4..]);
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn test_models_aggregate_into_agent_status() {
+        use AgentPresenceStatus::*;
+
+        let presence = handle_serving(&["a", "b"]);
+        presence.transition(LoadingModel, "startup").await.unwrap();
+        presence.transition(Ready, "model_loaded").await.unwrap();
+
+        presence.update_model_active_requests(1, 2).await;
+        let state = presence.snapshot().await;
+        assert_eq!(state.status, Serving);
+        assert_eq!(state.active_requests, 2);
+        assert_eq!(state.models[0].status, Ready);
+        assert_eq!(state.models[1].status, Serving);
+
+        // One model going down leaves the agent serving with the other.
+        presence
+            .report_model_degraded(1, AgentErrorCode::LlamaStopped, "stopped")
+            .await;
+        let state = presence.snapshot().await;
+        assert_eq!(state.status, Ready);
+        assert_eq!(state.active_requests, 0);
+        assert_eq!(state.error_code, None);
+        assert_eq!(
+            state.models[1].error_code,
+            Some(AgentErrorCode::LlamaStopped)
+        );
+
+        // Only once none is left does the agent report the failure.
+        presence
+            .report_model_error(0, AgentErrorCode::LlamaRestartFailed, "failed")
+            .await;
+        let state = presence.snapshot().await;
+        assert_eq!(state.status, Degraded);
+        assert_eq!(state.error_code, Some(AgentErrorCode::LlamaStopped));
+
+        presence.model_recovered(1, "llama_restarted").await;
+        assert_eq!(presence.status().await, Ready);
+        let steps: Vec<_> = presence
+            .history(3)
+            .iter()
+            .map(|e| (e.from.clone(), e.to.clone(), e.cause.clone()))
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                (Serving, Ready, "report_degraded".to_string()),
+                (Ready, Degraded, "report_error".to_string()),
+                (Degraded, Ready, "llama_restarted".to_string()),
+            ]
+        );
+
+        presence.transition(Draining, "drain").await.unwrap();
+        let models = presence.models().await;
+        assert_eq!(models.len(), 2);
+        assert!(models.iter().all(|m| m.status == Draining));
+    }
 }
@@ -0,0 +1,884 @@
+use std::collections::HashMap;
+use std::future::IntoFuture;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap, HeaderName, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use bytes::Bytes;
+use ed25519_dalek::{Signature, VerifyingKey};
+use futures_util::future::BoxFuture;
+use futures_util::Stream;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::Config;
+use crate::connection::PlatformConnection;
+use crate::reachability::{self, EchoNonce};
+use crate::registration::RegisterRequest;
+use crate::tls::TlsListener;
+use crate::usage::{UsageCapture, UsageLedger, UsageRecord};
+
+/// Prefix of the token format this agent understands.
+const TOKEN_VERSION: &str = "v1";
+/// Allowed clock difference between the platform and this machine.
+const CLOCK_SKEW_SECS: u64 = 30;
+/// Tokens valid for longer than this are rejected, which also bounds how long
+/// token ids must be remembered for replay protection.
+const MAX_TOKEN_LIFETIME_SECS: u64 = 600;
+const KEY_REFRESH_INTERVAL: Duration = Duration::from_secs(3600);
+const KEY_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const BACKEND_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest silence from llama-server, e.g. while it processes a long prompt
+/// before streaming the first token.
+const BACKEND_READ_TIMEOUT: Duration = Duration::from_secs(300);
+/// How long `/health` waits on each backend.
+const BACKEND_HEALTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// llama-server endpoints buyers may reach. Everything else it serves
+/// (`/slots`, `/props`, `/metrics`, ...) exposes or changes server state.
+const INFERENCE_PATHS: [&str; 4] = [
+    "/v1/chat/completions",
+    "/v1/completions",
+    "/v1/embeddings",
+    "/v1/models",
+];
+
+/// Headers that describe a single connection and must not be forwarded.
+pub(crate) const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Claims carried by a platform-issued request token.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct TokenClaims {
+    /// Provider the request was routed to.
+    pub provider_id: String,
+    /// Expiry as unix seconds.
+    pub exp: u64,
+    /// Unique token id; each token is accepted once.
+    pub jti: String,
+}
+
+/// Why a request was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenError {
+    Missing,
+    Malformed,
+    BadSignature,
+    Expired,
+    /// Valid for longer than `MAX_TOKEN_LIFETIME_SECS`.
+    LifetimeTooLong,
+    WrongProvider,
+    Replayed,
+    /// No signing keys are loaded yet, so nothing can be verified.
+    NoKeys,
+    /// This agent is not registered (or is draining) and takes no requests.
+    NotServing,
+}
+
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let msg = match self {
+            TokenError::Missing => "missing bearer token",
+            TokenError::Malformed => "malformed token",
+            TokenError::BadSignature => "invalid token signature",
+            TokenError::Expired => "token expired",
+            TokenError::LifetimeTooLong => "token lifetime too long",
+            TokenError::WrongProvider => "token was issued for a different provider",
+            TokenError::Replayed => "token already used",
+            TokenError::NoKeys => "signing keys not loaded",
+            TokenError::NotServing => "provider is not accepting requests",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl TokenError {
+    fn status(&self) -> StatusCode {
+        match self {
+            TokenError::NoKeys | TokenError::NotServing => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+}
+
+/// Checks request tokens signed by the platform.
+///
+/// A token is `v1.<claims>.<signature>`, both parts base64url without padding;
+/// the Ed25519 signature covers `v1.<claims>`.
+pub struct TokenVerifier {
+    keys: RwLock<Vec<VerifyingKey>>,
+    /// Token ids already accepted, with their expiry.
+    seen: std::sync::Mutex<HashMap<String, u64>>,
+}
+
+impl TokenVerifier {
+    pub fn new(keys: Vec<VerifyingKey>) -> Self {
+        TokenVerifier {
+            keys: RwLock::new(keys),
+            seen: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_keys(&self, keys: Vec<VerifyingKey>) {
+        *self.keys.write().expect("signing keys lock poisoned") = keys;
+    }
+
+    pub fn has_keys(&self) -> bool {
+        !self
+            .keys
+            .read()
+            .expect("signing keys lock poisoned")
+            .is_empty()
+    }
+
+    /// Verify `token` for one of `provider_ids` at time `now` (unix seconds).
+    pub fn verify(
+        &self,
+        token: &str,
+        provider_ids: &[&str],
+        now: u64,
+    ) -> std::result::Result<TokenClaims, TokenError> {
+        let (signed, signature) = token.rsplit_once('.').ok_or(TokenError::Malformed)?;
+        let (version, claims) = signed.split_once('.').ok_or(TokenError::Malformed)?;
+        if version != TOKEN_VERSION {
+            return Err(TokenError::Malformed);
+        }
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or(TokenError::Malformed)?;
+
+        {
+            let keys = self.keys.read().expect("signing keys lock poisoned");
+            if keys.is_empty() {
+                return Err(TokenError::NoKeys);
+            }
+            if !keys
+                .iter()
+                .any(|key| key.verify_strict(signed.as_bytes(), &signature).is_ok())
+            {
+                return Err(TokenError::BadSignature);
+            }
+        }
+
+        let claims: TokenClaims = URL_SAFE_NO_PAD
+            .decode(claims)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or(TokenError::Malformed)?;
+        if claims.exp + CLOCK_SKEW_SECS < now {
+            return Err(TokenError::Expired);
+        }
+        if claims.exp > now + MAX_TOKEN_LIFETIME_SECS {
+            return Err(TokenError::LifetimeTooLong);
+        }
+        if !provider_ids.contains(&claims.provider_id.as_str()) {
+            return Err(TokenError::WrongProvider);
+        }
+
+        let mut seen = self.seen.lock().expect("seen tokens lock poisoned");
+        seen.retain(|_, exp| *exp + CLOCK_SKEW_SECS >= now);
+        if seen.insert(claims.jti.clone(), claims.exp).is_some() {
+            return Err(TokenError::Replayed);
+        }
+        Ok(claims)
+    }
+
+    /// Accept the token `jti` again, e.g. because the request it authorized
+    /// never reached the backend.
+    pub fn release(&self, jti: &str) {
+        self.seen
+            .lock()
+            .expect("seen tokens lock poisoned")
+            .remove(jti);
+    }
+}
+
+/// Decode a base64 Ed25519 public key.
+pub fn parse_signing_key(encoded: &str) -> Result<VerifyingKey> {
+    let bytes = STANDARD
+        .decode(encoded.trim())
+        .context("Signing key is not valid base64")?;
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Signing key must be 32 bytes"))?;
+    VerifyingKey::from_bytes(&bytes).context("Signing key is not a valid Ed25519 key")
+}
+
+#[derive(Deserialize)]
+struct SigningKeysResponse {
+    keys: Vec<String>,
+}
+
+/// Fetch the platform's current request-signing keys from
+/// `/v1/agents/signing-keys`.
+pub async fn fetch_signing_keys(
+    client: &reqwest::Client,
+    config: &Config,
+    token: &Arc<Mutex<String>>,
+) -> Result<Vec<VerifyingKey>> {
+    let url = format!("{}/v1/agents/signing-keys", config.platform_url);
+    let current_token = token.lock().await.clone();
+    let res = client
+        .get(&url)
+        .header("Authorization", format!("Bearer {}", current_token))
+        .send()
+        .await
+        .context("Signing key request failed")?;
+    if !res.status().is_success() {
+        anyhow::bail!("Signing key request failed: {}", res.status());
+    }
+    let body: SigningKeysResponse = res.json().await.context("Invalid signing key response")?;
+    body.keys.iter().map(|k| parse_signing_key(k)).collect()
+}
+
+/// Load signing keys from config, or from the platform if none are
+/// configured. Platform keys are refreshed hourly (every minute until the
+/// first successful fetch) so key rotation needs no restart.
+pub fn spawn_key_refresh(
+    verifier: Arc<TokenVerifier>,
+    client: reqwest::Client,
+    config: Config,
+    token: Arc<Mutex<String>>,
+    shutdown: CancellationToken,
+) -> Result<Option<tokio::task::JoinHandle<()>>> {
+    if !config.platform_signing_keys.is_empty() {
+        let keys = config
+            .platform_signing_keys
+            .iter()
+            .map(|k| parse_signing_key(k))
+            .collect::<Result<Vec<_>>>()
+            .context("Invalid VRAM_SUPPLY_PLATFORM_SIGNING_KEYS")?;
+        verifier.set_keys(keys);
+        return Ok(None);
+    }
+
+    Ok(Some(tokio::spawn(async move {
+        loop {
+            let wait = match fetch_signing_keys(&client, &config, &token).await {
+                Ok(keys) if !keys.is_empty() => {
+                    tracing::debug!("Loaded {} request signing keys", keys.len());
+                    verifier.set_keys(keys);
+                    KEY_REFRESH_INTERVAL
+                }
+                Ok(_) => {
+                    tracing::warn!("Platform returned no request signing keys");
+                    KEY_RETRY_INTERVAL
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to fetch request signing keys: {:#}", e);
+                    if verifier.has_keys() {
+                        KEY_REFRESH_INTERVAL
+                    } else {
+                        KEY_RETRY_INTERVAL
+                    }
+                }
+            };
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = tokio::time::sleep(wait) => {}
+            }
+        }
+    })))
+}
+
+/// Where the proxy sends requests for one served model.
+pub struct Route {
+    /// Port of the model's llama-server on 127.0.0.1.
+    pub backend_port: u16,
+    /// Model and prices currently advertised, stamped onto usage records.
+    pub offer: Arc<Mutex<RegisterRequest>>,
+}
+
+struct Backend {
+    url: String,
+    offer: Arc<Mutex<RegisterRequest>>,
+}
+
+struct ProxyState {
+    client: reqwest::Client,
+    /// One per served model, by index.
+    backends: Vec<Backend>,
+    verifier: Arc<TokenVerifier>,
+    connection: PlatformConnection,
+    ledger: Arc<UsageLedger>,
+}
+
+/// Bind the public port and serve the authenticating proxy until `shutdown`.
+///
+/// Only llama-server's inference endpoints ([`INFERENCE_PATHS`]) are served.
+/// Requests must carry `Authorization: Bearer <token>` with a valid platform
+/// token; they are then forwarded, with the token stripped, to the llama-server
+/// of the model in `routes` registered as the token's provider, and the
+/// response is streamed back. `GET /health` is answered without a token by
+/// the first healthy model's backend so load balancers can probe the node, as
+/// is the reachability echo at [`reachability::ECHO_PATH`]. Every authorized
+/// request is metered into `ledger`, and reported to the platform, once its
+/// response has finished streaming.
+#[allow(clippy::too_many_arguments)]
+pub async fn spawn_proxy(
+    addr: SocketAddr,
+    routes: Vec<Route>,
+    verifier: Arc<TokenVerifier>,
+    connection: PlatformConnection,
+    ledger: Arc<UsageLedger>,
+    echo: EchoNonce,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    shutdown: CancellationToken,
+) -> Result<tokio::task::JoinHandle<()>> {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind proxy on {}", addr))?;
+    let client = reqwest::Client::builder()
+        .connect_timeout(BACKEND_CONNECT_TIMEOUT)
+        .read_timeout(BACKEND_READ_TIMEOUT)
+        .build()
+        .context("Failed to build proxy client")?;
+    let state = Arc::new(ProxyState {
+        client,
+        backends: routes
+            .into_iter()
+            .map(|route| Backend {
+                url: format!("http://127.0.0.1:{}", route.backend_port),
+                offer: route.offer,
+            })
+            .collect(),
+        verifier,
+        connection,
+        ledger,
+    });
+    let router = Router::new()
+        .fallback(handle)
+        .with_state(state)
+        .merge(reachability::router(echo));
+
+    let shutdown = shutdown.cancelled_owned();
+    let server: BoxFuture<'static, std::io::Result<()>> = match tls {
+        Some(tls) => Box::pin(
+            axum::serve(TlsListener::new(listener, tls)?, router)
+                .with_graceful_shutdown(shutdown)
+                .into_future(),
+        ),
+        None => Box::pin(
+            axum::serve(listener, router)
+                .with_graceful_shutdown(shutdown)
+                .into_future(),
+        ),
+    };
+
+    Ok(tokio::spawn(async move {
+        if let Err(e) = server.await {
+            tracing::error!("Proxy server failed: {}", e);
+        }
+    }))
+}
+
+async fn handle(State(state): State<Arc<ProxyState>>, request: Request) -> Response {
+    let is_health = request.method() == Method::GET && request.uri().path() == "/health";
+    if !is_health && !INFERENCE_PATHS.contains(&request.uri().path()) {
+        return (
+            StatusCode::NOT_FOUND,
+            axum::Json(serde_json::json!({ "error": "not found" })),
+        )
+            .into_response();
+    }
+    if is_health {
+        return health(&state).await;
+    }
+    let (claims, model) = match authorize(&state, request.headers()).await {
+        Ok(authorized) => authorized,
+        Err(e) => {
+            tracing::debug!("Rejected proxied request: {}", e);
+            return (
+                e.status(),
+                axum::Json(serde_json::json!({ "error": e.to_string() })),
+            )
+                .into_response();
+        }
+    };
+    let jti = claims.jti.clone();
+    let client_request_id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let mut pending = {
+        let offer = state.backends[model].offer.lock().await;
+        PendingUsage {
+            record: UsageRecord {
+                request_id: claims.jti,
+                client_request_id,
+                model: offer.model.clone(),
+                started_at: crate::outbox::unix_now(),
+                latency_ms: 0,
+                status: 0,
+                prompt_tokens: 0,
+                completion_tokens: 0,
+                input_price_per_million: offer.input_price_per_million,
+                output_price_per_million: offer.output_price_per_million,
+            },
+            started: Instant::now(),
+            ledger: Arc::clone(&state.ledger),
+            connection: state.connection.clone(),
+        }
+    };
+
+    match forward(&state, &state.backends[model].url, request).await {
+        Ok(backend_response) => {
+            let mut response = Response::builder().status(backend_response.status());
+            if let Some(headers) = response.headers_mut() {
+                *headers = strip_hop_by_hop(backend_response.headers());
+            }
+            pending.record.status = backend_response.status().as_u16();
+            let event_stream = backend_response
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v.starts_with("text/event-stream"));
+            let body = Body::from_stream(MeteredStream {
+                inner: Box::pin(backend_response.bytes_stream()),
+                capture: Some(UsageCapture::new(event_stream)),
+                pending: Some(pending),
+            });
+            response.body(body).unwrap_or_else(|e| {
+                tracing::warn!("Failed to build proxied response: {}", e);
+                StatusCode::BAD_GATEWAY.into_response()
+            })
+        }
+        Err(e) => {
+            tracing::warn!("Backend request failed: {:#}", e);
+            // Nothing was served, so the client may retry with the same token.
+            state.verifier.release(&jti);
+            (
+                StatusCode::BAD_GATEWAY,
+                axum::Json(serde_json::json!({ "error": "backend unavailable" })),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Answer `/health` with the first healthy backend's reply, or the last
+/// backend's when none is, so one model being down does not take the node
+/// out of a load balancer.
+async fn health(state: &ProxyState) -> Response {
+    let mut last = None;
+    for backend in &state.backends {
+        let res = state
+            .client
+            .get(format!("{}/health", backend.url))
+            .timeout(BACKEND_HEALTH_TIMEOUT)
+            .send()
+            .await;
+        match res {
+            Ok(res) if res.status().is_success() => return passthrough(res),
+            Ok(res) => last = Some(res),
+            Err(e) => tracing::debug!("Backend health check failed: {}", e),
+        }
+    }
+    match last {
+        Some(res) => passthrough(res),
+        None => (
+            StatusCode::BAD_GATEWAY,
+            axum::Json(serde_json::json!({ "error": "backend unavailable" })),
+        )
+            .into_response(),
+    }
+}
+
+/// A backend response, passed through unmetered.
+fn passthrough(backend_response: reqwest::Response) -> Response {
+    let mut response = Response::builder().status(backend_response.status());
+    if let Some(headers) = response.headers_mut() {
+        *headers = strip_hop_by_hop(backend_response.headers());
+    }
+    response
+        .body(Body::from_stream(backend_response.bytes_stream()))
+        .unwrap_or_else(|e| {
+            tracing::warn!("Failed to build proxied response: {}", e);
+            StatusCode::BAD_GATEWAY.into_response()
+        })
+}
+
+/// Check the request's token and return its claims with the index of the
+/// served model registered as the token's provider.
+async fn authorize(
+    state: &ProxyState,
+    headers: &HeaderMap,
+) -> std::result::Result<(TokenClaims, usize), TokenError> {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or(TokenError::Missing)?;
+    let provider_ids = state.connection.provider_ids().await;
+    if provider_ids.is_empty() {
+        return Err(TokenError::NotServing);
+    }
+    let ids: Vec<&str> = provider_ids.values().map(String::as_str).collect();
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let claims = state.verifier.verify(token.trim(), &ids, now)?;
+    let model = provider_ids
+        .iter()
+        .find(|(_, id)| **id == claims.provider_id)
+        .map(|(model, _)| *model)
+        .filter(|model| *model < state.backends.len())
+        .ok_or(TokenError::WrongProvider)?;
+    Ok((claims, model))
+}
+
+async fn forward(
+    state: &ProxyState,
+    backend_url: &str,
+    request: Request,
+) -> Result<reqwest::Response> {
+    let (parts, body) = request.into_parts();
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/");
+    let url = format!("{}{}", backend_url, path);
+
+    let mut headers = strip_hop_by_hop(&parts.headers);
+    headers.remove(header::AUTHORIZATION);
+    headers.remove(header::HOST);
+
+    Ok(state
+        .client
+        .request(parts.method, url)
+        .headers(headers)
+        .body(reqwest::Body::wrap_stream(body.into_data_stream()))
+        .send()
+        .await?)
+}
+
+/// A usage record waiting for its response to finish.
+struct PendingUsage {
+    record: UsageRecord,
+    started: Instant,
+    ledger: Arc<UsageLedger>,
+    connection: PlatformConnection,
+}
+
+type BackendStream = Pin<Box<dyn Stream<Item = reqwest::Result<Bytes>> + Send>>;
+
+/// Passes a backend response through while counting its tokens. The record
+/// is written when the stream ends, or when it is dropped because the client
+/// went away.
+struct MeteredStream {
+    inner: BackendStream,
+    capture: Option<UsageCapture>,
+    pending: Option<PendingUsage>,
+}
+
+impl MeteredStream {
+    fn finish(&mut self) {
+        let (Some(mut pending), Some(capture)) = (self.pending.take(), self.capture.take()) else {
+            return;
+        };
+        if let Some((prompt, completion)) = capture.finish() {
+            pending.record.prompt_tokens = prompt;
+            pending.record.completion_tokens = completion;
+        }
+        pending.record.latency_ms = pending.started.elapsed().as_millis() as u64;
+        pending.ledger.record(&pending.record);
+        pending.connection.report_usage(&pending.record);
+    }
+}
+
+impl Stream for MeteredStream {
+    type Item = reqwest::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        let poll = self.inner.as_mut().poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if let Some(capture) = self.capture.as_mut() {
+                    capture.feed(chunk);
+                }
+            }
+            Poll::Ready(None) => self.finish(),
+            _ => {}
+        }
+        poll
+    }
+}
+
+impl Drop for MeteredStream {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+fn strip_hop_by_hop(headers: &HeaderMap) -> HeaderMap {
+    let mut out = headers.clone();
+    // Headers named in `Connection` are hop-by-hop too.
+    let named: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::try_from(name.trim()).ok())
+        .collect();
+    for name in HOP_BY_HOP.iter().copied() {
+        out.remove(name);
+    }
+    for name in named {
+        out.remove(name);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::routing::{get, post};
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use crate::test_support;
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn token(key: &SigningKey, provider_id: &str, exp: u64, jti: &str) -> String {
+        let claims = serde_json::json!({ "provider_id": provider_id, "exp": exp, "jti": jti });
+        let signed = format!(
+            "{}.{}",
+            TOKEN_VERSION,
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature = key.sign(signed.as_bytes());
+        format!(
+            "{}.{}",
+            signed,
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        )
+    }
+
+    #[test]
+    fn test_verify_token() {
+        let key = signing_key();
+        let verifier = TokenVerifier::new(vec![key.verifying_key()]);
+        let now = 1_000_000;
+
+        let claims = verifier
+            .verify(&token(&key, "prov-1", now + 60, "a"), &["prov-1"], now)
+            .unwrap();
+        assert_eq!(claims.jti, "a");
+
+        let cases = [
+            (token(&key, "prov-1", now + 60, "a"), TokenError::Replayed),
+            (
+                token(&key, "prov-2", now + 60, "b"),
+                TokenError::WrongProvider,
+            ),
+            (token(&key, "prov-1", now - 120, "c"), TokenError::Expired),
+            (
+                token(&key, "prov-1", now + 7200, "d"),
+                TokenError::LifetimeTooLong,
+            ),
+            (
+                token(&SigningKey::from_bytes(&[9; 32]), "prov-1", now + 60, "e"),
+                TokenError::BadSignature,
+            ),
+            ("v1.garbage".to_string(), TokenError::Malformed),
+            ("v2.a.b".to_string(), TokenError::Malformed),
+        ];
+        for (token, expected) in cases {
+            assert_eq!(verifier.verify(&token, &["prov-1"], now), Err(expected));
+        }
+
+        verifier.release("a");
+        assert!(verifier
+            .verify(&token(&key, "prov-1", now + 60, "a"), &["prov-1"], now)
+            .is_ok());
+
+        let empty = TokenVerifier::new(Vec::new());
+        assert_eq!(
+            empty.verify(&token(&key, "prov-1", now + 60, "f"), &["prov-1"], now),
+            Err(TokenError::NoKeys)
+        );
+    }
+
+    #[test]
+    fn test_parse_signing_key() {
+        let encoded = STANDARD.encode(signing_key().verifying_key().to_bytes());
+        assert_eq!(
+            parse_signing_key(&encoded).unwrap(),
+            signing_key().verifying_key()
+        );
+        assert!(parse_signing_key("c2hvcnQ=").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_forwards_only_authorized_requests() {
+        let backend = Router::new().route(
+            "/v1/chat/completions",
+            post(|headers: HeaderMap, body: String| async move {
+                assert!(headers.get(header::AUTHORIZATION).is_none());
+                let chunks = [
+                    format!("data: {{\"echo\":\"{}\"}}\n\n", body),
+                    "data: {\"usage\":{\"prompt_tokens\":4,\"completion_tokens\":2}}\n\n"
+                        .to_string(),
+                ]
+                .map(Ok::<_, std::convert::Infallible>);
+                (
+                    [(header::CONTENT_TYPE, "text/event-stream")],
+                    Body::from_stream(futures_util::stream::iter(chunks)),
+                )
+            }),
+        );
+        let backend_url = test_support::serve(backend).await;
+        let backend_port: u16 = backend_url.rsplit(':').next().unwrap().parse().unwrap();
+        // A second served model, registered as its own provider. Only it
+        // answers /health, which is enough for the node to be healthy.
+        let other = Router::new()
+            .route("/health", get(|| async { "ok" }))
+            .route(
+                "/v1/chat/completions",
+                post(|| async { axum::Json(serde_json::json!({ "backend": "other" })) }),
+            );
+        let other_url = test_support::serve(other).await;
+        let other_port: u16 = other_url.rsplit(':').next().unwrap().parse().unwrap();
+
+        let (connection, _rx) = PlatformConnection::new(
+            reqwest::Client::new(),
+            test_support::config("http://127.0.0.1:1"),
+            Arc::new(Mutex::new("test-key".to_string())),
+            "test-agent".to_string(),
+            None,
+        );
+        connection
+            .set_provider_id(0, Some("prov-1".to_string()))
+            .await;
+        connection
+            .set_provider_id(1, Some("prov-2".to_string()))
+            .await;
+        let key = signing_key();
+        let verifier = Arc::new(TokenVerifier::new(vec![key.verifying_key()]));
+        let shutdown = CancellationToken::new();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let dir = tempfile::tempdir().unwrap();
+        let ledger_path = dir.path().join("usage.jsonl");
+        let ledger = Arc::new(UsageLedger::new(Some(ledger_path.clone())));
+        let offer = |model: &str| Arc::new(Mutex::new(test_support::offer(model)));
+        let routes = vec![
+            Route {
+                backend_port,
+                offer: offer("test-model"),
+            },
+            Route {
+                backend_port: other_port,
+                offer: offer("other-model"),
+            },
+        ];
+        let handle = spawn_proxy(
+            addr,
+            routes,
+            verifier,
+            connection,
+            Arc::clone(&ledger),
+            EchoNonce::default(),
+            None,
+            shutdown.clone(),
+        )
+        .await
+        .unwrap();
+
+        let client = reqwest::Client::new();
+        let url = format!("http://{}", addr);
+
+        let health = client.get(format!("{}/health", url)).send().await.unwrap();
+        assert_eq!(health.text().await.unwrap(), "ok");
+
+        let unauthorized = client
+            .post(format!("{}/v1/chat/completions", url))
+            .body("!")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let authorized = client
+            .post(format!("{}/v1/chat/completions", url))
+            .bearer_auth(token(&key, "prov-1", now + 60, "req-1"))
+            .body("hi")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(authorized.status(), StatusCode::OK);
+        let text = authorized.text().await.unwrap();
+        assert!(text.starts_with("data: {\"echo\":\"hi\"}\n\ndata: {\"usage\""));
+
+        let routed = client
+            .post(format!("{}/v1/chat/completions", url))
+            .bearer_auth(token(&key, "prov-2", now + 60, "req-2"))
+            .header("x-request-id", "client-2")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(routed.text().await.unwrap(), r#"{"backend":"other"}"#);
+
+        // Only inference endpoints are reachable, token or not.
+        for path in ["/slots", "/slots/0?action=erase", "/props", "/metrics"] {
+            let hidden = client
+                .post(format!("{}{}", url, path))
+                .bearer_auth(token(&key, "prov-1", now + 60, path))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(hidden.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+
+        shutdown.cancel();
+        handle.await.unwrap();
+        ledger.flush();
+
+        // Only the authorized request is metered, keyed by the token id.
+        let records = crate::usage::read_ledger(&ledger_path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].request_id, "req-1");
+        assert_eq!(records[0].client_request_id, None);
+        assert_eq!(records[1].request_id, "req-2");
+        assert_eq!(records[1].client_request_id.as_deref(), Some("client-2"));
+        assert_eq!(records[0].model, "test-model");
+        assert_eq!(records[1].model, "other-model");
+        assert_eq!(records[0].status, 200);
+        assert_eq!(
+            (records[0].prompt_tokens, records[0].completion_tokens),
+            (4, 2)
+        );
+    }
+}
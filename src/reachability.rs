@@ -0,0 +1,345 @@
+//! Check that `public_url` is an address the platform can actually reach.
+//!
+//! Before registering, the agent resolves the URL and refuses loopback and
+//! private addresses unless `VRAM_SUPPLY_ALLOW_PRIVATE_URL` is set. Once the
+//! proxy is listening, it asks the platform to fetch a fresh nonce from
+//! `{public_url}/.well-known/vramsply/reachability`; only an exact echo proves
+//! the URL leads back to this agent. The result is published in presence.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Router;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+/// Path on the public port that answers with the current nonce.
+pub const ECHO_PATH: &str = "/.well-known/vramsply/reachability";
+/// The platform fetches our URL before answering, so allow for a slow path.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Outcome of asking the platform to reach `public_url`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReachabilityStatus {
+    /// The platform fetched our nonce through `public_url`.
+    Reachable,
+    /// The platform could not fetch it, or got something else back.
+    Unreachable,
+    /// The platform could not be asked; nothing is known either way.
+    Unverified,
+}
+
+/// The result of the startup reachability check, as published in presence.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ReachabilityReport {
+    pub url: String,
+    pub status: ReachabilityStatus,
+    /// `public_url` resolves to a loopback or private address.
+    pub private: bool,
+    pub checked_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The nonce served at [`ECHO_PATH`]. Clones share the same value.
+#[derive(Debug, Clone, Default)]
+pub struct EchoNonce(Arc<Mutex<Option<String>>>);
+
+impl EchoNonce {
+    /// Replace the served nonce with a fresh one and return it.
+    pub fn rotate(&self) -> String {
+        let nonce = uuid::Uuid::new_v4().to_string();
+        *self.0.lock().expect("echo nonce lock poisoned") = Some(nonce.clone());
+        nonce
+    }
+
+    fn get(&self) -> Option<String> {
+        self.0.lock().expect("echo nonce lock poisoned").clone()
+    }
+}
+
+/// Routes answering [`ECHO_PATH`] without authentication, for merging into
+/// the proxy.
+pub fn router(nonce: EchoNonce) -> Router {
+    Router::new().route(
+        ECHO_PATH,
+        axum::routing::get(move || async move {
+            match nonce.get() {
+                Some(nonce) => nonce.into_response(),
+                None => StatusCode::NOT_FOUND.into_response(),
+            }
+        }),
+    )
+}
+
+/// Whether `ip` is only reachable from this machine or a private network.
+pub fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_private_v4(v4),
+            None => is_private_v6(ip),
+        },
+    }
+}
+
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        // Carrier-grade NAT (100.64.0.0/10).
+        || (a == 100 && (64..128).contains(&b))
+}
+
+fn is_private_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        // Unique local (fc00::/7) and link-local (fe80::/10).
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+}
+
+/// Resolve the host of `public_url`. Returns whether any of its addresses is
+/// private; fails if it does not resolve, or is private and that is not
+/// allowed by `allow_private_url`.
+pub async fn check_address(config: &Config) -> Result<bool> {
+    let url = reqwest::Url::parse(&config.public_url)
+        .with_context(|| format!("'{}' is not a valid URL", config.public_url))?;
+    let host = url
+        .host_str()
+        .with_context(|| format!("'{}' has no host", config.public_url))?;
+    let port = url.port_or_known_default().unwrap_or(config.port);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<IpAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .with_context(|| format!("Cannot resolve {}", host))?
+        .map(|addr| addr.ip())
+        .collect();
+    if addrs.is_empty() {
+        bail!("{} resolves to no addresses", host);
+    }
+    let private = addrs.iter().any(|ip| is_private(*ip));
+    if private && !config.allow_private_url {
+        let list: Vec<String> = addrs.iter().map(IpAddr::to_string).collect();
+        bail!(
+            "{} resolves to {}, which the platform cannot reach; \
+             set VRAM_SUPPLY_PUBLIC_URL to a public address, or \
+             VRAM_SUPPLY_ALLOW_PRIVATE_URL=true if the platform shares this network",
+            host,
+            list.join(", ")
+        );
+    }
+    Ok(private)
+}
+
+#[derive(Debug, Serialize)]
+struct CheckRequest<'a> {
+    agent_uid: &'a str,
+    url: String,
+    nonce: &'a str,
+}
+
+/// What the platform got back when it fetched `url`.
+#[derive(Debug, Deserialize)]
+struct CheckResponse {
+    #[serde(default)]
+    status_code: Option<u16>,
+    #[serde(default)]
+    body: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// Ask the platform to fetch a fresh nonce through `url` (the registered
+/// endpoint) via `POST /v1/agents/reachability-checks`. The proxy must
+/// already be serving [`router`] with `nonce`.
+pub async fn verify(
+    client: &reqwest::Client,
+    config: &Config,
+    token: &Arc<tokio::sync::Mutex<String>>,
+    agent_uid: &str,
+    url: &str,
+    nonce: &EchoNonce,
+    private: bool,
+) -> ReachabilityReport {
+    let expected = nonce.rotate();
+    let (status, error) = match ask_platform(client, config, token, agent_uid, url, &expected).await
+    {
+        Ok(response) => match response.body {
+            Some(body) if body.trim() == expected => (ReachabilityStatus::Reachable, None),
+            _ => {
+                let error = match (response.error, response.status_code) {
+                    (Some(e), _) => e,
+                    (None, Some(code)) => format!("answered HTTP {}, but not with our nonce", code),
+                    (None, None) => "no response".to_string(),
+                };
+                (ReachabilityStatus::Unreachable, Some(error))
+            }
+        },
+        Err(e) => (ReachabilityStatus::Unverified, Some(format!("{:#}", e))),
+    };
+    ReachabilityReport {
+        url: url.to_string(),
+        status,
+        private,
+        checked_at: crate::outbox::unix_now(),
+        error,
+    }
+}
+
+async fn ask_platform(
+    client: &reqwest::Client,
+    config: &Config,
+    token: &Arc<tokio::sync::Mutex<String>>,
+    agent_uid: &str,
+    url: &str,
+    nonce: &str,
+) -> Result<CheckResponse> {
+    let body = CheckRequest {
+        agent_uid,
+        url: format!("{}{}", url.trim_end_matches('/'), ECHO_PATH),
+        nonce,
+    };
+    let current_token = token.lock().await.clone();
+    let res = client
+        .post(format!(
+            "{}/v1/agents/reachability-checks",
+            config.platform_url
+        ))
+        .header("Authorization", format!("Bearer {}", current_token))
+        .timeout(CHECK_TIMEOUT)
+        .json(&body)
+        .send()
+        .await
+        .context("Reachability check request failed")?;
+    if !res.status().is_success() {
+        bail!("Reachability check returned HTTP {}", res.status());
+    }
+    res.json()
+        .await
+        .context("Invalid reachability check response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::routing::post;
+    use axum::Json;
+
+    use crate::test_support;
+
+    #[test]
+    fn test_private_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.1.10",
+            "172.16.0.1",
+            "169.254.1.1",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:192.168.0.1",
+        ] {
+            assert!(is_private(ip.parse().unwrap()), "{} should be private", ip);
+        }
+        for ip in ["8.8.8.8", "100.128.0.1", "2606:4700::1111"] {
+            assert!(!is_private(ip.parse().unwrap()), "{} should be public", ip);
+        }
+    }
+
+    /// A platform that fetches the requested URL itself, as the real one does.
+    async fn platform() -> String {
+        let router = Router::new().route(
+            "/v1/agents/reachability-checks",
+            post(|Json(req): Json<serde_json::Value>| async move {
+                let url = req["url"].as_str().unwrap().to_string();
+                Json(match reqwest::get(&url).await {
+                    Ok(res) => serde_json::json!({
+                        "status_code": res.status().as_u16(),
+                        "body": res.text().await.unwrap(),
+                    }),
+                    Err(e) => serde_json::json!({ "error": e.to_string() }),
+                })
+            }),
+        );
+        test_support::serve(router).await
+    }
+
+    #[tokio::test]
+    async fn test_verify_against_platform() {
+        let client = reqwest::Client::new();
+        let token = Arc::new(tokio::sync::Mutex::new("test-key".to_string()));
+        let nonce = EchoNonce::default();
+        let mut config = test_support::config(&platform().await);
+        config.public_url = test_support::serve(router(nonce.clone())).await;
+
+        let report = verify(
+            &client,
+            &config,
+            &token,
+            "test-agent",
+            &config.public_url,
+            &nonce,
+            true,
+        )
+        .await;
+        assert_eq!(report.status, ReachabilityStatus::Reachable, "{:?}", report);
+        assert!(report.private);
+
+        // Someone else's agent answers at the URL with its own nonce.
+        config.public_url = test_support::serve(router(EchoNonce::default())).await;
+        let report = verify(
+            &client,
+            &config,
+            &token,
+            "test-agent",
+            &config.public_url,
+            &nonce,
+            true,
+        )
+        .await;
+        assert_eq!(report.status, ReachabilityStatus::Unreachable);
+
+        // A platform without the endpoint leaves the URL unverified.
+        config.platform_url = test_support::serve(Router::new()).await;
+        let report = verify(
+            &client,
+            &config,
+            &token,
+            "test-agent",
+            &config.public_url,
+            &nonce,
+            true,
+        )
+        .await;
+        assert_eq!(report.status, ReachabilityStatus::Unverified);
+        assert!(report.error.unwrap().contains("404"));
+    }
+
+    #[tokio::test]
+    async fn test_check_address_refuses_private_url() {
+        let mut config = test_support::config("http://127.0.0.1:1");
+        config.public_url = "http://127.0.0.1:8080".to_string();
+        let err = check_address(&config).await.unwrap_err();
+        assert!(err.to_string().contains("VRAM_SUPPLY_ALLOW_PRIVATE_URL"));
+
+        config.allow_private_url = true;
+        assert!(check_address(&config).await.unwrap());
+
+        config.public_url = "not a url".to_string();
+        assert!(check_address(&config).await.is_err());
+    }
+}
@@ -0,0 +1,489 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ed25519_dalek::{Signer, SigningKey};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::outbox::unix_now;
+use crate::usage::{self, UsageRecord};
+
+/// File names under the state directory.
+pub const RECEIPT_CURSOR_FILE: &str = "receipts.json";
+pub const RECEIPT_ARCHIVE_FILE: &str = "receipts.jsonl";
+pub const RECEIPT_REJECTED_FILE: &str = "receipts.rejected.jsonl";
+
+const RECEIPT_INTERVAL: Duration = Duration::from_secs(300);
+const INITIAL_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_RECEIPT_RECORDS: usize = 500;
+
+/// What the agent attests to: a batch of usage records it served.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ReceiptBody {
+    pub batch_id: String,
+    pub agent_uid: String,
+    pub created_at: u64,
+    pub records: Vec<UsageRecord>,
+}
+
+/// A receipt as submitted: `receipt` is the exact JSON text of a
+/// [`ReceiptBody`] and `signature` is the agent's Ed25519 signature over it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SignedReceipt {
+    /// Idempotency key; resubmitting the same batch is harmless.
+    pub batch_id: String,
+    pub agent_uid: String,
+    pub public_key: String,
+    pub receipt: String,
+    pub signature: String,
+}
+
+impl SignedReceipt {
+    pub fn sign(body: &ReceiptBody, key: &SigningKey) -> Result<Self> {
+        let receipt = serde_json::to_string(body)?;
+        let signature = key.sign(receipt.as_bytes());
+        Ok(SignedReceipt {
+            batch_id: body.batch_id.clone(),
+            agent_uid: body.agent_uid.clone(),
+            public_key: STANDARD.encode(key.verifying_key().to_bytes()),
+            receipt,
+            signature: STANDARD.encode(signature.to_bytes()),
+        })
+    }
+
+    pub fn body(&self) -> Result<ReceiptBody> {
+        serde_json::from_str(&self.receipt).context("Invalid receipt body")
+    }
+}
+
+/// How far the ledger has been submitted, plus the batch in flight. The batch
+/// is saved before its first submission so retries after a crash reuse the
+/// same id and signature.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ReceiptCursor {
+    /// Byte offset in the ledger up to which every record has been submitted.
+    pub offset: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending: Option<PendingReceipt>,
+}
+
+/// A signed batch not yet settled with the platform.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PendingReceipt {
+    pub receipt: SignedReceipt,
+    /// Ledger offset just past the batch's last record.
+    pub ledger_end: u64,
+}
+
+/// How the platform settled a batch.
+enum Submission {
+    Accepted,
+    /// Refused for good; resending the same batch cannot succeed.
+    Rejected(reqwest::StatusCode),
+}
+
+impl ReceiptCursor {
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(ReceiptCursor::default());
+        }
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed reading {}", path.display()))?;
+        serde_json::from_str(&contents)
+            .with_context(|| format!("Failed parsing {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed creating directory {}", parent.display()))?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed writing {}", tmp.display()))?;
+        fs::rename(&tmp, path)
+            .with_context(|| format!("Failed renaming {} → {}", tmp.display(), path.display()))
+    }
+}
+
+/// Signs ledger records in batches and submits them to
+/// `/v1/agents/usage-receipts`, keeping every accepted receipt locally.
+pub struct ReceiptSubmitter {
+    client: reqwest::Client,
+    config: Config,
+    token: Arc<Mutex<String>>,
+    agent_uid: String,
+    key: SigningKey,
+    ledger_path: PathBuf,
+    cursor_path: PathBuf,
+    archive_path: PathBuf,
+    rejected_path: PathBuf,
+}
+
+impl ReceiptSubmitter {
+    /// Submit records from the ledger at `ledger_path`, keeping the cursor
+    /// and accepted receipts under `state_dir`.
+    pub fn new(
+        client: reqwest::Client,
+        config: Config,
+        token: Arc<Mutex<String>>,
+        agent_uid: String,
+        key: SigningKey,
+        ledger_path: PathBuf,
+        state_dir: &Path,
+    ) -> Self {
+        ReceiptSubmitter {
+            client,
+            config,
+            token,
+            agent_uid,
+            key,
+            ledger_path,
+            cursor_path: state_dir.join(RECEIPT_CURSOR_FILE),
+            archive_path: state_dir.join(RECEIPT_ARCHIVE_FILE),
+            rejected_path: state_dir.join(RECEIPT_REJECTED_FILE),
+        }
+    }
+
+    /// Submit every unsubmitted record, one batch at a time. Returns how many
+    /// records were covered by accepted receipts.
+    pub async fn submit_pending(&self) -> Result<usize> {
+        let mut cursor = ReceiptCursor::load(&self.cursor_path)?;
+        let ledger_len = fs::metadata(&self.ledger_path).map_or(0, |m| m.len());
+        if cursor.offset > ledger_len {
+            tracing::warn!(
+                "Usage ledger {} is shorter than already submitted, starting over from its beginning",
+                self.ledger_path.display()
+            );
+            cursor.offset = 0;
+        }
+        let mut covered = 0;
+        loop {
+            let pending = match cursor.pending.clone() {
+                Some(pending) => pending,
+                None => {
+                    let (batch, ledger_end) = usage::read_ledger_from(
+                        &self.ledger_path,
+                        cursor.offset,
+                        MAX_RECEIPT_RECORDS,
+                    )?;
+                    if batch.is_empty() {
+                        // Skip over lines that did not parse.
+                        if ledger_end != cursor.offset {
+                            cursor.offset = ledger_end;
+                            cursor.save(&self.cursor_path)?;
+                        }
+                        return Ok(covered);
+                    }
+                    let body = ReceiptBody {
+                        batch_id: Uuid::new_v4().to_string(),
+                        agent_uid: self.agent_uid.clone(),
+                        created_at: unix_now(),
+                        records: batch,
+                    };
+                    let pending = PendingReceipt {
+                        receipt: SignedReceipt::sign(&body, &self.key)?,
+                        ledger_end,
+                    };
+                    cursor.pending = Some(pending.clone());
+                    cursor.save(&self.cursor_path)?;
+                    pending
+                }
+            };
+
+            let receipt = &pending.receipt;
+            let count = receipt.body()?.records.len();
+            match self.send(receipt).await? {
+                Submission::Accepted => {
+                    if let Err(e) = append_receipt(&self.archive_path, receipt) {
+                        tracing::warn!("Failed to archive usage receipt: {:#}", e);
+                    }
+                    covered += count;
+                    tracing::debug!(
+                        "Usage receipt {} accepted ({} records)",
+                        receipt.batch_id,
+                        count
+                    );
+                }
+                Submission::Rejected(status) => {
+                    if let Err(e) = append_receipt(&self.rejected_path, receipt) {
+                        tracing::warn!("Failed to keep rejected usage receipt: {:#}", e);
+                    }
+                    tracing::warn!(
+                        "Usage receipt {} ({} records) rejected by the platform ({}), moving on",
+                        receipt.batch_id,
+                        count,
+                        status
+                    );
+                }
+            }
+            cursor.offset = pending.ledger_end;
+            cursor.pending = None;
+            cursor.save(&self.cursor_path)?;
+        }
+    }
+
+    /// Submit one batch. Errors are worth retrying; a permanent refusal is
+    /// returned as [`Submission::Rejected`].
+    async fn send(&self, receipt: &SignedReceipt) -> Result<Submission> {
+        let url = format!("{}/v1/agents/usage-receipts", self.config.platform_url);
+        let current_token = self.token.lock().await.clone();
+        let res = self
+            .client
+            .post(url)
+            .header("Authorization", format!("Bearer {}", current_token))
+            .header("Idempotency-Key", &receipt.batch_id)
+            .json(receipt)
+            .send()
+            .await
+            .context("Usage receipt submission failed")?;
+        let status = res.status();
+        // 409 means the platform already has this batch.
+        if status.is_success() || status == reqwest::StatusCode::CONFLICT {
+            return Ok(Submission::Accepted);
+        }
+        // An expired or rotated key, or rate limiting, can clear up on its own.
+        let retryable = matches!(
+            status,
+            reqwest::StatusCode::UNAUTHORIZED
+                | reqwest::StatusCode::FORBIDDEN
+                | reqwest::StatusCode::REQUEST_TIMEOUT
+                | reqwest::StatusCode::TOO_MANY_REQUESTS
+        );
+        if status.is_client_error() && !retryable {
+            return Ok(Submission::Rejected(status));
+        }
+        bail!("Usage receipt submission failed: {}", status);
+    }
+
+    /// Submit receipts every five minutes, backing off from 30s while the
+    /// platform rejects or cannot be reached, and once more on shutdown.
+    pub fn spawn(self, shutdown: CancellationToken) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut wait = RECEIPT_INTERVAL;
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => {
+                        if let Err(e) = self.submit_pending().await {
+                            tracing::warn!("Final usage receipt submission failed: {:#}", e);
+                        }
+                        break;
+                    }
+                    _ = tokio::time::sleep(wait) => {}
+                }
+                wait = match self.submit_pending().await {
+                    Ok(_) => RECEIPT_INTERVAL,
+                    Err(e) => {
+                        let next = if wait >= RECEIPT_INTERVAL {
+                            INITIAL_RETRY_BACKOFF
+                        } else {
+                            (wait * 2).min(RECEIPT_INTERVAL)
+                        };
+                        tracing::warn!(
+                            "Usage receipt submission failed, retrying in {}s: {:#}",
+                            next.as_secs(),
+                            e
+                        );
+                        next
+                    }
+                };
+            }
+        })
+    }
+}
+
+fn append_receipt(path: &Path, receipt: &SignedReceipt) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed creating directory {}", parent.display()))?;
+    }
+    let mut line = serde_json::to_string(receipt)?;
+    line.push('\n');
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed opening {}", path.display()))?;
+    file.write_all(line.as_bytes())
+        .with_context(|| format!("Failed writing {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::Router;
+    use ed25519_dalek::{Signature, Verifier};
+
+    use crate::test_support;
+    use crate::usage::UsageLedger;
+
+    fn record(n: u64) -> UsageRecord {
+        UsageRecord {
+            request_id: format!("req-{}", n),
+            client_request_id: None,
+            model: "m".to_string(),
+            started_at: n,
+            latency_ms: 10,
+            status: 200,
+            prompt_tokens: n,
+            completion_tokens: n,
+            input_price_per_million: 100,
+            output_price_per_million: 200,
+        }
+    }
+
+    #[test]
+    fn test_receipt_signature_verifies() {
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let body = ReceiptBody {
+            batch_id: "b1".to_string(),
+            agent_uid: "test-agent".to_string(),
+            created_at: 1,
+            records: vec![record(1)],
+        };
+        let signed = SignedReceipt::sign(&body, &key).unwrap();
+        assert_eq!(signed.body().unwrap(), body);
+
+        let signature: [u8; 64] = STANDARD
+            .decode(&signed.signature)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        key.verifying_key()
+            .verify(
+                signed.receipt.as_bytes(),
+                &Signature::from_bytes(&signature),
+            )
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_batch_is_retried_with_the_same_id() {
+        // The first submission fails; the retry must resend the identical batch.
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let batch_ids = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let app = {
+            let attempts = Arc::clone(&attempts);
+            let batch_ids = Arc::clone(&batch_ids);
+            Router::new().route(
+                "/v1/agents/usage-receipts",
+                post(move |headers: HeaderMap, body: axum::Json<SignedReceipt>| {
+                    let attempts = Arc::clone(&attempts);
+                    let batch_ids = Arc::clone(&batch_ids);
+                    async move {
+                        assert_eq!(headers["idempotency-key"], body.batch_id.as_str());
+                        batch_ids.lock().unwrap().push(body.batch_id.clone());
+                        if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                            StatusCode::SERVICE_UNAVAILABLE
+                        } else {
+                            StatusCode::OK
+                        }
+                    }
+                }),
+            )
+        };
+        let url = test_support::serve(app).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let ledger_path = dir.path().join("usage.jsonl");
+        let ledger = UsageLedger::new(Some(ledger_path.clone()));
+        for n in 0..3 {
+            ledger.record(&record(n));
+        }
+        ledger.flush();
+        let submitter = ReceiptSubmitter::new(
+            reqwest::Client::new(),
+            test_support::config(&url),
+            Arc::new(Mutex::new("test-key".to_string())),
+            "test-agent".to_string(),
+            SigningKey::from_bytes(&[3; 32]),
+            ledger_path.clone(),
+            dir.path(),
+        );
+
+        assert!(submitter.submit_pending().await.is_err());
+        assert!(ReceiptCursor::load(&dir.path().join("receipts.json"))
+            .unwrap()
+            .pending
+            .is_some());
+
+        ledger.record(&record(3));
+        ledger.flush();
+        assert_eq!(submitter.submit_pending().await.unwrap(), 4);
+        let ids = batch_ids.lock().unwrap().clone();
+        assert_eq!(ids.len(), 3);
+        assert_eq!(ids[0], ids[1]);
+        assert_ne!(ids[1], ids[2]);
+
+        let cursor = ReceiptCursor::load(&dir.path().join("receipts.json")).unwrap();
+        assert_eq!(cursor.offset, fs::metadata(&ledger_path).unwrap().len());
+        assert_eq!(cursor.pending, None);
+        let archived = fs::read_to_string(dir.path().join("receipts.jsonl")).unwrap();
+        assert_eq!(archived.lines().count(), 2);
+        assert_eq!(submitter.submit_pending().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_batch_is_set_aside() {
+        // The first batch is refused for good; later records still go through.
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let app = {
+            let attempts = Arc::clone(&attempts);
+            Router::new().route(
+                "/v1/agents/usage-receipts",
+                post(move || {
+                    let attempts = Arc::clone(&attempts);
+                    async move {
+                        if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                            StatusCode::UNPROCESSABLE_ENTITY
+                        } else {
+                            StatusCode::OK
+                        }
+                    }
+                }),
+            )
+        };
+        let url = test_support::serve(app).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let ledger_path = dir.path().join("usage.jsonl");
+        let ledger = UsageLedger::new(Some(ledger_path.clone()));
+        ledger.record(&record(0));
+        ledger.flush();
+        let submitter = ReceiptSubmitter::new(
+            reqwest::Client::new(),
+            test_support::config(&url),
+            Arc::new(Mutex::new("test-key".to_string())),
+            "test-agent".to_string(),
+            SigningKey::from_bytes(&[3; 32]),
+            ledger_path,
+            dir.path(),
+        );
+
+        assert_eq!(submitter.submit_pending().await.unwrap(), 0);
+        let rejected = fs::read_to_string(dir.path().join(RECEIPT_REJECTED_FILE)).unwrap();
+        assert_eq!(rejected.lines().count(), 1);
+
+        ledger.record(&record(1));
+        ledger.flush();
+        assert_eq!(submitter.submit_pending().await.unwrap(), 1);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        let archived = fs::read_to_string(dir.path().join(RECEIPT_ARCHIVE_FILE)).unwrap();
+        assert_eq!(archived.lines().count(), 1);
+    }
+}
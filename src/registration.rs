@@ -0,0 +1,627 @@
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::config::Config;
+
+const INITIAL_REGISTER_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_REGISTER_BACKOFF: Duration = Duration::from_secs(60);
+/// Attempts before giving up; with the backoff above this is about 3 minutes.
+const MAX_REGISTER_ATTEMPTS: u32 = 9;
+
+/// What this provider offers, as sent to `/v1/providers/register` and in
+/// later updates.
+#[derive(Debug, Clone, Serialize)]
+pub struct RegisterRequest {
+    pub endpoint_url: String,
+    pub model: String,
+    pub max_concurrent: u32,
+    pub context_length_offered: u32,
+    pub input_price_per_million: u32,
+    pub output_price_per_million: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model_sha256: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterResponse {
+    pub id: String,
+    pub status: String,
+}
+
+/// A registration remembered in the state directory so a restart (or a crash)
+/// reuses the platform's provider record instead of leaving a zombie behind.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct StoredRegistration {
+    pub agent_uid: String,
+    pub model: String,
+    pub provider_id: String,
+    pub registered_at: u64,
+}
+
+/// Registration ids persisted as JSON, keyed by agent uid and model.
+#[derive(Debug, Clone)]
+pub struct RegistrationStore {
+    path: PathBuf,
+}
+
+impl RegistrationStore {
+    pub fn new(path: PathBuf) -> Self {
+        RegistrationStore { path }
+    }
+
+    /// The store in the default state directory.
+    pub fn open_default() -> Result<Self> {
+        Ok(Self::new(
+            crate::config::state_dir()?.join("registrations.json"),
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn list(&self) -> Result<Vec<StoredRegistration>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let contents = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed reading {}", self.path.display()))?;
+        serde_json::from_str(&contents)
+            .with_context(|| format!("Failed parsing {}", self.path.display()))
+    }
+
+    fn save(&self, entries: &[StoredRegistration]) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed creating directory {}", parent.display()))?;
+        }
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(entries)?)
+            .with_context(|| format!("Failed writing {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path).with_context(|| {
+            format!(
+                "Failed renaming {} → {}",
+                tmp.display(),
+                self.path.display()
+            )
+        })
+    }
+
+    /// The stored provider id for this agent and model, if any.
+    pub fn find(&self, agent_uid: &str, model: &str) -> Result<Option<String>> {
+        Ok(self
+            .list()?
+            .into_iter()
+            .find(|e| e.agent_uid == agent_uid && e.model == model)
+            .map(|e| e.provider_id))
+    }
+
+    /// Remember `provider_id` for this agent and model, replacing any entry
+    /// for the same pair or the same provider id (e.g. after a model reload).
+    pub fn record(&self, agent_uid: &str, model: &str, provider_id: &str) -> Result<()> {
+        let mut entries = self.list()?;
+        entries.retain(|e| {
+            e.provider_id != provider_id && !(e.agent_uid == agent_uid && e.model == model)
+        });
+        entries.push(StoredRegistration {
+            agent_uid: agent_uid.to_string(),
+            model: model.to_string(),
+            provider_id: provider_id.to_string(),
+            registered_at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        });
+        self.save(&entries)
+    }
+
+    /// Forget a provider id. Does nothing if it is not stored.
+    pub fn remove(&self, provider_id: &str) -> Result<()> {
+        let mut entries = self.list()?;
+        let before = entries.len();
+        entries.retain(|e| e.provider_id != provider_id);
+        if entries.len() != before {
+            self.save(&entries)?;
+        }
+        Ok(())
+    }
+}
+
+/// Registers this agent with the platform and keeps the stored registration
+/// ids in step with it. Cheap to Clone.
+#[derive(Clone)]
+pub struct Registrar {
+    client: reqwest::Client,
+    config: Config,
+    token: Arc<Mutex<String>>,
+    store: RegistrationStore,
+    agent_uid: String,
+}
+
+impl Registrar {
+    pub fn new(
+        client: reqwest::Client,
+        config: Config,
+        token: Arc<Mutex<String>>,
+        store: RegistrationStore,
+        agent_uid: String,
+    ) -> Self {
+        Registrar {
+            client,
+            config,
+            token,
+            store,
+            agent_uid,
+        }
+    }
+
+    /// Reuse the stored registration for this agent and model if the platform
+    /// still knows it, otherwise register afresh. Returns the provider id,
+    /// which is stored for the next run.
+    ///
+    /// The stored id is only replaced once the platform has said it is gone;
+    /// any other failure to update it is returned, so a registration that
+    /// may still be live is never orphaned by a second one.
+    #[tracing::instrument(
+        name = "register",
+        skip_all,
+        fields(
+            agent_uid = %self.agent_uid,
+            model = %body.model,
+            provider_id = tracing::field::Empty
+        )
+    )]
+    pub async fn register(&self, body: &RegisterRequest) -> Result<String> {
+        let stored = self
+            .store
+            .find(&self.agent_uid, &body.model)
+            .unwrap_or_else(|e| {
+                tracing::warn!("Ignoring stored registrations: {:#}", e);
+                None
+            });
+        if let Some(id) = stored {
+            let reused = retry("Registration update", || {
+                try_update(&self.client, &self.config, &self.token, &id, body)
+            })
+            .await;
+            match reused {
+                Ok(()) => {
+                    tracing::info!("Reusing registration from a previous run: id={}", id);
+                    tracing::Span::current().record("provider_id", id.as_str());
+                    return Ok(id);
+                }
+                Err(e) if e.downcast_ref::<RegistrationGone>().is_some() => {
+                    tracing::info!("Stored registration {} is gone from the platform", id);
+                    self.forget(&id);
+                }
+                Err(e) => {
+                    return Err(e.context(format!("Failed to reuse stored registration {}", id)))
+                }
+            }
+        }
+
+        let reg = register(&self.client, &self.config, &self.token, body).await?;
+        tracing::Span::current().record("provider_id", reg.id.as_str());
+        self.remember(&body.model, &reg.id);
+        Ok(reg.id)
+    }
+
+    /// Replace the offer of the registration `provider_id`.
+    pub async fn update(&self, provider_id: &str, body: &RegisterRequest) -> Result<()> {
+        update(&self.client, &self.config, &self.token, provider_id, body).await?;
+        self.remember(&body.model, provider_id);
+        Ok(())
+    }
+
+    /// Remove a registration from the platform, then from the store.
+    pub async fn deregister(&self, provider_id: &str) -> Result<()> {
+        deregister(&self.client, &self.config, &self.token, provider_id).await?;
+        self.forget(provider_id);
+        Ok(())
+    }
+
+    /// Registrations stored by earlier runs of this agent.
+    pub fn stored(&self) -> Result<Vec<StoredRegistration>> {
+        Ok(self
+            .store
+            .list()?
+            .into_iter()
+            .filter(|e| e.agent_uid == self.agent_uid)
+            .collect())
+    }
+
+    pub fn store_path(&self) -> &Path {
+        self.store.path()
+    }
+
+    /// Drop a registration from the store without contacting the platform,
+    /// e.g. once the platform has said it no longer knows it.
+    pub fn forget(&self, provider_id: &str) {
+        if let Err(e) = self.store.remove(provider_id) {
+            tracing::warn!("Failed to forget registration {}: {:#}", provider_id, e);
+        }
+    }
+
+    fn remember(&self, model: &str, provider_id: &str) {
+        if let Err(e) = self.store.record(&self.agent_uid, model, provider_id) {
+            tracing::warn!("Failed to store registration {}: {:#}", provider_id, e);
+        }
+    }
+}
+
+/// A registration attempt that failed, and whether trying again may help.
+#[derive(Debug)]
+enum RegisterFailure {
+    Transient(anyhow::Error),
+    Fatal(anyhow::Error),
+}
+
+impl RegisterFailure {
+    fn into_error(self) -> anyhow::Error {
+        match self {
+            RegisterFailure::Transient(e) | RegisterFailure::Fatal(e) => e,
+        }
+    }
+}
+
+/// The platform answered 404 or 410 for a registration: it no longer exists.
+#[derive(Debug)]
+pub struct RegistrationGone {
+    pub provider_id: String,
+}
+
+impl fmt::Display for RegistrationGone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The platform no longer knows provider {}",
+            self.provider_id
+        )
+    }
+}
+
+impl std::error::Error for RegistrationGone {}
+
+/// Run `attempt` until it succeeds or fails fatally, retrying transient
+/// failures with exponential backoff up to `MAX_REGISTER_ATTEMPTS` times.
+async fn retry<T, F>(what: &str, mut attempt: impl FnMut() -> F) -> Result<T>
+where
+    F: Future<Output = std::result::Result<T, RegisterFailure>>,
+{
+    let mut backoff = INITIAL_REGISTER_BACKOFF;
+    let mut n = 1;
+    loop {
+        match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(RegisterFailure::Fatal(e)) => return Err(e),
+            Err(RegisterFailure::Transient(e)) if n >= MAX_REGISTER_ATTEMPTS => {
+                return Err(e.context(format!("{} failed after {} attempts", what, n)))
+            }
+            Err(RegisterFailure::Transient(e)) => {
+                tracing::warn!(
+                    "{} attempt {} failed, retrying in {:?}: {:#}",
+                    what,
+                    n,
+                    backoff,
+                    e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_REGISTER_BACKOFF);
+                n += 1;
+            }
+        }
+    }
+}
+
+/// Register this provider instance with the platform, returning the response.
+///
+/// Network errors, 5xx and 429 responses are retried with exponential backoff.
+/// Other 4xx responses (e.g. an invalid API key) fail immediately.
+pub async fn register(
+    client: &reqwest::Client,
+    config: &Config,
+    token: &Arc<Mutex<String>>,
+    body: &RegisterRequest,
+) -> Result<RegisterResponse> {
+    let reg = retry("Registration", || async {
+        let result = try_register(client, config, token, body).await;
+        let outcome = match &result {
+            Ok(_) => "success",
+            Err(RegisterFailure::Fatal(_)) => "rejected",
+            Err(RegisterFailure::Transient(_)) => "failure",
+        };
+        crate::telemetry::metrics()
+            .registration_attempts
+            .with_label_values(&[outcome])
+            .inc();
+        result
+    })
+    .await?;
+    tracing::info!(
+        "Registered with platform: id={}, status={}",
+        reg.id,
+        reg.status
+    );
+    Ok(reg)
+}
+
+async fn try_register(
+    client: &reqwest::Client,
+    config: &Config,
+    token: &Arc<Mutex<String>>,
+    body: &RegisterRequest,
+) -> std::result::Result<RegisterResponse, RegisterFailure> {
+    let register_url = format!("{}/v1/providers/register", config.platform_url);
+    let current_token = token.lock().await.clone();
+    let res = client
+        .post(&register_url)
+        .header("Authorization", format!("Bearer {}", current_token))
+        .json(body)
+        .send()
+        .await
+        .context("Registration request failed")
+        .map_err(RegisterFailure::Transient)?;
+
+    let status = res.status();
+    if !status.is_success() {
+        let text = res.text().await.unwrap_or_default();
+        let error = anyhow::anyhow!("Registration failed ({}): {}", status, text);
+        return Err(match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => RegisterFailure::Fatal(
+                error.context("The platform rejected the API key; run `vramsply auth` to check it"),
+            ),
+            StatusCode::TOO_MANY_REQUESTS => RegisterFailure::Transient(error),
+            s if s.is_server_error() => RegisterFailure::Transient(error),
+            _ => RegisterFailure::Fatal(error),
+        });
+    }
+
+    res.json()
+        .await
+        .context("Invalid registration response")
+        .map_err(RegisterFailure::Transient)
+}
+
+/// Replace the offer of an existing registration (e.g. after a model reload
+/// or price change). Fails with [`RegistrationGone`] if the platform no
+/// longer knows it.
+pub async fn update(
+    client: &reqwest::Client,
+    config: &Config,
+    token: &Arc<Mutex<String>>,
+    provider_id: &str,
+    body: &RegisterRequest,
+) -> Result<()> {
+    try_update(client, config, token, provider_id, body)
+        .await
+        .map_err(RegisterFailure::into_error)
+}
+
+async fn try_update(
+    client: &reqwest::Client,
+    config: &Config,
+    token: &Arc<Mutex<String>>,
+    provider_id: &str,
+    body: &RegisterRequest,
+) -> std::result::Result<(), RegisterFailure> {
+    let url = format!("{}/v1/providers/{}", config.platform_url, provider_id);
+    let current_token = token.lock().await.clone();
+    let res = client
+        .patch(&url)
+        .header("Authorization", format!("Bearer {}", current_token))
+        .json(body)
+        .send()
+        .await
+        .context("Registration update request failed")
+        .map_err(RegisterFailure::Transient)?;
+
+    let status = res.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    if matches!(status, StatusCode::NOT_FOUND | StatusCode::GONE) {
+        return Err(RegisterFailure::Fatal(anyhow::Error::new(
+            RegistrationGone {
+                provider_id: provider_id.to_string(),
+            },
+        )));
+    }
+    let text = res.text().await.unwrap_or_default();
+    let error = anyhow::anyhow!("Registration update failed ({}): {}", status, text);
+    Err(
+        if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            RegisterFailure::Transient(error)
+        } else {
+            RegisterFailure::Fatal(error)
+        },
+    )
+}
+
+/// Remove a registration. A registration the platform no longer knows
+/// counts as removed.
+pub async fn deregister(
+    client: &reqwest::Client,
+    config: &Config,
+    token: &Arc<Mutex<String>>,
+    provider_id: &str,
+) -> Result<()> {
+    let deregister_url = format!("{}/v1/providers/{}", config.platform_url, provider_id);
+    let current_token = token.lock().await.clone();
+    let resp = client
+        .delete(&deregister_url)
+        .header("Authorization", format!("Bearer {}", current_token))
+        .send()
+        .await
+        .context("Deregister request failed")?;
+
+    match resp.status() {
+        s if s.is_success() => tracing::info!("Deregistered {} from platform", provider_id),
+        StatusCode::NOT_FOUND => tracing::info!("Provider {} was already gone", provider_id),
+        s => anyhow::bail!("Deregister returned HTTP {}", s),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::routing::{patch, post};
+    use axum::{Json, Router};
+
+    use crate::test_support;
+
+    fn body() -> RegisterRequest {
+        RegisterRequest {
+            endpoint_url: "https://provider.example".to_string(),
+            model: "m".to_string(),
+            max_concurrent: 1,
+            context_length_offered: 4096,
+            input_price_per_million: 1,
+            output_price_per_million: 2,
+            model_sha256: None,
+        }
+    }
+
+    async fn register_against(url: &str) -> Result<RegisterResponse> {
+        let config = test_support::config(url);
+        let token = Arc::new(Mutex::new("test-key".to_string()));
+        register(&reqwest::Client::new(), &config, &token, &body()).await
+    }
+
+    #[tokio::test]
+    async fn test_register_retries_transient_errors() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let router = Router::new().route(
+            "/v1/providers/register",
+            post(move || {
+                let n = counter.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if n == 0 {
+                        Err(StatusCode::BAD_GATEWAY)
+                    } else {
+                        Ok(Json(
+                            serde_json::json!({"id": "prov-1", "status": "active"}),
+                        ))
+                    }
+                }
+            }),
+        );
+        let url = test_support::serve(router).await;
+
+        let result = register_against(&url).await;
+        assert_eq!(result.unwrap().id, "prov-1");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_register_fails_fast_on_auth_error() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let router = Router::new().route(
+            "/v1/providers/register",
+            post(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async { StatusCode::UNAUTHORIZED }
+            }),
+        );
+        let url = test_support::serve(router).await;
+
+        let error = format!("{:#}", register_against(&url).await.unwrap_err());
+        assert!(error.contains("API key"), "{}", error);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_store_record_find_remove() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = RegistrationStore::new(dir.path().join("registrations.json"));
+        assert_eq!(store.find("agent", "m").unwrap(), None);
+
+        store.record("agent", "m", "prov-1").unwrap();
+        store.record("agent", "other", "prov-2").unwrap();
+        assert_eq!(store.find("agent", "m").unwrap().as_deref(), Some("prov-1"));
+
+        // A reload keeps the provider id but changes the model.
+        store.record("agent", "m2", "prov-1").unwrap();
+        assert_eq!(store.find("agent", "m").unwrap(), None);
+        assert_eq!(
+            store.find("agent", "m2").unwrap().as_deref(),
+            Some("prov-1")
+        );
+
+        store.remove("prov-1").unwrap();
+        let remaining: Vec<_> = store
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|e| e.provider_id)
+            .collect();
+        assert_eq!(remaining, vec!["prov-2"]);
+    }
+
+    #[tokio::test]
+    async fn test_registrar_reuses_stored_registration() {
+        let router = Router::new()
+            .route(
+                "/v1/providers/{id}",
+                patch(
+                    |axum::extract::Path(id): axum::extract::Path<String>| async move {
+                        match id.as_str() {
+                            "prov-live" => StatusCode::OK,
+                            "prov-forbidden" => StatusCode::FORBIDDEN,
+                            _ => StatusCode::NOT_FOUND,
+                        }
+                    },
+                ),
+            )
+            .route(
+                "/v1/providers/register",
+                post(|| async { Json(serde_json::json!({"id": "prov-new", "status": "active"})) }),
+            );
+        let url = test_support::serve(router).await;
+        let config = test_support::config(&url);
+        let token = Arc::new(Mutex::new("test-key".to_string()));
+        let dir = tempfile::tempdir().unwrap();
+        let store = RegistrationStore::new(dir.path().join("registrations.json"));
+        let registrar = Registrar::new(
+            reqwest::Client::new(),
+            config,
+            token,
+            store.clone(),
+            "agent".to_string(),
+        );
+
+        store.record("agent", "m", "prov-live").unwrap();
+        assert_eq!(registrar.register(&body()).await.unwrap(), "prov-live");
+
+        store.record("agent", "m", "prov-gone").unwrap();
+        let id = registrar.register(&body()).await.unwrap();
+        assert_eq!(id, "prov-new");
+        assert_eq!(
+            store.find("agent", "m").unwrap().as_deref(),
+            Some("prov-new")
+        );
+        assert_eq!(store.list().unwrap().len(), 1);
+
+        // A registration that may still be live is kept, not replaced.
+        store.record("agent", "m", "prov-forbidden").unwrap();
+        assert!(registrar.register(&body()).await.is_err());
+        assert_eq!(
+            store.find("agent", "m").unwrap().as_deref(),
+            Some("prov-forbidden")
+        );
+    }
+}
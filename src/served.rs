@@ -0,0 +1,184 @@
+//! The models one `serve` offers.
+//!
+//! Each model gets its own llama-server on `backend_port + index` and its
+//! own registration with the platform, so it has a provider id, price and
+//! concurrency of its own. The proxy routes each request to the model whose
+//! provider id its token names.
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use tokio::sync::Mutex;
+
+use crate::backend::{LlamaServer, LogTail};
+use crate::config::Config;
+use crate::registration::RegisterRequest;
+
+/// A `--model` argument: `PATH[,key=value...]`, where the keys override the
+/// configured defaults for this model: `name`, `hf_repo`, `input_price`,
+/// `output_price`, `max_concurrent` and `context_length`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModelSpec {
+    /// Path, or name in the model directory.
+    pub model: String,
+    pub name: Option<String>,
+    pub hf_repo: Option<String>,
+    pub input_price_per_million: Option<u32>,
+    pub output_price_per_million: Option<u32>,
+    pub max_concurrent: Option<u32>,
+    pub context_length: Option<u32>,
+}
+
+impl FromStr for ModelSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let model = parts.next().unwrap_or_default().trim();
+        if model.is_empty() {
+            return Err("expected a model path or name".to_string());
+        }
+        let mut spec = ModelSpec {
+            model: model.to_string(),
+            ..Default::default()
+        };
+        for part in parts {
+            let (key, value) = part
+                .split_once('=')
+                .map(|(k, v)| (k.trim(), v.trim()))
+                .ok_or_else(|| format!("expected key=value, got '{}'", part))?;
+            let number = || {
+                value
+                    .parse::<u32>()
+                    .map_err(|_| format!("{} must be a whole number, got '{}'", key, value))
+            };
+            match key {
+                "name" => spec.name = Some(value.to_string()),
+                "hf_repo" => spec.hf_repo = Some(value.to_string()),
+                "input_price" => spec.input_price_per_million = Some(number()?),
+                "output_price" => spec.output_price_per_million = Some(number()?),
+                "max_concurrent" => spec.max_concurrent = Some(number()?),
+                "context_length" => spec.context_length = Some(number()?),
+                other => return Err(format!("unknown model option '{}'", other)),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+/// The llama-server ports for `count` models: consecutive from
+/// `backend_port`, none of them the public port.
+pub fn backend_ports(config: &Config, count: usize) -> Result<Vec<u16>> {
+    (0..count)
+        .map(|i| {
+            let port = u16::try_from(i)
+                .ok()
+                .and_then(|i| config.backend_port.checked_add(i));
+            match port {
+                Some(port) if port != config.port => Ok(port),
+                _ => bail!(
+                    "Cannot give model {} a backend port after {} that is not the public port {}; \
+                     set VRAM_SUPPLY_BACKEND_PORT to a free range",
+                    i + 1,
+                    config.backend_port,
+                    config.port
+                ),
+            }
+        })
+        .collect()
+}
+
+/// One model being served, with its backend and what it is registered as.
+#[derive(Clone)]
+pub struct ServedModel {
+    pub llama: Arc<Mutex<LlamaServer>>,
+    pub registration: Arc<Mutex<RegisterRequest>>,
+    pub backend_port: u16,
+    pub backend_log: LogTail,
+}
+
+/// The index of the model `target` names by its registered name, or the
+/// only model when there is one and no target is given.
+pub async fn find(models: &[ServedModel], target: Option<&str>) -> Result<usize> {
+    let Some(target) = target else {
+        if models.len() == 1 {
+            return Ok(0);
+        }
+        bail!(
+            "{} models are served; name the one to change with a target",
+            models.len()
+        );
+    };
+    let mut names = Vec::with_capacity(models.len());
+    for (i, model) in models.iter().enumerate() {
+        let name = model.registration.lock().await.model.clone();
+        if name == target {
+            return Ok(i);
+        }
+        names.push(name);
+    }
+    bail!(
+        "No served model named '{}' (serving {})",
+        target,
+        names.join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support;
+
+    #[test]
+    fn test_parse_model_spec() {
+        assert_eq!(
+            "llama.gguf".parse::<ModelSpec>().unwrap(),
+            ModelSpec {
+                model: "llama.gguf".to_string(),
+                ..Default::default()
+            }
+        );
+        let spec: ModelSpec =
+            "/models/qwen.gguf,name=qwen/qwen2.5-7b,hf_repo=Qwen/Qwen2.5-7B-GGUF,input_price=50,output_price=150,max_concurrent=2,context_length=4096"
+                .parse()
+                .unwrap();
+        assert_eq!(spec.model, "/models/qwen.gguf");
+        assert_eq!(spec.name.as_deref(), Some("qwen/qwen2.5-7b"));
+        assert_eq!(spec.hf_repo.as_deref(), Some("Qwen/Qwen2.5-7B-GGUF"));
+        assert_eq!(spec.input_price_per_million, Some(50));
+        assert_eq!(spec.output_price_per_million, Some(150));
+        assert_eq!(spec.max_concurrent, Some(2));
+        assert_eq!(spec.context_length, Some(4096));
+
+        for bad in [
+            "",
+            ",name=x",
+            "m.gguf,name",
+            "m.gguf,price=1",
+            "m.gguf,input_price=x",
+        ] {
+            assert!(
+                bad.parse::<ModelSpec>().is_err(),
+                "{:?} should not parse",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_backend_ports_avoid_public_port() {
+        let mut config = test_support::config("http://127.0.0.1:1");
+        config.port = 8080;
+        config.backend_port = 8081;
+        assert_eq!(backend_ports(&config, 3).unwrap(), vec![8081, 8082, 8083]);
+
+        config.backend_port = 8079;
+        assert_eq!(backend_ports(&config, 1).unwrap(), vec![8079]);
+        assert!(backend_ports(&config, 2).is_err());
+
+        config.backend_port = u16::MAX;
+        assert!(backend_ports(&config, 2).is_err());
+    }
+}
@@ -0,0 +1,466 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::{self, Config};
+
+const UNIT_NAME: &str = "vramsply.service";
+/// How often systemd expects a watchdog ping; serve pings at half this.
+const WATCHDOG_SECS: u64 = 60;
+/// How long `serve --daemon` watches the background process for an early exit.
+const DAEMON_STARTUP_CHECK: Duration = Duration::from_secs(2);
+
+/// Where a generated unit and its environment file live.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServicePaths {
+    pub unit: PathBuf,
+    pub env_file: PathBuf,
+}
+
+impl ServicePaths {
+    /// User units go under `~/.config/systemd/user`; system units under
+    /// `/etc/systemd/system`.
+    pub fn resolve(system: bool) -> Result<Self> {
+        if system {
+            return Ok(ServicePaths {
+                unit: PathBuf::from("/etc/systemd/system").join(UNIT_NAME),
+                env_file: PathBuf::from("/etc/vramsply/env"),
+            });
+        }
+        let config_dir = dirs::config_dir()
+            .ok_or_else(|| anyhow::anyhow!("Could not determine config directory"))?;
+        Ok(ServicePaths {
+            unit: config_dir.join("systemd").join("user").join(UNIT_NAME),
+            env_file: config_dir.join("vramsply").join("env"),
+        })
+    }
+}
+
+/// Everything that goes into a generated unit.
+#[derive(Debug, Clone)]
+pub struct UnitOptions {
+    pub exec: PathBuf,
+    pub serve_args: Vec<String>,
+    pub env_file: PathBuf,
+    pub system: bool,
+    /// Account a system unit runs as.
+    pub user: Option<String>,
+    /// Matches the agent's own shutdown budget, plus headroom.
+    pub stop_timeout_secs: u64,
+}
+
+/// Quote an `ExecStart` argument for systemd.
+fn quote_arg(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%");
+    if !escaped.is_empty()
+        && !escaped
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';' | '$'))
+    {
+        return escaped;
+    }
+    let escaped = escaped
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('$', "$$");
+    format!("\"{}\"", escaped)
+}
+
+/// Render a `Type=notify` unit that restarts the agent on failure and expects
+/// watchdog pings.
+pub fn render_unit(opts: &UnitOptions) -> String {
+    let mut exec = vec![quote_arg(&opts.exec.to_string_lossy()), "serve".to_string()];
+    exec.extend(opts.serve_args.iter().map(|a| quote_arg(a)));
+
+    let mut unit = String::new();
+    unit.push_str("[Unit]\n");
+    unit.push_str("Description=vram.supply provider agent\n");
+    unit.push_str("After=network-online.target\n");
+    unit.push_str("Wants=network-online.target\n\n");
+    unit.push_str("[Service]\n");
+    unit.push_str("Type=notify\n");
+    unit.push_str("NotifyAccess=main\n");
+    // Verifying or downloading a model before READY=1 can take any length of time.
+    unit.push_str("TimeoutStartSec=infinity\n");
+    if let Some(user) = opts.user.as_deref().filter(|_| opts.system) {
+        unit.push_str(&format!("User={}\n", user));
+    }
+    unit.push_str(&format!(
+        "EnvironmentFile={}\n",
+        quote_arg(&opts.env_file.to_string_lossy())
+    ));
+    unit.push_str(&format!("ExecStart={}\n", exec.join(" ")));
+    unit.push_str("Restart=on-failure\n");
+    unit.push_str("RestartSec=5\n");
+    unit.push_str(&format!("WatchdogSec={}\n", WATCHDOG_SECS));
+    unit.push_str("KillSignal=SIGTERM\n");
+    unit.push_str(&format!("TimeoutStopSec={}\n\n", opts.stop_timeout_secs));
+    unit.push_str("[Install]\n");
+    unit.push_str(if opts.system {
+        "WantedBy=multi-user.target\n"
+    } else {
+        "WantedBy=default.target\n"
+    });
+    unit
+}
+
+/// The `VRAM_SUPPLY_*`, `OTEL_*` and `RUST_LOG` variables set in this shell,
+/// as an environment file.
+fn render_env_file() -> String {
+    let mut vars: Vec<(String, String)> = std::env::vars()
+        .filter(|(k, _)| k.starts_with("VRAM_SUPPLY_") || k.starts_with("OTEL_") || k == "RUST_LOG")
+        .collect();
+    vars.sort();
+    let mut out = String::from("# Environment for vramsply serve, see `vramsply --help`\n");
+    for (k, v) in vars {
+        out.push_str(&format!("{}={}\n", k, quote_env_value(&v)));
+    }
+    out
+}
+
+/// Quote an environment file value so systemd reads it back unchanged.
+fn quote_env_value(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '\\' | '"' | '$' | '`' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn systemctl(system: bool, args: &[&str]) -> Result<()> {
+    let mut cmd = Command::new("systemctl");
+    if !system {
+        cmd.arg("--user");
+    }
+    let status = cmd
+        .args(args)
+        .status()
+        .with_context(|| format!("Failed to run systemctl {}", args.join(" ")))?;
+    if !status.success() {
+        bail!("systemctl {} failed ({})", args.join(" "), status);
+    }
+    Ok(())
+}
+
+/// Write the unit (and an environment file from the current `VRAM_SUPPLY_*`
+/// variables, unless one exists), then enable and start it.
+pub fn install(config: &Config, system: bool, serve_args: Vec<String>) -> Result<()> {
+    let paths = ServicePaths::resolve(system)?;
+
+    if paths.env_file.exists() {
+        println!(
+            "Keeping existing environment file {}",
+            paths.env_file.display()
+        );
+    } else {
+        write_private(&paths.env_file, &render_env_file())?;
+        println!("Wrote environment file {}", paths.env_file.display());
+    }
+
+    let user = std::env::var("SUDO_USER")
+        .or_else(|_| std::env::var("USER"))
+        .ok()
+        .filter(|u| u != "root");
+    let unit = render_unit(&UnitOptions {
+        exec: std::env::current_exe().context("Could not determine the vramsply binary path")?,
+        serve_args,
+        env_file: paths.env_file.clone(),
+        system,
+        user,
+        stop_timeout_secs: config.shutdown_timeout_secs + 10,
+    });
+    if let Some(parent) = paths.unit.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed creating directory {}", parent.display()))?;
+    }
+    fs::write(&paths.unit, unit)
+        .with_context(|| format!("Failed writing {}", paths.unit.display()))?;
+    println!("Wrote unit {}", paths.unit.display());
+
+    systemctl(system, &["daemon-reload"])?;
+    systemctl(system, &["enable", "--now", UNIT_NAME])?;
+    println!("Service enabled and started.");
+    if !system {
+        println!("To keep it running after you log out: loginctl enable-linger");
+    }
+    Ok(())
+}
+
+/// Stop and disable the unit and remove it. The environment file is kept,
+/// since it holds the API key.
+pub fn uninstall(system: bool) -> Result<()> {
+    let paths = ServicePaths::resolve(system)?;
+    if !paths.unit.exists() {
+        println!("No unit installed at {}", paths.unit.display());
+        return Ok(());
+    }
+    if let Err(e) = systemctl(system, &["disable", "--now", UNIT_NAME]) {
+        tracing::warn!("{:#}", e);
+    }
+    fs::remove_file(&paths.unit)
+        .with_context(|| format!("Failed removing {}", paths.unit.display()))?;
+    systemctl(system, &["daemon-reload"])?;
+    println!("Removed {}", paths.unit.display());
+    println!(
+        "Environment file left in place: {}",
+        paths.env_file.display()
+    );
+    Ok(())
+}
+
+pub fn status(system: bool) -> Result<()> {
+    let paths = ServicePaths::resolve(system)?;
+    if !paths.unit.exists() {
+        println!("Not installed (no unit at {})", paths.unit.display());
+        return Ok(());
+    }
+    println!("Unit: {}", paths.unit.display());
+    println!("Environment file: {}", paths.env_file.display());
+    // `systemctl status` exits non-zero for stopped units; its output says why.
+    let _ = systemctl(system, &["status", "--no-pager", UNIT_NAME]);
+    Ok(())
+}
+
+pub(crate) fn write_private(path: &Path, contents: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed creating directory {}", parent.display()))?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Failed creating {}", path.display()))?;
+    file.write_all(contents.as_bytes())
+        .with_context(|| format!("Failed writing {}", path.display()))
+}
+
+/// Send a state change to systemd (`READY=1`, `STOPPING=1`, `WATCHDOG=1`, ...).
+/// Does nothing unless started by systemd with `NOTIFY_SOCKET` set.
+pub fn notify(state: &str) {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send_notify(&socket, state) {
+        tracing::debug!("sd_notify failed: {}", e);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_notify(socket: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let bytes = socket.as_bytes();
+    let addr = match bytes.strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(Path::new(socket))?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_notify(_socket: &std::ffi::OsStr, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// How often to send `WATCHDOG=1`, if systemd asked for watchdog pings.
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // WATCHDOG_PID, when set, names the process that must ping.
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    Some(Duration::from_micros(usec / 2)).filter(|d| !d.is_zero())
+}
+
+/// Ping the systemd watchdog until `shutdown`, if it asked for pings. The
+/// pings come from a task of their own so that a slow platform request never
+/// holds them up.
+pub fn spawn_watchdog(shutdown: CancellationToken) -> Option<tokio::task::JoinHandle<()>> {
+    let mut interval = tokio::time::interval(watchdog_interval()?);
+    Some(tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = interval.tick() => notify("WATCHDOG=1"),
+            }
+        }
+    }))
+}
+
+/// Default PID and log files for `serve --daemon`.
+pub fn default_pid_file() -> Result<PathBuf> {
+    Ok(config::state_dir()?.join("vramsply.pid"))
+}
+
+pub fn default_log_file() -> Result<PathBuf> {
+    Ok(config::state_dir()?.join("serve.log"))
+}
+
+/// Re-run this `serve` command in the background, detached from the
+/// terminal, with output appended to `log_file` and its PID written to
+/// `pid_file` by the background process itself.
+pub async fn daemonize(pid_file: &Path, log_file: &Path) -> Result<()> {
+    if let Some(parent) = log_file.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed creating directory {}", parent.display()))?;
+    }
+    let log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file)
+        .with_context(|| format!("Failed opening log file {}", log_file.display()))?;
+
+    let mut args: Vec<OsString> = std::env::args_os()
+        .skip(1)
+        .filter(|a| a != "--daemon")
+        .collect();
+    if !args.iter().any(|a| a == "--pid-file") {
+        args.push("--pid-file".into());
+        args.push(pid_file.into());
+    }
+
+    let mut cmd = Command::new(std::env::current_exe().context("Could not find own binary")?);
+    cmd.args(&args)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // SAFETY: setsid is async-signal-safe and touches no parent state.
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setsid() == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    let mut child = cmd.spawn().context("Failed to start background serve")?;
+
+    tokio::time::sleep(DAEMON_STARTUP_CHECK).await;
+    if let Some(status) = child.try_wait()? {
+        bail!(
+            "Background serve exited immediately ({}); see {}",
+            status,
+            log_file.display()
+        );
+    }
+    println!(
+        "vramsply serve running in the background (pid {})",
+        child.id()
+    );
+    println!("  Log: {}", log_file.display());
+    println!("  PID file: {}", pid_file.display());
+    println!("Stop it with: vramsply stop");
+    Ok(())
+}
+
+/// Removes the PID file when dropped.
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    pub fn create(path: PathBuf) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed creating directory {}", parent.display()))?;
+        }
+        fs::write(&path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("Failed writing PID file {}", path.display()))?;
+        Ok(PidFile(path))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_unit() {
+        let unit = render_unit(&UnitOptions {
+            exec: PathBuf::from("/home/gpu/.local/bin/vramsply"),
+            serve_args: vec![
+                "--model".to_string(),
+                "/models/My Model.gguf".to_string(),
+                "--skip-verify".to_string(),
+            ],
+            env_file: PathBuf::from("/etc/vramsply/env"),
+            system: true,
+            user: Some("gpu".to_string()),
+            stop_timeout_secs: 70,
+        });
+        assert!(unit.contains("Type=notify\n"));
+        assert!(unit.contains("TimeoutStartSec=infinity\n"));
+        assert!(unit.contains("User=gpu\n"));
+        assert!(unit.contains("EnvironmentFile=/etc/vramsply/env\n"));
+        assert!(unit.contains(
+            "ExecStart=/home/gpu/.local/bin/vramsply serve --model \"/models/My Model.gguf\" --skip-verify\n"
+        ));
+        assert!(unit.contains("Restart=on-failure\n"));
+        assert!(unit.contains("WatchdogSec=60\n"));
+        assert!(unit.contains("TimeoutStopSec=70\n"));
+        assert!(unit.contains("WantedBy=multi-user.target\n"));
+    }
+
+    #[test]
+    fn test_quote_arg() {
+        assert_eq!(quote_arg("plain"), "plain");
+        assert_eq!(quote_arg("50%"), "50%%");
+        assert_eq!(quote_arg("a \"b\""), "\"a \\\"b\\\"\"");
+        assert_eq!(quote_arg(""), "\"\"");
+    }
+
+    #[test]
+    fn test_quote_env_value() {
+        assert_eq!(quote_env_value("info"), "\"info\"");
+        assert_eq!(quote_env_value("a b#c"), "\"a b#c\"");
+        assert_eq!(
+            quote_env_value("k=\"v\" $HOME \\ `x`\nnext"),
+            "\"k=\\\"v\\\" \\$HOME \\\\ \\`x\\`\\nnext\""
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_notify_sends_datagram() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let socket = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        send_notify(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0u8; 64];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+}
@@ -0,0 +1,168 @@
+use std::fmt;
+use std::future::Future;
+
+use anyhow::Result;
+
+#[cfg(unix)]
+use anyhow::Context;
+#[cfg(unix)]
+use std::task::{self, Poll};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+/// Listens for the signals that should trigger a graceful shutdown.
+///
+/// On Unix this is SIGINT (Ctrl+C), SIGTERM (`systemctl stop`, `docker stop`)
+/// and SIGQUIT; elsewhere only Ctrl+C. Create it once and call `recv()`
+/// repeatedly so a second signal during shutdown is not lost.
+#[cfg(unix)]
+pub struct ShutdownSignals<S = Signal> {
+    interrupt: S,
+    terminate: S,
+    quit: S,
+}
+
+#[cfg(not(unix))]
+pub struct ShutdownSignals {}
+
+/// Where one kind of signal comes from: the OS, or a channel in tests.
+#[cfg(unix)]
+pub trait SignalSource {
+    fn poll_recv(&mut self, cx: &mut task::Context<'_>) -> Poll<Option<()>>;
+}
+
+#[cfg(unix)]
+impl SignalSource for Signal {
+    fn poll_recv(&mut self, cx: &mut task::Context<'_>) -> Poll<Option<()>> {
+        Signal::poll_recv(self, cx)
+    }
+}
+
+/// Startup was abandoned because a shutdown signal arrived.
+#[derive(Debug)]
+pub struct Interrupted(pub &'static str);
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Received {} during startup", self.0)
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+#[cfg(unix)]
+impl ShutdownSignals {
+    pub fn new() -> Result<Self> {
+        Ok(ShutdownSignals {
+            interrupt: signal(SignalKind::interrupt()).context("Failed to listen for SIGINT")?,
+            terminate: signal(SignalKind::terminate()).context("Failed to listen for SIGTERM")?,
+            quit: signal(SignalKind::quit()).context("Failed to listen for SIGQUIT")?,
+        })
+    }
+}
+
+#[cfg(unix)]
+impl<S: SignalSource> ShutdownSignals<S> {
+    /// Wait for the next shutdown signal, returning its name.
+    pub async fn recv(&mut self) -> &'static str {
+        let ShutdownSignals {
+            interrupt,
+            terminate,
+            quit,
+        } = self;
+        tokio::select! {
+            _ = std::future::poll_fn(|cx| interrupt.poll_recv(cx)) => "SIGINT",
+            _ = std::future::poll_fn(|cx| terminate.poll_recv(cx)) => "SIGTERM",
+            _ = std::future::poll_fn(|cx| quit.poll_recv(cx)) => "SIGQUIT",
+        }
+    }
+
+    /// Run a step of startup, abandoning it with [`Interrupted`] if a shutdown
+    /// signal arrives first.
+    pub async fn interruptible<T>(&mut self, step: impl Future<Output = Result<T>>) -> Result<T> {
+        tokio::select! {
+            result = step => result,
+            name = self.recv() => Err(Interrupted(name).into()),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+impl ShutdownSignals {
+    pub fn new() -> Result<Self> {
+        Ok(ShutdownSignals {})
+    }
+
+    /// Wait for the next shutdown signal, returning its name.
+    pub async fn recv(&mut self) -> &'static str {
+        if tokio::signal::ctrl_c().await.is_err() {
+            // Without a signal handler, never report a signal.
+            std::future::pending::<()>().await;
+        }
+        "Ctrl+C"
+    }
+
+    /// Run a step of startup, abandoning it with [`Interrupted`] if a shutdown
+    /// signal arrives first.
+    pub async fn interruptible<T>(&mut self, step: impl Future<Output = Result<T>>) -> Result<T> {
+        tokio::select! {
+            result = step => result,
+            name = self.recv() => Err(Interrupted(name).into()),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+    impl SignalSource for UnboundedReceiver<()> {
+        fn poll_recv(&mut self, cx: &mut task::Context<'_>) -> Poll<Option<()>> {
+            UnboundedReceiver::poll_recv(self, cx)
+        }
+    }
+
+    /// Signals fed from channels: interrupt, terminate and quit.
+    fn injected() -> (
+        ShutdownSignals<UnboundedReceiver<()>>,
+        [UnboundedSender<()>; 3],
+    ) {
+        let (interrupt_tx, interrupt) = unbounded_channel();
+        let (terminate_tx, terminate) = unbounded_channel();
+        let (quit_tx, quit) = unbounded_channel();
+        let signals = ShutdownSignals {
+            interrupt,
+            terminate,
+            quit,
+        };
+        (signals, [interrupt_tx, terminate_tx, quit_tx])
+    }
+
+    #[tokio::test]
+    async fn test_sigquit_is_a_shutdown_signal() {
+        let (mut signals, [_interrupt, _terminate, quit]) = injected();
+        quit.send(()).unwrap();
+        let name = tokio::time::timeout(Duration::from_secs(5), signals.recv())
+            .await
+            .unwrap();
+        assert_eq!(name, "SIGQUIT");
+    }
+
+    #[tokio::test]
+    async fn test_signal_interrupts_startup_step() {
+        let (mut signals, [_interrupt, terminate, _quit]) = injected();
+        let finished = signals.interruptible(async { Ok(1) }).await.unwrap();
+        assert_eq!(finished, 1);
+
+        terminate.send(()).unwrap();
+        let err = signals
+            .interruptible(std::future::pending::<Result<()>>())
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<Interrupted>().unwrap().0, "SIGTERM");
+    }
+}
@@ -0,0 +1,253 @@
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use axum::http::header;
+use axum::routing::get;
+use axum::Router;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::presence::AgentPresenceStatus;
+
+/// Agent-side Prometheus metrics, served on `VRAM_SUPPLY_METRICS_ADDR`.
+pub struct AgentMetrics {
+    registry: Registry,
+    /// 1 for the current presence status, 0 for the others.
+    pub presence_status: IntGaugeVec,
+    pub transitions: IntCounterVec,
+    pub backend_restarts: IntCounter,
+    pub backend_restart_backoff: prometheus::Gauge,
+    /// Heartbeat and presence sends, by `kind` and `outcome`.
+    pub platform_requests: IntCounterVec,
+    pub platform_request_duration: HistogramVec,
+    pub active_requests: IntGauge,
+    pub verification_duration: Histogram,
+    pub download_bytes: IntCounter,
+    /// 1 while registered with the platform.
+    pub registered: IntGauge,
+    pub registration_attempts: IntCounterVec,
+    /// 1 while the relay tunnel is connected.
+    pub tunnel_connected: IntGauge,
+    pub tunnel_streams: IntGauge,
+    pub tunnel_rejected_streams: IntCounter,
+}
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(registry: &Registry, metric: T) -> T {
+    registry
+        .register(Box::new(metric.clone()))
+        .expect("metric registered twice");
+    metric
+}
+
+impl AgentMetrics {
+    fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("vramsply".to_string()), None)?;
+        let r = &registry;
+        Ok(AgentMetrics {
+            presence_status: register(
+                r,
+                IntGaugeVec::new(
+                    Opts::new("presence_status", "Current presence status (1 = active)"),
+                    &["status"],
+                )?,
+            ),
+            transitions: register(
+                r,
+                IntCounterVec::new(
+                    Opts::new("presence_transitions_total", "Presence status transitions"),
+                    &["to", "cause"],
+                )?,
+            ),
+            backend_restarts: register(
+                r,
+                IntCounter::new(
+                    "backend_restarts_total",
+                    "Times llama-server was restarted after exiting",
+                )?,
+            ),
+            backend_restart_backoff: register(
+                r,
+                prometheus::Gauge::new(
+                    "backend_restart_backoff_seconds",
+                    "Delay before the most recent llama-server restart",
+                )?,
+            ),
+            platform_requests: register(
+                r,
+                IntCounterVec::new(
+                    Opts::new(
+                        "platform_requests_total",
+                        "Heartbeats and presence updates sent",
+                    ),
+                    &["kind", "outcome"],
+                )?,
+            ),
+            platform_request_duration: register(
+                r,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "platform_request_duration_seconds",
+                        "Latency of heartbeats and presence updates",
+                    ),
+                    &["kind"],
+                )?,
+            ),
+            active_requests: register(
+                r,
+                IntGauge::new("active_requests", "Requests llama-server is processing")?,
+            ),
+            verification_duration: register(
+                r,
+                Histogram::with_opts(
+                    HistogramOpts::new(
+                        "model_verification_duration_seconds",
+                        "Time to verify a model's SHA-256",
+                    )
+                    .buckets(vec![0.01, 0.1, 1.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0]),
+                )?,
+            ),
+            download_bytes: register(
+                r,
+                IntCounter::new("model_download_bytes_total", "Model bytes downloaded")?,
+            ),
+            registered: register(
+                r,
+                IntGauge::new("registered", "1 while registered with the platform")?,
+            ),
+            registration_attempts: register(
+                r,
+                IntCounterVec::new(
+                    Opts::new("registration_attempts_total", "Registration requests sent"),
+                    &["outcome"],
+                )?,
+            ),
+            tunnel_connected: register(
+                r,
+                IntGauge::new("tunnel_connected", "1 while the relay tunnel is connected")?,
+            ),
+            tunnel_streams: register(
+                r,
+                IntGauge::new("tunnel_streams", "Requests open over the relay tunnel")?,
+            ),
+            tunnel_rejected_streams: register(
+                r,
+                IntCounter::new(
+                    "tunnel_rejected_streams_total",
+                    "Tunneled requests refused because too many were open",
+                )?,
+            ),
+            registry,
+        })
+    }
+
+    pub fn set_status(&self, status: &AgentPresenceStatus) {
+        for s in AgentPresenceStatus::ALL {
+            self.presence_status
+                .with_label_values(&[s.as_str()])
+                .set((s == *status) as i64);
+        }
+    }
+
+    /// Count a heartbeat or presence send.
+    pub fn observe_platform_request(&self, kind: &str, ok: bool, elapsed: Duration) {
+        let outcome = if ok { "success" } else { "failure" };
+        self.platform_requests
+            .with_label_values(&[kind, outcome])
+            .inc();
+        self.platform_request_duration
+            .with_label_values(&[kind])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// All metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buf = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buf) {
+            tracing::warn!("Failed to encode metrics: {}", e);
+        }
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+static METRICS: LazyLock<AgentMetrics> =
+    LazyLock::new(|| AgentMetrics::new().expect("metric definitions are valid"));
+
+/// The process-wide metrics. Recording is cheap, so callers record whether
+/// or not the endpoint is enabled.
+pub fn metrics() -> &'static AgentMetrics {
+    &METRICS
+}
+
+/// Serve `GET /metrics` on `addr` until `shutdown`.
+pub async fn spawn_server(
+    addr: SocketAddr,
+    shutdown: CancellationToken,
+) -> Result<tokio::task::JoinHandle<()>> {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind metrics endpoint on {}", addr))?;
+    let router = Router::new().route(
+        "/metrics",
+        get(|| async {
+            (
+                [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+                metrics().render(),
+            )
+        }),
+    );
+    Ok(tokio::spawn(async move {
+        let result = axum::serve(listener, router)
+            .with_graceful_shutdown(shutdown.cancelled_owned())
+            .await;
+        if let Err(e) = result {
+            tracing::error!("Metrics endpoint failed: {}", e);
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_status_and_platform_requests() {
+        // A private instance: other tests move the global status around.
+        let metrics = AgentMetrics::new().unwrap();
+        metrics.set_status(&AgentPresenceStatus::Ready);
+        metrics.observe_platform_request("heartbeat", true, Duration::from_millis(20));
+
+        let text = metrics.render();
+        assert!(text.contains("vramsply_presence_status{status=\"ready\"} 1"));
+        assert!(text.contains("vramsply_presence_status{status=\"idle\"} 0"));
+        assert!(text.contains(
+            "vramsply_platform_requests_total{kind=\"heartbeat\",outcome=\"success\"} 1"
+        ));
+        assert!(
+            text.contains("vramsply_platform_request_duration_seconds_count{kind=\"heartbeat\"} 1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let shutdown = CancellationToken::new();
+        let handle = spawn_server(addr, shutdown.clone()).await.unwrap();
+
+        let res = reqwest::get(format!("http://{}/metrics", addr))
+            .await
+            .unwrap();
+        assert!(res.status().is_success());
+        let body = res.text().await.unwrap();
+        assert!(body.contains("# TYPE vramsply_registered gauge"));
+
+        shutdown.cancel();
+        handle.await.unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+//! Shared helpers for unit tests that talk to a mock platform.
+
+use std::path::PathBuf;
+
+use crate::config::{Config, PlatformChannel};
+use crate::identity::AgentIdentity;
+use crate::registration::RegisterRequest;
+
+/// A config pointing at `platform_url` with defaults matching `Config::load()`.
+pub fn config(platform_url: &str) -> Config {
+    Config {
+        platform_url: platform_url.to_string(),
+        public_url: "http://localhost:8080".to_string(),
+        model_dir: PathBuf::from("/nonexistent"),
+        llama_server_path: "llama-server".to_string(),
+        gpu_layers: 99,
+        port: 8080,
+        bind_address: std::net::IpAddr::from([127, 0, 0, 1]),
+        backend_port: 8081,
+        max_concurrent: 1,
+        context_length_offered: 8192,
+        input_price_per_million: 100,
+        output_price_per_million: 200,
+        api_key: "test-key".to_string(),
+        platform_channel: PlatformChannel::Http,
+        drain_timeout_secs: 30,
+        shutdown_timeout_secs: 60,
+        platform_signing_keys: Vec::new(),
+        metrics_addr: None,
+        allow_private_url: false,
+        tunnel: false,
+        tls_cert: None,
+        tls_key: None,
+        acme_domains: Vec::new(),
+        acme_directory: crate::acme::LETS_ENCRYPT_DIRECTORY.to_string(),
+        acme_email: None,
+        acme_ca_cert: None,
+    }
+}
+
+/// What a model registered with `config()`'s defaults offers.
+pub fn offer(model: &str) -> RegisterRequest {
+    RegisterRequest {
+        endpoint_url: "http://localhost:8080".to_string(),
+        model: model.to_string(),
+        max_concurrent: 1,
+        context_length_offered: 8192,
+        input_price_per_million: 100,
+        output_price_per_million: 200,
+        model_sha256: None,
+    }
+}
+
+pub fn identity() -> AgentIdentity {
+    AgentIdentity {
+        agent_uid: "test-agent".to_string(),
+        device_name: "test-host (linux)".to_string(),
+        platform: "linux".to_string(),
+        arch: "x86_64".to_string(),
+        agent_version: "0.0.0".to_string(),
+        public_key: String::new(),
+    }
+}
+
+/// Serve `router` on an ephemeral localhost port, returning its base URL.
+pub async fn serve(router: axum::Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, router).await.unwrap();
+    });
+    format!("http://{}", addr)
+}
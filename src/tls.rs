@@ -0,0 +1,372 @@
+//! TLS for the public listener.
+//!
+//! Certificates come either from files named by `VRAM_SUPPLY_TLS_CERT` and
+//! `VRAM_SUPPLY_TLS_KEY`, which are reloaded when they change, or from ACME
+//! (see [`crate::acme`]). Either way they are served from one [`CertStore`],
+//! which also answers ACME `tls-alpn-01` challenges on the same port.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context, Result};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::Config;
+
+/// ALPN protocol of ACME `tls-alpn-01` validation connections (RFC 8737).
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Handshaken connections waiting for the server to pick them up.
+const ACCEPT_QUEUE: usize = 64;
+
+/// The certificate the public listener presents, plus any ACME challenge
+/// certificates in flight.
+#[derive(Debug, Default)]
+pub struct CertStore {
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+    challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl CertStore {
+    /// Serve `key` for all new connections.
+    pub fn set(&self, key: CertifiedKey) {
+        *self.current.write().expect("cert store lock poisoned") = Some(Arc::new(key));
+    }
+
+    /// Answer `tls-alpn-01` validation for `domain` with `key` until removed.
+    pub fn set_challenge(&self, domain: &str, key: CertifiedKey) {
+        self.challenges
+            .write()
+            .expect("cert store lock poisoned")
+            .insert(domain.to_ascii_lowercase(), Arc::new(key));
+    }
+
+    pub fn remove_challenge(&self, domain: &str) {
+        self.challenges
+            .write()
+            .expect("cert store lock poisoned")
+            .remove(&domain.to_ascii_lowercase());
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let acme = hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN));
+        if acme {
+            let domain = hello.server_name()?.to_ascii_lowercase();
+            return self
+                .challenges
+                .read()
+                .expect("cert store lock poisoned")
+                .get(&domain)
+                .cloned();
+        }
+        self.current
+            .read()
+            .expect("cert store lock poisoned")
+            .clone()
+    }
+}
+
+/// Parse a PEM certificate chain and private key.
+pub fn certified_key(cert_pem: &[u8], key_pem: &[u8]) -> Result<CertifiedKey> {
+    let certs = CertificateDer::pem_slice_iter(cert_pem)
+        .collect::<Result<Vec<_>, _>>()
+        .context("Invalid certificate PEM")?;
+    if certs.is_empty() {
+        bail!("No certificate found");
+    }
+    let key = PrivateKeyDer::from_pem_slice(key_pem).context("Invalid private key PEM")?;
+    let signing_key = rustls::crypto::ring::sign::any_supported_type(&key)
+        .context("Unsupported private key type")?;
+    let certified = CertifiedKey::new(certs, signing_key);
+    certified
+        .keys_match()
+        .context("Private key does not match the certificate")?;
+    Ok(certified)
+}
+
+/// Load a certificate chain and key from PEM files.
+pub fn load_files(cert: &Path, key: &Path) -> Result<CertifiedKey> {
+    let cert_pem =
+        std::fs::read(cert).with_context(|| format!("Failed to read {}", cert.display()))?;
+    let key_pem =
+        std::fs::read(key).with_context(|| format!("Failed to read {}", key.display()))?;
+    certified_key(&cert_pem, &key_pem)
+        .with_context(|| format!("Invalid TLS files {} and {}", cert.display(), key.display()))
+}
+
+/// What the agent needs to know about a certificate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertInfo {
+    /// Expiry, as unix seconds.
+    pub not_after: u64,
+    /// DNS names from the subject alternative name extension.
+    pub dns_names: Vec<String>,
+}
+
+/// Inspect the first certificate in a PEM chain.
+pub fn inspect(cert_pem: &[u8]) -> Result<CertInfo> {
+    let der = CertificateDer::pem_slice_iter(cert_pem)
+        .next()
+        .context("No certificate found")?
+        .context("Invalid certificate PEM")?;
+    let (_, cert) =
+        x509_parser::parse_x509_certificate(&der).context("Invalid X.509 certificate")?;
+    let dns_names = match cert.subject_alternative_name() {
+        Ok(Some(san)) => san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                x509_parser::extensions::GeneralName::DNSName(dns) => Some(dns.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    Ok(CertInfo {
+        not_after: cert.validity().not_after.timestamp().max(0) as u64,
+        dns_names,
+    })
+}
+
+/// Server config resolving certificates from `store`. Offers HTTP/1.1, and
+/// `acme-tls/1` for ACME validation.
+pub fn server_config(store: Arc<CertStore>) -> Result<Arc<ServerConfig>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .context("Failed to configure TLS")?
+        .with_no_client_auth()
+        .with_cert_resolver(store);
+    config.alpn_protocols = vec![b"http/1.1".to_vec(), ACME_TLS_ALPN.to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// A TCP listener that completes TLS handshakes in the background, so a slow
+/// client cannot hold up others, and yields only finished connections.
+pub struct TlsListener {
+    accepted: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, config: Arc<ServerConfig>) -> Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let acceptor = TlsAcceptor::from(config);
+        let (tx, accepted) = mpsc::channel(ACCEPT_QUEUE);
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = tokio::select! {
+                    _ = tx.closed() => break,
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            tracing::debug!("TLS listener accept failed: {}", e);
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            continue;
+                        }
+                    },
+                };
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let handshake =
+                        tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await;
+                    match handshake {
+                        Ok(Ok(stream)) => {
+                            // Validation connections end with the handshake.
+                            if stream.get_ref().1.alpn_protocol() == Some(ACME_TLS_ALPN) {
+                                return;
+                            }
+                            let _ = tx.send((stream, addr)).await;
+                        }
+                        Ok(Err(e)) => tracing::debug!("TLS handshake from {} failed: {}", addr, e),
+                        Err(_) => tracing::debug!("TLS handshake from {} timed out", addr),
+                    }
+                });
+            }
+        });
+        Ok(TlsListener {
+            accepted,
+            local_addr,
+        })
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.accepted.recv().await {
+            Some(accepted) => accepted,
+            // The accept loop only stops once this listener is dropped.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// Whether the public listener speaks TLS under `config`.
+pub fn enabled(config: &Config) -> bool {
+    config.tls_cert.is_some() || !config.acme_domains.is_empty()
+}
+
+/// A file's modification time and length, to notice it being replaced.
+type FileStamp = Option<(SystemTime, u64)>;
+
+/// Certificate files, and how they looked when last loaded.
+struct WatchedFiles {
+    cert: PathBuf,
+    key: PathBuf,
+    seen: (FileStamp, FileStamp),
+}
+
+fn modified(path: &Path) -> FileStamp {
+    let meta = std::fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+impl WatchedFiles {
+    fn new(cert: PathBuf, key: PathBuf) -> Self {
+        let seen = (modified(&cert), modified(&key));
+        WatchedFiles { cert, key, seen }
+    }
+
+    /// Load the pair into `store` if either file changed. A pair that fails
+    /// to load is logged and the previous certificate kept, so a half-written
+    /// renewal does not take the node down.
+    fn reload_if_changed(&mut self, store: &CertStore) {
+        let current = (modified(&self.cert), modified(&self.key));
+        if current == self.seen {
+            return;
+        }
+        match load_files(&self.cert, &self.key) {
+            Ok(certified) => {
+                store.set(certified);
+                self.seen = current;
+                tracing::info!("Reloaded TLS certificate from {}", self.cert.display());
+            }
+            Err(e) => tracing::warn!("Keeping previous TLS certificate: {:#}", e),
+        }
+    }
+}
+
+/// Spawn a task that reloads `cert` and `key` into `store` whenever either
+/// file changes.
+pub fn spawn_reload(
+    store: Arc<CertStore>,
+    cert: PathBuf,
+    key: PathBuf,
+    shutdown: CancellationToken,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut files = WatchedFiles::new(cert, key);
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = interval.tick() => files.reload_if_changed(&store),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::routing::get;
+    use axum::Router;
+
+    /// A self-signed certificate and key for `localhost`, as PEM.
+    fn self_signed(name: &str) -> (String, String) {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        let cert = params.self_signed(&key).unwrap();
+        (cert.pem(), key.serialize_pem())
+    }
+
+    fn client(cert_pem: &str) -> reqwest::Client {
+        reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(cert_pem.as_bytes()).unwrap())
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_serves_and_reloads_certificate_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        let (cert, key) = self_signed("first");
+        std::fs::write(&cert_path, &cert).unwrap();
+        std::fs::write(&key_path, &key).unwrap();
+
+        let store = Arc::new(CertStore::default());
+        store.set(load_files(&cert_path, &key_path).unwrap());
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = tcp.local_addr().unwrap().port();
+        let listener = TlsListener::new(tcp, server_config(Arc::clone(&store)).unwrap()).unwrap();
+        let router = Router::new().route("/health", get(|| async { "ok" }));
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        let mut files = WatchedFiles::new(cert_path.clone(), key_path.clone());
+
+        let url = format!("https://localhost:{}/health", port);
+        let body = client(&cert).get(&url).send().await.unwrap().text().await;
+        assert_eq!(body.unwrap(), "ok");
+
+        // A mismatched pair is ignored; the next complete pair is served.
+        let (new_cert, new_key) = self_signed("second");
+        std::fs::write(&cert_path, &new_cert).unwrap();
+        files.reload_if_changed(&store);
+        assert!(client(&cert).get(&url).send().await.is_ok());
+        std::fs::write(&key_path, &new_key).unwrap();
+        files.reload_if_changed(&store);
+        assert!(client(&cert).get(&url).send().await.is_err());
+        assert!(client(&new_cert).get(&url).send().await.is_ok());
+    }
+
+    #[test]
+    fn test_inspect() {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(vec![
+            "localhost".to_string(),
+            "node.example.com".to_string(),
+        ])
+        .unwrap();
+        params.not_after = rcgen::date_time_ymd(2030, 1, 2);
+        let cert = params.self_signed(&key).unwrap();
+        assert_eq!(
+            inspect(cert.pem().as_bytes()).unwrap(),
+            CertInfo {
+                not_after: 1_893_542_400,
+                dns_names: vec!["localhost".to_string(), "node.example.com".to_string()],
+            }
+        );
+        assert!(certified_key(cert.pem().as_bytes(), b"").is_err());
+    }
+}
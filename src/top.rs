@@ -0,0 +1,459 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph, Row, Table, Wrap};
+use ratatui::Frame;
+use tokio::sync::mpsc;
+
+use crate::control::Dashboard;
+use crate::presence::AgentPresenceStatus;
+use crate::transitions::format_timestamp;
+
+/// A dashboard request that takes longer than this counts as a failed refresh.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often the input thread checks whether `top` has exited.
+const INPUT_POLL: Duration = Duration::from_millis(200);
+
+/// What the screen shows: the last dashboard fetched, and why the latest
+/// refresh failed, if it did.
+struct View {
+    dashboard: Option<Dashboard>,
+    error: Option<String>,
+}
+
+async fn fetch(path: &Path) -> Result<Dashboard> {
+    let value = tokio::time::timeout(
+        FETCH_TIMEOUT,
+        crate::control::request(path, "dashboard", serde_json::Value::Null),
+    )
+    .await
+    .context("Timed out waiting for the agent")??;
+    serde_json::from_value(value).context("Invalid dashboard response")
+}
+
+/// Run the live dashboard until `q`, `Esc` or Ctrl+C.
+pub async fn run(path: PathBuf, interval: Duration) -> Result<()> {
+    // Fail before taking over the terminal if nothing is serving.
+    let mut view = View {
+        dashboard: Some(fetch(&path).await?),
+        error: None,
+    };
+
+    let (quit_tx, mut quit_rx) = mpsc::unbounded_channel();
+    // crossterm's event reads block, so they get their own thread.
+    std::thread::spawn(move || loop {
+        if quit_tx.is_closed() {
+            break;
+        }
+        match event::poll(INPUT_POLL) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(_) => break,
+        }
+        if let Ok(Event::Key(key)) = event::read() {
+            let ctrl_c =
+                key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c');
+            if key.kind == KeyEventKind::Press
+                && (ctrl_c || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc))
+            {
+                let _ = quit_tx.send(());
+                break;
+            }
+        }
+    });
+
+    let mut terminal = ratatui::init();
+    let mut ticker = tokio::time::interval(interval);
+    let result = loop {
+        if let Err(e) = terminal.draw(|frame| draw(frame, &view, crate::outbox::unix_now())) {
+            break Err(e.into());
+        }
+        tokio::select! {
+            _ = quit_rx.recv() => break Ok(()),
+            _ = ticker.tick() => {}
+        }
+        match fetch(&path).await {
+            Ok(dashboard) => {
+                view.dashboard = Some(dashboard);
+                view.error = None;
+            }
+            Err(e) => view.error = Some(format!("{:#}", e)),
+        }
+    };
+    ratatui::restore();
+    result
+}
+
+fn status_color(status: &AgentPresenceStatus) -> Color {
+    match status {
+        AgentPresenceStatus::Ready | AgentPresenceStatus::Serving => Color::Green,
+        AgentPresenceStatus::LoadingModel | AgentPresenceStatus::Draining => Color::Yellow,
+        AgentPresenceStatus::Degraded => Color::LightRed,
+        AgentPresenceStatus::Error => Color::Red,
+        AgentPresenceStatus::Idle | AgentPresenceStatus::Unavailable => Color::Gray,
+    }
+}
+
+fn format_uptime(secs: u64) -> String {
+    let (days, hours, mins) = (secs / 86_400, secs % 86_400 / 3600, secs % 3600 / 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, mins)
+    } else {
+        format!("{}m {}s", mins, secs % 60)
+    }
+}
+
+fn dollars(cents: f64) -> String {
+    format!("${:.2}", cents / 100.0)
+}
+
+fn draw(frame: &mut Frame, view: &View, now: u64) {
+    let [header, slots, requests, bottom, footer] = Layout::vertical([
+        Constraint::Length(6),
+        Constraint::Length(6),
+        Constraint::Min(6),
+        Constraint::Length(12),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let mut footer_line = vec![
+        Span::styled(" q ", Style::new().add_modifier(Modifier::REVERSED)),
+        Span::raw(" quit"),
+    ];
+    if let Some(error) = &view.error {
+        footer_line.push(Span::styled(
+            format!("   Refresh failed: {}", error),
+            Style::new().fg(Color::Red),
+        ));
+    }
+    frame.render_widget(Line::from(footer_line), footer);
+
+    let Some(dashboard) = &view.dashboard else {
+        return;
+    };
+    draw_header(frame, header, dashboard, now);
+    draw_slots(frame, slots, dashboard);
+    draw_requests(frame, requests, dashboard);
+
+    let [errors, log] =
+        Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(bottom);
+    draw_errors(frame, errors, dashboard);
+    draw_log(frame, log, dashboard);
+}
+
+fn draw_header(frame: &mut Frame, area: Rect, dashboard: &Dashboard, now: u64) {
+    let status = &dashboard.status;
+    let label = Style::new().fg(Color::DarkGray);
+    let mut state = vec![
+        Span::styled("Status ", label),
+        Span::styled(
+            status.status.as_str(),
+            Style::new()
+                .fg(status_color(&status.status))
+                .add_modifier(Modifier::BOLD),
+        ),
+        Span::styled("   Model ", label),
+        Span::raw(status.model_label()),
+        Span::styled("   Provider ", label),
+        Span::raw(status.provider_label()),
+    ];
+    if let Some(code) = &status.error_code {
+        state.push(Span::styled(
+            format!(
+                "   [{}] {}",
+                code,
+                status.error_message.as_deref().unwrap_or("")
+            ),
+            Style::new().fg(Color::Red),
+        ));
+    }
+
+    let process = Line::from(vec![
+        Span::styled("Up ", label),
+        Span::raw(format_uptime(now.saturating_sub(status.started_at))),
+        Span::styled("   Agent ", label),
+        Span::raw(format!("pid {}", status.pid)),
+        Span::styled("   llama-server ", label),
+        Span::raw(status.backend_label()),
+        Span::styled("   Platform ", label),
+        if status.platform_connected {
+            Span::styled("connected", Style::new().fg(Color::Green))
+        } else {
+            Span::styled("unreachable", Style::new().fg(Color::Red))
+        },
+    ]);
+
+    let throughput = match &status.utilization {
+        Some(u) => {
+            let kv = u
+                .kv_cache_usage_pct
+                .map(|pct| format!("   KV cache {:.0}%", pct))
+                .unwrap_or_default();
+            format!(
+                "Prompt {:.1} tok/s   Generation {:.1} tok/s   Active {}   Queued {}{}",
+                u.prompt_tokens_per_sec,
+                u.generation_tokens_per_sec,
+                status.active_requests,
+                u.queue_depth,
+                kv
+            )
+        }
+        None => format!(
+            "Active {}   (no llama-server metrics yet)",
+            status.active_requests
+        ),
+    };
+
+    let hour = &dashboard.last_hour;
+    let earnings = format!(
+        "Last hour: {} requests   {} prompt + {} completion tokens   earning {}/h",
+        hour.requests,
+        hour.prompt_tokens,
+        hour.completion_tokens,
+        dollars(hour.earned_cents)
+    );
+
+    let text = vec![
+        Line::from(state),
+        process,
+        Line::raw(throughput),
+        Line::raw(earnings),
+    ];
+    frame.render_widget(
+        Paragraph::new(text).block(Block::bordered().title(" vramsply top ")),
+        area,
+    );
+}
+
+fn draw_slots(frame: &mut Frame, area: Rect, dashboard: &Dashboard) {
+    let slots = dashboard
+        .status
+        .utilization
+        .as_ref()
+        .map(|u| u.slots.as_slice())
+        .unwrap_or_default();
+    let rows = slots.iter().map(|slot| {
+        let used = slot
+            .n_ctx_used
+            .map(|n| format!("{}/{}", n, slot.n_ctx))
+            .unwrap_or_else(|| format!("?/{}", slot.n_ctx));
+        let (state, style) = if slot.is_processing {
+            ("processing", Style::new().fg(Color::Green))
+        } else {
+            ("idle", Style::new().fg(Color::DarkGray))
+        };
+        Row::new(vec![slot.id.to_string(), state.to_string(), used]).style(style)
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Length(6),
+            Constraint::Length(12),
+            Constraint::Min(12),
+        ],
+    )
+    .header(
+        Row::new(vec!["SLOT", "STATE", "CONTEXT"]).style(Style::new().add_modifier(Modifier::BOLD)),
+    )
+    .block(Block::bordered().title(" Slots "));
+    frame.render_widget(table, area);
+}
+
+fn draw_requests(frame: &mut Frame, area: Rect, dashboard: &Dashboard) {
+    // Newest first.
+    let rows = dashboard.recent_requests.iter().rev().map(|r| {
+        let style = if (200..300).contains(&r.status) {
+            Style::new()
+        } else {
+            Style::new().fg(Color::Red)
+        };
+        Row::new(vec![
+            format_timestamp(r.started_at)[11..19].to_string(),
+            r.request_id.clone(),
+            r.status.to_string(),
+            r.prompt_tokens.to_string(),
+            r.completion_tokens.to_string(),
+            format!("{} ms", r.latency_ms),
+            dollars(r.earnings_cents()),
+        ])
+        .style(style)
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Length(8),
+            Constraint::Min(20),
+            Constraint::Length(6),
+            Constraint::Length(9),
+            Constraint::Length(9),
+            Constraint::Length(10),
+            Constraint::Length(8),
+        ],
+    )
+    .header(
+        Row::new(vec![
+            "TIME", "REQUEST", "STATUS", "PROMPT", "COMPL", "LATENCY", "EARNED",
+        ])
+        .style(Style::new().add_modifier(Modifier::BOLD)),
+    )
+    .block(Block::bordered().title(" Recent requests "));
+    frame.render_widget(table, area);
+}
+
+fn draw_errors(frame: &mut Frame, area: Rect, dashboard: &Dashboard) {
+    let lines: Vec<Line> = dashboard
+        .status
+        .recent_errors
+        .iter()
+        .rev()
+        .map(|e| {
+            Line::from(vec![
+                Span::styled(
+                    format!("{} ", &format_timestamp(e.timestamp)[5..19]),
+                    Style::new().fg(Color::DarkGray),
+                ),
+                Span::styled(
+                    format!("[{}] ", e.error_code.as_deref().unwrap_or("")),
+                    Style::new().fg(Color::Red),
+                ),
+                Span::raw(e.error_message.clone().unwrap_or_default()),
+            ])
+        })
+        .collect();
+    frame.render_widget(
+        Paragraph::new(lines)
+            .wrap(Wrap { trim: false })
+            .block(Block::bordered().title(" Errors ")),
+        area,
+    );
+}
+
+fn draw_log(frame: &mut Frame, area: Rect, dashboard: &Dashboard) {
+    // Show the lines that fit, ending at the newest.
+    let visible = area.height.saturating_sub(2) as usize;
+    let skip = dashboard.backend_log.len().saturating_sub(visible);
+    let lines: Vec<Line> = dashboard.backend_log[skip..]
+        .iter()
+        .map(|l| Line::raw(l.as_str()))
+        .collect();
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(" llama-server ")),
+        area,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    use crate::backend::metrics::{SlotUsage, UtilizationSummary};
+    use crate::control::{HourSummary, ServeStatus};
+    use crate::transitions::TransitionEvent;
+    use crate::usage::UsageRecord;
+
+    #[test]
+    fn test_draw_dashboard() {
+        let started_at = 1_760_000_000;
+        let dashboard = Dashboard {
+            status: ServeStatus {
+                pid: 4242,
+                started_at,
+                status: AgentPresenceStatus::Serving,
+                model: Some("llama-3".to_string()),
+                provider_id: Some("prov-1".to_string()),
+                backend_pid: Some(4343),
+                active_requests: 1,
+                error_code: None,
+                error_message: None,
+                platform_connected: true,
+                utilization: Some(UtilizationSummary {
+                    window_secs: 60,
+                    prompt_tokens_per_sec: 512.0,
+                    generation_tokens_per_sec: 42.5,
+                    requests_processing: 1,
+                    queue_depth: 0,
+                    kv_cache_usage_pct: Some(37.0),
+                    slots: vec![SlotUsage {
+                        id: 0,
+                        n_ctx: 8192,
+                        n_ctx_used: Some(1024),
+                        is_processing: true,
+                    }],
+                }),
+                recent_errors: vec![TransitionEvent {
+                    timestamp: started_at + 60,
+                    from: AgentPresenceStatus::Ready,
+                    to: AgentPresenceStatus::Degraded,
+                    cause: "report_degraded".to_string(),
+                    error_code: Some("llama_stopped".to_string()),
+                    error_message: Some("llama-server process stopped".to_string()),
+                    model: None,
+                }],
+                models: Vec::new(),
+            },
+            last_hour: HourSummary {
+                requests: 12,
+                prompt_tokens: 3000,
+                completion_tokens: 9000,
+                earned_cents: 150.0,
+            },
+            recent_requests: vec![UsageRecord {
+                request_id: "req-abc".to_string(),
+                client_request_id: None,
+                model: "llama-3".to_string(),
+                started_at: started_at + 120,
+                latency_ms: 830,
+                status: 200,
+                prompt_tokens: 250,
+                completion_tokens: 750,
+                input_price_per_million: 100,
+                output_price_per_million: 200,
+            }],
+            backend_log: vec!["slot launch_slot_: id 0 | task 7".to_string()],
+        };
+        let view = View {
+            dashboard: Some(dashboard),
+            error: Some("Timed out waiting for the agent".to_string()),
+        };
+
+        let mut terminal = Terminal::new(TestBackend::new(120, 40)).unwrap();
+        terminal
+            .draw(|frame| draw(frame, &view, started_at + 3_725))
+            .unwrap();
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+
+        for expected in [
+            "serving",
+            "prov-1",
+            "Up 1h 2m",
+            "Generation 42.5 tok/s",
+            "KV cache 37%",
+            "earning $1.50/h",
+            "1024/8192",
+            "req-abc",
+            "830 ms",
+            "[llama_stopped]",
+            "launch_slot_",
+            "Refresh failed: Timed out",
+        ] {
+            assert!(screen.contains(expected), "missing {:?}", expected);
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::presence::AgentPresenceStatus;
+
+/// Number of transitions kept in memory.
+const HISTORY_CAPACITY: usize = 100;
+/// Size at which the event log is rotated to `<log>.1`, replacing any
+/// earlier rotation.
+const LOG_ROTATE_BYTES: u64 = 1024 * 1024;
+
+/// A single presence status change.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TransitionEvent {
+    pub timestamp: u64,
+    pub from: AgentPresenceStatus,
+    pub to: AgentPresenceStatus,
+    /// What triggered the change, e.g. `model_loaded` or `report_degraded`.
+    pub cause: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_message: Option<String>,
+    /// The served model that changed, or none for the agent as a whole.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+impl TransitionEvent {
+    pub fn new(
+        from: AgentPresenceStatus,
+        to: AgentPresenceStatus,
+        cause: &str,
+        error_code: Option<&str>,
+        error_message: Option<&str>,
+    ) -> Self {
+        TransitionEvent {
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            from,
+            to,
+            cause: cause.to_string(),
+            error_code: error_code.map(str::to_string),
+            error_message: error_message.map(str::to_string),
+            model: None,
+        }
+    }
+
+    /// Attribute the change to the served model `model`.
+    pub fn for_model(mut self, model: &str) -> Self {
+        self.model = Some(model.to_string());
+        self
+    }
+}
+
+/// Bounded in-memory history of transitions, mirrored to a JSONL event log.
+#[derive(Debug)]
+pub struct TransitionHistory {
+    events: VecDeque<TransitionEvent>,
+    log: Option<EventLog>,
+}
+
+impl TransitionHistory {
+    /// Create a history. With `log_path` set every event is also appended to
+    /// that file.
+    pub fn new(log_path: Option<PathBuf>) -> Self {
+        TransitionHistory {
+            events: VecDeque::with_capacity(HISTORY_CAPACITY),
+            log: log_path.map(EventLog::spawn),
+        }
+    }
+
+    /// Record an event. Failing to write the log is logged, never fatal.
+    pub fn record(&mut self, event: TransitionEvent) {
+        tracing::debug!(
+            "Presence {:?} → {:?} ({})",
+            event.from,
+            event.to,
+            event.cause
+        );
+        if let Some(log) = &self.log {
+            log.append(event.clone());
+        }
+        if self.events.len() == HISTORY_CAPACITY {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// The most recent `limit` events, oldest first.
+    pub fn recent(&self, limit: usize) -> Vec<TransitionEvent> {
+        let skip = self.events.len().saturating_sub(limit);
+        self.events.iter().skip(skip).cloned().collect()
+    }
+}
+
+/// Appends events to the log file on a thread of its own, so recording a
+/// transition (often under the presence lock) never waits on the disk.
+#[derive(Debug)]
+struct EventLog {
+    events: Option<mpsc::Sender<TransitionEvent>>,
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+impl EventLog {
+    fn spawn(path: PathBuf) -> Self {
+        let (tx, rx) = mpsc::channel::<TransitionEvent>();
+        let writer = thread::Builder::new()
+            .name("presence-log".to_string())
+            .spawn(move || {
+                for event in rx {
+                    if let Err(e) = append_event(&path, &event) {
+                        tracing::warn!("Failed to write presence event log: {:#}", e);
+                    }
+                }
+            });
+        match writer {
+            Ok(writer) => EventLog {
+                events: Some(tx),
+                writer: Some(writer),
+            },
+            Err(e) => {
+                tracing::warn!("Failed to start presence event log writer: {}", e);
+                EventLog {
+                    events: None,
+                    writer: None,
+                }
+            }
+        }
+    }
+
+    fn append(&self, event: TransitionEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
+        }
+    }
+}
+
+impl Drop for EventLog {
+    /// Finish writing queued events before the history goes away.
+    fn drop(&mut self) {
+        self.events.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Default location of the presence event log.
+pub fn log_path() -> Result<PathBuf> {
+    Ok(crate::config::state_dir()?.join("presence.jsonl"))
+}
+
+/// Where `path` is moved once it reaches `LOG_ROTATE_BYTES`.
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".1");
+    PathBuf::from(name)
+}
+
+fn append_event(path: &Path, event: &TransitionEvent) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed creating directory {}", parent.display()))?;
+    }
+    if fs::metadata(path).is_ok_and(|m| m.len() >= LOG_ROTATE_BYTES) {
+        fs::rename(path, rotated_path(path))
+            .with_context(|| format!("Failed rotating event log {}", path.display()))?;
+    }
+    let mut line = serde_json::to_string(event)?;
+    line.push('\n');
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed opening event log {}", path.display()))?;
+    file.write_all(line.as_bytes())
+        .with_context(|| format!("Failed writing event log {}", path.display()))?;
+    Ok(())
+}
+
+/// Read the last `limit` events from a log file and its rotation, oldest
+/// first. Lines that do not parse (e.g. a torn final write) are skipped.
+pub fn read_log(path: &Path, limit: usize) -> Result<Vec<TransitionEvent>> {
+    let mut events: Vec<TransitionEvent> = Vec::new();
+    for file in [rotated_path(path), path.to_path_buf()] {
+        if !file.exists() {
+            continue;
+        }
+        let contents = fs::read_to_string(&file)
+            .with_context(|| format!("Failed reading event log {}", file.display()))?;
+        events.extend(
+            contents
+                .lines()
+                .filter_map(|line| serde_json::from_str::<TransitionEvent>(line).ok()),
+        );
+    }
+    let skip = events.len().saturating_sub(limit);
+    Ok(events.into_iter().skip(skip).collect())
+}
+
+/// Format a unix timestamp as `YYYY-MM-DD HH:MM:SS UTC`.
+pub fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+
+    // Civil-from-days (Howard Hinnant), valid for all dates after 1970.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use AgentPresenceStatus::*;
+
+    #[test]
+    fn test_history_is_bounded_and_logged() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("presence.jsonl");
+        let mut history = TransitionHistory::new(Some(path.clone()));
+
+        for _ in 0..HISTORY_CAPACITY + 5 {
+            history.record(TransitionEvent::new(
+                Ready,
+                Serving,
+                "active_requests",
+                None,
+                None,
+            ));
+        }
+        history.record(TransitionEvent::new(
+            Serving,
+            Degraded,
+            "report_degraded",
+            Some("llama_stopped"),
+            Some("llama-server process stopped unexpectedly"),
+        ));
+
+        assert_eq!(history.recent(usize::MAX).len(), HISTORY_CAPACITY);
+        let last = history.recent(1);
+        assert_eq!(last[0].to, Degraded);
+        assert_eq!(last[0].error_code.as_deref(), Some("llama_stopped"));
+        // Dropping the history waits for the log writer to catch up.
+        drop(history);
+
+        let logged = read_log(&path, 2).unwrap();
+        assert_eq!(logged.len(), 2);
+        assert_eq!(logged[0].to, Serving);
+        assert_eq!(logged[1], last[0]);
+        assert_eq!(
+            read_log(&path, usize::MAX).unwrap().len(),
+            HISTORY_CAPACITY + 6
+        );
+    }
+
+    #[test]
+    fn test_read_log_skips_bad_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("presence.jsonl");
+        assert!(read_log(&path, 10).unwrap().is_empty());
+
+        fs::write(
+            &path,
+            "{\"timestamp\":1,\"from\":\"idle\",\"to\":\"loading_model\",\"cause\":\"startup\"}\n{\"timest",
+        )
+        .unwrap();
+        let events = read_log(&path, 10).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].to, LoadingModel);
+    }
+
+    #[test]
+    fn test_log_is_rotated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("presence.jsonl");
+        fs::write(&path, " ".repeat(LOG_ROTATE_BYTES as usize)).unwrap();
+        fs::write(
+            rotated_path(&path),
+            "{\"timestamp\":1,\"from\":\"idle\",\"to\":\"loading_model\",\"cause\":\"startup\"}\n",
+        )
+        .unwrap();
+
+        let event = TransitionEvent::new(LoadingModel, Ready, "model_loaded", None, None);
+        append_event(&path, &event).unwrap();
+        assert!(fs::metadata(&path).unwrap().len() < LOG_ROTATE_BYTES);
+        // The earlier rotation is replaced by the full log.
+        assert_eq!(read_log(&path, usize::MAX).unwrap(), vec![event]);
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(1_709_260_245), "2024-03-01 02:30:45 UTC");
+    }
+}
@@ -0,0 +1,695 @@
+//! Outbound tunnel to a platform relay, for nodes without a public address.
+//!
+//! The agent keeps a WebSocket open to `/v1/agents/tunnel`. The relay greets
+//! it with the URL it accepts requests on for this agent, then forwards each
+//! inference request as a numbered stream of frames. Streams are replayed
+//! against the local proxy, so tunneled requests are authorized and metered
+//! exactly like direct ones, and the response is streamed back the same way.
+//!
+//! Every frame is JSON text:
+//!
+//! ```text
+//! relay → agent  {"type":"hello","url":"https://relay.vram.supply/a/..."}
+//!                {"type":"request","id":1,"method":"POST","path":"/v1/chat/completions","headers":[["content-type","application/json"]]}
+//!                {"type":"body","id":1,"data":"<base64>"}
+//!                {"type":"end","id":1}
+//!                {"type":"cancel","id":1}
+//! agent → relay  {"type":"response","id":1,"status":200,"headers":[...]}
+//!                {"type":"body","id":1,"data":"<base64>"}
+//!                {"type":"end","id":1}            (or with "error":"...")
+//!                {"type":"credit","id":1,"frames":8}
+//! ```
+//!
+//! Backpressure: each stream may have `REQUEST_BODY_QUEUE` request body
+//! frames in flight. The agent grants more with `credit` as its stream
+//! consumes them, so a slow request holds back only its own body, never the
+//! other streams on the socket; a stream that overruns its credit is failed.
+//! Outbound frames go through a bounded queue, and streams beyond
+//! `MAX_STREAMS_PER_SLOT` per offered slot are answered `503` straight away
+//! for the relay to route elsewhere.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::Config;
+use crate::connection::websocket_url;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const INITIAL_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+/// Open streams allowed per concurrent request offered to the platform.
+const MAX_STREAMS_PER_SLOT: usize = 4;
+/// Request body frames the relay may send per stream ahead of credit.
+const REQUEST_BODY_QUEUE: usize = 16;
+/// Body frames consumed before the agent grants the relay that many more.
+const CREDIT_BATCH: usize = REQUEST_BODY_QUEUE / 2;
+/// Frames buffered for the relay before response streams are made to wait.
+const OUTBOUND_QUEUE: usize = 64;
+/// Largest response body chunk sent in one frame.
+const MAX_CHUNK: usize = 64 * 1024;
+
+type SocketStream =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Frames received from the relay. Unknown types are ignored.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RelayFrame {
+    /// The URL the relay accepts requests on for this agent.
+    Hello {
+        url: String,
+    },
+    Request {
+        id: u64,
+        method: String,
+        path: String,
+        #[serde(default)]
+        headers: Vec<(String, String)>,
+    },
+    Body {
+        id: u64,
+        data: String,
+    },
+    /// The request body is complete.
+    End {
+        id: u64,
+    },
+    /// The client went away; stop working on the stream.
+    Cancel {
+        id: u64,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+/// Frames sent to the relay.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AgentFrame {
+    Response {
+        id: u64,
+        status: u16,
+        headers: Vec<(String, String)>,
+    },
+    Body {
+        id: u64,
+        data: String,
+    },
+    /// The response is complete, or failed with `error`.
+    End {
+        id: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// The relay may send `frames` more request body frames on the stream.
+    Credit {
+        id: u64,
+        frames: usize,
+    },
+}
+
+impl AgentFrame {
+    fn message(&self) -> Message {
+        Message::text(serde_json::to_string(self).expect("tunnel frame serializes"))
+    }
+}
+
+/// Keeps the relay tunnel open and serves the streams it carries.
+pub struct TunnelClient {
+    /// Talks to the local proxy; never follows redirects, which belong to the
+    /// relay's client.
+    proxy: reqwest::Client,
+    proxy_url: String,
+    config: Config,
+    token: Arc<Mutex<String>>,
+    agent_uid: String,
+    max_streams: usize,
+    url: watch::Sender<Option<String>>,
+}
+
+impl TunnelClient {
+    /// Create a client that forwards to the proxy listening on `proxy_addr`.
+    /// The receiver yields the relay-issued URL each time it changes.
+    pub fn new(
+        config: Config,
+        token: Arc<Mutex<String>>,
+        agent_uid: String,
+        proxy_addr: SocketAddr,
+    ) -> Result<(Self, watch::Receiver<Option<String>>)> {
+        let proxy = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .context("Failed to build tunnel HTTP client")?;
+        // A proxy bound to every interface is reached over loopback.
+        let ip = if proxy_addr.ip().is_unspecified() {
+            std::net::IpAddr::from([127, 0, 0, 1])
+        } else {
+            proxy_addr.ip()
+        };
+        let (url, url_rx) = watch::channel(None);
+        let max_streams = config.max_concurrent as usize * MAX_STREAMS_PER_SLOT;
+        Ok((
+            TunnelClient {
+                proxy,
+                proxy_url: format!("http://{}", SocketAddr::new(ip, proxy_addr.port())),
+                config,
+                token,
+                agent_uid,
+                max_streams,
+                url,
+            },
+            url_rx,
+        ))
+    }
+
+    /// Keep the tunnel connected until `shutdown`, reconnecting with
+    /// exponential backoff. Streams in flight when the tunnel drops are lost.
+    pub fn spawn(self, shutdown: CancellationToken) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut backoff = INITIAL_RECONNECT_BACKOFF;
+            loop {
+                match self.connect().await {
+                    Ok(stream) => {
+                        backoff = INITIAL_RECONNECT_BACKOFF;
+                        tracing::info!("Connected to platform relay");
+                        crate::telemetry::metrics().tunnel_connected.set(1);
+                        self.run(stream, &shutdown).await;
+                        crate::telemetry::metrics().tunnel_connected.set(0);
+                        if shutdown.is_cancelled() {
+                            break;
+                        }
+                        tracing::warn!("Relay tunnel disconnected, reconnecting");
+                    }
+                    Err(e) => tracing::warn!("Relay tunnel unavailable: {:#}", e),
+                }
+
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = tokio::time::sleep(backoff) => {}
+                }
+                backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+            }
+        })
+    }
+
+    async fn connect(&self) -> Result<SocketStream> {
+        let url = format!(
+            "{}?agent_uid={}",
+            websocket_url(&self.config.platform_url, "/v1/agents/tunnel")?,
+            self.agent_uid
+        );
+        let mut request = url
+            .as_str()
+            .into_client_request()
+            .with_context(|| format!("Invalid relay URL {}", url))?;
+        let current_token = self.token.lock().await.clone();
+        request.headers_mut().insert(
+            "Authorization",
+            format!("Bearer {}", current_token)
+                .parse()
+                .context("API key is not a valid header value")?,
+        );
+        let (stream, _) =
+            tokio::time::timeout(CONNECT_TIMEOUT, tokio_tungstenite::connect_async(request))
+                .await
+                .with_context(|| format!("Timed out connecting to {}", url))?
+                .with_context(|| format!("Failed to connect to {}", url))?;
+        Ok(stream)
+    }
+
+    /// Serve streams from one connection until it closes or `shutdown`.
+    async fn run(&self, stream: SocketStream, shutdown: &CancellationToken) {
+        let (mut sink, mut source) = stream.split();
+        let (out_tx, mut out_rx) = mpsc::channel::<Message>(OUTBOUND_QUEUE);
+        let writer = tokio::spawn(async move {
+            while let Some(msg) = out_rx.recv().await {
+                if let Err(e) = sink.send(msg).await {
+                    tracing::debug!("Relay tunnel write failed: {}", e);
+                    break;
+                }
+            }
+            let _ = sink.send(Message::Close(None)).await;
+        });
+
+        let (done_tx, mut done_rx) = mpsc::unbounded_channel::<u64>();
+        let mut streams: HashMap<u64, Stream> = HashMap::new();
+        loop {
+            let incoming = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                Some(id) = done_rx.recv() => {
+                    streams.remove(&id);
+                    crate::telemetry::metrics().tunnel_streams.set(streams.len() as i64);
+                    continue;
+                }
+                incoming = source.next() => incoming,
+            };
+            let text = match incoming {
+                Some(Ok(Message::Text(text))) => text,
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    tracing::debug!("Relay tunnel read failed: {}", e);
+                    break;
+                }
+            };
+            let frame = match serde_json::from_str::<RelayFrame>(&text) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    tracing::warn!("Malformed relay frame: {}", e);
+                    continue;
+                }
+            };
+            match frame {
+                RelayFrame::Hello { url } => {
+                    tracing::info!("Relay is accepting requests at {}", url);
+                    self.url.send_if_modified(|current| {
+                        let changed = current.as_deref() != Some(url.as_str());
+                        *current = Some(url);
+                        changed
+                    });
+                }
+                RelayFrame::Request {
+                    id,
+                    method,
+                    path,
+                    headers,
+                } => {
+                    if streams.len() >= self.max_streams {
+                        crate::telemetry::metrics().tunnel_rejected_streams.inc();
+                        tracing::debug!("Refusing tunnel stream {}: {} open", id, streams.len());
+                        let out = out_tx.clone();
+                        tokio::spawn(async move {
+                            let _ = out.send(busy_response(id).message()).await;
+                            let _ = out
+                                .send(AgentFrame::End { id, error: None }.message())
+                                .await;
+                        });
+                        continue;
+                    }
+                    let (body_tx, body_rx) = mpsc::channel(REQUEST_BODY_QUEUE);
+                    let head = RequestHead {
+                        id,
+                        method,
+                        path,
+                        headers,
+                    };
+                    let task = tokio::spawn(forward(
+                        self.proxy.clone(),
+                        self.proxy_url.clone(),
+                        head,
+                        body_rx,
+                        out_tx.clone(),
+                        done_tx.clone(),
+                    ));
+                    streams.insert(
+                        id,
+                        Stream {
+                            body: Some(body_tx),
+                            task,
+                        },
+                    );
+                    crate::telemetry::metrics()
+                        .tunnel_streams
+                        .set(streams.len() as i64);
+                }
+                RelayFrame::Body { id, data } => {
+                    let Some(body) = streams.get(&id).and_then(|s| s.body.as_ref()) else {
+                        continue;
+                    };
+                    // Never wait here: that would hold up every other stream.
+                    let error = match STANDARD.decode(data) {
+                        Ok(chunk) => match body.try_send(Bytes::from(chunk)) {
+                            Ok(()) | Err(mpsc::error::TrySendError::Closed(_)) => continue,
+                            Err(mpsc::error::TrySendError::Full(_)) => {
+                                tracing::warn!("Tunnel stream {} sent body past its credit", id);
+                                "request body exceeded flow control credit"
+                            }
+                        },
+                        Err(e) => {
+                            tracing::warn!("Invalid body on tunnel stream {}: {}", id, e);
+                            "invalid request body"
+                        }
+                    };
+                    if let Some(stream) = streams.remove(&id) {
+                        stream.task.abort();
+                    }
+                    let out = out_tx.clone();
+                    tokio::spawn(async move {
+                        let frame = AgentFrame::End {
+                            id,
+                            error: Some(error.to_string()),
+                        };
+                        let _ = out.send(frame.message()).await;
+                    });
+                }
+                RelayFrame::End { id } => {
+                    if let Some(stream) = streams.get_mut(&id) {
+                        stream.body = None;
+                    }
+                }
+                RelayFrame::Cancel { id } => {
+                    if let Some(stream) = streams.remove(&id) {
+                        stream.task.abort();
+                    }
+                }
+                RelayFrame::Unknown => tracing::trace!("Ignoring unknown relay frame"),
+            }
+        }
+
+        for (_, stream) in streams.drain() {
+            stream.task.abort();
+        }
+        crate::telemetry::metrics().tunnel_streams.set(0);
+        drop(out_tx);
+        let _ = tokio::time::timeout(Duration::from_secs(1), writer).await;
+    }
+}
+
+/// A stream being served: its request body queue, until the relay ends it.
+struct Stream {
+    body: Option<mpsc::Sender<Bytes>>,
+    task: JoinHandle<()>,
+}
+
+struct RequestHead {
+    id: u64,
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+}
+
+fn busy_response(id: u64) -> AgentFrame {
+    AgentFrame::Response {
+        id,
+        status: 503,
+        headers: vec![("retry-after".to_string(), "1".to_string())],
+    }
+}
+
+fn is_hop_by_hop(name: &str) -> bool {
+    name.eq_ignore_ascii_case("host")
+        || crate::proxy::HOP_BY_HOP
+            .iter()
+            .any(|h| name.eq_ignore_ascii_case(h))
+}
+
+/// Replay one stream against the local proxy and send the response back.
+async fn forward(
+    client: reqwest::Client,
+    proxy_url: String,
+    head: RequestHead,
+    body: mpsc::Receiver<Bytes>,
+    out: mpsc::Sender<Message>,
+    done: mpsc::UnboundedSender<u64>,
+) {
+    let id = head.id;
+    let result = forward_inner(&client, &proxy_url, head, body, &out).await;
+    let error = result.err().map(|e| format!("{:#}", e));
+    if let Some(error) = &error {
+        tracing::debug!("Tunnel stream {} failed: {}", id, error);
+    }
+    let _ = out.send(AgentFrame::End { id, error }.message()).await;
+    let _ = done.send(id);
+}
+
+async fn forward_inner(
+    client: &reqwest::Client,
+    proxy_url: &str,
+    head: RequestHead,
+    body: mpsc::Receiver<Bytes>,
+    out: &mpsc::Sender<Message>,
+) -> Result<()> {
+    let id = head.id;
+    let method = reqwest::Method::from_bytes(head.method.as_bytes())
+        .with_context(|| format!("Invalid method '{}'", head.method))?;
+    if !head.path.starts_with('/') {
+        anyhow::bail!("Invalid path '{}'", head.path);
+    }
+    let mut request = client.request(method, format!("{}{}", proxy_url, head.path));
+    for (name, value) in &head.headers {
+        if !is_hop_by_hop(name) {
+            request = request.header(name, value);
+        }
+    }
+    // Grant the relay more body frames as they are consumed.
+    let credit = out.clone();
+    let body = futures_util::stream::unfold((body, 0), move |(mut rx, consumed)| {
+        let credit = credit.clone();
+        async move {
+            let chunk = rx.recv().await?;
+            let mut consumed = consumed + 1;
+            if consumed == CREDIT_BATCH {
+                let frame = AgentFrame::Credit {
+                    id,
+                    frames: consumed,
+                };
+                let _ = credit.send(frame.message()).await;
+                consumed = 0;
+            }
+            Some((Ok::<_, std::io::Error>(chunk), (rx, consumed)))
+        }
+    });
+    let response = request
+        .body(reqwest::Body::wrap_stream(body))
+        .send()
+        .await
+        .context("Proxy request failed")?;
+
+    let headers = response
+        .headers()
+        .iter()
+        .filter(|(name, _)| !is_hop_by_hop(name.as_str()))
+        .filter_map(|(name, value)| {
+            value
+                .to_str()
+                .ok()
+                .map(|v| (name.as_str().to_string(), v.to_string()))
+        })
+        .collect();
+    let frame = AgentFrame::Response {
+        id,
+        status: response.status().as_u16(),
+        headers,
+    };
+    out.send(frame.message())
+        .await
+        .context("Relay tunnel closed")?;
+
+    let mut chunks = response.bytes_stream();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.context("Proxy response failed")?;
+        for part in chunk.chunks(MAX_CHUNK) {
+            let frame = AgentFrame::Body {
+                id,
+                data: STANDARD.encode(part),
+            };
+            // Waits while the relay is behind.
+            out.send(frame.message())
+                .await
+                .context("Relay tunnel closed")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
+    use axum::extract::State;
+    use axum::http::HeaderMap;
+    use axum::routing::{any, post};
+    use axum::Router;
+
+    use crate::test_support;
+
+    /// A relay stand-in: each connection is handed to the test to script.
+    type Connections = mpsc::UnboundedSender<WebSocket>;
+
+    async fn relay(
+        ws: WebSocketUpgrade,
+        State(connections): State<Connections>,
+    ) -> axum::response::Response {
+        ws.on_upgrade(move |socket| async move {
+            let _ = connections.send(socket);
+        })
+    }
+
+    async fn send(socket: &mut WebSocket, frame: serde_json::Value) {
+        socket
+            .send(WsMessage::text(frame.to_string()))
+            .await
+            .unwrap();
+    }
+
+    /// Collect frames from the agent until every stream in `ids` has ended.
+    async fn collect(socket: &mut WebSocket, ids: &[u64]) -> HashMap<u64, Vec<serde_json::Value>> {
+        let mut frames: HashMap<u64, Vec<serde_json::Value>> = HashMap::new();
+        let mut ended = 0;
+        while ended < ids.len() {
+            let Some(Ok(WsMessage::Text(text))) = socket.recv().await else {
+                panic!("tunnel closed early");
+            };
+            let frame: serde_json::Value = serde_json::from_str(&text).unwrap();
+            if frame["type"] == "end" {
+                ended += 1;
+            }
+            frames
+                .entry(frame["id"].as_u64().unwrap())
+                .or_default()
+                .push(frame);
+        }
+        frames
+    }
+
+    fn body_of(frames: &[serde_json::Value]) -> String {
+        let bytes: Vec<u8> = frames
+            .iter()
+            .filter(|f| f["type"] == "body")
+            .flat_map(|f| STANDARD.decode(f["data"].as_str().unwrap()).unwrap())
+            .collect();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_streams_through_local_relay() {
+        // Stands in for the authenticating proxy.
+        let proxy = Router::new().route(
+            "/v1/echo",
+            post(|headers: HeaderMap, body: String| async move {
+                let tag = headers["x-test"].to_str().unwrap().to_string();
+                format!("{}:{}", tag, body.to_uppercase())
+            }),
+        );
+        let proxy_addr: SocketAddr = test_support::serve(proxy)
+            .await
+            .trim_start_matches("http://")
+            .parse()
+            .unwrap();
+
+        let (connections, mut accepted) = mpsc::unbounded_channel();
+        let platform = test_support::serve(
+            Router::new()
+                .route("/v1/agents/tunnel", any(relay))
+                .with_state(connections),
+        )
+        .await;
+        let (client, mut url) = TunnelClient::new(
+            test_support::config(&platform),
+            Arc::new(Mutex::new("test-key".to_string())),
+            "test-agent".to_string(),
+            proxy_addr,
+        )
+        .unwrap();
+        assert_eq!(client.max_streams, MAX_STREAMS_PER_SLOT);
+        let shutdown = CancellationToken::new();
+        let handle = client.spawn(shutdown.clone());
+
+        let mut socket = accepted.recv().await.unwrap();
+        send(
+            &mut socket,
+            serde_json::json!({"type": "hello", "url": "https://relay.test/a1"}),
+        )
+        .await;
+        url.changed().await.unwrap();
+        assert_eq!(url.borrow().as_deref(), Some("https://relay.test/a1"));
+
+        // Fill every stream slot with a request whose body is unfinished, so
+        // the next request is over the limit.
+        let limit = MAX_STREAMS_PER_SLOT as u64;
+        for id in 1..=limit {
+            send(
+                &mut socket,
+                serde_json::json!({
+                    "type": "request", "id": id, "method": "POST", "path": "/v1/echo",
+                    "headers": [["x-test", format!("s{}", id)], ["connection", "close"]],
+                }),
+            )
+            .await;
+            send(
+                &mut socket,
+                serde_json::json!({"type": "body", "id": id, "data": STANDARD.encode("hel")}),
+            )
+            .await;
+        }
+        send(&mut socket, serde_json::json!({
+            "type": "request", "id": limit + 1, "method": "POST", "path": "/v1/echo", "headers": [],
+        }))
+        .await;
+        let frames = collect(&mut socket, &[limit + 1]).await;
+        assert_eq!(frames[&(limit + 1)][0]["status"], 503);
+
+        for id in 1..=limit {
+            send(
+                &mut socket,
+                serde_json::json!({"type": "body", "id": id, "data": STANDARD.encode("lo")}),
+            )
+            .await;
+            send(&mut socket, serde_json::json!({"type": "end", "id": id})).await;
+        }
+        let ids: Vec<u64> = (1..=limit).collect();
+        let frames = collect(&mut socket, &ids).await;
+        for id in ids {
+            let stream = &frames[&id];
+            assert_eq!(stream[0]["status"], 200);
+            assert_eq!(body_of(stream), format!("s{}:HELLO", id));
+            assert!(stream.last().unwrap().get("error").is_none());
+        }
+
+        // Consuming a batch of body frames grants the relay as many more.
+        let id = limit + 2;
+        send(
+            &mut socket,
+            serde_json::json!({
+                "type": "request", "id": id, "method": "POST", "path": "/v1/echo",
+                "headers": [["x-test", "credit"]],
+            }),
+        )
+        .await;
+        for _ in 0..CREDIT_BATCH {
+            send(
+                &mut socket,
+                serde_json::json!({"type": "body", "id": id, "data": STANDARD.encode("x")}),
+            )
+            .await;
+        }
+        send(&mut socket, serde_json::json!({"type": "end", "id": id})).await;
+        let frames = collect(&mut socket, &[id]).await;
+        assert!(frames[&id]
+            .iter()
+            .any(|f| f["type"] == "credit" && f["frames"] == CREDIT_BATCH));
+        assert_eq!(
+            body_of(&frames[&id]),
+            format!("credit:{}", "X".repeat(CREDIT_BATCH))
+        );
+
+        // The relay drops the tunnel; the agent reconnects and gets a new URL.
+        drop(socket);
+        let mut socket = accepted.recv().await.unwrap();
+        send(
+            &mut socket,
+            serde_json::json!({"type": "hello", "url": "https://relay.test/a2"}),
+        )
+        .await;
+        url.changed().await.unwrap();
+        assert_eq!(url.borrow().as_deref(), Some("https://relay.test/a2"));
+
+        shutdown.cancel();
+        handle.await.unwrap();
+    }
+}
@@ -0,0 +1,431 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Non-streamed response bodies larger than this are not parsed for usage.
+const MAX_CAPTURED_BODY: usize = 4 * 1024 * 1024;
+/// Records kept in memory for `vramsply top`: the last hour, at most this many.
+const RECENT_WINDOW_SECS: u64 = 3600;
+const MAX_RECENT: usize = 10_000;
+
+/// One request served through the proxy.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct UsageRecord {
+    /// The id of the token that authorized the request.
+    pub request_id: String,
+    /// The client's `X-Request-Id` header, if it sent one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_request_id: Option<String>,
+    pub model: String,
+    /// When the request arrived, as unix seconds.
+    pub started_at: u64,
+    /// Time until the response finished streaming.
+    pub latency_ms: u64,
+    /// HTTP status returned by llama-server.
+    pub status: u16,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Prices in effect when the request was served, in cents per million tokens.
+    pub input_price_per_million: u32,
+    pub output_price_per_million: u32,
+}
+
+impl UsageRecord {
+    /// Expected earnings for this request, in cents.
+    pub fn earnings_cents(&self) -> f64 {
+        (self.prompt_tokens as f64 * self.input_price_per_million as f64
+            + self.completion_tokens as f64 * self.output_price_per_million as f64)
+            / 1_000_000.0
+    }
+}
+
+/// Append-only JSONL ledger of served requests.
+///
+/// Records are written on a thread of their own, so metering a request (from
+/// a response stream on the runtime) never waits on the disk.
+#[derive(Debug)]
+pub struct UsageLedger {
+    writes: Option<mpsc::Sender<LedgerWrite>>,
+    writer: Option<thread::JoinHandle<()>>,
+    /// Requests served in the last hour, oldest first.
+    recent: Mutex<VecDeque<UsageRecord>>,
+}
+
+#[derive(Debug)]
+enum LedgerWrite {
+    Record(UsageRecord),
+    /// Acknowledged once everything queued before it is written.
+    #[cfg(test)]
+    Flush(mpsc::Sender<()>),
+}
+
+impl UsageLedger {
+    /// Without a path records are only logged, not stored.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let (writes, writer) = match path.map(spawn_writer) {
+            Some(Ok((writes, writer))) => (Some(writes), Some(writer)),
+            Some(Err(e)) => {
+                tracing::warn!("Failed to start usage ledger writer: {}", e);
+                (None, None)
+            }
+            None => (None, None),
+        };
+        UsageLedger {
+            writes,
+            writer,
+            recent: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Requests served in the last hour, oldest first.
+    pub fn recent(&self) -> Vec<UsageRecord> {
+        let mut recent = self.recent.lock().expect("usage ledger lock poisoned");
+        prune_recent(&mut recent, crate::outbox::unix_now());
+        recent.iter().cloned().collect()
+    }
+
+    /// Record a served request. Failing to write is logged, never fatal.
+    pub fn record(&self, record: &UsageRecord) {
+        tracing::debug!(
+            "Served {} ({}): {} prompt + {} completion tokens in {}ms",
+            record.request_id,
+            record.model,
+            record.prompt_tokens,
+            record.completion_tokens,
+            record.latency_ms
+        );
+        {
+            let mut recent = self.recent.lock().expect("usage ledger lock poisoned");
+            recent.push_back(record.clone());
+            prune_recent(&mut recent, crate::outbox::unix_now());
+        }
+        if let Some(writes) = &self.writes {
+            let _ = writes.send(LedgerWrite::Record(record.clone()));
+        }
+    }
+
+    /// Wait until every record so far is on disk.
+    #[cfg(test)]
+    pub fn flush(&self) {
+        if let Some(writes) = &self.writes {
+            let (tx, rx) = mpsc::channel();
+            if writes.send(LedgerWrite::Flush(tx)).is_ok() {
+                let _ = rx.recv();
+            }
+        }
+    }
+}
+
+impl Drop for UsageLedger {
+    /// Finish writing queued records before the ledger goes away.
+    fn drop(&mut self) {
+        self.writes.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn spawn_writer(
+    path: PathBuf,
+) -> std::io::Result<(mpsc::Sender<LedgerWrite>, thread::JoinHandle<()>)> {
+    let (tx, rx) = mpsc::channel::<LedgerWrite>();
+    let writer = thread::Builder::new()
+        .name("usage-ledger".to_string())
+        .spawn(move || {
+            for write in rx {
+                match write {
+                    LedgerWrite::Record(record) => {
+                        if let Err(e) = append_record(&path, &record) {
+                            tracing::warn!("Failed to write usage ledger: {:#}", e);
+                        }
+                    }
+                    #[cfg(test)]
+                    LedgerWrite::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        })?;
+    Ok((tx, writer))
+}
+
+fn prune_recent(recent: &mut VecDeque<UsageRecord>, now: u64) {
+    let cutoff = now.saturating_sub(RECENT_WINDOW_SECS);
+    while recent
+        .front()
+        .is_some_and(|r| r.started_at < cutoff || recent.len() > MAX_RECENT)
+    {
+        recent.pop_front();
+    }
+}
+
+/// Default location of the usage ledger.
+pub fn ledger_path() -> Result<PathBuf> {
+    Ok(crate::config::state_dir()?.join("usage.jsonl"))
+}
+
+fn append_record(path: &Path, record: &UsageRecord) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed creating directory {}", parent.display()))?;
+    }
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed opening usage ledger {}", path.display()))?;
+    file.write_all(line.as_bytes())
+        .with_context(|| format!("Failed writing usage ledger {}", path.display()))?;
+    Ok(())
+}
+
+/// Read up to `limit` records starting at byte `offset` of a ledger, skipping
+/// lines that do not parse. Returns them with the offset just past the last
+/// line read; a final line still being written is left for the next read.
+pub fn read_ledger_from(path: &Path, offset: u64, limit: usize) -> Result<(Vec<UsageRecord>, u64)> {
+    let mut records = Vec::new();
+    if !path.exists() {
+        return Ok((records, offset));
+    }
+    let mut file = fs::File::open(path)
+        .with_context(|| format!("Failed opening usage ledger {}", path.display()))?;
+    file.seek(SeekFrom::Start(offset))
+        .with_context(|| format!("Failed seeking usage ledger {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut end = offset;
+    let mut line = Vec::new();
+    while records.len() < limit {
+        line.clear();
+        let n = reader
+            .read_until(b'\n', &mut line)
+            .with_context(|| format!("Failed reading usage ledger {}", path.display()))?;
+        if n == 0 || !line.ends_with(b"\n") {
+            break;
+        }
+        end += n as u64;
+        if let Ok(record) = serde_json::from_slice(&line) {
+            records.push(record);
+        }
+    }
+    Ok((records, end))
+}
+
+/// Read every record from a ledger, skipping lines that do not parse.
+pub fn read_ledger(path: &Path) -> Result<Vec<UsageRecord>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed reading usage ledger {}", path.display()))?;
+    Ok(contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// Totals for one model.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UsageTotals {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub earnings_cents: f64,
+}
+
+impl UsageTotals {
+    fn add(&mut self, record: &UsageRecord) {
+        self.requests += 1;
+        self.prompt_tokens += record.prompt_tokens;
+        self.completion_tokens += record.completion_tokens;
+        self.earnings_cents += record.earnings_cents();
+    }
+}
+
+/// Sum successful requests started at or after `since`, per model.
+pub fn summarize(records: &[UsageRecord], since: u64) -> BTreeMap<String, UsageTotals> {
+    let mut totals: BTreeMap<String, UsageTotals> = BTreeMap::new();
+    for record in records
+        .iter()
+        .filter(|r| r.started_at >= since && (200..300).contains(&r.status))
+    {
+        totals.entry(record.model.clone()).or_default().add(record);
+    }
+    totals
+}
+
+/// Picks token counts out of a response body as it streams past.
+///
+/// Understands OpenAI-style `usage` objects, llama-server's native
+/// `tokens_evaluated`/`tokens_predicted` and its `timings`, in both plain JSON
+/// responses and server-sent event streams (where the last chunk carrying
+/// counts wins).
+#[derive(Debug)]
+pub struct UsageCapture {
+    event_stream: bool,
+    buf: Vec<u8>,
+    overflowed: bool,
+    tokens: Option<(u64, u64)>,
+}
+
+impl UsageCapture {
+    pub fn new(event_stream: bool) -> Self {
+        UsageCapture {
+            event_stream,
+            buf: Vec::new(),
+            overflowed: false,
+            tokens: None,
+        }
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) {
+        if self.overflowed {
+            return;
+        }
+        self.buf.extend_from_slice(chunk);
+        if self.event_stream {
+            while let Some(end) = self.buf.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=end).collect();
+                self.parse_event_line(&line);
+            }
+        }
+        if self.buf.len() > MAX_CAPTURED_BODY {
+            self.buf = Vec::new();
+            self.overflowed = true;
+        }
+    }
+
+    /// Prompt and completion tokens seen in the body, if any.
+    pub fn finish(mut self) -> Option<(u64, u64)> {
+        if !self.overflowed {
+            let rest = std::mem::take(&mut self.buf);
+            if self.event_stream {
+                self.parse_event_line(&rest);
+            } else if let Ok(value) = serde_json::from_slice::<serde_json::Value>(&rest) {
+                self.tokens = token_counts(&value).or(self.tokens);
+            }
+        }
+        self.tokens
+    }
+
+    fn parse_event_line(&mut self, line: &[u8]) {
+        let Ok(line) = std::str::from_utf8(line) else {
+            return;
+        };
+        let Some(data) = line.trim().strip_prefix("data:") else {
+            return;
+        };
+        if let Ok(value) = serde_json::from_str::<serde_json::Value>(data.trim()) {
+            if let Some(tokens) = token_counts(&value) {
+                self.tokens = Some(tokens);
+            }
+        }
+    }
+}
+
+fn token_counts(value: &serde_json::Value) -> Option<(u64, u64)> {
+    let pair = |obj: &serde_json::Value, prompt: &str, completion: &str| {
+        Some((obj.get(prompt)?.as_u64()?, obj.get(completion)?.as_u64()?))
+    };
+    value
+        .get("usage")
+        .and_then(|u| pair(u, "prompt_tokens", "completion_tokens"))
+        .or_else(|| pair(value, "tokens_evaluated", "tokens_predicted"))
+        .or_else(|| {
+            value
+                .get("timings")
+                .and_then(|t| pair(t, "prompt_n", "predicted_n"))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(model: &str, started_at: u64, status: u16, tokens: (u64, u64)) -> UsageRecord {
+        UsageRecord {
+            request_id: format!("req-{}", started_at),
+            client_request_id: None,
+            model: model.to_string(),
+            started_at,
+            latency_ms: 100,
+            status,
+            prompt_tokens: tokens.0,
+            completion_tokens: tokens.1,
+            input_price_per_million: 100,
+            output_price_per_million: 200,
+        }
+    }
+
+    #[test]
+    fn test_capture_json_body() {
+        let mut capture = UsageCapture::new(false);
+        capture.feed(br#"{"choices":[],"usage":{"prompt_tokens":12,"#);
+        capture.feed(br#""completion_tokens":34,"total_tokens":46}}"#);
+        assert_eq!(capture.finish(), Some((12, 34)));
+
+        let mut native = UsageCapture::new(false);
+        native.feed(br#"{"content":"hi","tokens_evaluated":5,"tokens_predicted":7}"#);
+        assert_eq!(native.finish(), Some((5, 7)));
+    }
+
+    #[test]
+    fn test_capture_event_stream() {
+        let mut capture = UsageCapture::new(true);
+        capture.feed(b"data: {\"choices\":[{\"delta\":{\"content\":\"a\"}}]}\n\nda");
+        capture.feed(b"ta: {\"choices\":[],\"timings\":{\"prompt_n\":3,\"predicted_n\":9}}\n\n");
+        capture.feed(b"data: [DONE]\n\n");
+        assert_eq!(capture.finish(), Some((3, 9)));
+
+        assert_eq!(UsageCapture::new(true).finish(), None);
+    }
+
+    #[test]
+    fn test_ledger_round_trip_and_summary() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("usage.jsonl");
+        let ledger = UsageLedger::new(Some(path.clone()));
+        ledger.record(&record("llama", 10, 200, (1_000_000, 500_000)));
+        ledger.record(&record("llama", 20, 200, (0, 1_000_000)));
+        ledger.record(&record("llama", 30, 500, (50, 0)));
+        ledger.record(&record("qwen", 5, 200, (10, 10)));
+        ledger.flush();
+
+        let records = read_ledger(&path).unwrap();
+        assert_eq!(records.len(), 4);
+
+        let (first, offset) = read_ledger_from(&path, 0, 3).unwrap();
+        assert_eq!(first, records[..3]);
+        let (rest, end) = read_ledger_from(&path, offset, 10).unwrap();
+        assert_eq!(rest, records[3..]);
+        assert_eq!(end, fs::metadata(&path).unwrap().len());
+
+        // A line still being written is not consumed.
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"request_id\":").unwrap();
+        assert_eq!(read_ledger_from(&path, end, 10).unwrap(), (Vec::new(), end));
+
+        let totals = summarize(&records, 10);
+        assert_eq!(totals.len(), 1);
+        let llama = &totals["llama"];
+        assert_eq!(llama.requests, 2);
+        assert_eq!(llama.prompt_tokens, 1_000_000);
+        assert_eq!(llama.completion_tokens, 1_500_000);
+        assert_eq!(llama.earnings_cents, 400.0);
+
+        // Only the last hour is kept in memory.
+        assert!(ledger.recent().is_empty());
+        let now = crate::outbox::unix_now();
+        ledger.record(&record("llama", now, 200, (1, 1)));
+        assert_eq!(ledger.recent().len(), 1);
+    }
+}
@@ -37,6 +37,7 @@ struct VerificationCacheEntry {
 ///
 /// Returns the SHA-256 hex string of the model, or `"unverified"` if
 /// `skip_verify` is true.
+#[tracing::instrument(name = "verify_model", skip_all, fields(model = model_path, hf_repo = hf_repo_id))]
 pub async fn verify_model(model_path: &str, hf_repo_id: &str, skip_verify: bool) -> Result<String> {
     if skip_verify {
         return Ok("unverified".to_string());
@@ -75,9 +76,26 @@ pub async fn verify_model(model_path: &str, hf_repo_id: &str, skip_verify: bool)
         .unwrap_or(&lfs_info.oid)
         .to_string();
 
+    // A file of the wrong size cannot match; don't spend minutes hashing it.
+    if file_size != lfs_info.size {
+        anyhow::bail!(
+            "Model verification failed!\n  \
+             Expected size: {} bytes\n  \
+             Local size: {} bytes\n  \
+             The local file does not match the HuggingFace repository '{}'.",
+            lfs_info.size,
+            file_size,
+            hf_repo_id
+        );
+    }
+
     // Compute local hash
     println!("Verifying model integrity (this may take a moment for large files)...");
+    let started = std::time::Instant::now();
     let local_sha256 = compute_sha256(model_path)?;
+    crate::telemetry::metrics()
+        .verification_duration
+        .observe(started.elapsed().as_secs_f64());
 
     if local_sha256 != expected_hash {
         anyhow::bail!(